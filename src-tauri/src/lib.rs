@@ -4,13 +4,14 @@ use fancy_regex::Regex;
 use md5;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::io::{Read, Write};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::process; // 引入进程控制
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tauri::Emitter;
 // Tauri's `desktop` cfg alias is not consistently available during the
@@ -47,6 +48,19 @@ impl EpubCache {
 }
 
 static EPUB_CACHE: Lazy<Mutex<Option<EpubCache>>> = Lazy::new(|| Mutex::new(None));
+
+// --- TXT 大文件缓冲 ---
+// 超大 TXT 常驻后端，前端只按行区间读取/提交补丁，避免整本书反复走 IPC。
+struct TextBuffer {
+    path: String,
+    lines: Arc<Vec<String>>,
+    report: TextEncodingReport,
+    byte_len: u64,
+    modified: bool,
+}
+
+static TEXT_BUFFERS: Lazy<Mutex<HashMap<String, TextBuffer>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 static TOOLBOX_BATCH_CANCEL: Lazy<Mutex<HashSet<String>>> =
     Lazy::new(|| Mutex::new(HashSet::new()));
 static QUOTED_PATH_REF_RE: Lazy<Regex> =
@@ -69,6 +83,83 @@ fn lock_epub_cache() -> Result<std::sync::MutexGuard<'static, Option<EpubCache>>
         .map_err(|_| "EPUB 缓存已被中毒，无法访问".to_string())
 }
 
+/// 取当前已打开 EPUB 的解压目录，路径不一致视为缓存失效。
+fn cached_epub_temp_path(epub_path: &str) -> Result<PathBuf, String> {
+    let cache_guard = lock_epub_cache()?;
+    cache_guard
+        .as_ref()
+        .filter(|cache| cache.epub_path == epub_path)
+        .and_then(|cache| cache.temp_dir.as_ref())
+        .map(|temp| temp.path().to_path_buf())
+        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())
+}
+
+// 逐个改写已打开 EPUB 解压目录中的文本文件；rewrite 返回 Some 时落盘并同步 text_cache，返回变化的相对路径。
+fn rewrite_cached_epub_text_entries<F>(epub_path: &str, rewrite: F) -> Result<Vec<String>, String>
+where
+    F: FnMut(&str, &str) -> Option<String>,
+{
+    let updated = collect_cached_epub_text_rewrites(epub_path, rewrite)?;
+    commit_cached_epub_text_rewrites(epub_path, updated)
+}
+
+// 只计算改写结果不落盘，供需要“全部成功才写入”的调用方先行校验。
+fn collect_cached_epub_text_rewrites<F>(
+    epub_path: &str,
+    mut rewrite: F,
+) -> Result<Vec<(String, String)>, String>
+where
+    F: FnMut(&str, &str) -> Option<String>,
+{
+    let temp_path = cached_epub_temp_path(epub_path)?;
+    let mut updated: Vec<(String, String)> = Vec::new();
+    for entry in WalkDir::new(&temp_path).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(&temp_path)
+            .map_err(|e| format!("计算相对路径失败: {}", e))?
+            .to_string_lossy()
+            .replace('\\', "/");
+        if !is_text_like_entry(&relative) {
+            continue;
+        }
+        let Ok(text) = fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        if let Some(next) = rewrite(&relative, &text) {
+            updated.push((relative, next));
+        }
+    }
+    Ok(updated)
+}
+
+fn commit_cached_epub_text_rewrites(
+    epub_path: &str,
+    updated: Vec<(String, String)>,
+) -> Result<Vec<String>, String> {
+    let temp_path = cached_epub_temp_path(epub_path)?;
+    for (relative, next) in &updated {
+        fs::write(temp_path.join(relative), next).map_err(|e| format!("写入文件失败: {}", e))?;
+    }
+
+    let mut changed_files: Vec<String> = updated.iter().map(|(path, _)| path.clone()).collect();
+    let mut cache_guard = lock_epub_cache()?;
+    if let Some(ref mut cache) = *cache_guard {
+        if cache.epub_path == epub_path {
+            for (path, content) in updated {
+                if cache.text_cache.contains_key(&path) {
+                    cache.text_cache.insert(path, content);
+                }
+            }
+        }
+    }
+    changed_files.sort();
+    Ok(changed_files)
+}
+
 /// 读取 EPUB 文件字节，统一错误消息。
 fn read_epub_bytes(source: &Path) -> Result<Vec<u8>, String> {
     fs::read(source).map_err(|e| format!("读取 EPUB 失败: {}", e))
@@ -362,6 +453,49 @@ p.te-divider-line {
     margin: 1em 0;
 }
 
+/* 插图：正文中的 [img:文件名|图注] 标记 */
+.te-illustration {
+    text-align: center;
+    text-indent: 0;
+    duokan-text-indent: 0em;
+    margin: 1em 0;
+}
+
+.te-illustration-img {
+    max-width: 100%;
+    border: none;
+}
+
+p.te-illustration-caption {
+    text-align: center;
+    text-indent: 0;
+    duokan-text-indent: 0em;
+    font-size: 85%;
+    margin: 0.3em 0 0 0;
+}
+
+/* 注释：[1] 形式的上标引用，注释正文收在章节末尾 */
+a.te-noteref {
+    text-decoration: none;
+    font-size: 75%;
+}
+
+.te-footnote,
+ol.te-footnotes {
+    margin: 1em 0 0 0;
+    font-size: 85%;
+}
+
+p.te-footnote-text {
+    text-indent: 0;
+    duokan-text-indent: 0em;
+    margin: 0.3em 0;
+}
+
+a.te-footnote-backlink {
+    text-decoration: none;
+}
+
 /* 分割图：当模板提供 dividerImage 时，用图片替换孤立省略号 */
 .te-divider-image {
     text-align: center;
@@ -564,6 +698,8 @@ struct HistoryMeta {
     timestamp: u64,
     size: u64,
     date_str: String,
+    // 保存原因：manual / before_ai_proofing / before_batch_replace 等，旧版快照为空
+    reason: String,
 }
 
 #[derive(Serialize)]
@@ -604,10 +740,83 @@ struct EpubMetadata {
     subset_fonts: bool,
     #[serde(default)]
     assets: Vec<AssetInfo>,
+    // 源 TXT 路径，插图标记在素材列表里找不到时到它所在目录查找
+    #[serde(default)]
+    source_path: String,
+    #[serde(default)]
+    notes: NoteExportOptions,
+    // "3.0" 输出 EPUB 3（附 nav.xhtml，保留 toc.ncx 兼容旧阅读器），其余按 2.0
+    #[serde(default)]
+    epub_version: String,
+    // 单章正文超过阈值时拆成续页文件，0 表示不限
+    #[serde(default)]
+    split_max_kb: usize,
+    #[serde(default)]
+    split_max_paragraphs: usize,
+    // 须在 extra 之前，先取走已知字段，剩下的才进 extra
+    #[serde(flatten)]
+    opf: OpfMetadataExt,
     #[serde(flatten)]
     extra: HashMap<String, String>,
 }
 
+/// 作者 / 译者等人员；role 为 MARC relator 代码（aut、trl、edt、ill…）。
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct OpfContributor {
+    name: String,
+    #[serde(default)]
+    role: String,
+    #[serde(default)]
+    file_as: String,
+}
+
+/// 书籍自身 UUID 以外的标识符，scheme 如 ISBN、DOI。
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct OpfIdentifier {
+    #[serde(default)]
+    scheme: String,
+    value: String,
+}
+
+/// export_epub 与 write_opf_metadata 共用的扩展 OPF 元数据。
+/// EPUB 2 用 opf:role / opf:scheme 属性，EPUB 3 用 refines meta；系列同时写 calibre 与 belongs-to-collection。
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct OpfMetadataExt {
+    #[serde(default)]
+    language: String,
+    #[serde(default)]
+    creators: Vec<OpfContributor>,
+    #[serde(default)]
+    contributors: Vec<OpfContributor>,
+    #[serde(default)]
+    series: String,
+    #[serde(default)]
+    series_index: Option<f64>,
+    #[serde(default)]
+    identifiers: Vec<OpfIdentifier>,
+    #[serde(default)]
+    date: String,
+    #[serde(default)]
+    rights: String,
+    #[serde(default)]
+    original_title: String,
+}
+
+impl EpubMetadata {
+    fn is_epub3(&self) -> bool {
+        self.epub_version.trim().starts_with('3')
+    }
+
+    fn language_tag(&self) -> &str {
+        let language = self.opf.language.trim();
+        if language.is_empty() {
+            "zh-CN"
+        } else {
+            language
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct EpubTemplateRepository {
     id: String,
@@ -781,6 +990,157 @@ fn split_title(full_title: &str) -> (String, String) {
     (full_title.to_string(), "".to_string())
 }
 
+// 解析章节号：支持阿拉伯数字、全角数字、中文数字（一百零三、两千零五、十二）
+// 以及逐位写法（一〇三）和混写（十2）。
+fn parse_cjk_number(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let digit_value = |c: char| -> Option<u64> {
+        match c {
+            '0'..='9' => Some(c as u64 - '0' as u64),
+            '０'..='９' => Some(c as u64 - '０' as u64),
+            '零' | '〇' => Some(0),
+            '一' => Some(1),
+            '二' | '两' => Some(2),
+            '三' => Some(3),
+            '四' => Some(4),
+            '五' => Some(5),
+            '六' => Some(6),
+            '七' => Some(7),
+            '八' => Some(8),
+            '九' => Some(9),
+            _ => None,
+        }
+    };
+    let unit_value = |c: char| -> Option<u64> {
+        match c {
+            '十' => Some(10),
+            '百' => Some(100),
+            '千' => Some(1000),
+            '万' => Some(10_000),
+            '亿' => Some(100_000_000),
+            _ => None,
+        }
+    };
+
+    if !text.chars().any(|c| unit_value(c).is_some()) {
+        // 没有单位字：逐位拼接（123、１２３、一〇三）
+        let mut value: u64 = 0;
+        for c in text.chars() {
+            value = value.checked_mul(10)?.checked_add(digit_value(c)?)?;
+        }
+        return Some(value);
+    }
+
+    let (mut total, mut section, mut current) = (0u64, 0u64, 0u64);
+    let mut prev_was_ascii_digit = false;
+    for c in text.chars() {
+        if let Some(d) = digit_value(c) {
+            let is_ascii = c.is_ascii_digit() || ('０'..='９').contains(&c);
+            current = if is_ascii && prev_was_ascii_digit {
+                current.checked_mul(10)?.checked_add(d)?
+            } else {
+                d
+            };
+            prev_was_ascii_digit = is_ascii;
+            continue;
+        }
+        prev_was_ascii_digit = false;
+        let unit = unit_value(c)?;
+        if unit >= 10_000 {
+            section = section.checked_add(current)?;
+            total = total.checked_add(section.max(1).checked_mul(unit)?)?;
+            section = 0;
+        } else {
+            // “十二”省略了前面的“一”
+            let multiplier = if current == 0 && unit == 10 {
+                1
+            } else {
+                current
+            };
+            section = section.checked_add(multiplier.checked_mul(unit)?)?;
+        }
+        current = 0;
+    }
+    total.checked_add(section)?.checked_add(current)
+}
+
+// parse_cjk_number 的逆过程：103 → 一百零三，12 → 十二，10001 → 一万零一。
+fn format_cjk_number(n: u64) -> String {
+    const DIGITS: [char; 10] = ['零', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+    if n == 0 {
+        return "零".to_string();
+    }
+    let format_section = |value: u64| -> String {
+        let mut out = String::new();
+        let mut pending_zero = false;
+        for (div, unit) in [(1000, "千"), (100, "百"), (10, "十"), (1, "")] {
+            let digit = (value / div % 10) as usize;
+            if digit == 0 {
+                pending_zero = !out.is_empty();
+                continue;
+            }
+            if pending_zero {
+                out.push('零');
+                pending_zero = false;
+            }
+            out.push(DIGITS[digit]);
+            out.push_str(unit);
+        }
+        out
+    };
+
+    let mut out = String::new();
+    let mut higher_written = false;
+    for (div, unit) in [(100_000_000, "亿"), (10_000, "万"), (1, "")] {
+        let section = if div == 100_000_000 {
+            n / div
+        } else {
+            n / div % 10_000
+        };
+        if section == 0 {
+            continue;
+        }
+        if higher_written && section < 1000 {
+            out.push('零');
+        }
+        out.push_str(&format_section(section));
+        out.push_str(unit);
+        higher_written = true;
+    }
+    match out.strip_prefix("一十") {
+        Some(rest) => format!("十{}", rest),
+        None => out,
+    }
+}
+
+static CHAPTER_NUMBER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)^\s*(?:第\s*([0-9０-９零〇一二两三四五六七八九十百千万亿]+)\s*([卷部集章回节])|chapter\s*(\d+)|(\d+)(?:\s|[.．、:：]|$))",
+    )
+    .expect("valid chapter number regex")
+});
+
+// 从标题中取章节号与单位字（章/回/节/卷…；Chapter 与纯编号记为空）。
+// 先经 split_title 拆出编号部分，拆不出时再看整行。
+fn chapter_number_from_title(title: &str) -> Option<(u64, String)> {
+    let (num_part, _) = split_title(title);
+    for candidate in [num_part.as_str(), title] {
+        if let Ok(Some(caps)) = CHAPTER_NUMBER_RE.captures(candidate) {
+            if let Some(raw) = caps.get(1) {
+                let unit = caps.get(2).map_or("", |m| m.as_str()).to_string();
+                return parse_cjk_number(raw.as_str()).map(|n| (n, unit));
+            }
+            if let Some(raw) = caps.get(3).or_else(|| caps.get(4)) {
+                return raw.as_str().parse().ok().map(|n| (n, String::new()));
+            }
+        }
+    }
+    None
+}
+
 // --- 换行符规范化 ---
 // 将所有换行符（包括 Mac 旧时代的 \r 以及影响底层布局框架的特殊 Unicode 行分割符 U+2028）
 // 统一为正统的 \n，确保后端行号计算与 CodeMirror 编辑器的严格分行计算完全一致。
@@ -1010,6 +1370,79 @@ fn image_slot_html(role: &str, href: &str) -> String {
     )
 }
 
+// TXT 插图标记：[img:文件名] 或 [img:文件名|图注]
+static ILLUSTRATION_MARKER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\[img:([^\]|\n]+)(?:\|([^\]\n]*))?\]").expect("valid illustration marker regex")
+});
+
+/// 按出现顺序去重收集正文里的插图文件名。
+fn illustration_marker_names(content: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut names = Vec::new();
+    for caps in ILLUSTRATION_MARKER_RE.captures_iter(content).flatten() {
+        let name = caps.get(1).map(|m| m.as_str().trim()).unwrap_or("");
+        if !name.is_empty() && seen.insert(name.to_string()) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+fn illustration_html(href: &str, caption: &str) -> String {
+    let caption = escape_xml(caption.trim());
+    let caption_html = if caption.is_empty() {
+        String::new()
+    } else {
+        format!("<p class=\"te-illustration-caption\">{}</p>", caption)
+    };
+    format!(
+        "  <div class=\"te-illustration\"><img class=\"te-illustration-img\" src=\"../{}\" alt=\"{}\" />{}</div>\n",
+        escape_xml(href),
+        caption,
+        caption_html
+    )
+}
+
+/// 输出一个正文段落；能解析到文件的插图标记拆成独立插图块，解析不到的原样保留为文字。
+fn append_paragraph_with_illustrations(
+    html_body: &mut String,
+    text: &str,
+    illustrations: &HashMap<String, String>,
+) {
+    let mut pending = String::new();
+    let mut last = 0;
+    if !illustrations.is_empty() {
+        for caps in ILLUSTRATION_MARKER_RE.captures_iter(text).flatten() {
+            let (Some(whole), Some(name)) = (caps.get(0), caps.get(1)) else {
+                continue;
+            };
+            let Some(href) = illustrations.get(name.as_str().trim()) else {
+                continue;
+            };
+            pending.push_str(&text[last..whole.start()]);
+            if !pending.trim().is_empty() {
+                html_body.push_str(&format!(
+                    "  <p class=\"te-paragraph\">{}</p>\n",
+                    escape_xml(pending.trim())
+                ));
+            }
+            pending.clear();
+            html_body.push_str(&illustration_html(
+                href,
+                caps.get(2).map(|m| m.as_str()).unwrap_or(""),
+            ));
+            last = whole.end();
+        }
+    }
+    pending.push_str(&text[last..]);
+    if !pending.trim().is_empty() {
+        html_body.push_str(&format!(
+            "  <p class=\"te-paragraph\">{}</p>\n",
+            escape_xml(pending.trim())
+        ));
+    }
+}
+
 fn divider_image_html(role: &str, href: &str) -> String {
     let stem = asset_slot_class_stem(role);
     format!(
@@ -1062,6 +1495,23 @@ fn first_image_slot_for_placement<'a>(
         .map(|href| (fallback_role, href.as_str()))
 }
 
+const ELLIPSIS_MARKS: [char; 2] = ['…', '⋯'];
+const ELLIPSIS_DOTS: [char; 4] = ['.', '。', '．', '·'];
+
+fn is_ellipsis_char(c: char) -> bool {
+    ELLIPSIS_MARKS.contains(&c) || ELLIPSIS_DOTS.contains(&c)
+}
+
+// 标点规范化用的省略号：至少一个 … / ⋯，或连续三个以上的点（含 。 ． ·）；
+// 。 只在整串都是 。 时才算省略号，否则是真正的句号
+fn is_ellipsis_run(run: &str) -> bool {
+    !run.is_empty()
+        && run.chars().all(is_ellipsis_char)
+        && (!run.contains('。') || run.chars().all(|c| c == '。'))
+        && (run.chars().any(|c| ELLIPSIS_MARKS.contains(&c)) || run.chars().count() >= 3)
+}
+
+// 分隔符只认 … 与半角点，不受标点规范化的宽松写法影响
 fn is_ellipsis_paragraph(line: &str) -> bool {
     let compact: String = line.chars().filter(|c| !c.is_whitespace()).collect();
     if compact.is_empty() {
@@ -1074,6 +1524,7 @@ fn is_ellipsis_paragraph(line: &str) -> bool {
 fn append_text_body_lines(
     html_body: &mut String,
     body_lines: &[&str],
+    illustrations: &HashMap<String, String>,
     enable_dividers: bool,
     divider_image: Option<(&str, &str)>,
 ) {
@@ -1111,23 +1562,21 @@ fn append_text_body_lines(
                 html_body.push_str("  <p class=\"te-divider-line\">※※※</p>\n");
             }
         } else {
-            html_body.push_str(&format!(
-                "  <p class=\"te-paragraph\">{}</p>\n",
-                escape_xml(trim)
-            ));
+            append_paragraph_with_illustrations(html_body, trim, illustrations);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::append_text_body_lines;
+    use super::{append_text_body_lines, illustration_marker_names};
+    use std::collections::HashMap;
 
     #[test]
     fn isolated_ellipsis_becomes_divider() {
         let mut html = String::new();
         let lines = ["第一段", "……", "第二段"];
-        append_text_body_lines(&mut html, &lines, true, None);
+        append_text_body_lines(&mut html, &lines, &HashMap::new(), true, None);
 
         assert!(html.contains(r#"<p class="te-divider-line">※※※</p>"#));
         assert!(html.contains(r#"<p class="te-paragraph">第一段</p>"#));
@@ -1138,7 +1587,7 @@ mod tests {
     fn consecutive_ellipsis_stay_as_normal_paragraphs() {
         let mut html = String::new();
         let lines = ["第一段", "……", "……", "第二段"];
-        append_text_body_lines(&mut html, &lines, true, None);
+        append_text_body_lines(&mut html, &lines, &HashMap::new(), true, None);
 
         assert!(!html.contains(r#"<p class="te-divider-line">※※※</p>"#));
         assert_eq!(html.matches(r#"<p class="te-paragraph">……</p>"#).count(), 2);
@@ -1148,7 +1597,7 @@ mod tests {
     fn last_ellipsis_stays_as_normal_paragraph() {
         let mut html = String::new();
         let lines = ["第一段", "……"];
-        append_text_body_lines(&mut html, &lines, true, None);
+        append_text_body_lines(&mut html, &lines, &HashMap::new(), true, None);
 
         assert!(!html.contains(r#"<p class="te-divider-line">※※※</p>"#));
         assert!(html.contains(r#"<p class="te-paragraph">……</p>"#));
@@ -1158,12 +1607,23 @@ mod tests {
     fn blank_lines_do_not_break_isolated_ellipsis_detection() {
         let mut html = String::new();
         let lines = ["第一段", "", "   ", "……", "", "第二段"];
-        append_text_body_lines(&mut html, &lines, true, None);
+        append_text_body_lines(&mut html, &lines, &HashMap::new(), true, None);
 
         assert!(html.contains(r#"<p class="te-divider-line">※※※</p>"#));
         assert!(!html.contains(r#"<p class="te-paragraph"></p>"#));
     }
 
+    #[test]
+    fn only_baseline_ellipsis_forms_become_dividers() {
+        let mut html = String::new();
+        let lines = ["第一段", "。。。", "第二段", "···", "第三段"];
+        append_text_body_lines(&mut html, &lines, &HashMap::new(), true, None);
+
+        assert!(!html.contains("※※※"));
+        assert!(html.contains(r#"<p class="te-paragraph">。。。</p>"#));
+        assert!(html.contains(r#"<p class="te-paragraph">···</p>"#));
+    }
+
     #[test]
     fn divider_image_replaces_isolated_ellipsis_when_available() {
         let mut html = String::new();
@@ -1171,6 +1631,7 @@ mod tests {
         append_text_body_lines(
             &mut html,
             &lines,
+            &HashMap::new(),
             true,
             Some(("dividerImage", "Images/divider-image.png")),
         );
@@ -1178,6 +1639,30 @@ mod tests {
         assert!(html.contains(r#"<div class="te-divider-image"><img class="te-divider-image-img te-divider-img" src="../Images/divider-image.png" alt="分隔符" /></div>"#));
         assert!(!html.contains("※※※"));
     }
+
+    #[test]
+    fn illustration_markers_expand_to_figure_blocks() {
+        let mut html = String::new();
+        let lines = [
+            "[img:插图01.jpg|初见 <夜>]",
+            "前文[img:插图02.png]后文",
+            "[img:缺失.jpg|图注]",
+        ];
+        let illustrations = HashMap::from([
+            ("插图01.jpg".to_string(), "Images/插图01.jpg".to_string()),
+            ("插图02.png".to_string(), "Images/插图02.png".to_string()),
+        ]);
+        append_text_body_lines(&mut html, &lines, &illustrations, true, None);
+
+        assert!(html.contains(r#"<div class="te-illustration"><img class="te-illustration-img" src="../Images/插图01.jpg" alt="初见 &lt;夜&gt;" /><p class="te-illustration-caption">初见 &lt;夜&gt;</p></div>"#));
+        assert!(html.contains("  <p class=\"te-paragraph\">前文</p>\n  <div class=\"te-illustration\"><img class=\"te-illustration-img\" src=\"../Images/插图02.png\" alt=\"\" /></div>\n  <p class=\"te-paragraph\">后文</p>\n"));
+        // 找不到文件的标记保留原文，方便排查
+        assert!(html.contains(r#"<p class="te-paragraph">[img:缺失.jpg|图注]</p>"#));
+        assert_eq!(
+            illustration_marker_names(&lines.join("\n")),
+            vec!["插图01.jpg", "插图02.png", "缺失.jpg"]
+        );
+    }
 }
 
 fn is_zip_archive_bytes(data: &[u8]) -> bool {
@@ -3019,785 +3504,1382 @@ fn toolbox_image_convert_impl(
     ))
 }
 
-#[derive(Serialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct ToolboxBatchEvent {
-    task_id: String,
-    event: String,
-    level: String,
-    index: usize,
-    total: usize,
-    input_path: Option<String>,
-    output_path: Option<String>,
-    message: String,
-}
+// --- 简繁转换 ---
+// 词典为 OpenCC 格式（src-tauri/dict）：单字表 + 词组表，词组按最长匹配优先。
+// 内置表为精简版，未收录的词退回逐字转换，覆盖范围与替换方法见 dict/README.md。
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct ToolboxBatchSummary {
-    task_id: String,
-    total: usize,
-    succeeded: usize,
-    failed: usize,
-}
+const ST_CHARACTERS_DICT: &str = include_str!("../dict/st_characters.txt");
+const ST_PHRASES_DICT: &str = include_str!("../dict/st_phrases.txt");
+const TS_PHRASES_DICT: &str = include_str!("../dict/ts_phrases.txt");
+const TW_PHRASES_DICT: &str = include_str!("../dict/tw_phrases.txt");
+const TW_VARIANTS_DICT: &str = include_str!("../dict/tw_variants.txt");
+const HK_VARIANTS_DICT: &str = include_str!("../dict/hk_variants.txt");
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct ToolboxBatchScanResult {
-    input_paths: Vec<String>,
-    output_dir: String,
-}
+static CHINESE_CONVERTERS: Lazy<Mutex<HashMap<&'static str, Arc<ChineseConverter>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-fn emit_toolbox_batch_event(app: &tauri::AppHandle, event: ToolboxBatchEvent) {
-    let _ = app.emit("toolbox-batch-event", event);
+#[derive(Default)]
+struct ChineseConvertTable {
+    chars: HashMap<char, char>,
+    // 以词组首字索引，同一首字下长词在前
+    phrases: HashMap<char, Vec<(Vec<char>, String)>>,
 }
 
-fn format_toolbox_duration(duration: Duration) -> String {
-    let secs = duration.as_secs();
-    if secs >= 3600 {
-        format!("{}小时{}分{}秒", secs / 3600, (secs % 3600) / 60, secs % 60)
-    } else if secs >= 60 {
-        format!("{}分{}秒", secs / 60, secs % 60)
-    } else if secs >= 1 {
-        format!("{}秒", secs)
-    } else {
-        format!("{}毫秒", duration.as_millis())
+impl ChineseConvertTable {
+    fn insert_phrase(&mut self, from: &str, to: &str) {
+        let key: Vec<char> = from.chars().collect();
+        let Some(first) = key.first().copied() else {
+            return;
+        };
+        let entries = self.phrases.entry(first).or_default();
+        entries.retain(|(existing, _)| *existing != key);
+        entries.push((key, to.to_string()));
+    }
+
+    fn finish(mut self) -> Self {
+        for entries in self.phrases.values_mut() {
+            entries.sort_by_key(|(key, _)| std::cmp::Reverse(key.len()));
+        }
+        self
+    }
+
+    fn convert(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut out = String::with_capacity(text.len());
+        let mut index = 0usize;
+        while index < chars.len() {
+            let c = chars[index];
+            let phrase = self.phrases.get(&c).and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|(key, _)| chars[index..].starts_with(key))
+            });
+            if let Some((key, target)) = phrase {
+                out.push_str(target);
+                index += key.len();
+            } else {
+                out.push(self.chars.get(&c).copied().unwrap_or(c));
+                index += 1;
+            }
+        }
+        out
     }
 }
 
-fn append_toolbox_duration(message: String, duration: Duration) -> String {
-    format!("{}（耗时 {}）", message, format_toolbox_duration(duration))
+struct ChineseConverter {
+    tables: Vec<ChineseConvertTable>,
 }
 
-fn emit_toolbox_batch_stage_with_level(
-    app: &tauri::AppHandle,
-    task_id: &str,
-    index: usize,
-    total: usize,
-    input_path: &Path,
-    level: &str,
-    message: impl Into<String>,
-) {
-    emit_toolbox_batch_event(
-        app,
-        ToolboxBatchEvent {
-            task_id: task_id.to_string(),
-            event: "file-stage".to_string(),
-            level: level.to_string(),
-            index,
-            total,
-            input_path: Some(input_path.to_string_lossy().to_string()),
-            output_path: None,
-            message: message.into(),
-        },
-    );
+impl ChineseConverter {
+    fn convert(&self, text: &str) -> String {
+        if text.is_ascii() {
+            return text.to_string();
+        }
+        let mut out = text.to_string();
+        for table in &self.tables {
+            out = table.convert(&out);
+        }
+        out
+    }
 }
 
-fn emit_toolbox_batch_stage(
-    app: &tauri::AppHandle,
-    task_id: &str,
-    index: usize,
-    total: usize,
-    input_path: &Path,
-    message: impl Into<String>,
-) {
-    emit_toolbox_batch_stage_with_level(app, task_id, index, total, input_path, "info", message);
+fn parse_opencc_dict(text: &str) -> impl Iterator<Item = (&str, Vec<&str>)> {
+    text.lines().filter_map(|line| {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut parts = line.split_whitespace();
+        let key = parts.next()?;
+        let values: Vec<&str> = parts.collect();
+        (!values.is_empty()).then_some((key, values))
+    })
 }
 
-fn reset_toolbox_batch_cancel(task_id: &str) {
-    if let Ok(mut set) = TOOLBOX_BATCH_CANCEL.lock() {
-        set.remove(task_id);
+fn single_char(text: &str) -> Option<char> {
+    let mut chars = text.chars();
+    let c = chars.next()?;
+    chars.next().is_none().then_some(c)
+}
+
+fn build_s2t_table(extra_phrases: Option<&str>) -> ChineseConvertTable {
+    let mut table = ChineseConvertTable::default();
+    for (key, values) in parse_opencc_dict(ST_CHARACTERS_DICT) {
+        if let (Some(from), Some(to)) = (single_char(key), single_char(values[0])) {
+            if from != to {
+                table.chars.insert(from, to);
+            }
+        }
+    }
+    for dict in [Some(ST_PHRASES_DICT), extra_phrases].into_iter().flatten() {
+        for (key, values) in parse_opencc_dict(dict) {
+            table.insert_phrase(key, values[0]);
+        }
     }
+    table.finish()
 }
 
-fn request_toolbox_batch_cancel(task_id: &str) {
-    if let Ok(mut set) = TOOLBOX_BATCH_CANCEL.lock() {
-        set.insert(task_id.to_string());
+fn build_variant_table(dict: &str) -> ChineseConvertTable {
+    let mut table = ChineseConvertTable::default();
+    for (key, values) in parse_opencc_dict(dict) {
+        if let (Some(from), Some(to)) = (single_char(key), single_char(values[0])) {
+            table.chars.insert(from, to);
+        }
     }
+    table
 }
 
-fn is_toolbox_batch_cancelled(task_id: &str) -> bool {
-    TOOLBOX_BATCH_CANCEL
-        .lock()
-        .map(|set| set.contains(task_id))
-        .unwrap_or(false)
+// 繁转简：单字表所有候选反查回简体，台湾/香港字形先归到同一简体，再叠加例外词组。
+fn build_t2s_table() -> ChineseConvertTable {
+    let mut table = ChineseConvertTable::default();
+    for (key, values) in parse_opencc_dict(ST_CHARACTERS_DICT) {
+        let Some(simplified) = single_char(key) else {
+            continue;
+        };
+        for traditional in values.into_iter().filter_map(single_char) {
+            if traditional != simplified {
+                table.chars.insert(traditional, simplified);
+            }
+        }
+    }
+    for dict in [TW_VARIANTS_DICT, HK_VARIANTS_DICT] {
+        for (key, values) in parse_opencc_dict(dict) {
+            let (Some(from), Some(to)) = (single_char(key), single_char(values[0])) else {
+                continue;
+            };
+            let target = table
+                .chars
+                .get(&from)
+                .or_else(|| table.chars.get(&to))
+                .copied()
+                .unwrap_or(to);
+            for c in [from, to] {
+                if c != target {
+                    table.chars.entry(c).or_insert(target);
+                }
+            }
+        }
+    }
+    for (key, values) in parse_opencc_dict(ST_PHRASES_DICT) {
+        if key != values[0] {
+            table.insert_phrase(values[0], key);
+        }
+    }
+    for (key, values) in parse_opencc_dict(TS_PHRASES_DICT) {
+        table.insert_phrase(key, values[0]);
+    }
+    table.finish()
 }
 
-fn is_same_path(left: &Path, right: &Path) -> bool {
-    let left_canon = left.canonicalize().unwrap_or_else(|_| left.to_path_buf());
-    let right_canon = right.canonicalize().unwrap_or_else(|_| right.to_path_buf());
-    left_canon == right_canon
+fn normalize_chinese_profile(profile: &str) -> Result<&'static str, String> {
+    match profile.trim().to_ascii_lowercase().as_str() {
+        "s2t" => Ok("s2t"),
+        "t2s" | "tw2s" | "hk2s" => Ok("t2s"),
+        "s2tw" => Ok("s2tw"),
+        "s2twp" => Ok("s2twp"),
+        "s2hk" => Ok("s2hk"),
+        other => Err(format!("不支持的简繁转换方案: {}", other)),
+    }
 }
 
-fn path_is_under(path: &Path, parent: &Path) -> bool {
-    let path_canon = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
-    let parent_canon = parent
-        .canonicalize()
-        .unwrap_or_else(|_| parent.to_path_buf());
-    path_canon.starts_with(parent_canon)
+fn chinese_converter(profile: &str) -> Result<Arc<ChineseConverter>, String> {
+    let profile = normalize_chinese_profile(profile)?;
+    let mut converters = CHINESE_CONVERTERS
+        .lock()
+        .map_err(|_| "简繁转换词典缓存已被中毒".to_string())?;
+    let converter = converters.entry(profile).or_insert_with(|| {
+        let tables = match profile {
+            "t2s" => vec![build_t2s_table()],
+            "s2tw" => vec![build_s2t_table(None), build_variant_table(TW_VARIANTS_DICT)],
+            "s2twp" => vec![
+                build_s2t_table(Some(TW_PHRASES_DICT)),
+                build_variant_table(TW_VARIANTS_DICT),
+            ],
+            "s2hk" => vec![build_s2t_table(None), build_variant_table(HK_VARIANTS_DICT)],
+            _ => vec![build_s2t_table(None)],
+        };
+        Arc::new(ChineseConverter { tables })
+    });
+    Ok(converter.clone())
 }
 
-fn default_batch_output_dir(input_paths: &[String]) -> PathBuf {
-    let first = input_paths
-        .first()
-        .map(PathBuf::from)
-        .unwrap_or_else(|| PathBuf::from("."));
-    if first.is_dir() {
-        first.join("TEpub-batch-output")
-    } else {
-        first
-            .parent()
-            .unwrap_or(Path::new("."))
-            .join("TEpub-batch-output")
+fn markup_tag_end(tag: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    for (index, c) in tag.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return Some(index + 1),
+            _ => {}
+        }
     }
+    None
 }
 
-fn resolve_batch_output_dir(input_paths: &[String], output_dir: Option<String>) -> PathBuf {
-    output_dir
-        .filter(|value| !value.trim().is_empty())
-        .map(PathBuf::from)
-        .unwrap_or_else(|| default_batch_output_dir(input_paths))
+fn markup_tag_name(tag: &str) -> String {
+    let name: String = tag
+        .trim_start_matches('<')
+        .trim_start_matches('/')
+        .chars()
+        .take_while(|c| !c.is_whitespace() && *c != '/' && *c != '>')
+        .collect();
+    name.rsplit(':')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
 }
 
-fn build_output_path_in_dir(original_output: &Path, output_dir: &Path) -> PathBuf {
-    let file_name = original_output
-        .file_name()
-        .and_then(|value| value.to_str())
-        .unwrap_or("output.epub");
-    let candidate = output_dir.join(file_name);
-    if !candidate.exists() {
-        return candidate;
+const MARKUP_VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// 文本节点所在的元素（由外到内），保留起始标签原文以便按属性筛选。
+struct MarkupElement {
+    name: String,
+    tag: String,
+}
+
+impl MarkupElement {
+    fn has_class(&self, class: &str) -> bool {
+        parse_xmlish_attrs(&self.tag)
+            .get("class")
+            .is_some_and(|value| value.split_whitespace().any(|item| item == class))
     }
+}
 
-    let stem = original_output
-        .file_stem()
-        .and_then(|value| value.to_str())
-        .unwrap_or("output");
-    let ext = original_output
-        .extension()
-        .and_then(|value| value.to_str())
-        .unwrap_or("epub");
-    let mut index = 2usize;
-    loop {
-        let candidate = output_dir.join(format!("{}_{}.{}", stem, index, ext));
-        if !candidate.exists() {
-            return candidate;
+// 只处理标签之间的文本节点：标签与属性、注释、CDATA、script/style 内容原样保留。
+// 回调参数依次为文本、文本在 markup 中的字节偏移、所在元素链。
+fn map_markup_text_nodes_in_context<F>(markup: &str, mut map_text: F) -> String
+where
+    F: FnMut(&str, usize, &[MarkupElement]) -> String,
+{
+    let mut out = String::with_capacity(markup.len());
+    let mut rest = markup;
+    let mut stack: Vec<MarkupElement> = Vec::new();
+    while !rest.is_empty() {
+        let in_raw = stack
+            .last()
+            .is_some_and(|element| element.name == "script" || element.name == "style");
+        let offset = markup.len() - rest.len();
+        let (text, tail) = rest.split_at(rest.find('<').unwrap_or(rest.len()));
+        if !text.is_empty() {
+            if in_raw {
+                out.push_str(text);
+            } else {
+                out.push_str(&map_text(text, offset, &stack));
+            }
         }
-        index += 1;
+        if tail.is_empty() {
+            break;
+        }
+        let end = if tail.starts_with("<!--") {
+            tail.find("-->").map(|i| i + 3)
+        } else if tail.starts_with("<![CDATA[") {
+            tail.find("]]>").map(|i| i + 3)
+        } else {
+            markup_tag_end(tail)
+        };
+        let Some(end) = end else {
+            out.push_str(tail);
+            break;
+        };
+        let tag = &tail[..end];
+        if tag.starts_with("</") {
+            let name = markup_tag_name(tag);
+            let position = if in_raw {
+                stack
+                    .last()
+                    .filter(|e| e.name == name)
+                    .map(|_| stack.len() - 1)
+            } else {
+                stack.iter().rposition(|e| e.name == name)
+            };
+            if let Some(position) = position {
+                stack.truncate(position);
+            }
+        } else if !in_raw
+            && !tag.starts_with("<!")
+            && !tag.starts_with("<?")
+            && !tag.ends_with("/>")
+        {
+            let name = markup_tag_name(tag);
+            if !MARKUP_VOID_ELEMENTS.contains(&name.as_str()) {
+                stack.push(MarkupElement {
+                    name,
+                    tag: tag.to_string(),
+                });
+            }
+        }
+        out.push_str(tag);
+        rest = &tail[end..];
     }
+    out
 }
 
-fn finalize_toolbox_output(
-    mut result: ToolboxEpubToolResult,
-    source: &Path,
-    output_root: &Path,
-    output_mode: &str,
-    conflict_mode: &str,
-    input_roots: &[String],
-) -> Result<ToolboxEpubToolResult, String> {
-    if !result.changed {
-        return Ok(result);
-    }
+fn map_markup_text_nodes<F: FnMut(&str) -> String>(markup: &str, mut map_text: F) -> String {
+    map_markup_text_nodes_in_context(markup, |text, _, _| map_text(text))
+}
 
-    // 决定最终目标目录：
-    // - batch_dir（默认）：统一放到输出根目录
-    // - same_dir：放到源文件所在目录（与原文件并列）
-    // - preserve_structure：在输出根目录下按源文件相对输入根目录的子路径重建目录结构
-    let target_dir: PathBuf = match output_mode {
-        "same_dir" => source
-            .parent()
-            .map(|p| p.to_path_buf())
-            .unwrap_or_else(|| output_root.to_path_buf()),
-        "preserve_structure" => {
-            let rel = input_roots
-                .iter()
-                .filter_map(|root| {
-                    let root_path = Path::new(root);
-                    source.strip_prefix(root_path).ok().map(|rel| rel.to_path_buf())
-                })
-                .next();
-            match rel {
-                Some(rel) => {
-                    let parent = rel.parent().unwrap_or_else(|| Path::new(""));
-                    if parent.as_os_str().is_empty() {
-                        output_root.to_path_buf()
-                    } else {
-                        output_root.join(parent)
+static OPF_METADATA_BLOCK_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)(<(?:opf:)?metadata\b[^>]*>)(.*?)(</(?:opf:)?metadata>)")
+        .expect("valid opf metadata block regex")
+});
+static OPF_META_TAG_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<(?:opf:)?meta\b[^>]*>").expect("valid opf meta tag regex"));
+
+// OPF 只转换 metadata 内的文本（书名、作者、简介等）和 calibre:series 的 content。
+fn convert_opf_metadata_chinese(opf: &str, converter: &ChineseConverter) -> String {
+    OPF_METADATA_BLOCK_RE
+        .replace(opf, |caps: &fancy_regex::Captures| {
+            let inner = caps.get(2).map_or("", |m| m.as_str());
+            let inner = map_markup_text_nodes(inner, |text| converter.convert(text));
+            let inner = OPF_META_TAG_RE.replace_all(&inner, |meta: &fancy_regex::Captures| {
+                let tag = meta.get(0).map_or("", |m| m.as_str());
+                let attrs = parse_xmlish_attrs(tag);
+                match (attrs.get("name"), attrs.get("content")) {
+                    (Some(name), Some(content)) if name == "calibre:series" => {
+                        set_xmlish_attr(tag, "content", &converter.convert(content))
                     }
+                    _ => tag.to_string(),
                 }
-                None => output_root.to_path_buf(),
-            }
-        }
-        _ => output_root.to_path_buf(),
-    };
+            });
+            format!(
+                "{}{}{}",
+                caps.get(1).map_or("", |m| m.as_str()),
+                inner,
+                caps.get(3).map_or("", |m| m.as_str())
+            )
+        })
+        .to_string()
+}
 
-    fs::create_dir_all(&target_dir).map_err(|e| format!("创建输出目录失败: {}", e))?;
+fn is_markup_text_entry(path: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    lower.ends_with(".xhtml")
+        || lower.ends_with(".html")
+        || lower.ends_with(".htm")
+        || lower.ends_with(".ncx")
+}
 
-    let source_output = PathBuf::from(&result.output_path);
-    if !source_output.exists() {
-        return Ok(result);
-    }
+// 返回转换后有变化的条目；XHTML/nav 与 NCX 转换文本节点，OPF 只转换元数据。
+fn convert_epub_entry_chinese(
+    name: &str,
+    text: &str,
+    opf_path: &str,
+    converter: &ChineseConverter,
+) -> Option<String> {
+    let converted = if name == opf_path || name.to_ascii_lowercase().ends_with(".opf") {
+        convert_opf_metadata_chinese(text, converter)
+    } else if is_markup_text_entry(name) {
+        map_markup_text_nodes(text, |node| converter.convert(node))
+    } else {
+        return None;
+    };
+    (converted != text).then_some(converted)
+}
 
-    let file_name = source_output
-        .file_name()
-        .and_then(|value| value.to_str())
-        .unwrap_or("output.epub");
-    let target = target_dir.join(file_name);
+fn write_epub_with_text_replacements(
+    bytes: &[u8],
+    output_path: &Path,
+    replacements: &HashMap<String, String>,
+) -> Result<(), String> {
+    let out_file =
+        fs::File::create(output_path).map_err(|e| format!("创建输出 EPUB 失败: {}", e))?;
+    let mut writer = zip::ZipWriter::new(out_file);
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes.to_vec()))
+        .map_err(|e| format!("读取 EPUB 失败: {}", e))?;
 
-    // 已经在目标位置（例如 same_dir 模式下工具输出就在目标目录）
-    if is_same_path(&source_output, &target) {
-        return Ok(result);
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| format!("读取 ZIP 条目失败: {}", e))?;
+        let name = file.name().replace('\\', "/");
+        let options = FileOptions::default().compression_method(file.compression());
+        if name.ends_with('/') {
+            writer
+                .add_directory(&name, options)
+                .map_err(|e| format!("写入目录失败: {}", e))?;
+            continue;
+        }
+        let data = match replacements.get(&name) {
+            Some(text) => text.clone().into_bytes(),
+            None => {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)
+                    .map_err(|e| format!("读取条目数据失败: {}", e))?;
+                data
+            }
+        };
+        writer
+            .start_file(&name, options)
+            .map_err(|e| format!("写入文件失败: {}", e))?;
+        writer
+            .write_all(&data)
+            .map_err(|e| format!("写入文件内容失败: {}", e))?;
     }
 
-    // 冲突处理
-    if target.exists() {
-        match conflict_mode {
-            "skip" => {
-                // 目标已存在，跳过：删除刚生成的临时输出，标记未变更
-                let _ = fs::remove_file(&source_output);
-                result.changed = false;
-                result.message = format!("跳过（目标已存在）: {}", target.to_string_lossy());
-                result.output_path = target.to_string_lossy().to_string();
-                return Ok(result);
-            }
-            "overwrite" => {
-                fs::remove_file(&target).map_err(|e| format!("覆盖目标失败: {}", e))?;
-            }
-            _ => {
-                // auto_number（默认）：自动编号避免冲突
-                let alt = build_output_path_in_dir(&source_output, &target_dir);
-                match fs::rename(&source_output, &alt) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        fs::copy(&source_output, &alt)
-                            .map_err(|e| format!("复制到输出目录失败: {}", e))?;
-                        fs::remove_file(&source_output)
-                            .map_err(|e| format!("清理临时输出失败: {}", e))?;
-                    }
-                }
-                result.output_path = alt.to_string_lossy().to_string();
-                return Ok(result);
-            }
+    writer
+        .finish()
+        .map_err(|e| format!("完成写入失败: {}", e))?;
+    Ok(())
+}
+
+fn toolbox_chinese_convert_impl(
+    source: &Path,
+    profile: &str,
+) -> Result<ToolboxEpubToolResult, String> {
+    if !source.exists() {
+        return Err(format!("文件不存在: {}", source.to_string_lossy()));
+    }
+    let profile = normalize_chinese_profile(profile)?;
+    let converter = chinese_converter(profile)?;
+    let bytes = read_epub_bytes(source)?;
+    let opf_path = find_opf_path_in_epub_bytes(&bytes).unwrap_or_default();
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes.clone()))
+        .map_err(|e| format!("读取 EPUB 失败: {}", e))?;
+    let mut replacements: HashMap<String, String> = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| format!("读取 ZIP 条目失败: {}", e))?;
+        let name = file.name().replace('\\', "/");
+        if name.ends_with('/') || !is_text_like_entry(&name) {
+            continue;
+        }
+        let mut text = String::new();
+        if file.read_to_string(&mut text).is_err() {
+            continue;
+        }
+        if let Some(converted) = convert_epub_entry_chinese(&name, &text, &opf_path, &converter) {
+            replacements.insert(name, converted);
         }
     }
 
-    // 移动到目标
-    match fs::rename(&source_output, &target) {
-        Ok(_) => {}
-        Err(_) => {
-            fs::copy(&source_output, &target)
-                .map_err(|e| format!("复制到输出目录失败: {}", e))?;
-            fs::remove_file(&source_output).map_err(|e| format!("清理临时输出失败: {}", e))?;
-        }
+    let action = format!("chinese_{}", profile);
+    if replacements.is_empty() {
+        return Ok(toolbox_epub_tool_result(
+            source,
+            source,
+            false,
+            &action,
+            "未发现需要简繁转换的文本".to_string(),
+        ));
     }
-    result.output_path = target.to_string_lossy().to_string();
-    Ok(result)
+
+    let output_path = build_processed_epub_path(source, &format!("_{}", profile));
+    write_epub_with_text_replacements(&bytes, &output_path, &replacements)?;
+    Ok(toolbox_epub_tool_result(
+        source,
+        &output_path,
+        true,
+        &action,
+        format!(
+            "简繁转换完成（{}），更新 {} 个文件",
+            profile,
+            replacements.len()
+        ),
+    ))
 }
 
-fn list_epub_sources(input_paths: &[String], output_dir: &Path) -> Result<Vec<PathBuf>, String> {
-    let mut result = Vec::new();
-    let mut seen = HashSet::new();
-    for raw in input_paths {
-        let path = PathBuf::from(raw);
-        if path.is_file() {
-            let is_epub = path
-                .extension()
-                .and_then(|value| value.to_str())
-                .map(|value| value.eq_ignore_ascii_case("epub"))
-                .unwrap_or(false);
-            if is_epub {
-                let key = path.to_string_lossy().to_ascii_lowercase();
-                if seen.insert(key) {
-                    result.push(path);
-                }
-            }
-            continue;
-        }
-        if path.is_dir() {
-            for entry in WalkDir::new(&path).into_iter().filter_map(Result::ok) {
-                let item = entry.path();
-                if item.is_dir() && is_same_path(item, output_dir) {
-                    continue;
-                }
-                if !item.is_file() {
-                    continue;
-                }
-                if path_is_under(item, output_dir) {
-                    continue;
-                }
-                let is_epub = item
-                    .extension()
-                    .and_then(|value| value.to_str())
-                    .map(|value| value.eq_ignore_ascii_case("epub"))
-                    .unwrap_or(false);
-                if is_epub {
-                    let key = item.to_string_lossy().to_ascii_lowercase();
-                    if seen.insert(key) {
-                        result.push(item.to_path_buf());
-                    }
+#[tauri::command]
+fn toolbox_chinese_convert(
+    epub_path: String,
+    profile: String,
+) -> Result<ToolboxEpubToolResult, String> {
+    toolbox_chinese_convert_impl(&PathBuf::from(epub_path), &profile)
+}
+
+#[tauri::command]
+async fn convert_chinese_text(content: String, profile: String) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let converter = chinese_converter(&profile)?;
+        Ok(converter.convert(&content))
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+// 返回发生变化的行数
+#[tauri::command]
+async fn convert_text_buffer_chinese(buffer_id: String, profile: String) -> Result<usize, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let converter = chinese_converter(&profile)?;
+        let lines = text_buffer_lines(&buffer_id)?;
+        let mut changed = 0usize;
+        let converted: Vec<String> = lines
+            .iter()
+            .map(|line| {
+                let next = converter.convert(line);
+                if next != *line {
+                    changed += 1;
                 }
+                next
+            })
+            .collect();
+        if changed > 0 {
+            let mut buffers = lock_text_buffers()?;
+            let buffer = buffers
+                .get_mut(&buffer_id)
+                .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+            if !Arc::ptr_eq(&buffer.lines, &lines) {
+                return Err("内容已变化，请重新执行简繁转换".to_string());
             }
-            continue;
+            buffer.lines = Arc::new(converted);
+            buffer.modified = true;
         }
-        return Err(format!("路径不存在: {}", raw));
-    }
-    result.sort();
-    Ok(result)
+        Ok(changed)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
-fn collect_epub_sources(
-    app: &tauri::AppHandle,
-    task_id: &str,
-    input_paths: &[String],
-    output_dir: &Path,
-) -> Result<Vec<PathBuf>, String> {
-    let sources = list_epub_sources(input_paths, output_dir)?;
-    for (index, path) in sources.iter().enumerate() {
-        emit_toolbox_batch_event(
-            app,
-            ToolboxBatchEvent {
-                task_id: task_id.to_string(),
-                event: "scan-file".to_string(),
-                level: "info".to_string(),
-                index: index + 1,
-                total: sources.len(),
-                input_path: Some(path.to_string_lossy().to_string()),
-                output_path: None,
-                message: format!("扫描到 EPUB: {}", path.to_string_lossy()),
-            },
+// 直接改写已打开 EPUB 的解压目录，返回变化的文件（相对路径），保存仍走 save_epub_to_disk。
+#[tauri::command]
+async fn convert_epub_chinese(epub_path: String, profile: String) -> Result<Vec<String>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let converter = chinese_converter(&profile)?;
+        rewrite_cached_epub_text_entries(&epub_path, |path, text| {
+            convert_epub_entry_chinese(path, text, "", &converter)
+        })
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+#[cfg(test)]
+mod chinese_convert_tests {
+    use super::*;
+
+    #[test]
+    fn converts_simplified_to_traditional_with_phrases() -> Result<(), String> {
+        let s2t = chinese_converter("s2t")?;
+        assert_eq!(
+            s2t.convert("头发干燥，他干净利落地出发。"),
+            "頭髮乾燥，他乾淨利落地出發。"
         );
+        assert_eq!(s2t.convert("皇后说以后再来"), "皇后說以後再來");
+        assert_eq!(s2t.convert("计划里程"), "計劃里程");
+        assert_eq!(s2t.convert("plain ascii"), "plain ascii");
+        Ok(())
     }
-    Ok(sources)
-}
 
-fn run_toolbox_batch_impl(
-    app: tauri::AppHandle,
-    task_id: String,
-    tool: String,
-    input_paths: Vec<String>,
-    image_format: Option<String>,
-    output_dir: Option<String>,
-    output_mode: Option<String>,
-    conflict_mode: Option<String>,
-) -> Result<ToolboxBatchSummary, String> {
-    reset_toolbox_batch_cancel(&task_id);
-    let output_dir = resolve_batch_output_dir(&input_paths, output_dir);
-    let output_mode = output_mode
-        .map(|v| v.trim().to_string())
-        .filter(|v| !v.is_empty())
-        .unwrap_or_else(|| "batch_dir".to_string());
-    let conflict_mode = conflict_mode
-        .map(|v| v.trim().to_string())
-        .filter(|v| !v.is_empty())
-        .unwrap_or_else(|| "auto_number".to_string());
-    emit_toolbox_batch_event(
-        &app,
-        ToolboxBatchEvent {
-            task_id: task_id.clone(),
-            event: "scan-start".to_string(),
-            level: "info".to_string(),
-            index: 0,
-            total: 0,
-            input_path: None,
-            output_path: Some(output_dir.to_string_lossy().to_string()),
-            message: format!(
-                "正在扫描 EPUB 文件，输出目录: {}",
-                output_dir.to_string_lossy()
-            ),
-        },
-    );
-    let sources = collect_epub_sources(&app, &task_id, &input_paths, &output_dir)?;
-    let total = sources.len();
-    emit_toolbox_batch_event(
-        &app,
-        ToolboxBatchEvent {
-            task_id: task_id.clone(),
-            event: "started".to_string(),
-            level: "info".to_string(),
-            index: 0,
-            total,
-            input_path: None,
-            output_path: None,
-            message: format!("发现 {} 个 EPUB 文件", total),
-        },
-    );
-    if total == 0 {
-        emit_toolbox_batch_event(
-            &app,
-            ToolboxBatchEvent {
-                task_id: task_id.clone(),
-                event: "finished".to_string(),
-                level: "warning".to_string(),
-                index: 0,
-                total,
-                input_path: None,
-                output_path: None,
-                message: "未找到 EPUB 文件".to_string(),
-            },
+    #[test]
+    fn converts_regional_variants_and_back() -> Result<(), String> {
+        assert_eq!(chinese_converter("s2tw")?.convert("心里说"), "心裡說");
+        assert_eq!(chinese_converter("s2hk")?.convert("心里说"), "心裏説");
+        assert_eq!(chinese_converter("s2twp")?.convert("打开软件"), "打開軟體");
+        let t2s = chinese_converter("tw2s")?;
+        assert_eq!(
+            t2s.convert("心裡說著，這是名著，頭髮乾了"),
+            "心里说着，这是名著，头发干了"
         );
-        return Ok(ToolboxBatchSummary {
-            task_id,
-            total,
-            succeeded: 0,
-            failed: 0,
-        });
+        assert_eq!(t2s.convert("乾隆年間，心裏説"), "乾隆年间，心里说");
+        assert!(chinese_converter("x2y").is_err());
+        Ok(())
     }
 
-    let normalized_tool = tool.replace('-', "_");
-    let mut succeeded = 0usize;
-    let mut failed = 0usize;
-    let batch_started_at = Instant::now();
-    for (idx, source) in sources.iter().enumerate() {
-        let index = idx + 1;
-        if is_toolbox_batch_cancelled(&task_id) {
-            reset_toolbox_batch_cancel(&task_id);
-            emit_toolbox_batch_event(
-                &app,
-                ToolboxBatchEvent {
-                    task_id: task_id.clone(),
-                    event: "finished".to_string(),
-                    level: "warning".to_string(),
-                    index: idx,
-                    total,
-                    input_path: None,
-                    output_path: None,
-                    message: format!(
-                        "批量处理已取消：成功 {}，失败 {}，剩余 {}，总耗时 {}",
-                        succeeded,
-                        failed,
-                        total.saturating_sub(idx),
-                        format_toolbox_duration(batch_started_at.elapsed())
-                    ),
-                },
-            );
-            return Ok(ToolboxBatchSummary {
-                task_id,
-                total,
-                succeeded,
-                failed,
-            });
-        }
-        let file_started_at = Instant::now();
-        let mut slow_warned = false;
-        let mut cancel_stage_warned = false;
-        emit_toolbox_batch_event(
-            &app,
-            ToolboxBatchEvent {
-                task_id: task_id.clone(),
-                event: "file-start".to_string(),
-                level: "info".to_string(),
-                index,
-                total,
-                input_path: Some(source.to_string_lossy().to_string()),
-                output_path: None,
-                message: format!("开始处理 {}", source.to_string_lossy()),
-            },
+    #[test]
+    fn markup_conversion_only_touches_text_nodes() -> Result<(), String> {
+        let s2t = chinese_converter("s2t")?;
+        let html = r#"<p class="简体" title="说明">说话<!-- 说 --><script>var s = "说";</script><b>说</b></p>"#;
+        let converted = map_markup_text_nodes(html, |text| s2t.convert(text));
+        assert_eq!(
+            converted,
+            r#"<p class="简体" title="说明">說話<!-- 说 --><script>var s = "说";</script><b>說</b></p>"#
         );
-        emit_toolbox_batch_stage(&app, &task_id, index, total, source, "读取文件并准备处理");
+        let opf = r#"<package><metadata><dc:title>三体</dc:title><meta name="calibre:series" content="地球往事"/></metadata><manifest><item href="说.xhtml"/></manifest></package>"#;
+        let converted = convert_opf_metadata_chinese(opf, &s2t);
+        assert!(converted.contains("<dc:title>三體</dc:title>"));
+        assert!(converted.contains(r#"content="地球往事""#));
+        assert!(converted.contains(r#"href="说.xhtml""#));
+        Ok(())
+    }
+}
 
-        let result = match normalized_tool.as_str() {
-            "file_encrypt" => toolbox_file_encrypt_impl(source),
-            "file_decrypt" => {
-                let mut progress = |message: &str| {
-                    let elapsed = file_started_at.elapsed();
-                    if elapsed >= Duration::from_secs(30) && !slow_warned {
-                        slow_warned = true;
-                        emit_toolbox_batch_stage_with_level(
-                            &app,
-                            &task_id,
-                            index,
-                            total,
-                            source,
-                            "warning",
-                            format!(
-                                "当前文件处理超过 {}，仍在继续",
-                                format_toolbox_duration(elapsed)
-                            ),
-                        );
-                    }
-                    if is_toolbox_batch_cancelled(&task_id) && !cancel_stage_warned {
-                        cancel_stage_warned = true;
-                        emit_toolbox_batch_stage_with_level(
-                            &app,
-                            &task_id,
-                            index,
-                            total,
-                            source,
-                            "warning",
-                            "已收到取消请求，当前文件完成后停止",
-                        );
-                    }
-                    emit_toolbox_batch_stage(
-                        &app,
-                        &task_id,
-                        index,
-                        total,
-                        source,
-                        format!("{}（已用 {}）", message, format_toolbox_duration(elapsed)),
-                    );
-                };
-                toolbox_file_decrypt_impl_with_progress(source, &mut progress)
-            }
-            "font_encrypt" => toolbox_font_encrypt_impl(source),
-            "font_decrypt" => toolbox_font_decrypt_impl(source, None),
-            "epub_reformat" => toolbox_epub_reformat_impl(source),
-            "epub_diagnose" => toolbox_epub_diagnose_tool_result(source),
-            "image_convert" => toolbox_image_convert_impl(source, image_format.as_deref()),
-            _ => Err(format!("不支持的批量工具: {}", tool)),
-        }
-        .and_then(|done| {
-            emit_toolbox_batch_stage(&app, &task_id, index, total, source, "整理输出文件");
-            finalize_toolbox_output(
-                done,
-                source,
-                &output_dir,
-                &output_mode,
-                &conflict_mode,
-                &input_paths,
-            )
-        });
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ToolboxBatchEvent {
+    task_id: String,
+    event: String,
+    level: String,
+    index: usize,
+    total: usize,
+    input_path: Option<String>,
+    output_path: Option<String>,
+    message: String,
+}
 
-        match result {
-            Ok(done) => {
-                let elapsed = file_started_at.elapsed();
-                succeeded += 1;
-                emit_toolbox_batch_event(
-                    &app,
-                    ToolboxBatchEvent {
-                        task_id: task_id.clone(),
-                        event: "file-done".to_string(),
-                        level: if done.action == "epub_diagnose_clean" || done.changed {
-                            "info"
-                        } else {
-                            "warning"
-                        }
-                        .to_string(),
-                        index,
-                        total,
-                        input_path: Some(done.source_path),
-                        output_path: Some(done.output_path),
-                        message: append_toolbox_duration(done.message, elapsed),
-                    },
-                );
-            }
-            Err(error) => {
-                let elapsed = file_started_at.elapsed();
-                failed += 1;
-                emit_toolbox_batch_event(
-                    &app,
-                    ToolboxBatchEvent {
-                        task_id: task_id.clone(),
-                        event: "file-error".to_string(),
-                        level: "error".to_string(),
-                        index,
-                        total,
-                        input_path: Some(source.to_string_lossy().to_string()),
-                        output_path: None,
-                        message: append_toolbox_duration(error, elapsed),
-                    },
-                );
-            }
-        }
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolboxBatchSummary {
+    task_id: String,
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolboxBatchScanResult {
+    input_paths: Vec<String>,
+    output_dir: String,
+}
+
+fn emit_toolbox_batch_event(app: &tauri::AppHandle, event: ToolboxBatchEvent) {
+    let _ = app.emit("toolbox-batch-event", event);
+}
+
+fn format_toolbox_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}小时{}分{}秒", secs / 3600, (secs % 3600) / 60, secs % 60)
+    } else if secs >= 60 {
+        format!("{}分{}秒", secs / 60, secs % 60)
+    } else if secs >= 1 {
+        format!("{}秒", secs)
+    } else {
+        format!("{}毫秒", duration.as_millis())
     }
+}
+
+fn append_toolbox_duration(message: String, duration: Duration) -> String {
+    format!("{}（耗时 {}）", message, format_toolbox_duration(duration))
+}
 
+fn emit_toolbox_batch_stage_with_level(
+    app: &tauri::AppHandle,
+    task_id: &str,
+    index: usize,
+    total: usize,
+    input_path: &Path,
+    level: &str,
+    message: impl Into<String>,
+) {
     emit_toolbox_batch_event(
-        &app,
+        app,
         ToolboxBatchEvent {
-            task_id: task_id.clone(),
-            event: "finished".to_string(),
-            level: if failed == 0 { "info" } else { "warning" }.to_string(),
-            index: total,
+            task_id: task_id.to_string(),
+            event: "file-stage".to_string(),
+            level: level.to_string(),
+            index,
             total,
-            input_path: None,
+            input_path: Some(input_path.to_string_lossy().to_string()),
             output_path: None,
-            message: format!(
-                "批量处理完成：成功 {}，失败 {}，总耗时 {}",
-                succeeded,
-                failed,
-                format_toolbox_duration(batch_started_at.elapsed())
-            ),
+            message: message.into(),
         },
     );
-    reset_toolbox_batch_cancel(&task_id);
-    Ok(ToolboxBatchSummary {
-        task_id,
-        total,
-        succeeded,
-        failed,
-    })
 }
 
-const TOOLBOX_FONT_OBFUSCATION_SCRIPT: &str = r##"
-import json
-import html as html_lib
-import random
-import re
-import sys
-import unicodedata
-import zipfile
-from collections import Counter, defaultdict
-from io import BytesIO
-from urllib.parse import unquote
+fn emit_toolbox_batch_stage(
+    app: &tauri::AppHandle,
+    task_id: &str,
+    index: usize,
+    total: usize,
+    input_path: &Path,
+    message: impl Into<String>,
+) {
+    emit_toolbox_batch_stage_with_level(app, task_id, index, total, input_path, "info", message);
+}
 
-MAP_NAME = "META-INF/tepub-font-obfuscation.json"
+fn reset_toolbox_batch_cancel(task_id: &str) {
+    if let Ok(mut set) = TOOLBOX_BATCH_CANCEL.lock() {
+        set.remove(task_id);
+    }
+}
 
-def is_html_name(name):
-    lower = name.lower()
-    return lower.endswith(".html") or lower.endswith(".xhtml") or lower.endswith(".htm")
-
-def is_font_name(name):
-    lower = name.lower()
-    return lower.endswith(".ttf") or lower.endswith(".otf") or lower.endswith(".woff") or lower.endswith(".woff2")
+fn request_toolbox_batch_cancel(task_id: &str) {
+    if let Ok(mut set) = TOOLBOX_BATCH_CANCEL.lock() {
+        set.insert(task_id.to_string());
+    }
+}
 
-def decode_text(data):
-    for enc in ("utf-8", "utf-8-sig", "gb18030", "latin-1"):
-        try:
-            return data.decode(enc)
-        except UnicodeDecodeError:
-            pass
-    return data.decode("utf-8", errors="replace")
+fn is_toolbox_batch_cancelled(task_id: &str) -> bool {
+    TOOLBOX_BATCH_CANCEL
+        .lock()
+        .map(|set| set.contains(task_id))
+        .unwrap_or(false)
+}
 
-def is_private_use_char(ch):
-    code = ord(ch)
-    return 0xE000 <= code <= 0xF8FF or 0xF0000 <= code <= 0x10FFFD
+fn is_same_path(left: &Path, right: &Path) -> bool {
+    let left_canon = left.canonicalize().unwrap_or_else(|_| left.to_path_buf());
+    let right_canon = right.canonicalize().unwrap_or_else(|_| right.to_path_buf());
+    left_canon == right_canon
+}
 
-def is_cjk_ideograph(ch):
-    code = ord(ch)
-    return (
-        0x3400 <= code <= 0x4DBF
-        or 0x4E00 <= code <= 0x9FFF
-        or 0xF900 <= code <= 0xFAFF
-        or 0x20000 <= code <= 0x3FFFF
-    )
+fn path_is_under(path: &Path, parent: &Path) -> bool {
+    let path_canon = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let parent_canon = parent
+        .canonicalize()
+        .unwrap_or_else(|_| parent.to_path_buf());
+    path_canon.starts_with(parent_canon)
+}
 
-def should_map_char(ch):
-    if ch in "<>&":
-        return False
-    cat = unicodedata.category(ch)
-    if cat.startswith(("C", "Z", "P", "S", "N")):
-        return False
-    if is_private_use_char(ch):
-        return False
-    return is_cjk_ideograph(ch)
+fn default_batch_output_dir(input_paths: &[String]) -> PathBuf {
+    let first = input_paths
+        .first()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+    if first.is_dir() {
+        first.join("TEpub-batch-output")
+    } else {
+        first
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("TEpub-batch-output")
+    }
+}
 
-def tag_identity(tag):
-    body = tag.strip("<> \t\r\n")
-    closing = body.startswith("/")
-    if closing:
-        body = body[1:].lstrip()
-    if not body:
-        return closing, ""
-    name = body.split(None, 1)[0].split("/", 1)[0].lower()
-    return closing, name
+fn resolve_batch_output_dir(input_paths: &[String], output_dir: Option<String>) -> PathBuf {
+    output_dir
+        .filter(|value| !value.trim().is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_batch_output_dir(input_paths))
+}
 
-def iter_text_chars(html):
-    raw_tag = None
-    i = 0
-    while i < len(html):
-        ch = html[i]
-        if ch == "<":
-            end = html.find(">", i + 1)
-            if end == -1:
-                break
-            tag = html[i:end + 1]
-            closing, name = tag_identity(tag)
-            if name in ("script", "style"):
-                raw_tag = None if closing else name
-            i = end + 1
-            continue
-        if not raw_tag:
-            if ch == "&":
-                semi = html.find(";", i + 1, i + 16)
-                if semi != -1:
-                    i = semi + 1
-                    continue
-            if should_map_char(ch):
-                yield ch
-        i += 1
+fn build_output_path_in_dir(original_output: &Path, output_dir: &Path) -> PathBuf {
+    let file_name = original_output
+        .file_name()
+        .and_then(|value| value.to_str())
+        .unwrap_or("output.epub");
+    let candidate = output_dir.join(file_name);
+    if !candidate.exists() {
+        return candidate;
+    }
 
-def transform_text(html, table):
-    out = []
-    raw_tag = None
-    i = 0
-    while i < len(html):
-        ch = html[i]
-        if ch == "<":
-            end = html.find(">", i + 1)
-            if end == -1:
-                out.append(html[i:])
-                break
-            tag = html[i:end + 1]
-            out.append(tag)
-            closing, name = tag_identity(tag)
-            if name in ("script", "style"):
-                raw_tag = None if closing else name
-            i = end + 1
-            continue
-        if raw_tag:
-            out.append(ch)
-            i += 1
-            continue
-        if ch == "&":
-            semi = html.find(";", i + 1, i + 16)
-            if semi != -1:
-                out.append(html[i:semi + 1])
-                i = semi + 1
-                continue
-        out.append(table.get(ch, ch))
-        i += 1
-    return "".join(out)
+    let stem = original_output
+        .file_stem()
+        .and_then(|value| value.to_str())
+        .unwrap_or("output");
+    let ext = original_output
+        .extension()
+        .and_then(|value| value.to_str())
+        .unwrap_or("epub");
+    let mut index = 2usize;
+    loop {
+        let candidate = output_dir.join(format!("{}_{}.{}", stem, index, ext));
+        if !candidate.exists() {
+            return candidate;
+        }
+        index += 1;
+    }
+}
 
-def parse_attrs(tag_body):
-    attrs = {}
-    for m in re.finditer(r"([:\w.-]+)\s*=\s*(['\"])(.*?)\2", tag_body, flags=re.S):
-        attrs[m.group(1).lower()] = m.group(3)
-    return attrs
+fn finalize_toolbox_output(
+    mut result: ToolboxEpubToolResult,
+    source: &Path,
+    output_root: &Path,
+    output_mode: &str,
+    conflict_mode: &str,
+    input_roots: &[String],
+) -> Result<ToolboxEpubToolResult, String> {
+    if !result.changed {
+        return Ok(result);
+    }
 
-def parse_tag(tag):
-    body = tag.strip("<> \t\r\n")
-    if not body or body.startswith("!") or body.startswith("?"):
-        return True, "", True, {}
-    closing = body.startswith("/")
-    if closing:
-        body = body[1:].lstrip()
-    self_closing = body.endswith("/")
-    if self_closing:
-        body = body[:-1].rstrip()
-    if not body:
-        return closing, "", self_closing, {}
-    name = body.split(None, 1)[0].split("/", 1)[0].lower()
-    rest = body[len(name):]
-    return closing, name, self_closing, parse_attrs(rest)
+    // 决定最终目标目录：
+    // - batch_dir（默认）：统一放到输出根目录
+    // - same_dir：放到源文件所在目录（与原文件并列）
+    // - preserve_structure：在输出根目录下按源文件相对输入根目录的子路径重建目录结构
+    let target_dir: PathBuf = match output_mode {
+        "same_dir" => source
+            .parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| output_root.to_path_buf()),
+        "preserve_structure" => {
+            let rel = input_roots
+                .iter()
+                .filter_map(|root| {
+                    let root_path = Path::new(root);
+                    source.strip_prefix(root_path).ok().map(|rel| rel.to_path_buf())
+                })
+                .next();
+            match rel {
+                Some(rel) => {
+                    let parent = rel.parent().unwrap_or_else(|| Path::new(""));
+                    if parent.as_os_str().is_empty() {
+                        output_root.to_path_buf()
+                    } else {
+                        output_root.join(parent)
+                    }
+                }
+                None => output_root.to_path_buf(),
+            }
+        }
+        _ => output_root.to_path_buf(),
+    };
 
-def first_font_family(value):
-    if not value:
-        return ""
-    parts = []
-    buf = []
-    quote = None
-    for ch in value:
-        if quote:
-            if ch == quote:
-                quote = None
-            else:
-                buf.append(ch)
-            continue
-        if ch in ("'", '"'):
-            quote = ch
-            continue
-        if ch == ",":
-            part = "".join(buf).strip().strip("'\"")
-            if part:
-                parts.append(part)
-            buf = []
-            continue
-        buf.append(ch)
-    part = "".join(buf).strip().strip("'\"")
-    if part:
-        parts.append(part)
-    return parts[0].lower() if parts else ""
+    fs::create_dir_all(&target_dir).map_err(|e| format!("创建输出目录失败: {}", e))?;
 
-def font_family_from_style(style):
+    let source_output = PathBuf::from(&result.output_path);
+    if !source_output.exists() {
+        return Ok(result);
+    }
+
+    let file_name = source_output
+        .file_name()
+        .and_then(|value| value.to_str())
+        .unwrap_or("output.epub");
+    let target = target_dir.join(file_name);
+
+    // 已经在目标位置（例如 same_dir 模式下工具输出就在目标目录）
+    if is_same_path(&source_output, &target) {
+        return Ok(result);
+    }
+
+    // 冲突处理
+    if target.exists() {
+        match conflict_mode {
+            "skip" => {
+                // 目标已存在，跳过：删除刚生成的临时输出，标记未变更
+                let _ = fs::remove_file(&source_output);
+                result.changed = false;
+                result.message = format!("跳过（目标已存在）: {}", target.to_string_lossy());
+                result.output_path = target.to_string_lossy().to_string();
+                return Ok(result);
+            }
+            "overwrite" => {
+                fs::remove_file(&target).map_err(|e| format!("覆盖目标失败: {}", e))?;
+            }
+            _ => {
+                // auto_number（默认）：自动编号避免冲突
+                let alt = build_output_path_in_dir(&source_output, &target_dir);
+                match fs::rename(&source_output, &alt) {
+                    Ok(_) => {}
+                    Err(_) => {
+                        fs::copy(&source_output, &alt)
+                            .map_err(|e| format!("复制到输出目录失败: {}", e))?;
+                        fs::remove_file(&source_output)
+                            .map_err(|e| format!("清理临时输出失败: {}", e))?;
+                    }
+                }
+                result.output_path = alt.to_string_lossy().to_string();
+                return Ok(result);
+            }
+        }
+    }
+
+    // 移动到目标
+    match fs::rename(&source_output, &target) {
+        Ok(_) => {}
+        Err(_) => {
+            fs::copy(&source_output, &target)
+                .map_err(|e| format!("复制到输出目录失败: {}", e))?;
+            fs::remove_file(&source_output).map_err(|e| format!("清理临时输出失败: {}", e))?;
+        }
+    }
+    result.output_path = target.to_string_lossy().to_string();
+    Ok(result)
+}
+
+fn list_epub_sources(input_paths: &[String], output_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut result = Vec::new();
+    let mut seen = HashSet::new();
+    for raw in input_paths {
+        let path = PathBuf::from(raw);
+        if path.is_file() {
+            let is_epub = path
+                .extension()
+                .and_then(|value| value.to_str())
+                .map(|value| value.eq_ignore_ascii_case("epub"))
+                .unwrap_or(false);
+            if is_epub {
+                let key = path.to_string_lossy().to_ascii_lowercase();
+                if seen.insert(key) {
+                    result.push(path);
+                }
+            }
+            continue;
+        }
+        if path.is_dir() {
+            for entry in WalkDir::new(&path).into_iter().filter_map(Result::ok) {
+                let item = entry.path();
+                if item.is_dir() && is_same_path(item, output_dir) {
+                    continue;
+                }
+                if !item.is_file() {
+                    continue;
+                }
+                if path_is_under(item, output_dir) {
+                    continue;
+                }
+                let is_epub = item
+                    .extension()
+                    .and_then(|value| value.to_str())
+                    .map(|value| value.eq_ignore_ascii_case("epub"))
+                    .unwrap_or(false);
+                if is_epub {
+                    let key = item.to_string_lossy().to_ascii_lowercase();
+                    if seen.insert(key) {
+                        result.push(item.to_path_buf());
+                    }
+                }
+            }
+            continue;
+        }
+        return Err(format!("路径不存在: {}", raw));
+    }
+    result.sort();
+    Ok(result)
+}
+
+fn collect_epub_sources(
+    app: &tauri::AppHandle,
+    task_id: &str,
+    input_paths: &[String],
+    output_dir: &Path,
+) -> Result<Vec<PathBuf>, String> {
+    let sources = list_epub_sources(input_paths, output_dir)?;
+    for (index, path) in sources.iter().enumerate() {
+        emit_toolbox_batch_event(
+            app,
+            ToolboxBatchEvent {
+                task_id: task_id.to_string(),
+                event: "scan-file".to_string(),
+                level: "info".to_string(),
+                index: index + 1,
+                total: sources.len(),
+                input_path: Some(path.to_string_lossy().to_string()),
+                output_path: None,
+                message: format!("扫描到 EPUB: {}", path.to_string_lossy()),
+            },
+        );
+    }
+    Ok(sources)
+}
+
+fn run_toolbox_batch_impl(
+    app: tauri::AppHandle,
+    task_id: String,
+    tool: String,
+    input_paths: Vec<String>,
+    image_format: Option<String>,
+    output_dir: Option<String>,
+    output_mode: Option<String>,
+    conflict_mode: Option<String>,
+) -> Result<ToolboxBatchSummary, String> {
+    reset_toolbox_batch_cancel(&task_id);
+    let output_dir = resolve_batch_output_dir(&input_paths, output_dir);
+    let output_mode = output_mode
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "batch_dir".to_string());
+    let conflict_mode = conflict_mode
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| "auto_number".to_string());
+    emit_toolbox_batch_event(
+        &app,
+        ToolboxBatchEvent {
+            task_id: task_id.clone(),
+            event: "scan-start".to_string(),
+            level: "info".to_string(),
+            index: 0,
+            total: 0,
+            input_path: None,
+            output_path: Some(output_dir.to_string_lossy().to_string()),
+            message: format!(
+                "正在扫描 EPUB 文件，输出目录: {}",
+                output_dir.to_string_lossy()
+            ),
+        },
+    );
+    let sources = collect_epub_sources(&app, &task_id, &input_paths, &output_dir)?;
+    let total = sources.len();
+    emit_toolbox_batch_event(
+        &app,
+        ToolboxBatchEvent {
+            task_id: task_id.clone(),
+            event: "started".to_string(),
+            level: "info".to_string(),
+            index: 0,
+            total,
+            input_path: None,
+            output_path: None,
+            message: format!("发现 {} 个 EPUB 文件", total),
+        },
+    );
+    if total == 0 {
+        emit_toolbox_batch_event(
+            &app,
+            ToolboxBatchEvent {
+                task_id: task_id.clone(),
+                event: "finished".to_string(),
+                level: "warning".to_string(),
+                index: 0,
+                total,
+                input_path: None,
+                output_path: None,
+                message: "未找到 EPUB 文件".to_string(),
+            },
+        );
+        return Ok(ToolboxBatchSummary {
+            task_id,
+            total,
+            succeeded: 0,
+            failed: 0,
+        });
+    }
+
+    let normalized_tool = tool.replace('-', "_");
+    let mut succeeded = 0usize;
+    let mut failed = 0usize;
+    let batch_started_at = Instant::now();
+    for (idx, source) in sources.iter().enumerate() {
+        let index = idx + 1;
+        if is_toolbox_batch_cancelled(&task_id) {
+            reset_toolbox_batch_cancel(&task_id);
+            emit_toolbox_batch_event(
+                &app,
+                ToolboxBatchEvent {
+                    task_id: task_id.clone(),
+                    event: "finished".to_string(),
+                    level: "warning".to_string(),
+                    index: idx,
+                    total,
+                    input_path: None,
+                    output_path: None,
+                    message: format!(
+                        "批量处理已取消：成功 {}，失败 {}，剩余 {}，总耗时 {}",
+                        succeeded,
+                        failed,
+                        total.saturating_sub(idx),
+                        format_toolbox_duration(batch_started_at.elapsed())
+                    ),
+                },
+            );
+            return Ok(ToolboxBatchSummary {
+                task_id,
+                total,
+                succeeded,
+                failed,
+            });
+        }
+        let file_started_at = Instant::now();
+        let mut slow_warned = false;
+        let mut cancel_stage_warned = false;
+        emit_toolbox_batch_event(
+            &app,
+            ToolboxBatchEvent {
+                task_id: task_id.clone(),
+                event: "file-start".to_string(),
+                level: "info".to_string(),
+                index,
+                total,
+                input_path: Some(source.to_string_lossy().to_string()),
+                output_path: None,
+                message: format!("开始处理 {}", source.to_string_lossy()),
+            },
+        );
+        emit_toolbox_batch_stage(&app, &task_id, index, total, source, "读取文件并准备处理");
+
+        let result = match normalized_tool.as_str() {
+            "file_encrypt" => toolbox_file_encrypt_impl(source),
+            "file_decrypt" => {
+                let mut progress = |message: &str| {
+                    let elapsed = file_started_at.elapsed();
+                    if elapsed >= Duration::from_secs(30) && !slow_warned {
+                        slow_warned = true;
+                        emit_toolbox_batch_stage_with_level(
+                            &app,
+                            &task_id,
+                            index,
+                            total,
+                            source,
+                            "warning",
+                            format!(
+                                "当前文件处理超过 {}，仍在继续",
+                                format_toolbox_duration(elapsed)
+                            ),
+                        );
+                    }
+                    if is_toolbox_batch_cancelled(&task_id) && !cancel_stage_warned {
+                        cancel_stage_warned = true;
+                        emit_toolbox_batch_stage_with_level(
+                            &app,
+                            &task_id,
+                            index,
+                            total,
+                            source,
+                            "warning",
+                            "已收到取消请求，当前文件完成后停止",
+                        );
+                    }
+                    emit_toolbox_batch_stage(
+                        &app,
+                        &task_id,
+                        index,
+                        total,
+                        source,
+                        format!("{}（已用 {}）", message, format_toolbox_duration(elapsed)),
+                    );
+                };
+                toolbox_file_decrypt_impl_with_progress(source, &mut progress)
+            }
+            "font_encrypt" => toolbox_font_encrypt_impl(source),
+            "font_decrypt" => toolbox_font_decrypt_impl(source, None),
+            "epub_reformat" => toolbox_epub_reformat_impl(source),
+            "epub_diagnose" => toolbox_epub_diagnose_tool_result(source),
+            "image_convert" => toolbox_image_convert_impl(source, image_format.as_deref()),
+            other => match other.strip_prefix("chinese_") {
+                Some(profile) => toolbox_chinese_convert_impl(source, profile),
+                None => Err(format!("不支持的批量工具: {}", tool)),
+            },
+        }
+        .and_then(|done| {
+            emit_toolbox_batch_stage(&app, &task_id, index, total, source, "整理输出文件");
+            finalize_toolbox_output(
+                done,
+                source,
+                &output_dir,
+                &output_mode,
+                &conflict_mode,
+                &input_paths,
+            )
+        });
+
+        match result {
+            Ok(done) => {
+                let elapsed = file_started_at.elapsed();
+                succeeded += 1;
+                emit_toolbox_batch_event(
+                    &app,
+                    ToolboxBatchEvent {
+                        task_id: task_id.clone(),
+                        event: "file-done".to_string(),
+                        level: if done.action == "epub_diagnose_clean" || done.changed {
+                            "info"
+                        } else {
+                            "warning"
+                        }
+                        .to_string(),
+                        index,
+                        total,
+                        input_path: Some(done.source_path),
+                        output_path: Some(done.output_path),
+                        message: append_toolbox_duration(done.message, elapsed),
+                    },
+                );
+            }
+            Err(error) => {
+                let elapsed = file_started_at.elapsed();
+                failed += 1;
+                emit_toolbox_batch_event(
+                    &app,
+                    ToolboxBatchEvent {
+                        task_id: task_id.clone(),
+                        event: "file-error".to_string(),
+                        level: "error".to_string(),
+                        index,
+                        total,
+                        input_path: Some(source.to_string_lossy().to_string()),
+                        output_path: None,
+                        message: append_toolbox_duration(error, elapsed),
+                    },
+                );
+            }
+        }
+    }
+
+    emit_toolbox_batch_event(
+        &app,
+        ToolboxBatchEvent {
+            task_id: task_id.clone(),
+            event: "finished".to_string(),
+            level: if failed == 0 { "info" } else { "warning" }.to_string(),
+            index: total,
+            total,
+            input_path: None,
+            output_path: None,
+            message: format!(
+                "批量处理完成：成功 {}，失败 {}，总耗时 {}",
+                succeeded,
+                failed,
+                format_toolbox_duration(batch_started_at.elapsed())
+            ),
+        },
+    );
+    reset_toolbox_batch_cancel(&task_id);
+    Ok(ToolboxBatchSummary {
+        task_id,
+        total,
+        succeeded,
+        failed,
+    })
+}
+
+const TOOLBOX_FONT_OBFUSCATION_SCRIPT: &str = r##"
+import json
+import html as html_lib
+import random
+import re
+import sys
+import unicodedata
+import zipfile
+from collections import Counter, defaultdict
+from io import BytesIO
+from urllib.parse import unquote
+
+MAP_NAME = "META-INF/tepub-font-obfuscation.json"
+
+def is_html_name(name):
+    lower = name.lower()
+    return lower.endswith(".html") or lower.endswith(".xhtml") or lower.endswith(".htm")
+
+def is_font_name(name):
+    lower = name.lower()
+    return lower.endswith(".ttf") or lower.endswith(".otf") or lower.endswith(".woff") or lower.endswith(".woff2")
+
+def decode_text(data):
+    for enc in ("utf-8", "utf-8-sig", "gb18030", "latin-1"):
+        try:
+            return data.decode(enc)
+        except UnicodeDecodeError:
+            pass
+    return data.decode("utf-8", errors="replace")
+
+def is_private_use_char(ch):
+    code = ord(ch)
+    return 0xE000 <= code <= 0xF8FF or 0xF0000 <= code <= 0x10FFFD
+
+def is_cjk_ideograph(ch):
+    code = ord(ch)
+    return (
+        0x3400 <= code <= 0x4DBF
+        or 0x4E00 <= code <= 0x9FFF
+        or 0xF900 <= code <= 0xFAFF
+        or 0x20000 <= code <= 0x3FFFF
+    )
+
+def should_map_char(ch):
+    if ch in "<>&":
+        return False
+    cat = unicodedata.category(ch)
+    if cat.startswith(("C", "Z", "P", "S", "N")):
+        return False
+    if is_private_use_char(ch):
+        return False
+    return is_cjk_ideograph(ch)
+
+def tag_identity(tag):
+    body = tag.strip("<> \t\r\n")
+    closing = body.startswith("/")
+    if closing:
+        body = body[1:].lstrip()
+    if not body:
+        return closing, ""
+    name = body.split(None, 1)[0].split("/", 1)[0].lower()
+    return closing, name
+
+def iter_text_chars(html):
+    raw_tag = None
+    i = 0
+    while i < len(html):
+        ch = html[i]
+        if ch == "<":
+            end = html.find(">", i + 1)
+            if end == -1:
+                break
+            tag = html[i:end + 1]
+            closing, name = tag_identity(tag)
+            if name in ("script", "style"):
+                raw_tag = None if closing else name
+            i = end + 1
+            continue
+        if not raw_tag:
+            if ch == "&":
+                semi = html.find(";", i + 1, i + 16)
+                if semi != -1:
+                    i = semi + 1
+                    continue
+            if should_map_char(ch):
+                yield ch
+        i += 1
+
+def transform_text(html, table):
+    out = []
+    raw_tag = None
+    i = 0
+    while i < len(html):
+        ch = html[i]
+        if ch == "<":
+            end = html.find(">", i + 1)
+            if end == -1:
+                out.append(html[i:])
+                break
+            tag = html[i:end + 1]
+            out.append(tag)
+            closing, name = tag_identity(tag)
+            if name in ("script", "style"):
+                raw_tag = None if closing else name
+            i = end + 1
+            continue
+        if raw_tag:
+            out.append(ch)
+            i += 1
+            continue
+        if ch == "&":
+            semi = html.find(";", i + 1, i + 16)
+            if semi != -1:
+                out.append(html[i:semi + 1])
+                i = semi + 1
+                continue
+        out.append(table.get(ch, ch))
+        i += 1
+    return "".join(out)
+
+def parse_attrs(tag_body):
+    attrs = {}
+    for m in re.finditer(r"([:\w.-]+)\s*=\s*(['\"])(.*?)\2", tag_body, flags=re.S):
+        attrs[m.group(1).lower()] = m.group(3)
+    return attrs
+
+def parse_tag(tag):
+    body = tag.strip("<> \t\r\n")
+    if not body or body.startswith("!") or body.startswith("?"):
+        return True, "", True, {}
+    closing = body.startswith("/")
+    if closing:
+        body = body[1:].lstrip()
+    self_closing = body.endswith("/")
+    if self_closing:
+        body = body[:-1].rstrip()
+    if not body:
+        return closing, "", self_closing, {}
+    name = body.split(None, 1)[0].split("/", 1)[0].lower()
+    rest = body[len(name):]
+    return closing, name, self_closing, parse_attrs(rest)
+
+def first_font_family(value):
+    if not value:
+        return ""
+    parts = []
+    buf = []
+    quote = None
+    for ch in value:
+        if quote:
+            if ch == quote:
+                quote = None
+            else:
+                buf.append(ch)
+            continue
+        if ch in ("'", '"'):
+            quote = ch
+            continue
+        if ch == ",":
+            part = "".join(buf).strip().strip("'\"")
+            if part:
+                parts.append(part)
+            buf = []
+            continue
+        buf.append(ch)
+    part = "".join(buf).strip().strip("'\"")
+    if part:
+        parts.append(part)
+    return parts[0].lower() if parts else ""
+
+def font_family_from_style(style):
     if not style:
         return ""
     m = re.search(r"(?is)(?:^|;)\s*font-family\s*:\s*([^;}{]+)", style)
@@ -3805,4679 +4887,11506 @@ def font_family_from_style(style):
         return ""
     return first_font_family(m.group(1))
 
-def simple_selector_key(selector):
-    selector = selector.strip()
-    selector = selector.split(":", 1)[0].strip()
-    if not selector or any(token in selector for token in (" ", ">", "+", "~", "[", "]", "*")):
-        return None
-    if selector.startswith(".") or selector.startswith("#"):
-        return selector.lower()
-    if re.fullmatch(r"[a-zA-Z][\w-]*(?:[.#][\w-]+)?", selector):
-        return selector.lower()
-    return None
+def simple_selector_key(selector):
+    selector = selector.strip()
+    selector = selector.split(":", 1)[0].strip()
+    if not selector or any(token in selector for token in (" ", ">", "+", "~", "[", "]", "*")):
+        return None
+    if selector.startswith(".") or selector.startswith("#"):
+        return selector.lower()
+    if re.fullmatch(r"[a-zA-Z][\w-]*(?:[.#][\w-]+)?", selector):
+        return selector.lower()
+    return None
+
+def build_selector_font_rules(infos, contents):
+    selector_fonts = {}
+    for info in infos:
+        name = info.filename
+        if not name.lower().endswith(".css") or name.endswith("/"):
+            continue
+        css = decode_text(contents.get(name, b""))
+        css = re.sub(r"(?s)/\*.*?\*/", "", css)
+        for match in re.finditer(r"(?s)([^{}]+)\{([^{}]*)\}", css):
+            selectors = match.group(1).strip()
+            body = match.group(2)
+            if selectors.startswith("@"):
+                continue
+            family = font_family_from_style(body)
+            if not family:
+                continue
+            for selector in selectors.split(","):
+                key = simple_selector_key(selector)
+                if key:
+                    selector_fonts[key] = family
+    return selector_fonts
+
+def font_for_tag(tag_name, attrs, inherited, selector_fonts):
+    inline_family = font_family_from_style(attrs.get("style", ""))
+    if inline_family:
+        return inline_family
+    tag = (tag_name or "").lower()
+    elem_id = attrs.get("id", "").strip()
+    if elem_id:
+        for key in (f"{tag}#{elem_id.lower()}", f"#{elem_id.lower()}"):
+            if key in selector_fonts:
+                return selector_fonts[key]
+    classes = attrs.get("class", "").split()
+    for cls in classes:
+        cls = cls.lower()
+        for key in (f"{tag}.{cls}", f".{cls}"):
+            if key in selector_fonts:
+                return selector_fonts[key]
+    if tag in selector_fonts:
+        return selector_fonts[tag]
+    return inherited
+
+def zip_parent(name):
+    name = name.replace("\\", "/")
+    return name.rsplit("/", 1)[0] if "/" in name else ""
+
+def zip_join(base_dir, rel):
+    rel = unquote((rel or "").split("#", 1)[0].split("?", 1)[0]).replace("\\", "/").strip()
+    if not rel:
+        return ""
+    if rel.startswith("/"):
+        return rel.lstrip("/")
+    parts = [p for p in base_dir.split("/") if p]
+    for seg in rel.split("/"):
+        if not seg or seg == ".":
+            continue
+        if seg == "..":
+            if parts:
+                parts.pop()
+        else:
+            parts.append(seg)
+    return "/".join(parts)
+
+def strip_markup_text(text):
+    text = re.sub(r"(?is)<(script|style)\b.*?</\1>", "", text)
+    text = re.sub(r"(?is)<[^>]+>", "", text)
+    text = html_lib.unescape(text)
+    return re.sub(r"\s+", " ", text).strip()
+
+def extract_title_text(text):
+    m = re.search(r"(?is)<title\b[^>]*>(.*?)</title>", text)
+    return strip_markup_text(m.group(1)) if m else ""
+
+def collect_navigation_data(infos, contents):
+    opf_paths = [info.filename for info in infos if info.filename.lower().endswith(".opf")]
+    nav_paths = set()
+    ncx_paths = set()
+    title_by_target = {}
+    for opf_path in opf_paths:
+        opf_text = decode_text(contents.get(opf_path, b""))
+        opf_dir = zip_parent(opf_path)
+        toc_ids = set()
+        for spine in re.finditer(r"(?is)<spine\b([^>]*)>", opf_text):
+            toc_id = parse_attrs(spine.group(1)).get("toc", "")
+            if toc_id:
+                toc_ids.add(toc_id)
+        for item in re.finditer(r"(?is)<item\b([^>]*)>", opf_text):
+            attrs = parse_attrs(item.group(1))
+            item_id = attrs.get("id", "")
+            href = attrs.get("href", "")
+            if not href:
+                continue
+            media = attrs.get("media-type", "").lower()
+            properties = attrs.get("properties", "").lower().split()
+            abs_path = zip_join(opf_dir, href)
+            lower = abs_path.lower()
+            if "nav" in properties:
+                nav_paths.add(abs_path)
+            if item_id in toc_ids or media == "application/x-dtbncx+xml" or lower.endswith(".ncx"):
+                ncx_paths.add(abs_path)
+
+    for nav_path in list(nav_paths):
+        nav_text = decode_text(contents.get(nav_path, b""))
+        nav_dir = zip_parent(nav_path)
+        for a in re.finditer(r"(?is)<a\b([^>]*)>(.*?)</a>", nav_text):
+            href = parse_attrs(a.group(1)).get("href", "")
+            label = strip_markup_text(a.group(2))
+            target = zip_join(nav_dir, href)
+            if target and label:
+                title_by_target.setdefault(target, label)
+
+    for ncx_path in list(ncx_paths):
+        ncx_text = decode_text(contents.get(ncx_path, b""))
+        ncx_dir = zip_parent(ncx_path)
+        for point in re.finditer(r"(?is)<navPoint\b.*?</navPoint>", ncx_text):
+            block = point.group(0)
+            content = re.search(r"(?is)<content\b([^>]*)>", block)
+            label = re.search(r"(?is)<text\b[^>]*>(.*?)</text>", block)
+            if not content or not label:
+                continue
+            src = parse_attrs(content.group(1)).get("src", "")
+            target = zip_join(ncx_dir, src)
+            text = strip_markup_text(label.group(1))
+            if target and text:
+                title_by_target.setdefault(target, text)
+    return nav_paths, ncx_paths, title_by_target
+
+def collect_title_pairs(contents, title_by_target):
+    pairs = []
+    for target, plain_title in title_by_target.items():
+        html = contents.get(target)
+        if html is None:
+            continue
+        cipher_title = extract_title_text(decode_text(html))
+        if cipher_title and plain_title and cipher_title != plain_title:
+            pairs.append((cipher_title, plain_title))
+    return pairs
+
+def is_ignored_text_char(ch):
+    cat = unicodedata.category(ch)
+    return ch.isspace() or cat.startswith("C") or cat.startswith("Z")
+
+def is_cjk_or_private(ch):
+    code = ord(ch)
+    return (
+        0x3400 <= code <= 0x4DBF
+        or 0x4E00 <= code <= 0x9FFF
+        or 0xF900 <= code <= 0xFAFF
+        or 0x20000 <= code <= 0x3FFFF
+        or 0xE000 <= code <= 0xF8FF
+        or 0xF0000 <= code <= 0x10FFFD
+    )
+
+def is_alignment_stable(ch):
+    if is_ignored_text_char(ch):
+        return False
+    if is_cjk_or_private(ch):
+        return False
+    cat = unicodedata.category(ch)
+    return ord(ch) < 128 or cat[0] in ("P", "S", "N") or 0xFF00 <= ord(ch) <= 0xFFEF
+
+def is_mapping_candidate(ch):
+    return not is_ignored_text_char(ch) and not is_alignment_stable(ch)
+
+def iter_text_records(html, selector_fonts=None):
+    selector_fonts = selector_fonts or {}
+    stack = []
+    raw_tag = None
+    i = 0
+    while i < len(html):
+        ch = html[i]
+        if ch == "<":
+            end = html.find(">", i + 1)
+            if end == -1:
+                break
+            tag = html[i:end + 1]
+            closing, name, self_closing, attrs = parse_tag(tag)
+            if name in ("script", "style", "title"):
+                raw_tag = None if closing else name
+                i = end + 1
+                continue
+            if name and not raw_tag:
+                if closing:
+                    while stack:
+                        popped, _font = stack.pop()
+                        if popped == name:
+                            break
+                elif not self_closing:
+                    inherited = stack[-1][1] if stack else ""
+                    stack.append((name, font_for_tag(name, attrs, inherited, selector_fonts)))
+            elif name and closing:
+                while stack:
+                    popped, _font = stack.pop()
+                    if popped == name:
+                        break
+            i = end + 1
+            continue
+        if raw_tag:
+            i += 1
+            continue
+        if ch == "&":
+            semi = html.find(";", i + 1, i + 16)
+            if semi != -1:
+                i = semi + 1
+                continue
+        if not is_ignored_text_char(ch):
+            yield ch, (stack[-1][1] if stack else "")
+        i += 1
+
+def transform_text_font_aware(html, global_table, font_table, selector_fonts=None):
+    selector_fonts = selector_fonts or {}
+    out = []
+    stack = []
+    raw_tag = None
+    i = 0
+    while i < len(html):
+        ch = html[i]
+        if ch == "<":
+            end = html.find(">", i + 1)
+            if end == -1:
+                out.append(html[i:])
+                break
+            tag = html[i:end + 1]
+            out.append(tag)
+            closing, name, self_closing, attrs = parse_tag(tag)
+            if name in ("script", "style"):
+                raw_tag = None if closing else name
+                i = end + 1
+                continue
+            if name and not raw_tag:
+                if closing:
+                    while stack:
+                        popped, _font = stack.pop()
+                        if popped == name:
+                            break
+                elif not self_closing:
+                    inherited = stack[-1][1] if stack else ""
+                    stack.append((name, font_for_tag(name, attrs, inherited, selector_fonts)))
+            elif name and closing:
+                while stack:
+                    popped, _font = stack.pop()
+                    if popped == name:
+                        break
+            i = end + 1
+            continue
+        if raw_tag:
+            out.append(ch)
+            i += 1
+            continue
+        if ch == "&":
+            semi = html.find(";", i + 1, i + 16)
+            if semi != -1:
+                out.append(html[i:semi + 1])
+                i = semi + 1
+                continue
+        font_key = stack[-1][1] if stack else ""
+        out.append(font_table.get((font_key, ch), global_table.get(ch, ch)))
+        i += 1
+    return "".join(out)
+
+def normalize_plain_chars(text):
+    return [ch for ch in text if not is_ignored_text_char(ch)]
+
+def char_at(seq, index):
+    item = seq[index]
+    return item[0] if isinstance(item, tuple) else item
+
+def stable_signature(seq, pos, want=8, max_scan=1600):
+    sig = []
+    end = min(len(seq), pos + max_scan)
+    while pos < end and len(sig) < want:
+        ch = char_at(seq, pos)
+        if is_alignment_stable(ch):
+            sig.append(ch)
+        pos += 1
+    return "".join(sig)
+
+def find_resync(cipher_records, ci, plain_chars, pi, window=2400, anchors=8):
+    plain_sigs = {}
+    plain_end = min(len(plain_chars), pi + window)
+    for j in range(pi, plain_end):
+        sig = stable_signature(plain_chars, j, anchors)
+        if len(sig) >= anchors and sig not in plain_sigs:
+            plain_sigs[sig] = j
+    cipher_end = min(len(cipher_records), ci + window)
+    best = None
+    best_cost = None
+    for i in range(ci, cipher_end):
+        sig = stable_signature(cipher_records, i, anchors)
+        if len(sig) < anchors:
+            continue
+        j = plain_sigs.get(sig)
+        if j is None:
+            continue
+        cost = (i - ci) + (j - pi)
+        if best is None or cost < best_cost:
+            best = (i, j)
+            best_cost = cost
+            if cost == 0:
+                break
+    return best
+
+def decode_txt_file(txt_path):
+    with open(txt_path, "rb") as f:
+        return decode_text(f.read())
+
+def pick_mapping(counter):
+    if not counter:
+        return None, 0, 0, 0.0
+    total = sum(counter.values())
+    plain, top = counter.most_common(1)[0]
+    ratio = top / total if total else 0.0
+    return plain, top, total, ratio
+
+def choose_mapping_table(counts):
+    table = {}
+    conflicts = 0
+    for key, counter in counts.items():
+        plain, top, total, ratio = pick_mapping(counter)
+        if plain is None:
+            continue
+        if total >= 5 and ratio < 0.78:
+            conflicts += 1
+        if ratio >= 0.66 and (top >= 2 or top == total):
+            table[key] = plain
+    return table, conflicts
+
+def add_segment_pairs(cipher_records, ci0, ci1, plain_chars, pi0, pi1, global_counts, font_counts):
+    cipher_idxs = [idx for idx in range(ci0, ci1) if is_mapping_candidate(cipher_records[idx][0])]
+    plain_idxs = [idx for idx in range(pi0, pi1) if is_mapping_candidate(plain_chars[idx])]
+    if len(cipher_idxs) != len(plain_idxs):
+        return 0
+    added = 0
+    for ci, pi in zip(cipher_idxs, plain_idxs):
+        cipher, font_key = cipher_records[ci]
+        plain = plain_chars[pi]
+        global_counts[cipher][plain] += 1
+        if font_key:
+            font_counts[(font_key, cipher)][plain] += 1
+        added += 1
+    return added
+
+def add_direct_title_pair(cipher_title, plain_title, global_counts, font_counts, weight=4):
+    cipher_chars = normalize_plain_chars(cipher_title)
+    plain_chars = normalize_plain_chars(plain_title)
+    if len(cipher_chars) != len(plain_chars):
+        return 0
+    added = 0
+    for cipher, plain in zip(cipher_chars, plain_chars):
+        if cipher == plain:
+            continue
+        if is_mapping_candidate(cipher) and is_mapping_candidate(plain):
+            global_counts[cipher][plain] += weight
+            added += weight
+    return added
+
+def build_txt_alignment_tables(cipher_records, plain_chars, direct_title_pairs=None):
+    global_counts = defaultdict(Counter)
+    font_counts = defaultdict(Counter)
+    ci = 0
+    pi = 0
+    pairs = 0
+    stable_matches = 0
+    skipped_cipher = 0
+    skipped_plain = 0
+    while ci < len(cipher_records) and pi < len(plain_chars):
+        cipher, font_key = cipher_records[ci]
+        plain = plain_chars[pi]
+        cipher_stable = is_alignment_stable(cipher)
+        plain_stable = is_alignment_stable(plain)
+        if cipher_stable or plain_stable:
+            if cipher_stable and plain_stable and cipher == plain:
+                ci += 1
+                pi += 1
+                stable_matches += 1
+                continue
+            resync = find_resync(cipher_records, ci, plain_chars, pi)
+            if resync and (resync[0] > ci or resync[1] > pi):
+                pairs += add_segment_pairs(
+                    cipher_records, ci, resync[0], plain_chars, pi, resync[1], global_counts, font_counts
+                )
+                skipped_cipher += max(0, resync[0] - ci)
+                skipped_plain += max(0, resync[1] - pi)
+                ci, pi = resync
+                continue
+            if cipher_stable and not plain_stable:
+                ci += 1
+                skipped_cipher += 1
+            elif plain_stable and not cipher_stable:
+                pi += 1
+                skipped_plain += 1
+            else:
+                ci += 1
+                pi += 1
+                skipped_cipher += 1
+                skipped_plain += 1
+            continue
+        if is_mapping_candidate(cipher) and is_mapping_candidate(plain):
+            global_counts[cipher][plain] += 1
+            if font_key:
+                font_counts[(font_key, cipher)][plain] += 1
+            pairs += 1
+        ci += 1
+        pi += 1
+    title_pairs = 0
+    title_votes = 0
+    for cipher_title, plain_title in direct_title_pairs or []:
+        added = add_direct_title_pair(cipher_title, plain_title, global_counts, font_counts)
+        if added:
+            title_pairs += 1
+            title_votes += added
+    global_table, global_conflicts = choose_mapping_table(global_counts)
+    font_table, font_conflicts = choose_mapping_table(font_counts)
+    stats = {
+        "pairs": pairs,
+        "stable": stable_matches,
+        "skippedCipher": skipped_cipher,
+        "skippedPlain": skipped_plain,
+        "globalChars": len(global_table),
+        "fontChars": len(font_table),
+        "globalConflicts": global_conflicts,
+        "fontConflicts": font_conflicts,
+        "titlePairs": title_pairs,
+        "titleVotes": title_votes,
+    }
+    return global_table, font_table, stats
+
+def neutralize_css_fonts(text):
+    text = re.sub(r"(?is)@font-face\s*\{.*?\}", "", text)
+    text = re.sub(r"(?is)font-family\s*:\s*[^;}{]+;?", "font-family: serif;", text)
+    return text
+
+def neutralize_html_inline_fonts(text):
+    return re.sub(r"(?is)font-family\s*:\s*[^;\"'}]+;?", "font-family: serif;", text)
+
+def private_chars(count, excluded=None):
+    excluded = set(excluded or ())
+    ranges = [(0xE000, 0xF8FF), (0xF0000, 0xFFFFD), (0x100000, 0x10FFFD)]
+    available = []
+    for start, end in ranges:
+        for code in range(start, end + 1):
+            ch = chr(code)
+            if ch not in excluded:
+                available.append(ch)
+    if len(available) >= count:
+        random.SystemRandom().shuffle(available)
+        return available[:count]
+    raise RuntimeError("可用私用区字符不足")
+
+def collect_font_chars(font_items):
+    from fontTools.ttLib import TTFont
+
+    supported = set()
+    occupied_private = set()
+    coverage_by_name = {}
+    for name, font_data in font_items:
+        try:
+            font = TTFont(BytesIO(font_data))
+            best = font.getBestCmap() or {}
+            covered = set()
+            for code in best.keys():
+                ch = chr(code)
+                if should_map_char(ch):
+                    supported.add(ch)
+                    covered.add(ch)
+                if is_private_use_char(ch):
+                    occupied_private.add(ch)
+            coverage_by_name[name.lower()] = len(covered)
+        except Exception:
+            continue
+    return supported, occupied_private, coverage_by_name
+
+def split_font_families(value):
+    families = []
+    current = []
+    quote = None
+    for ch in value:
+        if quote:
+            current.append(ch)
+            if ch == quote:
+                quote = None
+        elif ch in ("'", '"'):
+            quote = ch
+            current.append(ch)
+        elif ch == ",":
+            item = "".join(current).strip()
+            if item:
+                families.append(item)
+            current = []
+        else:
+            current.append(ch)
+    item = "".join(current).strip()
+    if item:
+        families.append(item)
+    return families
+
+def unquote_font_family(value):
+    value = value.strip()
+    if len(value) >= 2 and value[0] == value[-1] and value[0] in ("'", '"'):
+        value = value[1:-1]
+    return value
+
+def normalize_font_family(value):
+    return unquote_font_family(value).lower()
+
+def css_font_family(value):
+    return '"' + value.replace("\\", "\\\\").replace('"', '\\"') + '"'
+
+def append_font_fallbacks(value, fallback_families):
+    important = ""
+    important_match = re.search(r"\s*!important\s*$", value, flags=re.I)
+    if important_match:
+        important = important_match.group(0)
+        value = value[:important_match.start()]
+    families = split_font_families(value)
+    existing = {normalize_font_family(family) for family in families}
+    additions = [css_font_family(family) for family in fallback_families if family.lower() not in existing]
+    if not additions:
+        return value + important
+    generics = {
+        "serif", "sans-serif", "monospace", "cursive", "fantasy", "system-ui", "ui-serif",
+        "ui-sans-serif", "ui-monospace", "ui-rounded", "emoji", "math", "fangsong"
+    }
+    insert_at = next((index for index, family in enumerate(families) if normalize_font_family(family) in generics), len(families))
+    families[insert_at:insert_at] = additions
+    return ", ".join(families) + important
+
+def rewrite_css_font_fallbacks(source, fallback_families):
+    if not fallback_families:
+        return source
+    font_faces = []
+    def mask_face(match):
+        marker = "__TEPUB_FONT_FACE_{}__".format(len(font_faces))
+        font_faces.append(match.group(0))
+        return marker
+    masked = re.sub(r"(?is)@font-face\s*\{[^{}]*\}", mask_face, source)
+    rewritten = re.sub(
+        r"(?is)(font-family\s*:\s*)([^;}{]+)",
+        lambda match: match.group(1) + append_font_fallbacks(match.group(2), fallback_families),
+        masked,
+    )
+    return re.sub(
+        r"__TEPUB_FONT_FACE_(\d+)__",
+        lambda match: font_faces[int(match.group(1))] if int(match.group(1)) < len(font_faces) else "",
+        rewritten,
+    )
+
+def rewrite_inline_font_fallbacks(source, fallback_families):
+    if not fallback_families:
+        return source
+    def rewrite_style(match):
+        style = re.sub(
+            r"(?is)(font-family\s*:\s*)([^;]+)",
+            lambda declaration: declaration.group(1) + append_font_fallbacks(declaration.group(2), fallback_families),
+            match.group(3),
+        )
+        return match.group(1) + match.group(2) + style + match.group(2)
+    return re.sub(r"(?is)(style\s*=\s*)(['\"])(.*?)\2", rewrite_style, source)
+
+def inject_obfuscated_text_compatibility(source, fallback_families):
+    if re.search(r"data-tepub-font-obfuscation\s*=", source, flags=re.I):
+        return source
+    default_family = ""
+    if fallback_families:
+        default_family = "html { font-family: " + ", ".join(css_font_family(family) for family in fallback_families) + ", serif; }\n"
+    style = '<style type="text/css" data-tepub-font-obfuscation="1">' + default_family + 'html, body { overflow-wrap: anywhere; word-break: break-all; }</style>'
+    if re.search(r"</head\s*>", source, flags=re.I):
+        return re.sub(r"</head\s*>", lambda _match: style + "</head>", source, count=1, flags=re.I)
+    return style + source
+
+def collect_embedded_font_families(infos, contents, coverage_by_name):
+    ranked = []
+    seen = set()
+    for info in infos:
+        name = info.filename
+        if not name.lower().endswith(".css") or name.endswith("/"):
+            continue
+        source = decode_text(contents.get(name, b""))
+        for face in re.finditer(r"(?is)@font-face\s*\{([^{}]*)\}", source):
+            body = face.group(1)
+            family_match = re.search(r"(?is)font-family\s*:\s*([^;}{]+)", body)
+            if not family_match:
+                continue
+            family = unquote_font_family(family_match.group(1))
+            family_key = family.lower()
+            if not family or family_key in seen:
+                continue
+            scores = []
+            for url_match in re.finditer(r"(?is)url\(\s*(['\"]?)(.*?)\1\s*\)", body):
+                target = zip_join(zip_parent(name), url_match.group(2))
+                if target.lower() in coverage_by_name:
+                    scores.append(coverage_by_name[target.lower()])
+            if scores:
+                seen.add(family_key)
+                ranked.append((family, max(scores), len(ranked)))
+    ranked.sort(key=lambda item: (-item[1], item[2]))
+    return [family for family, _score, _order in ranked]
+
+def add_private_cmap(font_data, mapping):
+    from fontTools.ttLib import TTFont
+
+    font = TTFont(BytesIO(font_data))
+    best = font.getBestCmap() or {}
+    if "cmap" not in font:
+        return font_data
+    added = 0
+    for plain, shadow in mapping.items():
+        glyph = best.get(ord(plain))
+        if not glyph:
+            continue
+        for table in font["cmap"].tables:
+            if table.isUnicode():
+                table.cmap[ord(shadow)] = glyph
+                added += 1
+    if added == 0:
+        return font_data
+    out = BytesIO()
+    font.save(out)
+    return out.getvalue()
+
+def write_entries(output_path, infos, contents, extra_map=None, skip_map=False):
+    with zipfile.ZipFile(output_path, "w") as zout:
+        for info in infos:
+            if info.filename == "mimetype":
+                zi = zipfile.ZipInfo(info.filename)
+                zi.compress_type = zipfile.ZIP_STORED
+                zout.writestr(zi, contents[info.filename])
+                break
+        for info in infos:
+            name = info.filename
+            if name == "mimetype":
+                continue
+            if skip_map and name == MAP_NAME:
+                continue
+            zi = zipfile.ZipInfo(name)
+            zi.compress_type = info.compress_type if info.compress_type is not None else zipfile.ZIP_DEFLATED
+            zi.external_attr = info.external_attr
+            zi.date_time = info.date_time
+            if name.endswith("/"):
+                zout.writestr(zi, b"")
+            else:
+                zout.writestr(zi, contents[name])
+        if extra_map is not None:
+            zi = zipfile.ZipInfo(MAP_NAME)
+            zi.compress_type = zipfile.ZIP_DEFLATED
+            zout.writestr(zi, json.dumps(extra_map, ensure_ascii=False, separators=(",", ":")).encode("utf-8"))
+
+BODY_ENCRYPTION_CLASS = "tepub-font-encrypted-body"
+BODY_ENCRYPTION_FAMILY = "TEpubEncryptedBodyFont"
+
+def update_body_tag_class(tag, add):
+    match = re.search(r"\bclass\s*=\s*(['\"])(.*?)\1", tag, flags=re.I | re.S)
+    if match:
+        classes = [name for name in match.group(2).split() if name != BODY_ENCRYPTION_CLASS]
+        if add:
+            classes.append(BODY_ENCRYPTION_CLASS)
+        if not classes:
+            return tag[:match.start()] + tag[match.end():]
+        replacement = "class=" + match.group(1) + " ".join(classes) + match.group(1)
+        return tag[:match.start()] + replacement + tag[match.end():]
+    if not add:
+        return tag
+    return re.sub(r"\s*(/?)>$", ' class="' + BODY_ENCRYPTION_CLASS + r'"\1>', tag)
+
+def is_title_like_tag(tag, name):
+    if re.fullmatch(r"h[1-6]", name or "") or name in ("title", "header", "nav"):
+        return True
+    attrs = parse_attrs(tag)
+    marker = (attrs.get("class", "") + " " + attrs.get("id", "")).lower()
+    return bool(
+        re.search(r"(^|[\s_-])(title|subtitle|heading|headline)([\s_-]|$)", marker)
+        or re.search(r"(^|[\s_-])(chapter|volume|part|section)[\s_-]*(title|subtitle|number|name)([\s_-]|$)", marker)
+    )
+
+def transform_body_paragraphs(source, mapping, mode="encrypt"):
+    out = []
+    paragraph_depth = 0
+    title_depth = 0
+    element_stack = []
+    raw_tag = None
+    i = 0
+    while i < len(source):
+        if source[i] == "<":
+            end = source.find(">", i + 1)
+            if end < 0:
+                out.append(source[i:])
+                break
+            tag = source[i:end + 1]
+            closing, name = tag_identity(tag)
+            self_closing = bool(re.search(r"/\s*>$", tag))
+            class_match = re.search(r"\bclass\s*=\s*(['\"])(.*?)\1", tag, flags=re.I | re.S)
+            marked = bool(class_match and BODY_ENCRYPTION_CLASS in class_match.group(2).split())
+            if closing:
+                while element_stack:
+                    popped_name, popped_paragraph, popped_title = element_stack.pop()
+                    paragraph_depth -= int(popped_paragraph)
+                    title_depth -= int(popped_title)
+                    if popped_name == name:
+                        break
+            if not closing and not self_closing:
+                title_like = is_title_like_tag(tag, name)
+                target = (name == "p" and title_depth == 0 and not title_like) if mode == "encrypt" else marked
+                if target:
+                    paragraph_depth += 1
+                    tag = update_body_tag_class(tag, mode == "encrypt")
+                if title_like:
+                    title_depth += 1
+                element_stack.append((name, target, title_like))
+            if name in ("script", "style"):
+                raw_tag = None if closing else name
+            out.append(tag)
+            i = end + 1
+            continue
+        if not raw_tag and source[i] == "&":
+            end = source.find(";", i + 1, i + 16)
+            if end >= 0:
+                out.append(source[i:end + 1])
+                i = end + 1
+                continue
+        ch = source[i]
+        out.append(mapping.get(ch, ch) if not raw_tag and paragraph_depth > 0 else ch)
+        i += 1
+    return "".join(out)
+
+def collect_body_and_protected_chars(source):
+    body_chars = []
+    protected_chars = []
+    paragraph_depth = 0
+    title_depth = 0
+    element_stack = []
+    raw_tag = None
+    i = 0
+    while i < len(source):
+        if source[i] == "<":
+            end = source.find(">", i + 1)
+            if end < 0:
+                break
+            tag = source[i:end + 1]
+            closing, name = tag_identity(tag)
+            self_closing = bool(re.search(r"/\s*>$", tag))
+            if closing:
+                while element_stack:
+                    popped_name, popped_paragraph, popped_title = element_stack.pop()
+                    paragraph_depth -= int(popped_paragraph)
+                    title_depth -= int(popped_title)
+                    if popped_name == name:
+                        break
+            elif not self_closing:
+                title_like = is_title_like_tag(tag, name)
+                paragraph = name == "p" and title_depth == 0 and not title_like
+                paragraph_depth += int(paragraph)
+                title_depth += int(title_like)
+                element_stack.append((name, paragraph, title_like))
+            if name in ("script", "style"):
+                raw_tag = None if closing else name
+            i = end + 1
+            continue
+        if not raw_tag and source[i] == "&":
+            end = source.find(";", i + 1, i + 16)
+            if end >= 0:
+                i = end + 1
+                continue
+        ch = source[i]
+        if not raw_tag and should_map_char(ch):
+            if paragraph_depth > 0 and title_depth == 0:
+                body_chars.append(ch)
+            else:
+                protected_chars.append(ch)
+        i += 1
+    return body_chars, protected_chars
+
+def collect_spine_html_names(infos, contents):
+    names = {info.filename for info in infos}
+    for info in infos:
+        opf_path = info.filename
+        if not opf_path.lower().endswith(".opf"):
+            continue
+        opf = decode_text(contents.get(opf_path, b""))
+        opf_dir = zip_parent(opf_path)
+        manifest = {}
+        for item in re.finditer(r"(?is)<item\b([^>]*)>", opf):
+            attrs = parse_attrs(item.group(1))
+            if attrs.get("id") and attrs.get("href"):
+                manifest[attrs["id"]] = zip_join(opf_dir, attrs["href"])
+        spine = []
+        for itemref in re.finditer(r"(?is)<itemref\b([^>]*)>", opf):
+            target = manifest.get(parse_attrs(itemref.group(1)).get("idref", ""), "")
+            if target in names and is_html_name(target):
+                spine.append(target)
+        if spine:
+            return spine
+    return [info.filename for info in infos if is_html_name(info.filename)]
+
+def relative_zip_path(from_file, target):
+    source_parts = [part for part in zip_parent(from_file).split("/") if part]
+    target_parts = [part for part in target.replace("\\", "/").split("/") if part]
+    while source_parts and target_parts and source_parts[0].lower() == target_parts[0].lower():
+        source_parts.pop(0)
+        target_parts.pop(0)
+    return "/".join([".."] * len(source_parts) + target_parts)
+
+def inject_body_font_style(source, html_path, font_path):
+    source = re.sub(
+        r"(?is)<style[^>]*data-tepub-body-font-encryption\s*=\s*(['\"])1\1[^>]*>.*?</style>",
+        "",
+        source,
+    )
+    font_url = relative_zip_path(html_path, font_path).replace('"', "%22")
+    style = (
+        '<style type="text/css" data-tepub-body-font-encryption="1">'
+        '@font-face { font-family: "' + BODY_ENCRYPTION_FAMILY + '"; src: url("' + font_url + '"); } '
+        '.' + BODY_ENCRYPTION_CLASS + ', .' + BODY_ENCRYPTION_CLASS + ' * { font-family: "' + BODY_ENCRYPTION_FAMILY + '" !important; }'
+        '</style>'
+    )
+    if re.search(r"</head\s*>", source, flags=re.I):
+        return re.sub(r"</head\s*>", lambda _match: style + "</head>", source, count=1, flags=re.I)
+    return style + source
+
+def random_derangement(chars):
+    shuffled = list(chars)
+    random_source = random.SystemRandom()
+    for index in range(len(shuffled) - 1, 0, -1):
+        target = random_source.randrange(index)
+        shuffled[index], shuffled[target] = shuffled[target], shuffled[index]
+    return shuffled
+
+def permute_font_cmap(font_data, mapping):
+    from fontTools.ttLib import TTFont
+
+    font = TTFont(BytesIO(font_data))
+    best = font.getBestCmap() or {}
+    if "cmap" not in font:
+        return font_data
+    plain_codes = {ord(ch) for ch in mapping}
+    changed = 0
+    for table in font["cmap"].tables:
+        if not table.isUnicode():
+            continue
+        for code in plain_codes:
+            table.cmap.pop(code, None)
+        for plain, cipher in mapping.items():
+            glyph = best.get(ord(plain))
+            cipher_code = ord(cipher)
+            if glyph and (table.format != 4 or cipher_code <= 0xFFFF):
+                table.cmap[cipher_code] = glyph
+                changed += 1
+    if not changed:
+        return font_data
+    out = BytesIO()
+    font.save(out)
+    return out.getvalue()
+
+def encrypt_epub_body_permutation(input_path, output_path):
+    from fontTools.ttLib import TTFont
+
+    with zipfile.ZipFile(input_path, "r") as zin:
+        infos = zin.infolist()
+        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
+        for info in infos:
+            if info.filename.endswith("/"):
+                contents[info.filename] = b""
+        font_names = [info.filename for info in infos if is_font_name(info.filename)]
+        html_names = collect_spine_html_names(infos, contents)
+        if not font_names:
+            raise RuntimeError("EPUB 内未找到字体文件")
+        if not html_names:
+            raise RuntimeError("EPUB 内未找到正文 HTML/XHTML 文件")
+        html_sources = {name: decode_text(contents[name]) for name in html_names}
+        body_chars = set()
+        protected_chars = set()
+        for source in html_sources.values():
+            body, protected = collect_body_and_protected_chars(source)
+            body_chars.update(body)
+            protected_chars.update(protected)
+        body_chars.difference_update(protected_chars)
+        if len(body_chars) < 2:
+            raise RuntimeError("未找到可加密的正文段落汉字")
+        candidates = []
+        for name in font_names:
+            try:
+                font = TTFont(BytesIO(contents[name]))
+                supported = {chr(code) for code in (font.getBestCmap() or {}) if is_cjk_ideograph(chr(code))}
+                chars = [ch for ch in body_chars if ch in supported]
+                candidates.append((len(chars), name, chars))
+            except Exception:
+                continue
+        if not candidates:
+            raise RuntimeError("正文字体中未找到可加密汉字")
+        _score, selected_font, chars = max(candidates, key=lambda item: item[0])
+        if len(chars) < 2:
+            raise RuntimeError("正文字体中未找到足够的可加密汉字")
+        chars = sorted(chars)
+        mapping = dict(zip(chars, random_derangement(chars)))
+        changed_files = 0
+        for name, source in html_sources.items():
+            transformed = transform_body_paragraphs(source, mapping, "encrypt")
+            if transformed != source:
+                contents[name] = inject_body_font_style(transformed, name, selected_font).encode("utf-8")
+                changed_files += 1
+        if not changed_files:
+            raise RuntimeError("未发现可加密的正文段落")
+        new_font = permute_font_cmap(contents[selected_font], mapping)
+        if new_font == contents[selected_font]:
+            raise RuntimeError("正文字体 cmap 未能完成随机置换")
+        contents[selected_font] = new_font
+        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
+        return len(mapping), 1
+
+def encrypt_epub(input_path, output_path):
+    with zipfile.ZipFile(input_path, "r") as zin:
+        infos = zin.infolist()
+        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
+        for info in infos:
+            if info.filename.endswith("/"):
+                contents[info.filename] = b""
+        font_names = [info.filename for info in infos if is_font_name(info.filename)]
+        html_names = [info.filename for info in infos if is_html_name(info.filename)]
+        if not font_names:
+            raise RuntimeError("EPUB 内未找到字体文件")
+        if not html_names:
+            raise RuntimeError("EPUB 内未找到 HTML/XHTML 文件")
+        supported_chars, existing_private_chars, coverage_by_name = collect_font_chars(
+            (name, contents[name]) for name in font_names
+        )
+        fallback_families = collect_embedded_font_families(infos, contents, coverage_by_name)
+        if not supported_chars:
+            raise RuntimeError("内嵌字体未发现可加密汉字")
+        chars = []
+        seen = set()
+        for name in html_names:
+            html = decode_text(contents[name])
+            existing_private_chars.update(ch for ch in html if is_private_use_char(ch))
+            for ch in iter_text_chars(html):
+                if ch in supported_chars and ch not in seen:
+                    seen.add(ch)
+                    chars.append(ch)
+        if not chars:
+            raise RuntimeError("未找到可加密的正文汉字")
+        shadows = private_chars(len(chars), existing_private_chars)
+        mapping = dict(zip(chars, shadows))
+        for name in html_names:
+            transformed = transform_text(decode_text(contents[name]), mapping)
+            transformed = rewrite_inline_font_fallbacks(transformed, fallback_families)
+            transformed = inject_obfuscated_text_compatibility(transformed, fallback_families)
+            contents[name] = transformed.encode("utf-8")
+        for info in infos:
+            name = info.filename
+            if name.lower().endswith(".css") and not name.endswith("/"):
+                contents[name] = rewrite_css_font_fallbacks(
+                    decode_text(contents[name]), fallback_families
+                ).encode("utf-8")
+        changed_fonts = 0
+        for name in font_names:
+            new_data = add_private_cmap(contents[name], mapping)
+            if new_data != contents[name]:
+                changed_fonts += 1
+                contents[name] = new_data
+        if changed_fonts == 0:
+            raise RuntimeError("字体 cmap 未能写入私用区映射，无法完成字体加密")
+        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
+        return len(chars), changed_fonts
+
+def decrypt_epub_with_saved_map(input_path, output_path):
+    with zipfile.ZipFile(input_path, "r") as zin:
+        infos = zin.infolist()
+        names = [info.filename for info in infos]
+        if MAP_NAME not in names:
+            raise RuntimeError("未找到字体加密映射文件，无法自动解密")
+        payload = json.loads(zin.read(MAP_NAME).decode("utf-8"))
+        pairs = payload.get("map") or []
+        reverse = {shadow: plain for plain, shadow in pairs}
+        if not reverse:
+            raise RuntimeError("字体加密映射为空")
+        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
+        for info in infos:
+            if info.filename.endswith("/"):
+                contents[info.filename] = b""
+        restored = 0
+        for info in infos:
+            name = info.filename
+            if is_html_name(name):
+                text = decode_text(contents[name])
+                new_text = transform_text(text, reverse)
+                if new_text != text:
+                    restored += 1
+                    contents[name] = new_text.encode("utf-8")
+        if restored == 0:
+            raise RuntimeError("未发现可恢复的字体加密正文")
+        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
+        return {"mode": "saved-map", "files": restored, "chars": len(reverse)}
+
+def decrypt_epub_with_txt_alignment(input_path, output_path, txt_path):
+    if not txt_path:
+        raise RuntimeError("未找到 TEpub 字体映射文件，请选择与 EPUB 对应的明文 TXT 进行对齐解密")
+    with zipfile.ZipFile(input_path, "r") as zin:
+        infos = zin.infolist()
+        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
+        for info in infos:
+            if info.filename.endswith("/"):
+                contents[info.filename] = b""
+        html_names = [info.filename for info in infos if is_html_name(info.filename)]
+        if not html_names:
+            raise RuntimeError("EPUB 内未找到 HTML/XHTML 文件")
+        nav_paths, ncx_paths, title_by_target = collect_navigation_data(infos, contents)
+        nav_path_keys = {path.lower() for path in nav_paths}
+        content_html_names = [name for name in html_names if name.lower() not in nav_path_keys]
+        direct_title_pairs = collect_title_pairs(contents, title_by_target)
+        selector_fonts = build_selector_font_rules(infos, contents)
+        cipher_records = []
+        for name in content_html_names:
+            cipher_records.extend(iter_text_records(decode_text(contents[name]), selector_fonts))
+        plain_chars = normalize_plain_chars(decode_txt_file(txt_path))
+        if len(cipher_records) < 50 or len(plain_chars) < 50:
+            raise RuntimeError("EPUB 或 TXT 文本过短，无法可靠对齐")
+        global_table, font_table, stats = build_txt_alignment_tables(cipher_records, plain_chars, direct_title_pairs)
+        if stats["pairs"] < 30 or (not global_table and not font_table):
+            raise RuntimeError(
+                "TXT 与 EPUB 对齐失败，未能生成可靠映射。请确认 TXT 与 EPUB 是同一版本。"
+            )
+        changed = 0
+        for info in infos:
+            name = info.filename
+            if name.endswith("/"):
+                continue
+            lower = name.lower()
+            if is_html_name(name) and name.lower() not in nav_path_keys:
+                old_text = decode_text(contents[name])
+                new_text = transform_text_font_aware(old_text, global_table, font_table, selector_fonts)
+                new_text = neutralize_html_inline_fonts(new_text)
+                if new_text != old_text:
+                    contents[name] = new_text.encode("utf-8")
+                    changed += 1
+            elif lower.endswith(".css"):
+                old_text = decode_text(contents[name])
+                new_text = neutralize_css_fonts(old_text)
+                if new_text != old_text:
+                    contents[name] = new_text.encode("utf-8")
+        if changed == 0:
+            raise RuntimeError("未发现可替换的字体混淆正文")
+        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
+        stats["mode"] = "txt-alignment-font-aware" if font_table else "txt-alignment-global"
+        stats["files"] = changed
+        stats["skippedNavFiles"] = len(nav_paths) + len(ncx_paths)
+        return stats
+
+def decrypt_epub(input_path, output_path, txt_path=None):
+    with zipfile.ZipFile(input_path, "r") as zin:
+        names = zin.namelist()
+    if MAP_NAME in names:
+        return decrypt_epub_with_saved_map(input_path, output_path)
+    return decrypt_epub_with_txt_alignment(input_path, output_path, txt_path)
+
+def main():
+    if len(sys.argv) not in (4, 5):
+        raise SystemExit("usage: font_obfuscation.py encrypt|decrypt input.epub output.epub [plain.txt]")
+    mode, input_path, output_path = sys.argv[1:4]
+    txt_path = sys.argv[4] if len(sys.argv) == 5 else None
+    if mode == "encrypt":
+        chars, fonts = encrypt_epub_body_permutation(input_path, output_path)
+        print(json.dumps({"chars": chars, "fonts": fonts}, ensure_ascii=False))
+    elif mode == "decrypt":
+        result = decrypt_epub(input_path, output_path, txt_path)
+        print(json.dumps(result, ensure_ascii=False))
+    else:
+        raise SystemExit("unknown mode: " + mode)
+
+if __name__ == "__main__":
+    main()
+"##;
+
+fn run_toolbox_font_obfuscation(
+    source: &Path,
+    output_path: &Path,
+    mode: &str,
+    txt_path: Option<&Path>,
+) -> Result<String, String> {
+    if mode == "encrypt" {
+        ensure_fonttools_available()?;
+    }
+    let temp_dir = tempfile::tempdir().map_err(|e| format!("创建字体工具临时目录失败: {}", e))?;
+    let script_path = temp_dir.path().join("tepub_font_obfuscation.py");
+    fs::write(&script_path, TOOLBOX_FONT_OBFUSCATION_SCRIPT)
+        .map_err(|e| format!("写入字体工具脚本失败: {}", e))?;
+    let mut command = hidden_process_command("python");
+    command
+        .arg(&script_path)
+        .arg(mode)
+        .arg(source)
+        .arg(output_path);
+    if let Some(txt_path) = txt_path {
+        command.arg(txt_path);
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("无法启动 Python 字体工具: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        return Err(if stderr.is_empty() { stdout } else { stderr });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn toolbox_font_encrypt_impl(source: &Path) -> Result<ToolboxEpubToolResult, String> {
+    if !source.exists() {
+        return Err(format!("文件不存在: {}", source.to_string_lossy()));
+    }
+    let output_path = build_processed_epub_path(source, "_font_encrypt");
+    let details = run_toolbox_font_obfuscation(source, &output_path, "encrypt", None)?;
+    Ok(toolbox_epub_tool_result(
+        source,
+        &output_path,
+        true,
+        "font_encrypt",
+        if details.is_empty() {
+            "字体加密完成".to_string()
+        } else {
+            format!("字体加密完成: {}", details)
+        },
+    ))
+}
+
+fn toolbox_font_decrypt_impl(
+    source: &Path,
+    txt_path: Option<&Path>,
+) -> Result<ToolboxEpubToolResult, String> {
+    if !source.exists() {
+        return Err(format!("文件不存在: {}", source.to_string_lossy()));
+    }
+    if let Some(txt_path) = txt_path {
+        if !txt_path.exists() {
+            return Err(format!("TXT 文件不存在: {}", txt_path.to_string_lossy()));
+        }
+    }
+    let output_path = build_processed_epub_path(source, "_font_decrypt");
+    let details = run_toolbox_font_obfuscation(source, &output_path, "decrypt", txt_path)?;
+    Ok(toolbox_epub_tool_result(
+        source,
+        &output_path,
+        true,
+        "font_decrypt",
+        if details.is_empty() {
+            "字体解密完成".to_string()
+        } else {
+            format!("字体解密完成: {}", details)
+        },
+    ))
+}
+
+#[tauri::command]
+fn toolbox_file_encrypt(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
+    toolbox_file_encrypt_impl(&PathBuf::from(epub_path))
+}
+
+#[tauri::command]
+fn toolbox_file_decrypt(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
+    toolbox_file_decrypt_impl(&PathBuf::from(epub_path))
+}
+
+#[tauri::command]
+fn toolbox_font_encrypt(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
+    toolbox_font_encrypt_impl(&PathBuf::from(epub_path))
+}
+
+#[tauri::command]
+fn toolbox_font_decrypt(
+    epub_path: String,
+    txt_path: Option<String>,
+) -> Result<ToolboxEpubToolResult, String> {
+    let txt_path_buf = txt_path.map(PathBuf::from);
+    toolbox_font_decrypt_impl(&PathBuf::from(epub_path), txt_path_buf.as_deref())
+}
+
+#[tauri::command]
+fn toolbox_epub_reformat(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
+    toolbox_epub_reformat_impl(&PathBuf::from(epub_path))
+}
+
+#[tauri::command]
+fn toolbox_image_convert(
+    epub_path: String,
+    image_format: Option<String>,
+) -> Result<ToolboxEpubToolResult, String> {
+    toolbox_image_convert_impl(&PathBuf::from(epub_path), image_format.as_deref())
+}
+
+const AI_IMAGE_MAX_INPUT_BYTES: usize = 16 * 1024 * 1024;
+const AI_IMAGE_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+const AI_IMAGE_USER_AGENT: &str = "TEpub-Editor/image-tools";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolboxAiImageRequest {
+    base_url: String,
+    api_key: String,
+    model: String,
+    target: String,
+    title: Option<String>,
+    author: Option<String>,
+    prompt: String,
+    size: String,
+    reference_data: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolboxAiImageResult {
+    bytes: Vec<u8>,
+    mime: String,
+    extension: String,
+    file_name: String,
+    prompt: String,
+    size: String,
+}
+
+fn normalize_ai_base_url(value: &str) -> String {
+    value.trim().trim_end_matches('/').to_string()
+}
+
+fn remove_suffix_ignore_case(value: &str, suffix: &str) -> String {
+    if value
+        .to_ascii_lowercase()
+        .ends_with(&suffix.to_ascii_lowercase())
+    {
+        value[..value.len() - suffix.len()].to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn is_versioned_ai_base_url(base_url: &str) -> bool {
+    let lower = base_url.to_ascii_lowercase();
+    let Some((prefix, tail)) = lower.rsplit_once("/api/v") else {
+        return false;
+    };
+    !prefix.is_empty() && !tail.is_empty() && tail.chars().all(|c| c.is_ascii_digit())
+}
+
+fn ai_image_generation_url(base_url: &str) -> Result<String, String> {
+    let base = normalize_ai_base_url(base_url);
+    if base.is_empty() {
+        return Err("Base URL 不能为空".to_string());
+    }
+    let lower = base.to_ascii_lowercase();
+    Ok(if lower.ends_with("/images/generations") {
+        base
+    } else if lower.ends_with("/chat/completions") {
+        remove_suffix_ignore_case(&base, "/chat/completions") + "/images/generations"
+    } else if lower.ends_with("/v1") || is_versioned_ai_base_url(&base) {
+        format!("{}/images/generations", base)
+    } else {
+        format!("{}/v1/images/generations", base)
+    })
+}
+
+fn ai_image_edits_url(base_url: &str) -> Result<String, String> {
+    let base = normalize_ai_base_url(base_url);
+    if base.is_empty() {
+        return Err("Base URL 不能为空".to_string());
+    }
+    let lower = base.to_ascii_lowercase();
+    Ok(if lower.ends_with("/images/edits") {
+        base
+    } else if lower.ends_with("/images/generations") {
+        remove_suffix_ignore_case(&base, "/images/generations") + "/images/edits"
+    } else if lower.ends_with("/chat/completions") {
+        remove_suffix_ignore_case(&base, "/chat/completions") + "/images/edits"
+    } else if lower.ends_with("/v1") || is_versioned_ai_base_url(&base) {
+        format!("{}/images/edits", base)
+    } else {
+        format!("{}/v1/images/edits", base)
+    })
+}
+
+fn ai_image_mime_from_bytes(bytes: &[u8]) -> (&'static str, &'static str) {
+    match detect_image_ext(bytes) {
+        "png" => ("png", "image/png"),
+        "webp" => ("webp", "image/webp"),
+        "gif" => ("gif", "image/gif"),
+        "jpg" | "jpeg" => ("jpg", "image/jpeg"),
+        _ => ("jpg", "image/jpeg"),
+    }
+}
+
+fn validate_ai_image_bytes(bytes: &[u8], label: &str) -> Result<(), String> {
+    if bytes.is_empty() {
+        return Err(format!("{}为空", label));
+    }
+    if bytes.len() > AI_IMAGE_MAX_RESPONSE_BYTES {
+        return Err(format!("{}过大", label));
+    }
+    image::load_from_memory(bytes)
+        .map(|_| ())
+        .map_err(|_| format!("无法识别{}的图片内容", label))
+}
+
+fn decode_ai_base64_image(value: &str) -> Option<Vec<u8>> {
+    let trimmed = value.trim();
+    let encoded = if trimmed.to_ascii_lowercase().starts_with("data:image/") {
+        trimmed.split_once(',')?.1.trim()
+    } else {
+        trimmed
+    };
+    if encoded.len() < 80 || encoded.contains(char::is_whitespace) {
+        return None;
+    }
+    let bytes = general_purpose::STANDARD.decode(encoded).ok()?;
+    validate_ai_image_bytes(&bytes, "返回图片").ok()?;
+    Some(bytes)
+}
+
+fn decode_ai_data_url(value: &str) -> Option<Vec<u8>> {
+    if !value.trim().to_ascii_lowercase().starts_with("data:image/") {
+        return None;
+    }
+    decode_ai_base64_image(value)
+}
+
+fn is_remote_ai_url(value: &str) -> bool {
+    let lower = value.trim().to_ascii_lowercase();
+    lower.starts_with("https://") || lower.starts_with("http://")
+}
+
+fn ai_json_error_preview(text: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|value| {
+            value
+                .get("error")
+                .and_then(|error| error.get("message").or(Some(error)))
+                .and_then(|message| message.as_str().map(|s| s.to_string()))
+        })
+        .unwrap_or_else(|| text.chars().take(240).collect())
+}
+
+fn image_size_candidates(requested: &str, target: &str) -> Vec<String> {
+    let fallback = match target {
+        "banner" => "1536x768",
+        "standard" => "1200x1600",
+        _ => "1024x1792",
+    };
+    let secondary_fallback = match target {
+        "banner" => "1280x640",
+        "standard" => "1024x1365",
+        _ => "1400x2400",
+    };
+    let mut values = vec![
+        requested.trim().to_string(),
+        fallback.to_string(),
+        secondary_fallback.to_string(),
+        String::new(),
+    ];
+    values.dedup();
+    values
+}
+
+fn render_ai_image_prompt(template: &str, title: Option<&str>, author: Option<&str>) -> String {
+    let title = title.unwrap_or("").trim();
+    let title = if title.is_empty() {
+        "当前书籍"
+    } else {
+        title
+    };
+    let raw_author = author.unwrap_or("").trim();
+    let mut prompt = template.trim().to_string();
+    if raw_author.is_empty() {
+        for marker in [
+            "作者{author}的",
+            "作者{{author}}的",
+            "作者 {author} 的",
+            "作者 {{author}} 的",
+        ] {
+            prompt = prompt.replace(marker, "");
+        }
+    }
+    let author = if raw_author.is_empty() {
+        ""
+    } else {
+        raw_author
+    };
+    let replaced = prompt
+        .replace("{title}", title)
+        .replace("{{title}}", title)
+        .replace("{{text}}", title)
+        .replace("{author}", author)
+        .replace("{{author}}", author);
+    if prompt.contains("{title}")
+        || prompt.contains("{{title}}")
+        || prompt.contains("{{text}}")
+        || prompt.contains("{author}")
+        || prompt.contains("{{author}}")
+    {
+        replaced
+    } else {
+        let book_info = if author.is_empty() {
+            title.to_string()
+        } else {
+            format!("{} / {}", title, author)
+        };
+        format!("{}\n\n书籍信息：{}", replaced, book_info)
+    }
+}
+
+fn build_ai_image_request_bodies(
+    model: &str,
+    prompt: &str,
+    size: &str,
+    reference_data_url: Option<&str>,
+) -> Vec<serde_json::Value> {
+    fn base_body(
+        model: &str,
+        prompt: &str,
+        size: &str,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut map = serde_json::Map::new();
+        map.insert(
+            "model".to_string(),
+            serde_json::Value::String(model.to_string()),
+        );
+        map.insert(
+            "prompt".to_string(),
+            serde_json::Value::String(prompt.to_string()),
+        );
+        map.insert("n".to_string(), serde_json::Value::Number(1.into()));
+        if !size.trim().is_empty() {
+            map.insert(
+                "size".to_string(),
+                serde_json::Value::String(size.trim().to_string()),
+            );
+        }
+        map
+    }
+
+    let mut bodies = Vec::new();
+    if let Some(reference) = reference_data_url.filter(|value| !value.trim().is_empty()) {
+        for variant in [
+            "image_array",
+            "image_string",
+            "image_urls_array",
+            "image_array_no_format",
+            "image_string_no_format",
+            "image_urls_array_no_format",
+        ] {
+            let mut map = base_body(model, prompt, size);
+            match variant {
+                "image_array" | "image_array_no_format" => {
+                    map.insert("image".to_string(), serde_json::json!([reference]));
+                }
+                "image_string" | "image_string_no_format" => {
+                    map.insert(
+                        "image".to_string(),
+                        serde_json::Value::String(reference.to_string()),
+                    );
+                }
+                _ => {
+                    map.insert("image_urls".to_string(), serde_json::json!([reference]));
+                }
+            }
+            if !variant.ends_with("_no_format") {
+                map.insert(
+                    "response_format".to_string(),
+                    serde_json::Value::String("b64_json".to_string()),
+                );
+            }
+            bodies.push(serde_json::Value::Object(map));
+        }
+    }
+
+    let mut with_format = base_body(model, prompt, size);
+    with_format.insert(
+        "response_format".to_string(),
+        serde_json::Value::String("b64_json".to_string()),
+    );
+    bodies.push(serde_json::Value::Object(with_format));
+    bodies.push(serde_json::Value::Object(base_body(model, prompt, size)));
+
+    let mut seen = HashSet::new();
+    bodies
+        .into_iter()
+        .filter(|body| seen.insert(body.to_string()))
+        .collect()
+}
+
+async fn download_ai_image_url(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, String> {
+    let response = client
+        .get(url.trim())
+        .header("Accept", "image/*,*/*;q=0.8")
+        .send()
+        .await
+        .map_err(|e| format!("下载生成图片失败: {}", e))?;
+    let status = response.status();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| format!("读取生成图片失败: {}", e))?;
+    if !status.is_success() {
+        return Err(format!("下载生成图片返回 {}", status));
+    }
+    let bytes = bytes.to_vec();
+    validate_ai_image_bytes(&bytes, "生成图片")?;
+    Ok(bytes)
+}
+
+const REMOTE_IMAGE_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteImageDownloadResponse {
+    data_base64: String,
+    content_type: String,
+    final_url: String,
+}
+
+fn validate_remote_image_url(url: &reqwest::Url) -> Result<(), String> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("仅支持 HTTP 或 HTTPS 图片地址".to_string());
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err("图片地址不能包含用户名或密码".to_string());
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| "图片地址缺少主机名".to_string())?
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    if host == "localhost" || host.ends_with(".localhost") || host.ends_with(".local") {
+        return Err("不允许下载本机或局域网图片地址".to_string());
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        let blocked = match ip {
+            IpAddr::V4(ip) => {
+                ip.is_private()
+                    || ip.is_loopback()
+                    || ip.is_link_local()
+                    || ip.is_broadcast()
+                    || ip.is_documentation()
+                    || ip.is_unspecified()
+            }
+            IpAddr::V6(ip) => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+                    || ip.to_ipv4().map_or(false, |mapped| {
+                        mapped.is_private()
+                            || mapped.is_loopback()
+                            || mapped.is_link_local()
+                            || mapped.is_broadcast()
+                            || mapped.is_documentation()
+                            || mapped.is_unspecified()
+                    })
+            }
+        };
+        if blocked {
+            return Err("不允许下载本机、私有网络或链路本地图片地址".to_string());
+        }
+    }
+    Ok(())
+}
+
+fn raster_image_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some("image/jpeg");
+    }
+    if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]) {
+        return Some("image/png");
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return Some("image/gif");
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    None
+}
+
+#[tauri::command]
+async fn download_remote_image(url: String) -> Result<RemoteImageDownloadResponse, String> {
+    let mut current = reqwest::Url::parse(url.trim())
+        .map_err(|e| format!("图片地址无效: {}", e))?;
+    validate_remote_image_url(&current)?;
+
+    let client = reqwest::Client::builder()
+        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) TEpub-Editor/1.0")
+        .timeout(Duration::from_secs(30))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| format!("初始化图片下载失败: {}", e))?;
+
+    for redirect_count in 0..=5 {
+        validate_remote_image_url(&current)?;
+        let mut referer = current.clone();
+        referer.set_path("/");
+        referer.set_query(None);
+        referer.set_fragment(None);
+        let response = client
+            .get(current.clone())
+            .header(
+                reqwest::header::ACCEPT,
+                "image/avif,image/webp,image/png,image/jpeg,image/gif,image/*;q=0.8,*/*;q=0.5",
+            )
+            .header(reqwest::header::REFERER, referer.as_str())
+            .send()
+            .await
+            .map_err(|e| format!("下载图片失败: {}", e))?;
+
+        if response.status().is_redirection() {
+            if redirect_count == 5 {
+                return Err("图片地址重定向次数超过 5 次".to_string());
+            }
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| format!("图片服务器返回了无目标的重定向 {}", response.status()))?;
+            current = current
+                .join(location)
+                .map_err(|e| format!("图片重定向地址无效: {}", e))?;
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(format!("图片服务器返回 {}", response.status()));
+        }
+        if response.content_length().unwrap_or(0) > REMOTE_IMAGE_MAX_BYTES as u64 {
+            return Err("图片超过 32 MB 限制".to_string());
+        }
+
+        let final_url = response.url().to_string();
+        let mut response = response;
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| format!("读取图片响应失败: {}", e))?
+        {
+            if bytes.len() + chunk.len() > REMOTE_IMAGE_MAX_BYTES {
+                return Err("图片超过 32 MB 限制".to_string());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        if bytes.is_empty() {
+            return Err("图片响应为空".to_string());
+        }
+        let content_type = raster_image_content_type(&bytes).ok_or_else(|| {
+            "响应不是支持的 JPG、PNG、WebP 或 GIF 图片".to_string()
+        })?;
+        return Ok(RemoteImageDownloadResponse {
+            data_base64: general_purpose::STANDARD.encode(bytes),
+            content_type: content_type.to_string(),
+            final_url,
+        });
+    }
+
+    Err("图片下载失败".to_string())
+}
+
+async fn extract_ai_image_bytes(
+    client: &reqwest::Client,
+    value: &serde_json::Value,
+    key_hint: Option<&str>,
+) -> Option<Vec<u8>> {
+    match value {
+        serde_json::Value::String(text) => {
+            if let Some(bytes) = decode_ai_data_url(text).or_else(|| decode_ai_base64_image(text)) {
+                return Some(bytes);
+            }
+            let key = key_hint.unwrap_or("").to_ascii_lowercase();
+            if (key.contains("url") || key == "uri" || key == "href") && is_remote_ai_url(text) {
+                return download_ai_image_url(client, text).await.ok();
+            }
+            None
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                if let Some(bytes) = Box::pin(extract_ai_image_bytes(client, item, key_hint)).await
+                {
+                    return Some(bytes);
+                }
+            }
+            None
+        }
+        serde_json::Value::Object(map) => {
+            for key in [
+                "b64_json",
+                "base64",
+                "image_base64",
+                "data",
+                "content",
+                "url",
+                "image_url",
+                "uri",
+                "href",
+            ] {
+                if let Some(item) = map.get(key) {
+                    if let Some(bytes) =
+                        Box::pin(extract_ai_image_bytes(client, item, Some(key))).await
+                    {
+                        return Some(bytes);
+                    }
+                }
+            }
+            for (key, item) in map {
+                if let Some(bytes) = Box::pin(extract_ai_image_bytes(client, item, Some(key))).await
+                {
+                    return Some(bytes);
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+async fn request_ai_image_generation(
+    client: &reqwest::Client,
+    request: &ToolboxAiImageRequest,
+    body: &serde_json::Value,
+) -> Result<Vec<u8>, String> {
+    let url = ai_image_generation_url(&request.base_url)?;
+    let response = client
+        .post(url)
+        .bearer_auth(request.api_key.trim())
+        .header("Accept", "application/json,image/*")
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| format!("请求生图接口失败: {}", e))?;
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| format!("读取生图响应失败: {}", e))?;
+    if bytes.len() > AI_IMAGE_MAX_RESPONSE_BYTES {
+        return Err("生图响应过大".to_string());
+    }
+    if !status.is_success() {
+        let text = String::from_utf8_lossy(&bytes).to_string();
+        return Err(format!(
+            "生图接口返回 {}: {}",
+            status,
+            ai_json_error_preview(&text)
+        ));
+    }
+    if content_type.starts_with("image/") {
+        let out = bytes.to_vec();
+        validate_ai_image_bytes(&out, "生成图片")?;
+        return Ok(out);
+    }
+    let text = String::from_utf8_lossy(&bytes).to_string();
+    let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| {
+        format!(
+            "生图接口未返回有效 JSON: {}; {}",
+            e,
+            text.chars().take(180).collect::<String>()
+        )
+    })?;
+    extract_ai_image_bytes(client, &value, None)
+        .await
+        .ok_or_else(|| "生图接口返回中没有图片数据".to_string())
+}
+
+async fn request_ai_image_edit(
+    client: &reqwest::Client,
+    request: &ToolboxAiImageRequest,
+    prompt: &str,
+    size: &str,
+    reference_bytes: &[u8],
+) -> Result<Vec<u8>, String> {
+    let url = ai_image_edits_url(&request.base_url)?;
+    let (ext, mime) = ai_image_mime_from_bytes(reference_bytes);
+    let mut form = reqwest::multipart::Form::new()
+        .text("model", request.model.trim().to_string())
+        .text("prompt", prompt.to_string())
+        .text("n", "1")
+        .text("response_format", "b64_json");
+    if !size.trim().is_empty() {
+        form = form.text("size", size.trim().to_string());
+    }
+    form = form.part(
+        "image",
+        reqwest::multipart::Part::bytes(reference_bytes.to_vec())
+            .file_name(format!("reference.{}", ext))
+            .mime_str(mime)
+            .map_err(|e| format!("构造参考图失败: {}", e))?,
+    );
+
+    let response = client
+        .post(url)
+        .bearer_auth(request.api_key.trim())
+        .header("Accept", "application/json,image/*")
+        .multipart(form)
+        .send()
+        .await
+        .map_err(|e| format!("请求图片编辑接口失败: {}", e))?;
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let bytes = response
+        .bytes()
+        .await
+        .map_err(|e| format!("读取图片编辑响应失败: {}", e))?;
+    if bytes.len() > AI_IMAGE_MAX_RESPONSE_BYTES {
+        return Err("图片编辑响应过大".to_string());
+    }
+    if !status.is_success() {
+        let text = String::from_utf8_lossy(&bytes).to_string();
+        return Err(format!(
+            "图片编辑接口返回 {}: {}",
+            status,
+            ai_json_error_preview(&text)
+        ));
+    }
+    if content_type.starts_with("image/") {
+        let out = bytes.to_vec();
+        validate_ai_image_bytes(&out, "生成图片")?;
+        return Ok(out);
+    }
+    let text = String::from_utf8_lossy(&bytes).to_string();
+    let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| {
+        format!(
+            "图片编辑接口未返回有效 JSON: {}; {}",
+            e,
+            text.chars().take(180).collect::<String>()
+        )
+    })?;
+    extract_ai_image_bytes(client, &value, None)
+        .await
+        .ok_or_else(|| "图片编辑接口返回中没有图片数据".to_string())
+}
+
+#[tauri::command]
+async fn toolbox_generate_ai_image(
+    request: ToolboxAiImageRequest,
+) -> Result<ToolboxAiImageResult, String> {
+    if request.base_url.trim().is_empty()
+        || request.api_key.trim().is_empty()
+        || request.model.trim().is_empty()
+    {
+        return Err("请先填写 Base URL、API Key 和模型".to_string());
+    }
+    if request.prompt.trim().is_empty() {
+        return Err("提示词不能为空".to_string());
+    }
+    let target = match request.target.trim() {
+        "duokan" | "cover" => "duokan",
+        "standard" => "standard",
+        "banner" => "banner",
+        _ => "duokan",
+    };
+    let reference_data = request.reference_data.as_deref();
+    if let Some(bytes) = reference_data {
+        if bytes.len() > AI_IMAGE_MAX_INPUT_BYTES {
+            return Err("参考封面图片过大".to_string());
+        }
+        validate_ai_image_bytes(bytes, "参考封面")?;
+    }
+
+    let prompt = render_ai_image_prompt(
+        &request.prompt,
+        request.title.as_deref(),
+        request.author.as_deref(),
+    );
+    let client = reqwest::Client::builder()
+        .user_agent(AI_IMAGE_USER_AGENT)
+        .connect_timeout(Duration::from_secs(20))
+        .timeout(Duration::from_secs(180))
+        .build()
+        .map_err(|e| format!("初始化生图客户端失败: {}", e))?;
+
+    let reference_data_url = reference_data.map(|bytes| {
+        let (_, mime) = ai_image_mime_from_bytes(bytes);
+        format!(
+            "data:{};base64,{}",
+            mime,
+            general_purpose::STANDARD.encode(bytes)
+        )
+    });
+    let sizes = image_size_candidates(&request.size, target);
+    let is_gpt_image = request.model.to_ascii_lowercase().contains("gpt-image");
+    let mut last_error = None;
+
+    if is_gpt_image {
+        if let Some(bytes) = reference_data {
+            for size in &sizes {
+                match request_ai_image_edit(&client, &request, &prompt, size, bytes).await {
+                    Ok(out) => {
+                        let (ext, mime) = ai_image_mime_from_bytes(&out);
+                        let stem =
+                            sanitize_filename_part(request.title.as_deref().unwrap_or(target));
+                        let stem = if stem.is_empty() {
+                            target.to_string()
+                        } else {
+                            stem
+                        };
+                        let file_name = format!("{}-{}.{}", stem, target, ext);
+                        return Ok(ToolboxAiImageResult {
+                            bytes: out,
+                            mime: mime.to_string(),
+                            extension: ext.to_string(),
+                            file_name,
+                            prompt,
+                            size: size.clone(),
+                        });
+                    }
+                    Err(e) => last_error = Some(e),
+                }
+            }
+        }
+    }
+
+    for size in &sizes {
+        for body in build_ai_image_request_bodies(
+            &request.model,
+            &prompt,
+            size,
+            reference_data_url.as_deref(),
+        ) {
+            match request_ai_image_generation(&client, &request, &body).await {
+                Ok(out) => {
+                    let (ext, mime) = ai_image_mime_from_bytes(&out);
+                    let stem = sanitize_filename_part(request.title.as_deref().unwrap_or(target));
+                    let stem = if stem.is_empty() {
+                        target.to_string()
+                    } else {
+                        stem
+                    };
+                    let file_name = format!("{}-{}.{}", stem, target, ext);
+                    return Ok(ToolboxAiImageResult {
+                        bytes: out,
+                        mime: mime.to_string(),
+                        extension: ext.to_string(),
+                        file_name,
+                        prompt,
+                        size: size.clone(),
+                    });
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| "未获取到生图结果".to_string()))
+}
+
+#[tauri::command]
+fn toolbox_epub_diagnose(epub_path: String) -> Result<ToolboxEpubDiagnosticResult, String> {
+    let source = PathBuf::from(&epub_path);
+    if !source.exists() {
+        return Err(format!("File does not exist: {}", source.to_string_lossy()));
+    }
+
+    let bytes = fs::read(&source).map_err(|e| format!("Read EPUB failed: {}", e))?;
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes.clone()))
+        .map_err(|e| format!("Read EPUB failed: {}", e))?;
+    let mut issues = Vec::new();
+    let mut entry_names = Vec::new();
+
+    for i in 0..archive.len() {
+        let file = archive
+            .by_index(i)
+            .map_err(|e| format!("Read ZIP entry failed: {}", e))?;
+        entry_names.push(file.name().replace('\\', "/"));
+    }
+
+    let entry_set: HashSet<String> = entry_names.iter().cloned().collect();
+    let mut lower_to_names: HashMap<String, Vec<String>> = HashMap::new();
+    for name in &entry_names {
+        lower_to_names
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(name.clone());
+    }
+
+    if !entry_set.contains("mimetype") {
+        push_diag_issue(
+            &mut issues,
+            "warning",
+            "missing-mimetype",
+            Some("mimetype".to_string()),
+            "EPUB has no top-level mimetype entry.",
+        );
+    }
+
+    if !entry_set.contains("META-INF/container.xml") {
+        if let Some(matches) = case_mismatch_names(&lower_to_names, "META-INF/container.xml") {
+            push_diag_issue(
+                &mut issues,
+                "warning",
+                "case-mismatch",
+                Some("META-INF/container.xml".to_string()),
+                format!(
+                    "container.xml exists with different case: {}",
+                    matches.join(", ")
+                ),
+            );
+        } else {
+            push_diag_issue(
+                &mut issues,
+                "error",
+                "missing-container",
+                Some("META-INF/container.xml".to_string()),
+                "EPUB has no META-INF/container.xml entry.",
+            );
+        }
+    }
+
+    let opf_path = match find_opf_path_in_epub_bytes(&bytes) {
+        Ok(path) => path,
+        Err(e) => {
+            push_diag_issue(
+                &mut issues,
+                "error",
+                "missing-opf",
+                None,
+                format!("Cannot locate OPF: {}", e),
+            );
+            let error_count = issues.iter().filter(|issue| issue.level == "error").count();
+            let warning_count = issues
+                .iter()
+                .filter(|issue| issue.level == "warning")
+                .count();
+            return Ok(ToolboxEpubDiagnosticResult {
+                source_path: source.to_string_lossy().to_string(),
+                opf_path: None,
+                total_entries: entry_names.len(),
+                manifest_items: 0,
+                error_count,
+                warning_count,
+                issues,
+            });
+        }
+    };
+
+    if !entry_set.contains(&opf_path) {
+        if let Some(matches) = case_mismatch_names(&lower_to_names, &opf_path) {
+            push_diag_issue(
+                &mut issues,
+                "warning",
+                "case-mismatch",
+                Some(opf_path.clone()),
+                format!("OPF exists with different case: {}", matches.join(", ")),
+            );
+        } else {
+            push_diag_issue(
+                &mut issues,
+                "error",
+                "missing-opf",
+                Some(opf_path.clone()),
+                "container.xml points to an OPF entry that is not in the ZIP.",
+            );
+        }
+    }
+
+    let manifest_items = match parse_toolbox_manifest_items(&bytes, &opf_path) {
+        Ok(items) => items,
+        Err(e) => {
+            push_diag_issue(
+                &mut issues,
+                "error",
+                "manifest-parse",
+                Some(opf_path.clone()),
+                format!("Parse OPF manifest failed: {}", e),
+            );
+            let error_count = issues.iter().filter(|issue| issue.level == "error").count();
+            let warning_count = issues
+                .iter()
+                .filter(|issue| issue.level == "warning")
+                .count();
+            return Ok(ToolboxEpubDiagnosticResult {
+                source_path: source.to_string_lossy().to_string(),
+                opf_path: Some(opf_path),
+                total_entries: entry_names.len(),
+                manifest_items: 0,
+                error_count,
+                warning_count,
+                issues,
+            });
+        }
+    };
+
+    let manifest_set: HashSet<String> = manifest_items
+        .iter()
+        .map(|item| item.abs_path.clone())
+        .collect();
+    let mut required_entries: HashSet<String> = HashSet::new();
+    required_entries.insert("mimetype".to_string());
+    required_entries.insert("META-INF/container.xml".to_string());
+    required_entries.insert(opf_path.clone());
+
+    for item in &manifest_items {
+        if entry_set.contains(&item.abs_path) {
+            continue;
+        }
+        if let Some(matches) = case_mismatch_names(&lower_to_names, &item.abs_path) {
+            push_diag_issue(
+                &mut issues,
+                "warning",
+                "case-mismatch",
+                Some(item.abs_path.clone()),
+                format!(
+                    "Manifest item '{}' exists with different case: {}",
+                    item.id,
+                    matches.join(", ")
+                ),
+            );
+        } else {
+            push_diag_issue(
+                &mut issues,
+                "error",
+                "manifest-missing",
+                Some(item.abs_path.clone()),
+                format!("Manifest item '{}' points to a missing resource.", item.id),
+            );
+        }
+    }
+
+    for name in &entry_names {
+        if manifest_set.contains(name) || required_entries.contains(name) {
+            continue;
+        }
+        if is_diagnostic_resource_entry(name) {
+            push_diag_issue(
+                &mut issues,
+                "warning",
+                "unregistered-resource",
+                Some(name.clone()),
+                "Resource exists in ZIP but is not registered in OPF manifest.",
+            );
+        }
+    }
+
+    let mut seen_refs = HashSet::new();
+    for name in &entry_names {
+        if !is_text_like_entry(name) || name.ends_with('/') {
+            continue;
+        }
+        let data = match read_zip_entry_bytes(&bytes, name) {
+            Ok(data) => data,
+            Err(e) => {
+                push_diag_issue(
+                    &mut issues,
+                    "warning",
+                    "read-entry",
+                    Some(name.clone()),
+                    format!("Cannot read text-like entry: {}", e),
+                );
+                continue;
+            }
+        };
+        let text = String::from_utf8_lossy(&data).to_string();
+        for raw_ref in collect_diagnostic_refs(&text) {
+            let Some(abs_ref) = resolve_epub_ref(name, &raw_ref) else {
+                continue;
+            };
+            if !seen_refs.insert((name.clone(), raw_ref.clone(), abs_ref.clone())) {
+                continue;
+            }
+            if entry_set.contains(&abs_ref) {
+                continue;
+            }
+            if let Some(matches) = case_mismatch_names(&lower_to_names, &abs_ref) {
+                push_diag_issue(
+                    &mut issues,
+                    "warning",
+                    "ref-case-mismatch",
+                    Some(name.clone()),
+                    format!(
+                        "Reference '{}' resolves to '{}' but ZIP contains different case: {}",
+                        raw_ref,
+                        abs_ref,
+                        matches.join(", ")
+                    ),
+                );
+            } else {
+                push_diag_issue(
+                    &mut issues,
+                    "error",
+                    "missing-reference",
+                    Some(name.clone()),
+                    format!(
+                        "Reference '{}' resolves to missing resource '{}'.",
+                        raw_ref, abs_ref
+                    ),
+                );
+            }
+        }
+    }
+
+    let error_count = issues.iter().filter(|issue| issue.level == "error").count();
+    let warning_count = issues
+        .iter()
+        .filter(|issue| issue.level == "warning")
+        .count();
+
+    Ok(ToolboxEpubDiagnosticResult {
+        source_path: source.to_string_lossy().to_string(),
+        opf_path: Some(opf_path),
+        total_entries: entry_names.len(),
+        manifest_items: manifest_items.len(),
+        error_count,
+        warning_count,
+        issues,
+    })
+}
+
+fn toolbox_epub_diagnose_tool_result(source: &Path) -> Result<ToolboxEpubToolResult, String> {
+    let result = toolbox_epub_diagnose(source.to_string_lossy().to_string())?;
+    let action = if result.error_count > 0 || result.warning_count > 0 {
+        "epub_diagnose_issue"
+    } else {
+        "epub_diagnose_clean"
+    };
+    let message = if result.error_count == 0 && result.warning_count == 0 {
+        format!(
+            "EPUB diagnosis completed: no structural issues found; {} ZIP entries, {} manifest items",
+            result.total_entries, result.manifest_items
+        )
+    } else {
+        let mut lines = vec![format!(
+            "EPUB diagnosis completed: {} errors, {} warnings; {} ZIP entries, {} manifest items",
+            result.error_count, result.warning_count, result.total_entries, result.manifest_items
+        )];
+        for issue in result.issues.iter().take(6) {
+            let path = issue
+                .path
+                .as_deref()
+                .filter(|value| !value.is_empty())
+                .map(|value| format!(" [{}]", value))
+                .unwrap_or_default();
+            lines.push(format!(
+                "{} {}{}: {}",
+                issue.level, issue.kind, path, issue.message
+            ));
+        }
+        if result.issues.len() > 6 {
+            lines.push(format!("... and {} more issues", result.issues.len() - 6));
+        }
+        lines.join("\n")
+    };
+
+    Ok(toolbox_epub_tool_result(
+        source,
+        source,
+        false,
+        action,
+        message,
+    ))
+}
+
+#[tauri::command]
+async fn toolbox_scan_batch_inputs(
+    input_paths: Vec<String>,
+    output_dir: Option<String>,
+) -> Result<ToolboxBatchScanResult, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let resolved_output_dir = resolve_batch_output_dir(&input_paths, output_dir);
+        let sources = list_epub_sources(&input_paths, &resolved_output_dir)?;
+        Ok(ToolboxBatchScanResult {
+            input_paths: sources
+                .into_iter()
+                .map(|path| path.to_string_lossy().to_string())
+                .collect(),
+            output_dir: resolved_output_dir.to_string_lossy().to_string(),
+        })
+    })
+    .await
+    .map_err(|e| format!("批量目录扫描失败: {}", e))?
+}
+
+#[tauri::command]
+fn toolbox_cancel_batch(task_id: String) -> Result<(), String> {
+    if task_id.trim().is_empty() {
+        return Err("缺少批量任务 ID".to_string());
+    }
+    request_toolbox_batch_cancel(&task_id);
+    Ok(())
+}
+
+#[tauri::command]
+async fn toolbox_run_batch(
+    app: tauri::AppHandle,
+    task_id: String,
+    tool: String,
+    input_paths: Vec<String>,
+    image_format: Option<String>,
+    output_dir: Option<String>,
+    output_mode: Option<String>,
+    conflict_mode: Option<String>,
+) -> Result<ToolboxBatchSummary, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        run_toolbox_batch_impl(
+            app,
+            task_id,
+            tool,
+            input_paths,
+            image_format,
+            output_dir,
+            output_mode,
+            conflict_mode,
+        )
+    })
+    .await
+    .map_err(|e| format!("批量任务执行失败: {}", e))?
+}
+
+// ============================================================
+// ===== App Data Layout (绿色版 vs 安装版自动路由) =====
+// ============================================================
+//
+// 判定方式：exe 同级目录存在 `portable.txt` 标记文件即视为绿色版。
+// 绿色版数据目录：<exe_dir>/data/
+// 安装版数据目录：Tauri app_data_dir (%APPDATA%/<bundle-id>)
+//
+// 凡是落到这个目录的内容：app 配置、TXT 编辑器 .history、library.pointer 等。
+// 书库的 epub 副本与封面是另一回事，由用户在书库设置里指定的 customWorkDir 决定。
+
+fn portable_marker_path() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    let dir = exe.parent()?;
+    Some(dir.join("portable.txt"))
+}
+
+fn is_portable() -> bool {
+    portable_marker_path().map(|p| p.exists()).unwrap_or(false)
+}
+
+fn portable_data_root() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    let dir = exe.parent()?;
+    Some(dir.join("data"))
+}
+
+fn app_data_root(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    if is_portable() {
+        if let Some(p) = portable_data_root() {
+            return Ok(p);
+        }
+    }
+    use tauri::Manager;
+    app.path()
+        .app_data_dir()
+        .map_err(|e| format!("无法获取 app_data_dir: {}", e))
+}
+
+fn epub_templates_root(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(app_data_root(app)?.join("epub-templates"))
+}
+
+fn epub_template_repositories_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(epub_templates_root(app)?.join("repositories.json"))
+}
+
+fn style_templates_root(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(app_data_root(app)?.join("style-templates"))
+}
+
+fn builtin_style_template_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(style_templates_root(app)?.join("builtin.css"))
+}
+
+fn style_template_path(app: &tauri::AppHandle, id: &str) -> Result<PathBuf, String> {
+    let safe_id = sanitize_filename_part(id);
+    if safe_id.is_empty() {
+        return Err("模板 ID 无效".to_string());
+    }
+    Ok(style_templates_root(app)?.join(format!("{}.css", safe_id)))
+}
+
+fn library_font_aliases_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(library_data_dir(app)?.join("font-aliases.json"))
+}
+
+fn read_library_font_aliases(app: &tauri::AppHandle) -> Result<LibraryFontAliasMap, String> {
+    let path = library_font_aliases_path(app)?;
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let bytes = fs::read(&path).map_err(|e| format!("读取字体别名失败: {}", e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("解析字体别名失败: {}", e))
+}
+
+fn write_library_font_aliases(
+    app: &tauri::AppHandle,
+    aliases: &LibraryFontAliasMap,
+) -> Result<(), String> {
+    let dir = library_data_dir(app)?;
+    ensure_dir(&dir)?;
+    let path = library_font_aliases_path(app)?;
+    let bytes =
+        serde_json::to_vec_pretty(aliases).map_err(|e| format!("序列化字体别名失败: {}", e))?;
+    fs::write(&path, bytes).map_err(|e| format!("保存字体别名失败: {}", e))
+}
+
+fn style_template_name_from_path(path: &Path) -> String {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .map(|s| s.replace('_', " ").replace('-', " "))
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| "样式模板".to_string())
+}
+
+fn build_style_template_info(path: &Path, is_builtin: bool) -> Result<StyleTemplateInfo, String> {
+    let file_name = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| "模板文件名无效".to_string())?
+        .to_string();
+    let id = if is_builtin {
+        "builtin".to_string()
+    } else {
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string())
+            .ok_or_else(|| "模板 ID 无效".to_string())?
+    };
+    Ok(StyleTemplateInfo {
+        id,
+        name: if is_builtin {
+            "内置模板".to_string()
+        } else {
+            style_template_name_from_path(path)
+        },
+        file_name,
+        path: path.to_string_lossy().to_string(),
+        is_builtin,
+    })
+}
+
+fn github_owner_repo(url: &str) -> Result<(String, String), String> {
+    let trimmed = url.trim().trim_end_matches('/');
+    let parsed = reqwest::Url::parse(trimmed).map_err(|e| format!("模板仓库 URL 无效: {}", e))?;
+    let host = parsed.host_str().unwrap_or_default().to_lowercase();
+    if host != "github.com" {
+        return Err("当前模板仓库仅支持 github.com".to_string());
+    }
+    let parts: Vec<&str> = parsed
+        .path_segments()
+        .map(|segments| segments.collect())
+        .unwrap_or_else(Vec::new);
+    if parts.len() < 2 {
+        return Err("GitHub 仓库 URL 需要包含 owner/repo".to_string());
+    }
+    let owner = parts[0].to_string();
+    let repo = parts[1].trim_end_matches(".git").to_string();
+    if owner.is_empty() || repo.is_empty() {
+        return Err("GitHub 仓库 URL 需要包含 owner/repo".to_string());
+    }
+    Ok((owner, repo))
+}
+
+fn raw_github_url(repo: &EpubTemplateRepository, path: &str) -> Result<String, String> {
+    let (owner, name) = github_owner_repo(&repo.url)?;
+    let clean_path = path.trim_start_matches('/');
+    Ok(format!(
+        "https://raw.githubusercontent.com/{}/{}/{}/{}",
+        owner, name, repo.branch, clean_path
+    ))
+}
+
+fn github_tree_url(repo: &EpubTemplateRepository, dir: &str) -> Result<String, String> {
+    let (owner, name) = github_owner_repo(&repo.url)?;
+    let clean_dir = dir.trim_matches('/');
+    Ok(format!(
+        "https://api.github.com/repos/{}/{}/git/trees/{}:{}?recursive=1",
+        owner, name, repo.branch, clean_dir
+    ))
+}
+
+fn read_template_repositories(
+    app: &tauri::AppHandle,
+) -> Result<Vec<EpubTemplateRepository>, String> {
+    let path = epub_template_repositories_path(app)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let bytes = fs::read(&path).map_err(|e| format!("读取模板仓库配置失败: {}", e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("解析模板仓库配置失败: {}", e))
+}
+
+fn write_template_repositories(
+    app: &tauri::AppHandle,
+    repos: &[EpubTemplateRepository],
+) -> Result<(), String> {
+    let root = epub_templates_root(app)?;
+    ensure_dir(&root)?;
+    let path = epub_template_repositories_path(app)?;
+    let bytes =
+        serde_json::to_vec_pretty(repos).map_err(|e| format!("序列化模板仓库配置失败: {}", e))?;
+    fs::write(path, bytes).map_err(|e| format!("保存模板仓库配置失败: {}", e))
+}
+
+fn get_history_base_dir(app: Option<&tauri::AppHandle>) -> PathBuf {
+    // 优先使用书库工作目录下的 _data/history（书库已配置时）
+    if let Some(app) = app {
+        if let Ok(p) = library_history_dir(app) {
+            return p;
+        }
+    }
+    // 回退：绿色版 exe_dir/data/history
+    if is_portable() {
+        if let Some(p) = portable_data_root() {
+            return p.join("history");
+        }
+    }
+    // 回退：安装版 app_data_root/history
+    if let Some(app) = app {
+        if let Ok(p) = app_data_root(app) {
+            return p.join("history");
+        }
+    }
+    // 终极兜底
+    PathBuf::from(".history")
+}
+
+fn history_key_for_path(original_path: &str) -> String {
+    // Keep names deterministic and avoid collisions for files that share the same stem.
+    let digest = format!("{:x}", md5::compute(original_path.as_bytes()));
+    digest[..8].to_string()
+}
+
+fn pick_font_family_name(font_data: &[u8], source_path: &Path) -> String {
+    if let Some(name) = pick_preferred_font_name(parse_font_internal_names(font_data)) {
+        return name;
+    }
+
+    source_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "CustomFont".to_string())
+}
+
+fn contains_cjk(text: &str) -> bool {
+    text.chars().any(|ch| {
+        matches!(
+            ch as u32,
+            0x3400..=0x4DBF
+                | 0x4E00..=0x9FFF
+                | 0xF900..=0xFAFF
+                | 0x20000..=0x2A6DF
+                | 0x2A700..=0x2B73F
+                | 0x2B740..=0x2B81F
+                | 0x2B820..=0x2CEAF
+                | 0x2CEB0..=0x2EBEF
+        )
+    })
+}
+
+fn font_name_preference_score(name: &str) -> i32 {
+    let trimmed = name.trim();
+    if trimmed.is_empty() {
+        return i32::MIN;
+    }
+
+    let mut score = 0i32;
+    if contains_cjk(trimmed) {
+        score += 1000;
+    }
+    if trimmed.contains(' ') {
+        score += 40;
+    }
+    if trimmed.len() >= 2 {
+        score += 10;
+    }
+    if trimmed
+        .chars()
+        .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '+' | '.'))
+    {
+        score -= 120;
+    }
+    if trimmed.eq_ignore_ascii_case("regular")
+        || trimmed.eq_ignore_ascii_case("bold")
+        || trimmed.eq_ignore_ascii_case("italic")
+    {
+        score -= 400;
+    }
+    score - trimmed.len() as i32 / 4
+}
+
+fn pick_preferred_font_name(names: Vec<String>) -> Option<String> {
+    names
+        .into_iter()
+        .filter(|name| !name.trim().is_empty())
+        .max_by_key(|name| font_name_preference_score(name))
+}
+
+fn build_library_font_info(
+    app: &tauri::AppHandle,
+    font_path: &Path,
+) -> Result<LibraryFontInfo, String> {
+    let font_data = fs::read(font_path).map_err(|e| format!("读取字体失败: {}", e))?;
+    let file_name = font_path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| "字体文件名无效".to_string())?
+        .to_string();
+    let aliases = read_library_font_aliases(app).unwrap_or_default();
+    let family = aliases
+        .get(&file_name)
+        .cloned()
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| pick_font_family_name(&font_data, font_path));
+    Ok(LibraryFontInfo {
+        css_value: format!(r#""{}", serif"#, family),
+        family,
+        file_name,
+        path: font_path.to_string_lossy().to_string(),
+    })
+}
+
+#[tauri::command]
+fn list_epub_template_repositories(
+    app: tauri::AppHandle,
+) -> Result<Vec<EpubTemplateRepository>, String> {
+    read_template_repositories(&app)
+}
+
+#[tauri::command]
+fn add_epub_template_repository(
+    app: tauri::AppHandle,
+    name: String,
+    url: String,
+    branch: Option<String>,
+) -> Result<Vec<EpubTemplateRepository>, String> {
+    let (owner, repo_name) = github_owner_repo(&url)?;
+    let fallback_name = format!("{}-{}", owner, repo_name);
+    let display_name = if name.trim().is_empty() {
+        fallback_name.clone()
+    } else {
+        name.trim().to_string()
+    };
+    let mut repo_id = sanitize_filename_part(&display_name);
+    if repo_id.trim().is_empty() {
+        repo_id = sanitize_filename_part(&fallback_name);
+    }
+    let mut branch_name = branch.unwrap_or_else(default_template_branch);
+    if branch_name.trim().is_empty() {
+        branch_name = default_template_branch();
+    }
+
+    let mut repos = read_template_repositories(&app)?;
+    let repo = EpubTemplateRepository {
+        id: repo_id,
+        name: display_name,
+        url: url.trim().to_string(),
+        branch: branch_name.trim().to_string(),
+        last_synced: String::new(),
+    };
+    repos.retain(|item| item.id != repo.id && item.url != repo.url);
+    repos.push(repo);
+    write_template_repositories(&app, &repos)?;
+    Ok(repos)
+}
+
+#[tauri::command]
+async fn sync_epub_template_repository(
+    app: tauri::AppHandle,
+    repository_id: String,
+) -> Result<EpubTemplateRepositoryIndex, String> {
+    let mut repos = read_template_repositories(&app)?;
+    let repo = repos
+        .iter()
+        .find(|item| item.id == repository_id)
+        .cloned()
+        .ok_or_else(|| "模板仓库不存在".to_string())?;
+    let index_url = raw_github_url(&repo, "index.json")?;
+    let client = reqwest::Client::builder()
+        .user_agent("TEpub-Editor")
+        .build()
+        .map_err(|e| format!("创建 HTTP 客户端失败: {}", e))?;
+    let text = client
+        .get(index_url)
+        .send()
+        .await
+        .map_err(|e| format!("拉取模板索引失败: {}", e))?
+        .error_for_status()
+        .map_err(|e| format!("模板索引响应异常: {}", e))?
+        .text()
+        .await
+        .map_err(|e| format!("读取模板索引失败: {}", e))?;
+    let index: EpubTemplateRepositoryIndex =
+        serde_json::from_str(&text).map_err(|e| format!("解析模板索引失败: {}", e))?;
+    for item in &mut repos {
+        if item.id == repository_id {
+            item.last_synced = chrono::Local::now().to_rfc3339();
+        }
+    }
+    write_template_repositories(&app, &repos)?;
+    Ok(index)
+}
+
+#[tauri::command]
+async fn install_remote_epub_template(
+    app: tauri::AppHandle,
+    repository_id: String,
+    template: EpubRemoteTemplate,
+) -> Result<EpubTemplateInstallResult, String> {
+    let repos = read_template_repositories(&app)?;
+    let repo = repos
+        .iter()
+        .find(|item| item.id == repository_id)
+        .cloned()
+        .ok_or_else(|| "模板仓库不存在".to_string())?;
+    let template_dir = Path::new(&template.path)
+        .parent()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| "模板 path 需要指向 template.json".to_string())?
+        .replace('\\', "/");
+    let tree_url = github_tree_url(&repo, &template_dir)?;
+    let client = reqwest::Client::builder()
+        .user_agent("TEpub-Editor")
+        .build()
+        .map_err(|e| format!("创建 HTTP 客户端失败: {}", e))?;
+    let tree_text = client
+        .get(tree_url)
+        .send()
+        .await
+        .map_err(|e| format!("拉取模板文件树失败: {}", e))?
+        .error_for_status()
+        .map_err(|e| format!("模板文件树响应异常: {}", e))?
+        .text()
+        .await
+        .map_err(|e| format!("读取模板文件树失败: {}", e))?;
+    let tree_json: serde_json::Value =
+        serde_json::from_str(&tree_text).map_err(|e| format!("解析模板文件树失败: {}", e))?;
+
+    let install_root = epub_templates_root(&app)?
+        .join("github")
+        .join(sanitize_filename_part(&repository_id))
+        .join(sanitize_filename_part(&template.id));
+    ensure_dir(&install_root)?;
+    let entries = tree_json
+        .get("tree")
+        .and_then(|v| v.as_array())
+        .ok_or_else(|| "GitHub tree 响应缺少 tree 字段".to_string())?;
+
+    let mut file_count = 0usize;
+    for entry in entries {
+        if entry.get("type").and_then(|v| v.as_str()) != Some("blob") {
+            continue;
+        }
+        let Some(path) = entry.get("path").and_then(|v| v.as_str()) else {
+            continue;
+        };
+        let relative = path
+            .trim_start_matches(&template_dir)
+            .trim_start_matches('/')
+            .replace('\\', "/");
+        if relative.is_empty() || relative.contains("..") {
+            continue;
+        }
+        let raw_url = raw_github_url(&repo, path)?;
+        let bytes = client
+            .get(raw_url)
+            .send()
+            .await
+            .map_err(|e| format!("下载模板文件失败 {}: {}", relative, e))?
+            .error_for_status()
+            .map_err(|e| format!("模板文件响应异常 {}: {}", relative, e))?
+            .bytes()
+            .await
+            .map_err(|e| format!("读取模板文件失败 {}: {}", relative, e))?;
+        let target = install_root.join(relative);
+        if let Some(parent) = target.parent() {
+            ensure_dir(parent)?;
+        }
+        fs::write(&target, &bytes).map_err(|e| format!("写入模板文件失败: {}", e))?;
+        file_count += 1;
+    }
+
+    Ok(EpubTemplateInstallResult {
+        template_id: template.id,
+        local_path: install_root.to_string_lossy().to_string(),
+        file_count,
+    })
+}
+
+// --- 指令区域 ---
+
+#[tauri::command]
+fn exit_app() {
+    process::exit(0);
+}
+
+// --- TXT 编码识别 ---
+
+#[derive(Serialize, Clone, Debug)]
+struct TextEncodingReport {
+    encoding: String,
+    confidence: f32,
+    has_bom: bool,
+    line_ending: String, // lf, crlf, cr
+    mixed_line_endings: bool,
+    malformed_count: usize,
+    forced: bool,
+}
+
+#[derive(Serialize)]
+struct TextFileOpenResult {
+    content: String,
+    report: TextEncodingReport,
+}
+
+// 解析用户指定的编码名。WHATWG 标签已覆盖 gbk/gb2312/big5/shift_jis/utf-16le 等，
+// 这里只补几个 Windows 代码页与无连字符写法。
+fn resolve_text_encoding(label: &str) -> Result<&'static encoding_rs::Encoding, String> {
+    let trimmed = label.trim();
+    let alias = match trimmed.to_ascii_lowercase().as_str() {
+        "utf8" => "utf-8",
+        "utf16" | "utf16le" => "utf-16le",
+        "utf16be" => "utf-16be",
+        "cp936" | "ms936" => "gbk",
+        "cp950" | "ms950" => "big5",
+        "cp932" => "shift_jis",
+        _ => trimmed,
+    };
+    encoding_rs::Encoding::for_label(alias.as_bytes())
+        .ok_or_else(|| format!("不支持的编码: {}", label))
+}
+
+// 逐段解码并统计非法字节序列数量；非法处替换为 U+FFFD，与 encoding_rs::decode 行为一致。
+fn decode_with_malformed_count(
+    encoding: &'static encoding_rs::Encoding,
+    bytes: &[u8],
+) -> (String, usize) {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut output = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length_without_replacement(bytes.len())
+            .unwrap_or(bytes.len()),
+    );
+    let mut malformed = 0;
+    let mut input = bytes;
+    loop {
+        let (result, read) = decoder.decode_to_string_without_replacement(input, &mut output, true);
+        input = &input[read..];
+        match result {
+            encoding_rs::DecoderResult::InputEmpty => break,
+            encoding_rs::DecoderResult::OutputFull => {
+                let extra = decoder
+                    .max_utf8_buffer_length_without_replacement(input.len())
+                    .unwrap_or(input.len() * 3);
+                output.reserve(extra.max(4));
+            }
+            encoding_rs::DecoderResult::Malformed(_, _) => {
+                malformed += 1;
+                output.push('\u{FFFD}');
+            }
+        }
+    }
+    (output, malformed)
+}
+
+// 统计原文换行风格，返回 (主导风格, 是否混用)。没有换行时按 lf 处理。
+fn detect_line_ending_style(text: &str) -> (&'static str, bool) {
+    let bytes = text.as_bytes();
+    let (mut crlf, mut lf, mut cr) = (0usize, 0usize, 0usize);
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\r' if bytes.get(i + 1) == Some(&b'\n') => {
+                crlf += 1;
+                i += 1;
+            }
+            b'\r' => cr += 1,
+            b'\n' => lf += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    let used_styles = [crlf, lf, cr].iter().filter(|&&n| n > 0).count();
+    let dominant = if crlf >= lf && crlf >= cr && crlf > 0 {
+        "crlf"
+    } else if cr > lf {
+        "cr"
+    } else {
+        "lf"
+    };
+    (dominant, used_styles > 1)
+}
+
+fn text_decode_confidence(base: f32, malformed: usize, content: &str) -> f32 {
+    if malformed == 0 {
+        return base;
+    }
+    let total = content.chars().count().max(1) as f32;
+    (base * (1.0 - malformed as f32 / total)).max(0.0)
+}
+
+// 解码 TXT 字节：BOM > 强制编码 > 严格 UTF-8 > chardetng 与候选编码打擂台。
+// 返回已规范化换行的正文与编码报告。
+fn decode_text_bytes(
+    buffer: &[u8],
+    forced_encoding: Option<&str>,
+) -> Result<(String, TextEncodingReport), String> {
+    let bom = encoding_rs::Encoding::for_bom(buffer);
+    let forced = match forced_encoding.map(str::trim).filter(|s| !s.is_empty()) {
+        Some(label) => Some(resolve_text_encoding(label)?),
+        None => None,
+    };
+
+    let (raw_content, encoding, confidence, has_bom, malformed) = if let Some(enc) = forced {
+        // 强制编码时只在 BOM 与所选编码一致时剥离 BOM
+        let (body, has_bom) = match bom {
+            Some((bom_enc, bom_len)) if bom_enc == enc => (&buffer[bom_len..], true),
+            _ => (buffer, false),
+        };
+        let (content, malformed) = decode_with_malformed_count(enc, body);
+        let confidence = text_decode_confidence(1.0, malformed, &content);
+        (content, enc, confidence, has_bom, malformed)
+    } else if let Some((enc, bom_len)) = bom {
+        let (content, malformed) = decode_with_malformed_count(enc, &buffer[bom_len..]);
+        let confidence = text_decode_confidence(1.0, malformed, &content);
+        (content, enc, confidence, true, malformed)
+    } else if let Ok(s) = std::str::from_utf8(buffer) {
+        // 1. 优先尝试 UTF-8 (严格)
+        (s.to_string(), encoding_rs::UTF_8, 1.0, false, 0)
+    } else {
+        // 策略：尝试多种编码，选取"乱码"（替换字符 ）最少的一个
+        let candidates = vec![
+            encoding_rs::UTF_8,
+            encoding_rs::GB18030,
+            encoding_rs::UTF_16LE,
+            encoding_rs::UTF_16BE,
+            encoding_rs::BIG5,
+        ];
+
+        // 2. Chardetng 检测作为基准
+        let mut detector = EncodingDetector::new();
+        detector.feed(buffer, true);
+        let (detected_encoding, assessed) = detector.guess_assess(Some(b"cn"), true);
+
+        let (mut best_content, mut min_errors) =
+            decode_with_malformed_count(detected_encoding, buffer);
+        let mut best_encoding = detected_encoding;
+        let mut confidence_base = if assessed { 0.9 } else { 0.6 };
+
+        let is_bad_guess = |enc: &'static encoding_rs::Encoding| {
+            enc == encoding_rs::WINDOWS_1252 || enc.name() == "ISO-8859-1"
+        };
+
+        // 如果检测结果完美且不是 windows-1252 (容易误判)，直接采用
+        if min_errors != 0 || is_bad_guess(best_encoding) {
+            // 3. 遍历候选编码打擂台
+            for enc in candidates {
+                let (content, errors) = decode_with_malformed_count(enc, buffer);
+
+                // 优选错误更少的。
+                // 特判：如果 best 是 windows-1252 (常见误判)，只要 candidates 里有 reasonably low error (<5%) 的中文编码，就替换它
+                if errors < min_errors
+                    || (is_bad_guess(best_encoding) && errors < buffer.len() / 20)
+                {
+                    min_errors = errors;
+                    best_content = content;
+                    best_encoding = enc;
+                    confidence_base = 0.7;
+                }
+            }
+        }
+
+        let confidence = text_decode_confidence(confidence_base, min_errors, &best_content);
+        (best_content, best_encoding, confidence, false, min_errors)
+    };
+
+    let (line_ending, mixed_line_endings) = detect_line_ending_style(&raw_content);
+    let report = TextEncodingReport {
+        encoding: encoding.name().to_string(),
+        confidence,
+        has_bom,
+        line_ending: line_ending.to_string(),
+        mixed_line_endings,
+        malformed_count: malformed,
+        forced: forced.is_some(),
+    };
+    Ok((normalize_line_endings(raw_content), report))
+}
+
+fn read_text_file_bytes(path: &str) -> Result<Vec<u8>, String> {
+    let mut file = fs::File::open(path).map_err(|e| format!("无法打开: {}", e))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)
+        .map_err(|e| format!("读取失败: {}", e))?;
+    Ok(buffer)
+}
+
+#[tauri::command]
+fn read_text_file(path: String) -> Result<String, String> {
+    let buffer = read_text_file_bytes(&path)?;
+    decode_text_bytes(&buffer, None).map(|(content, _)| content)
+}
+
+// 与 read_text_file 相同的解码流程，但同时返回编码报告；
+// encoding 非空时跳过自动检测，按指定编码解码（用于纠正 GB2312 被识别成 windows-1252 等情况）。
+#[tauri::command]
+fn read_text_file_with_report(
+    path: String,
+    encoding: Option<String>,
+) -> Result<TextFileOpenResult, String> {
+    let buffer = read_text_file_bytes(&path)?;
+    let (content, report) = decode_text_bytes(&buffer, encoding.as_deref())?;
+    Ok(TextFileOpenResult { content, report })
+}
+
+#[cfg(test)]
+mod text_encoding_tests {
+    use super::*;
+
+    #[test]
+    fn forced_gbk_decodes_chinese_and_reports_encoding() -> Result<(), String> {
+        let (bytes, _, _) = encoding_rs::GBK.encode("第一章 开端\r\n正文内容。\r\n");
+        let (content, report) = decode_text_bytes(&bytes, Some("gb2312"))?;
+
+        assert_eq!(content, "第一章 开端\n正文内容。\n");
+        assert_eq!(report.encoding, "GBK");
+        assert!(report.forced);
+        assert_eq!(report.line_ending, "crlf");
+        assert!(!report.mixed_line_endings);
+        assert_eq!(report.malformed_count, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn utf16_bom_is_detected_and_stripped() -> Result<(), String> {
+        let mut bytes = vec![0xFF, 0xFE];
+        for unit in "简介\n".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        let (content, report) = decode_text_bytes(&bytes, None)?;
+
+        assert_eq!(content, "简介\n");
+        assert_eq!(report.encoding, "UTF-16LE");
+        assert!(report.has_bom);
+        assert!(!report.forced);
+        Ok(())
+    }
+
+    #[test]
+    fn forced_utf8_counts_malformed_sequences() -> Result<(), String> {
+        let (bytes, _, _) = encoding_rs::GBK.encode("中文");
+        let (_, report) = decode_text_bytes(&bytes, Some("utf-8"))?;
+
+        assert!(report.malformed_count > 0);
+        assert!(report.confidence < 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_line_endings_are_flagged() {
+        assert_eq!(detect_line_ending_style("a\r\nb\r\nc\nd"), ("crlf", true));
+        assert_eq!(detect_line_ending_style("a\rb"), ("cr", false));
+        assert_eq!(detect_line_ending_style("abc"), ("lf", false));
+    }
+
+    #[test]
+    fn unknown_encoding_label_is_rejected() {
+        assert!(decode_text_bytes(b"abc", Some("not-an-encoding")).is_err());
+    }
+}
+
+#[tauri::command]
+fn prepare_epub_for_open(
+    app: tauri::AppHandle,
+    epub_path: String,
+) -> Result<EpubPrepareResult, String> {
+    let source = PathBuf::from(&epub_path);
+    if !source.exists() {
+        return Err(format!("文件不存在: {}", epub_path));
+    }
+
+    emit_epub_prepare_stage(&app, &source, "准备 EPUB 文件");
+
+    // Case 1: valid ZIP/EPUB, only repair pseudo-encryption bit if needed.
+    if is_zip_archive_path(&source) {
+        emit_epub_prepare_stage(&app, &source, "读取 EPUB 文件");
+        let mut bytes = read_epub_bytes(&source)?;
+        emit_epub_prepare_stage(&app, &source, "检查伪加密标记");
+        let fixed_encryption_flag = clear_zip_encryption_flags(&mut bytes);
+
+        let rebuilt = {
+            let mut progress = |message: &str| {
+                emit_epub_prepare_stage(&app, &source, message);
+            };
+            rebuild_epub_with_sanitized_names_from_bytes_with_progress(
+                &source,
+                &bytes,
+                "_deobf",
+                &mut progress,
+            )?
+        };
+
+        if let Some(out_path) = rebuilt {
+            return Ok(EpubPrepareResult {
+                source_path: source.to_string_lossy().to_string(),
+                processed_path: out_path.to_string_lossy().to_string(),
+                changed: true,
+                action: if fixed_encryption_flag {
+                    "已自动解除伪加密并修复文件名混淆".to_string()
+                } else {
+                    "已自动修复文件名混淆".to_string()
+                },
+            });
+        }
+
+        if fixed_encryption_flag {
+            emit_epub_prepare_stage(&app, &source, "写出伪加密修复文件");
+            let out_path = build_processed_epub_path(&source, "_decrypted");
+            fs::write(&out_path, &bytes).map_err(|e| format!("写入修复文件失败: {}", e))?;
+            return Ok(EpubPrepareResult {
+                source_path: source.to_string_lossy().to_string(),
+                processed_path: out_path.to_string_lossy().to_string(),
+                changed: true,
+                action: "已自动解除伪加密".to_string(),
+            });
+        }
+        emit_epub_prepare_stage(&app, &source, "EPUB 无需预处理");
+        return Ok(EpubPrepareResult {
+            source_path: source.to_string_lossy().to_string(),
+            processed_path: source.to_string_lossy().to_string(),
+            changed: false,
+            action: "无需处理".to_string(),
+        });
+    }
+
+    // Case 2: try simple XOR obfuscation recovery.
+    emit_epub_prepare_stage(&app, &source, "检测非标准混淆格式");
+    let bytes = read_epub_bytes(&source)?;
+    let mut candidate_keys: Vec<u8> = vec![0xFF, 0xA5, 0x5A];
+    if bytes.len() >= 4 {
+        let k0 = bytes[0] ^ 0x50;
+        if (bytes[1] ^ 0x4B) == k0 && (bytes[2] ^ 0x03) == k0 && (bytes[3] ^ 0x04) == k0 {
+            if !candidate_keys.contains(&k0) {
+                candidate_keys.insert(0, k0);
+            }
+        }
+    }
+
+    for key in candidate_keys {
+        emit_epub_prepare_stage(&app, &source, "尝试 XOR 解混淆");
+        let mut decoded = xor_bytes(&bytes, key);
+        emit_epub_prepare_stage(&app, &source, "检查解混淆后的 ZIP 标记");
+        let fixed_encryption_flag = clear_zip_encryption_flags(&mut decoded);
+        if is_zip_archive_bytes(&decoded) {
+            let suffix = if fixed_encryption_flag {
+                "_deobf_decrypted"
+            } else {
+                "_deobf"
+            };
+
+            let rebuilt = {
+                let mut progress = |message: &str| {
+                    emit_epub_prepare_stage(&app, &source, message);
+                };
+                rebuild_epub_with_sanitized_names_from_bytes_with_progress(
+                    &source,
+                    &decoded,
+                    suffix,
+                    &mut progress,
+                )?
+            };
+
+            if let Some(out_path) = rebuilt {
+                return Ok(EpubPrepareResult {
+                    source_path: source.to_string_lossy().to_string(),
+                    processed_path: out_path.to_string_lossy().to_string(),
+                    changed: true,
+                    action: "已自动解混淆并修复可读格式".to_string(),
+                });
+            }
+
+            emit_epub_prepare_stage(&app, &source, "写出解混淆后的 EPUB");
+            let out_path = build_processed_epub_path(&source, suffix);
+            fs::write(&out_path, &decoded).map_err(|e| format!("写入解混淆文件失败: {}", e))?;
+            return Ok(EpubPrepareResult {
+                source_path: source.to_string_lossy().to_string(),
+                processed_path: out_path.to_string_lossy().to_string(),
+                changed: true,
+                action: "已自动解混淆并修复可读格式".to_string(),
+            });
+        }
+    }
+
+    Err("该 EPUB 可能经过非通用加密/混淆，当前版本暂无法自动处理".to_string())
+}
+
+// 入库前自动检测并修复 EPUB 加密 / 混淆 / 错误文件名。
+// 处理结果落到系统临时目录，调用方负责通过 IngestTempFile 回收。
+// 返回值：
+//   Ok(None)  ：源文件已是合法 EPUB，无需处理
+//   Ok(Some)  ：(临时清洗文件 path, 动作描述)
+//   Err       ：检测到非 ZIP 且无法用通用密钥解混淆
+fn try_prepare_epub_for_ingest(source: &Path) -> Result<Option<(PathBuf, String)>, String> {
+    let bytes = read_epub_bytes(source)?;
+
+    // 在系统临时目录构造一个"虚拟 source"，使 build_processed_epub_path /
+    // rebuild_epub_with_sanitized_names_from_bytes 把输出落在临时目录而非源目录。
+    let temp_dir = std::env::temp_dir();
+    let stem = source
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("book");
+    let virtual_source = temp_dir.join(format!(
+        "tepub_ingest_{}_{}.epub",
+        uuid::Uuid::new_v4(),
+        stem
+    ));
+
+    // Case 1：源已是 ZIP，检查伪加密位 + 文件名混淆
+    if is_zip_archive_bytes(&bytes) {
+        let mut bytes_mut = bytes;
+        let fixed_encryption_flag = clear_zip_encryption_flags(&mut bytes_mut);
+
+        if let Some(out_path) =
+            rebuild_epub_with_sanitized_names_from_bytes(&virtual_source, &bytes_mut, "")?
+        {
+            let action = if fixed_encryption_flag {
+                "已自动解除伪加密并修复文件名混淆"
+            } else {
+                "已自动修复文件名混淆"
+            };
+            return Ok(Some((out_path, action.to_string())));
+        }
+
+        if fixed_encryption_flag {
+            let out_path = build_processed_epub_path(&virtual_source, "");
+            fs::write(&out_path, &bytes_mut).map_err(|e| format!("写入修复文件失败: {}", e))?;
+            return Ok(Some((out_path, "已自动解除伪加密".to_string())));
+        }
+        return Ok(None);
+    }
+
+    // Case 2：源非 ZIP，尝试 XOR 解混淆
+    let mut candidate_keys: Vec<u8> = vec![0xFF, 0xA5, 0x5A];
+    if bytes.len() >= 4 {
+        let k0 = bytes[0] ^ 0x50;
+        if (bytes[1] ^ 0x4B) == k0 && (bytes[2] ^ 0x03) == k0 && (bytes[3] ^ 0x04) == k0 {
+            if !candidate_keys.contains(&k0) {
+                candidate_keys.insert(0, k0);
+            }
+        }
+    }
+    for key in candidate_keys {
+        let mut decoded = xor_bytes(&bytes, key);
+        let _ = clear_zip_encryption_flags(&mut decoded);
+        if is_zip_archive_bytes(&decoded) {
+            if let Some(out_path) =
+                rebuild_epub_with_sanitized_names_from_bytes(&virtual_source, &decoded, "")?
+            {
+                return Ok(Some((out_path, "已自动解混淆并修复可读格式".to_string())));
+            }
+            let out_path = build_processed_epub_path(&virtual_source, "");
+            fs::write(&out_path, &decoded).map_err(|e| format!("写入解混淆文件失败: {}", e))?;
+            return Ok(Some((out_path, "已自动解混淆并修复可读格式".to_string())));
+        }
+    }
+
+    Err("该 EPUB 可能经过非通用加密/混淆，当前版本暂无法自动处理".to_string())
+}
+
+// RAII：函数结束时自动删除入库流程产生的临时清洗文件
+struct IngestTempFile(Option<PathBuf>);
+impl Drop for IngestTempFile {
+    fn drop(&mut self) {
+        if let Some(p) = self.0.take() {
+            let _ = fs::remove_file(p);
+        }
+    }
+}
+
+// --- TXT 保存编码 ---
+
+// 与 TextEncodingReport 对应：把打开时的报告原样传回即可按源编码、源换行保存。
+#[derive(Deserialize, Debug, Clone, Default)]
+struct TextSaveOptions {
+    #[serde(default)]
+    encoding: Option<String>,
+    #[serde(default)]
+    has_bom: bool,
+    #[serde(default)]
+    line_ending: Option<String>, // lf, crlf, cr；为空时不改动换行
+}
+
+fn apply_line_ending(content: &str, line_ending: &str) -> Result<String, String> {
+    let normalized = normalize_line_endings(content.to_string());
+    match line_ending.trim().to_ascii_lowercase().as_str() {
+        "lf" | "" => Ok(normalized),
+        "crlf" => Ok(normalized.replace('\n', "\r\n")),
+        "cr" => Ok(normalized.replace('\n', "\r")),
+        other => Err(format!("不支持的换行风格: {}", other)),
+    }
+}
+
+// 按目标编码编码文本。encoding_rs 遇到无法映射的字符会输出 HTML 数字实体，
+// 这对 TXT 是静默损坏，所以这里改为报错并列出无法保存的字符。
+fn encode_text_for_save(content: &str, options: &TextSaveOptions) -> Result<Vec<u8>, String> {
+    let text = match options.line_ending.as_deref() {
+        Some(style) => apply_line_ending(content, style)?,
+        None => content.to_string(),
+    };
+    let encoding = match options.encoding.as_deref().map(str::trim) {
+        Some(label) if !label.is_empty() => resolve_text_encoding(label)?,
+        _ => encoding_rs::UTF_8,
+    };
+
+    if encoding == encoding_rs::UTF_16LE || encoding == encoding_rs::UTF_16BE {
+        let little_endian = encoding == encoding_rs::UTF_16LE;
+        let mut bytes = Vec::with_capacity(text.len() * 2 + 2);
+        if options.has_bom {
+            bytes.extend_from_slice(if little_endian {
+                &[0xFF, 0xFE]
+            } else {
+                &[0xFE, 0xFF]
+            });
+        }
+        for unit in text.encode_utf16() {
+            let pair = if little_endian {
+                unit.to_le_bytes()
+            } else {
+                unit.to_be_bytes()
+            };
+            bytes.extend_from_slice(&pair);
+        }
+        return Ok(bytes);
+    }
+
+    if encoding == encoding_rs::UTF_8 {
+        let mut bytes = Vec::with_capacity(text.len() + 3);
+        if options.has_bom {
+            bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+        }
+        bytes.extend_from_slice(text.as_bytes());
+        return Ok(bytes);
+    }
+
+    if encoding.output_encoding() != encoding {
+        return Err(format!("不支持以 {} 编码保存", encoding.name()));
+    }
+
+    let mut encoder = encoding.new_encoder();
+    let mut bytes = Vec::with_capacity(
+        encoder
+            .max_buffer_length_from_utf8_without_replacement(text.len())
+            .unwrap_or(text.len() * 2),
+    );
+    let mut unmappable: Vec<char> = Vec::new();
+    let mut input = text.as_str();
+    loop {
+        let (result, read) =
+            encoder.encode_from_utf8_to_vec_without_replacement(input, &mut bytes, true);
+        input = &input[read..];
+        match result {
+            encoding_rs::EncoderResult::InputEmpty => break,
+            encoding_rs::EncoderResult::OutputFull => {
+                let extra = encoder
+                    .max_buffer_length_from_utf8_without_replacement(input.len())
+                    .unwrap_or(input.len() * 2);
+                bytes.reserve(extra.max(8));
+            }
+            encoding_rs::EncoderResult::Unmappable(c) => {
+                if !unmappable.contains(&c) {
+                    unmappable.push(c);
+                }
+            }
+        }
+    }
+
+    if !unmappable.is_empty() {
+        let preview: String = unmappable.iter().take(20).collect();
+        return Err(format!(
+            "{} 个字符无法以 {} 编码保存: {}",
+            unmappable.len(),
+            encoding.name(),
+            preview
+        ));
+    }
+    Ok(bytes)
+}
+
+// options 为空时保持旧行为：按 UTF-8 原样写入。
+#[tauri::command]
+async fn save_text_file(
+    path: String,
+    content: String,
+    options: Option<TextSaveOptions>,
+) -> Result<(), String> {
+    let bytes = encode_text_for_save(&content, &options.unwrap_or_default())?;
+    let mut file = fs::File::create(&path).map_err(|e| format!("无法创建: {}", e))?;
+    file.write_all(&bytes)
+        .map_err(|e| format!("写入失败: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod text_save_tests {
+    use super::*;
+
+    #[test]
+    fn gb18030_crlf_round_trips_through_decode() -> Result<(), String> {
+        let options = TextSaveOptions {
+            encoding: Some("gb18030".to_string()),
+            has_bom: false,
+            line_ending: Some("crlf".to_string()),
+        };
+        let bytes = encode_text_for_save("第一章\n正文\n", &options)?;
+        assert_eq!(bytes.windows(2).filter(|w| w == b"\r\n").count(), 2);
+
+        let (content, report) = decode_text_bytes(&bytes, Some("gb18030"))?;
+        assert_eq!(content, "第一章\n正文\n");
+        assert_eq!(report.line_ending, "crlf");
+        Ok(())
+    }
+
+    #[test]
+    fn utf16be_and_utf8_bom_are_written() -> Result<(), String> {
+        let utf16 = encode_text_for_save(
+            "简",
+            &TextSaveOptions {
+                encoding: Some("utf-16be".to_string()),
+                has_bom: true,
+                line_ending: None,
+            },
+        )?;
+        assert_eq!(utf16, vec![0xFE, 0xFF, 0x7B, 0x80]);
+
+        let utf8 = encode_text_for_save(
+            "a",
+            &TextSaveOptions {
+                encoding: None,
+                has_bom: true,
+                line_ending: None,
+            },
+        )?;
+        assert_eq!(utf8, vec![0xEF, 0xBB, 0xBF, b'a']);
+        Ok(())
+    }
+
+    #[test]
+    fn unmappable_characters_are_rejected_instead_of_escaped() {
+        let result = encode_text_for_save(
+            "汉字😀",
+            &TextSaveOptions {
+                encoding: Some("big5".to_string()),
+                has_bom: false,
+                line_ending: None,
+            },
+        );
+        let err = result.err().unwrap_or_default();
+        assert!(err.contains('😀'), "{}", err);
+    }
+}
+
+#[tauri::command]
+fn read_binary_file(path: String) -> Result<Vec<u8>, String> {
+    fs::read(&path).map_err(|e| format!("读取失败: {}", e))
+}
+
+#[derive(Serialize, Clone)]
+struct CoverSearchResult {
+    id: String,
+    title: String,
+    author: String,
+    image_url: String,
+    page_url: String,
+    source: String,
+    preferred: bool,
+}
+
+fn percent_encode_component(input: &str) -> String {
+    let mut out = String::new();
+    for b in input.as_bytes() {
+        match *b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(*b as char)
+            }
+            b' ' => out.push_str("%20"),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn normalize_cover_url(raw: &str) -> String {
+    let mut url = raw
+        .replace("\\/", "/")
+        .replace("&amp;", "&")
+        .replace("&quot;", "\"")
+        .trim()
+        .to_string();
+    if url.starts_with("//") {
+        url = format!("https:{}", url);
+    }
+    url
+}
+
+fn html_unescape_basic(raw: &str) -> String {
+    raw.replace("&quot;", "\"")
+        .replace("&#34;", "\"")
+        .replace("&#x22;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn compact_match_key(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace() && *c != '《' && *c != '》')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn is_preferred_cover_source(image_url: &str, page_url: &str, source: &str) -> bool {
+    let image = image_url.to_lowercase();
+    let page = page_url.to_lowercase();
+    let source = source.to_lowercase();
+    image.contains("bookcover.yuewen.com")
+        || image.contains("icode.qq.com")
+        || image.contains("p9-novel-sign.byteimg.com")
+        || image.contains("fanqienovel.com")
+        || page.contains("m.qidian.com")
+        || page.contains("icode.qq.com")
+        || page.contains("bookcover.yuewen.com")
+        || page.contains("fanqienovel.com")
+        || page.contains("p9-novel-sign.byteimg.com")
+        || source.contains("m.qidian.com")
+        || source.contains("icode.qq.com")
+        || source.contains("bookcover.yuewen.com")
+        || source.contains("fanqienovel.com")
+        || source.contains("p9-novel-sign.byteimg.com")
+}
+
+fn cover_download_referer(url: &str) -> &'static str {
+    let lower = url.to_lowercase();
+    if lower.contains("byteimg.com") || lower.contains("fanqienovel.com") {
+        "https://fanqienovel.com/"
+    } else {
+        "https://m.qidian.com/"
+    }
+}
+
+fn host_from_url(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_string()))
+        .unwrap_or_default()
+}
+
+fn json_num(value: &serde_json::Value, keys: &[&str]) -> Option<f64> {
+    for key in keys {
+        if let Some(number) = value.get(*key).and_then(|v| v.as_f64()) {
+            return Some(number);
+        }
+        if let Some(text) = value.get(*key).and_then(|v| v.as_str()) {
+            if let Ok(number) = text.parse::<f64>() {
+                return Some(number);
+            }
+        }
+    }
+    None
+}
 
-def build_selector_font_rules(infos, contents):
-    selector_fonts = {}
-    for info in infos:
-        name = info.filename
-        if not name.lower().endswith(".css") or name.endswith("/"):
-            continue
-        css = decode_text(contents.get(name, b""))
-        css = re.sub(r"(?s)/\*.*?\*/", "", css)
-        for match in re.finditer(r"(?s)([^{}]+)\{([^{}]*)\}", css):
-            selectors = match.group(1).strip()
-            body = match.group(2)
-            if selectors.startswith("@"):
-                continue
-            family = font_family_from_style(body)
-            if not family:
-                continue
-            for selector in selectors.split(","):
-                key = simple_selector_key(selector)
-                if key:
-                    selector_fonts[key] = family
-    return selector_fonts
+#[tauri::command]
+async fn search_book_covers(
+    title: String,
+    author: String,
+) -> Result<Vec<CoverSearchResult>, String> {
+    let clean_title = title.trim();
+    let clean_author = author.trim();
+    if clean_title.is_empty() || clean_title == "书名" || clean_title == "涔﹀悕" {
+        return Err("请先填写书名".to_string());
+    }
 
-def font_for_tag(tag_name, attrs, inherited, selector_fonts):
-    inline_family = font_family_from_style(attrs.get("style", ""))
-    if inline_family:
-        return inline_family
-    tag = (tag_name or "").lower()
-    elem_id = attrs.get("id", "").strip()
-    if elem_id:
-        for key in (f"{tag}#{elem_id.lower()}", f"#{elem_id.lower()}"):
-            if key in selector_fonts:
-                return selector_fonts[key]
-    classes = attrs.get("class", "").split()
-    for cls in classes:
-        cls = cls.lower()
-        for key in (f"{tag}.{cls}", f".{cls}"):
-            if key in selector_fonts:
-                return selector_fonts[key]
-    if tag in selector_fonts:
-        return selector_fonts[tag]
-    return inherited
+    let client = reqwest::Client::builder()
+        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) TEpub-Editor/0.5")
+        .build()
+        .map_err(|e| format!("初始化封面搜索失败: {}", e))?;
+    let image_meta_re =
+        Regex::new(r#"m="([^"]+)""#).map_err(|e| format!("封面结果解析失败: {}", e))?;
+    let mut results: Vec<(i32, CoverSearchResult)> = Vec::new();
+    let mut seen = HashSet::new();
+    let title_key = compact_match_key(clean_title);
+    let author_key = compact_match_key(clean_author);
 
-def zip_parent(name):
-    name = name.replace("\\", "/")
-    return name.rsplit("/", 1)[0] if "/" in name else ""
+    let mut query_texts = vec![clean_title.to_string()];
+    if !clean_author.is_empty() && clean_author != "作者" && clean_author != "浣滆€?" {
+        query_texts.push(format!("{} {}", clean_title, clean_author));
+    }
+    query_texts.push(format!("{} 小说封面", clean_title));
 
-def zip_join(base_dir, rel):
-    rel = unquote((rel or "").split("#", 1)[0].split("?", 1)[0]).replace("\\", "/").strip()
-    if not rel:
-        return ""
-    if rel.startswith("/"):
-        return rel.lstrip("/")
-    parts = [p for p in base_dir.split("/") if p]
-    for seg in rel.split("/"):
-        if not seg or seg == ".":
-            continue
-        if seg == "..":
-            if parts:
-                parts.pop()
-        else:
-            parts.append(seg)
-    return "/".join(parts)
+    for (query_idx, query_text) in query_texts.iter().enumerate() {
+        let query = percent_encode_component(query_text);
+        let search_url = format!(
+            "https://cn.bing.com/images/search?q={}&form=HDRSC2&first=1",
+            query
+        );
+        let html = client
+            .get(&search_url)
+            .header("Referer", "https://cn.bing.com/images")
+            .header("Accept-Language", "zh-CN,zh;q=0.9")
+            .send()
+            .await
+            .map_err(|e| format!("搜索封面失败: {}", e))?
+            .text()
+            .await
+            .map_err(|e| format!("读取封面搜索结果失败: {}", e))?;
 
-def strip_markup_text(text):
-    text = re.sub(r"(?is)<(script|style)\b.*?</\1>", "", text)
-    text = re.sub(r"(?is)<[^>]+>", "", text)
-    text = html_lib.unescape(text)
-    return re.sub(r"\s+", " ", text).strip()
+        for (idx, item) in image_meta_re.captures_iter(&html).enumerate() {
+            let captures = match item {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            let raw_meta = captures.get(1).map(|m| m.as_str()).unwrap_or("");
+            let meta_text = html_unescape_basic(raw_meta);
+            let meta = match serde_json::from_str::<serde_json::Value>(&meta_text) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            let image_url = match meta
+                .get("murl")
+                .and_then(|value| value.as_str())
+                .or_else(|| meta.get("turl").and_then(|value| value.as_str()))
+            {
+                Some(url) => normalize_cover_url(url),
+                None => continue,
+            };
+            if image_url.is_empty()
+                || !(image_url.starts_with("https://") || image_url.starts_with("http://"))
+                || !seen.insert(image_url.clone())
+            {
+                continue;
+            }
 
-def extract_title_text(text):
-    m = re.search(r"(?is)<title\b[^>]*>(.*?)</title>", text)
-    return strip_markup_text(m.group(1)) if m else ""
+            let page_url = meta
+                .get("purl")
+                .and_then(|value| value.as_str())
+                .map(normalize_cover_url)
+                .unwrap_or_default();
+            let result_title = meta
+                .get("t")
+                .and_then(|value| value.as_str())
+                .map(html_unescape_basic)
+                .unwrap_or_else(|| clean_title.to_string());
+            let id = meta
+                .get("md5")
+                .and_then(|value| value.as_str())
+                .or_else(|| meta.get("cid").and_then(|value| value.as_str()))
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| format!("{}-{}", query_idx, idx));
+            let source = {
+                let page_host = host_from_url(&page_url);
+                if page_host.is_empty() {
+                    host_from_url(&image_url)
+                } else {
+                    page_host
+                }
+            };
+            let preferred = is_preferred_cover_source(&image_url, &page_url, &source);
+
+            let result_title_key = compact_match_key(&result_title);
+            let page_key = compact_match_key(&page_url);
+            let source_key = compact_match_key(&source);
+            let image_key = compact_match_key(&image_url);
+            let combined_key = format!(
+                "{}{}{}{}",
+                result_title_key, page_key, source_key, image_key
+            );
+            let has_title_match = !title_key.is_empty() && combined_key.contains(&title_key);
+
+            let mut score = if preferred { 180 } else { 0 };
+            score += match query_idx {
+                0 => 70,
+                1 => 30,
+                _ => 10,
+            };
+            if !title_key.is_empty() && result_title_key == title_key {
+                score += 150;
+            } else if has_title_match {
+                score += 100;
+            } else if !title_key.is_empty() && result_title_key.contains(&title_key) {
+                score += 70;
+            } else {
+                score -= 80;
+            }
+            if !author_key.is_empty()
+                && (result_title_key.contains(&author_key) || page_key.contains(&author_key))
+            {
+                score += 20;
+            }
+            if source.contains("icode.qq.com") {
+                score += 90;
+            }
+            if let (Some(width), Some(height)) = (
+                json_num(&meta, &["w", "width", "ow", "imgw"]),
+                json_num(&meta, &["h", "height", "oh", "imgh"]),
+            ) {
+                if width > 0.0 && height > 0.0 {
+                    let ratio = width / height;
+                    if (0.62..=0.82).contains(&ratio) {
+                        score += 35;
+                    } else if ratio >= 1.0 {
+                        score -= 45;
+                    }
+                }
+            }
+            score -= (query_idx as i32 * 20) + idx as i32;
+
+            results.push((
+                score,
+                CoverSearchResult {
+                    id,
+                    title: if result_title.trim().is_empty() {
+                        clean_title.to_string()
+                    } else {
+                        result_title
+                    },
+                    author: source.clone(),
+                    image_url,
+                    page_url,
+                    source,
+                    preferred,
+                },
+            ));
+        }
+    }
+
+    results.sort_by(|a, b| b.0.cmp(&a.0));
+    Ok(results
+        .into_iter()
+        .take(12)
+        .map(|(_, result)| result)
+        .collect())
+}
+#[tauri::command]
+async fn download_cover_to_temp(image_url: String, title: String) -> Result<String, String> {
+    let url = normalize_cover_url(&image_url);
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return Err("封面地址无效".to_string());
+    }
 
-def collect_navigation_data(infos, contents):
-    opf_paths = [info.filename for info in infos if info.filename.lower().endswith(".opf")]
-    nav_paths = set()
-    ncx_paths = set()
-    title_by_target = {}
-    for opf_path in opf_paths:
-        opf_text = decode_text(contents.get(opf_path, b""))
-        opf_dir = zip_parent(opf_path)
-        toc_ids = set()
-        for spine in re.finditer(r"(?is)<spine\b([^>]*)>", opf_text):
-            toc_id = parse_attrs(spine.group(1)).get("toc", "")
-            if toc_id:
-                toc_ids.add(toc_id)
-        for item in re.finditer(r"(?is)<item\b([^>]*)>", opf_text):
-            attrs = parse_attrs(item.group(1))
-            item_id = attrs.get("id", "")
-            href = attrs.get("href", "")
-            if not href:
-                continue
-            media = attrs.get("media-type", "").lower()
-            properties = attrs.get("properties", "").lower().split()
-            abs_path = zip_join(opf_dir, href)
-            lower = abs_path.lower()
-            if "nav" in properties:
-                nav_paths.add(abs_path)
-            if item_id in toc_ids or media == "application/x-dtbncx+xml" or lower.endswith(".ncx"):
-                ncx_paths.add(abs_path)
+    let client = reqwest::Client::builder()
+        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) TEpub-Editor/0.5")
+        .build()
+        .map_err(|e| format!("初始化封面下载失败: {}", e))?;
+    let bytes = client
+        .get(&url)
+        .header("Referer", cover_download_referer(&url))
+        .send()
+        .await
+        .map_err(|e| format!("下载封面失败: {}", e))?
+        .bytes()
+        .await
+        .map_err(|e| format!("读取封面数据失败: {}", e))?;
+    if bytes.is_empty() {
+        return Err("下载到的封面为空".to_string());
+    }
+    if bytes.len() > 12 * 1024 * 1024 {
+        return Err("封面图片过大".to_string());
+    }
 
-    for nav_path in list(nav_paths):
-        nav_text = decode_text(contents.get(nav_path, b""))
-        nav_dir = zip_parent(nav_path)
-        for a in re.finditer(r"(?is)<a\b([^>]*)>(.*?)</a>", nav_text):
-            href = parse_attrs(a.group(1)).get("href", "")
-            label = strip_markup_text(a.group(2))
-            target = zip_join(nav_dir, href)
-            if target and label:
-                title_by_target.setdefault(target, label)
+    let ext = detect_image_ext(&bytes);
+    let base = sanitize_filename_part(&title);
+    let stem = if base.is_empty() {
+        "cover".to_string()
+    } else {
+        base
+    };
+    let dir = std::env::temp_dir().join("TEpub-Editor").join("covers");
+    fs::create_dir_all(&dir).map_err(|e| format!("创建封面缓存目录失败: {}", e))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("生成封面缓存文件名失败: {}", e))?
+        .as_millis();
+    let path = dir.join(format!("{}-{}.{}", stem, now, ext));
+    fs::write(&path, &bytes).map_err(|e| format!("保存封面失败: {}", e))?;
+    Ok(path.to_string_lossy().to_string())
+}
 
-    for ncx_path in list(ncx_paths):
-        ncx_text = decode_text(contents.get(ncx_path, b""))
-        ncx_dir = zip_parent(ncx_path)
-        for point in re.finditer(r"(?is)<navPoint\b.*?</navPoint>", ncx_text):
-            block = point.group(0)
-            content = re.search(r"(?is)<content\b([^>]*)>", block)
-            label = re.search(r"(?is)<text\b[^>]*>(.*?)</text>", block)
-            if not content or not label:
-                continue
-            src = parse_attrs(content.group(1)).get("src", "")
-            target = zip_join(ncx_dir, src)
-            text = strip_markup_text(label.group(1))
-            if target and text:
-                title_by_target.setdefault(target, text)
-    return nav_paths, ncx_paths, title_by_target
+#[tauri::command]
+fn calculate_md5(content: String) -> String {
+    format!("{:x}", md5::compute(content.as_bytes()))
+}
 
-def collect_title_pairs(contents, title_by_target):
-    pairs = []
-    for target, plain_title in title_by_target.items():
-        html = contents.get(target)
-        if html is None:
-            continue
-        cipher_title = extract_title_text(decode_text(html))
-        if cipher_title and plain_title and cipher_title != plain_title:
-            pairs.append((cipher_title, plain_title))
-    return pairs
+// 历史快照命名：{文件名}-{路径哈希}.{毫秒时间戳}.{原因}.bak.gz（gzip 压缩）；
+// 旧版为未压缩的 {文件名}-{路径哈希}.{秒}.bak 或 {文件名}.{秒}.bak，仍可列出、读取与清理。
+const HISTORY_SNAPSHOT_EXT: &str = ".bak.gz";
+const HISTORY_LEGACY_EXT: &str = ".bak";
 
-def is_ignored_text_char(ch):
-    cat = unicodedata.category(ch)
-    return ch.isspace() or cat.startswith("C") or cat.startswith("Z")
+fn history_file_prefix(original_path: &str) -> (String, String) {
+    let file_stem = Path::new(original_path)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let prefix = format!("{}-{}", file_stem, history_key_for_path(original_path));
+    (file_stem, prefix)
+}
 
-def is_cjk_or_private(ch):
-    code = ord(ch)
-    return (
-        0x3400 <= code <= 0x4DBF
-        or 0x4E00 <= code <= 0x9FFF
-        or 0xF900 <= code <= 0xFAFF
-        or 0x20000 <= code <= 0x3FFFF
-        or 0xE000 <= code <= 0xF8FF
-        or 0xF0000 <= code <= 0x10FFFD
-    )
+fn is_history_snapshot_name(original_path: &str, name: &str) -> bool {
+    let (file_stem, prefix) = history_file_prefix(original_path);
+    (name.ends_with(HISTORY_SNAPSHOT_EXT) || name.ends_with(HISTORY_LEGACY_EXT))
+        && (name.starts_with(&prefix) || name.starts_with(&format!("{}.", file_stem)))
+}
 
-def is_alignment_stable(ch):
-    if is_ignored_text_char(ch):
-        return False
-    if is_cjk_or_private(ch):
-        return False
-    cat = unicodedata.category(ch)
-    return ord(ch) < 128 or cat[0] in ("P", "S", "N") or 0xFF00 <= ord(ch) <= 0xFFEF
+fn history_reason_slug(reason: &str) -> String {
+    let slug: String = reason
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let slug = slug.trim_matches('_');
+    if slug.is_empty() {
+        "manual".to_string()
+    } else {
+        slug.to_string()
+    }
+}
 
-def is_mapping_candidate(ch):
-    return not is_ignored_text_char(ch) and not is_alignment_stable(ch)
+// 新格式从文件名取毫秒时间与原因；旧格式用修改时间，原因留空。
+fn history_snapshot_meta(path: &Path) -> Option<HistoryMeta> {
+    let filename = path.file_name()?.to_string_lossy().to_string();
+    let meta = fs::metadata(path).ok()?;
+    let mut timestamp = meta
+        .modified()
+        .unwrap_or(SystemTime::now())
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut reason = String::new();
+    if let Some(stem) = filename.strip_suffix(HISTORY_SNAPSHOT_EXT) {
+        let mut parts = stem.rsplitn(3, '.');
+        if let (Some(slug), Some(millis)) = (parts.next(), parts.next()) {
+            if let Ok(millis) = millis.parse::<u64>() {
+                timestamp = millis / 1000;
+                reason = slug.to_string();
+            }
+        }
+    }
+    Some(HistoryMeta {
+        filename,
+        path: path.to_string_lossy().to_string(),
+        timestamp,
+        size: meta.len(),
+        date_str: String::new(),
+        reason,
+    })
+}
 
-def iter_text_records(html, selector_fonts=None):
-    selector_fonts = selector_fonts or {}
-    stack = []
-    raw_tag = None
-    i = 0
-    while i < len(html):
-        ch = html[i]
-        if ch == "<":
-            end = html.find(">", i + 1)
-            if end == -1:
-                break
-            tag = html[i:end + 1]
-            closing, name, self_closing, attrs = parse_tag(tag)
-            if name in ("script", "style", "title"):
-                raw_tag = None if closing else name
-                i = end + 1
-                continue
-            if name and not raw_tag:
-                if closing:
-                    while stack:
-                        popped, _font = stack.pop()
-                        if popped == name:
-                            break
-                elif not self_closing:
-                    inherited = stack[-1][1] if stack else ""
-                    stack.append((name, font_for_tag(name, attrs, inherited, selector_fonts)))
-            elif name and closing:
-                while stack:
-                    popped, _font = stack.pop()
-                    if popped == name:
-                        break
-            i = end + 1
-            continue
-        if raw_tag:
-            i += 1
-            continue
-        if ch == "&":
-            semi = html.find(";", i + 1, i + 16)
-            if semi != -1:
-                i = semi + 1
-                continue
-        if not is_ignored_text_char(ch):
-            yield ch, (stack[-1][1] if stack else "")
-        i += 1
+// 按时间从新到旧
+fn list_history_snapshots(history_dir: &Path, original_path: &str) -> Vec<HistoryMeta> {
+    let mut list: Vec<HistoryMeta> = fs::read_dir(history_dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| {
+                    is_history_snapshot_name(original_path, &e.file_name().to_string_lossy())
+                })
+                .filter_map(|e| history_snapshot_meta(&e.path()))
+                .collect()
+        })
+        .unwrap_or_default();
+    list.sort_by(|a, b| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| b.filename.cmp(&a.filename))
+    });
+    list
+}
 
-def transform_text_font_aware(html, global_table, font_table, selector_fonts=None):
-    selector_fonts = selector_fonts or {}
-    out = []
-    stack = []
-    raw_tag = None
-    i = 0
-    while i < len(html):
-        ch = html[i]
-        if ch == "<":
-            end = html.find(">", i + 1)
-            if end == -1:
-                out.append(html[i:])
-                break
-            tag = html[i:end + 1]
-            out.append(tag)
-            closing, name, self_closing, attrs = parse_tag(tag)
-            if name in ("script", "style"):
-                raw_tag = None if closing else name
-                i = end + 1
-                continue
-            if name and not raw_tag:
-                if closing:
-                    while stack:
-                        popped, _font = stack.pop()
-                        if popped == name:
-                            break
-                elif not self_closing:
-                    inherited = stack[-1][1] if stack else ""
-                    stack.append((name, font_for_tag(name, attrs, inherited, selector_fonts)))
-            elif name and closing:
-                while stack:
-                    popped, _font = stack.pop()
-                    if popped == name:
-                        break
-            i = end + 1
-            continue
-        if raw_tag:
-            out.append(ch)
-            i += 1
-            continue
-        if ch == "&":
-            semi = html.find(";", i + 1, i + 16)
-            if semi != -1:
-                out.append(html[i:semi + 1])
-                i = semi + 1
-                continue
-        font_key = stack[-1][1] if stack else ""
-        out.append(font_table.get((font_key, ch), global_table.get(ch, ch)))
-        i += 1
-    return "".join(out)
+fn read_history_snapshot_file(path: &Path) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("读取历史版本失败: {}", e))?;
+    if !path.to_string_lossy().ends_with(HISTORY_SNAPSHOT_EXT) {
+        return String::from_utf8(bytes).map_err(|e| format!("历史版本不是 UTF-8 文本: {}", e));
+    }
+    let mut content = String::new();
+    flate2::read::GzDecoder::new(bytes.as_slice())
+        .read_to_string(&mut content)
+        .map_err(|e| format!("解压历史版本失败: {}", e))?;
+    Ok(content)
+}
+
+// 最新一份总是保留；其余按数量、天数、总大小依次淘汰。
+fn prune_history_snapshots(
+    snapshots: &[HistoryMeta],
+    retention: &HistoryRetentionConfig,
+    now_secs: u64,
+) -> Vec<String> {
+    let max_age = retention.max_age_days.saturating_mul(24 * 60 * 60);
+    let max_bytes = retention.max_total_mb.saturating_mul(1024 * 1024);
+    let mut total = 0u64;
+    let mut removed = Vec::new();
+    for (index, snapshot) in snapshots.iter().enumerate() {
+        total += snapshot.size;
+        if index == 0 {
+            continue;
+        }
+        let over_count = retention.max_count > 0 && index >= retention.max_count;
+        let too_old = max_age > 0 && now_secs.saturating_sub(snapshot.timestamp) > max_age;
+        let too_large = max_bytes > 0 && total > max_bytes;
+        if over_count || too_old || too_large {
+            removed.push(snapshot.path.clone());
+        }
+    }
+    removed
+}
+
+fn save_history_snapshot(
+    history_dir: &Path,
+    original_path: &str,
+    content: &str,
+    reason: &str,
+    retention: &HistoryRetentionConfig,
+) -> Result<bool, String> {
+    fs::create_dir_all(history_dir).map_err(|e| e.to_string())?;
+    let existing = list_history_snapshots(history_dir, original_path);
+    // 与上一份内容相同则不再保存
+    let unchanged = existing.first().is_some_and(|latest| {
+        read_history_snapshot_file(Path::new(&latest.path))
+            .is_ok_and(|text| text_revision(&text) == text_revision(content))
+    });
+    if !unchanged {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let (_, prefix) = history_file_prefix(original_path);
+        let backup_path = history_dir.join(format!(
+            "{}.{}.{}{}",
+            prefix,
+            millis,
+            history_reason_slug(reason),
+            HISTORY_SNAPSHOT_EXT
+        ));
+        let file = fs::File::create(&backup_path).map_err(|e| e.to_string())?;
+        let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        encoder
+            .write_all(content.as_bytes())
+            .and_then(|_| encoder.finish().map(|_| ()))
+            .map_err(|e| e.to_string())?;
+    }
+    let now_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let snapshots = list_history_snapshots(history_dir, original_path);
+    for path in prune_history_snapshots(&snapshots, retention, now_secs) {
+        let _ = fs::remove_file(path);
+    }
+    Ok(!unchanged)
+}
 
-def normalize_plain_chars(text):
-    return [ch for ch in text if not is_ignored_text_char(ch)]
+// 返回是否写入了新快照（内容与上一份相同时跳过）。
+#[tauri::command]
+async fn save_history(
+    app: tauri::AppHandle,
+    original_path: String,
+    content: String,
+    reason: Option<String>,
+) -> Result<bool, String> {
+    let history_dir = get_history_base_dir(Some(&app));
+    let retention = read_library_data(&app)
+        .map(|data| data.config.history_retention)
+        .unwrap_or_default();
+    tauri::async_runtime::spawn_blocking(move || {
+        save_history_snapshot(
+            &history_dir,
+            &original_path,
+            &content,
+            reason.as_deref().unwrap_or("manual"),
+            &retention,
+        )
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-def char_at(seq, index):
-    item = seq[index]
-    return item[0] if isinstance(item, tuple) else item
+#[tauri::command]
+async fn get_history_list(app: tauri::AppHandle, original_path: String) -> Vec<HistoryMeta> {
+    list_history_snapshots(&get_history_base_dir(Some(&app)), &original_path)
+}
 
-def stable_signature(seq, pos, want=8, max_scan=1600):
-    sig = []
-    end = min(len(seq), pos + max_scan)
-    while pos < end and len(sig) < want:
-        ch = char_at(seq, pos)
-        if is_alignment_stable(ch):
-            sig.append(ch)
-        pos += 1
-    return "".join(sig)
+#[tauri::command]
+async fn read_history_snapshot(
+    app: tauri::AppHandle,
+    original_path: String,
+    path: String,
+) -> Result<String, String> {
+    let path = resolve_history_snapshot(&app, &original_path, &path)?;
+    read_history_snapshot_file(&path)
+}
 
-def find_resync(cipher_records, ci, plain_chars, pi, window=2400, anchors=8):
-    plain_sigs = {}
-    plain_end = min(len(plain_chars), pi + window)
-    for j in range(pi, plain_end):
-        sig = stable_signature(plain_chars, j, anchors)
-        if len(sig) >= anchors and sig not in plain_sigs:
-            plain_sigs[sig] = j
-    cipher_end = min(len(cipher_records), ci + window)
-    best = None
-    best_cost = None
-    for i in range(ci, cipher_end):
-        sig = stable_signature(cipher_records, i, anchors)
-        if len(sig) < anchors:
-            continue
-        j = plain_sigs.get(sig)
-        if j is None:
-            continue
-        cost = (i - ci) + (j - pi)
-        if best is None or cost < best_cost:
-            best = (i, j)
-            best_cost = cost
-            if cost == 0:
-                break
-    return best
+// --- 历史版本对比 ---
+// 按行比较：先用两侧都只出现一次的行做锚点（patience），锚点之间再做 LCS，
+// 小说正文段落几乎都唯一，大范围全局替换也能拆成逐段的小块。
 
-def decode_txt_file(txt_path):
-    with open(txt_path, "rb") as f:
-        return decode_text(f.read())
+const HISTORY_DIFF_CONTEXT_LINES: usize = 2;
+// 锚点间的 LCS 表上限（行数乘积），超出时整段视为一处修改
+const HISTORY_DIFF_LCS_LIMIT: usize = 4_000_000;
 
-def pick_mapping(counter):
-    if not counter:
-        return None, 0, 0, 0.0
-    total = sum(counter.values())
-    plain, top = counter.most_common(1)[0]
-    ratio = top / total if total else 0.0
-    return plain, top, total, ratio
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum HistoryDiffSource {
+    Snapshot { path: String },
+    Buffer { buffer_id: String },
+    Content { content: String },
+}
 
-def choose_mapping_table(counts):
-    table = {}
-    conflicts = 0
-    for key, counter in counts.items():
-        plain, top, total, ratio = pick_mapping(counter)
-        if plain is None:
-            continue
-        if total >= 5 and ratio < 0.78:
-            conflicts += 1
-        if ratio >= 0.66 and (top >= 2 or top == total):
-            table[key] = plain
-    return table, conflicts
+#[derive(Serialize)]
+struct HistoryDiffHunk {
+    id: usize,
+    // 1 起的行号；纯删除/纯插入时指向插入位置
+    old_start: usize,
+    new_start: usize,
+    old_lines: Vec<String>,
+    new_lines: Vec<String>,
+    context_before: Vec<String>,
+    context_after: Vec<String>,
+}
 
-def add_segment_pairs(cipher_records, ci0, ci1, plain_chars, pi0, pi1, global_counts, font_counts):
-    cipher_idxs = [idx for idx in range(ci0, ci1) if is_mapping_candidate(cipher_records[idx][0])]
-    plain_idxs = [idx for idx in range(pi0, pi1) if is_mapping_candidate(plain_chars[idx])]
-    if len(cipher_idxs) != len(plain_idxs):
-        return 0
-    added = 0
-    for ci, pi in zip(cipher_idxs, plain_idxs):
-        cipher, font_key = cipher_records[ci]
-        plain = plain_chars[pi]
-        global_counts[cipher][plain] += 1
-        if font_key:
-            font_counts[(font_key, cipher)][plain] += 1
-        added += 1
-    return added
+#[derive(Serialize)]
+struct HistoryDiffChapter {
+    // 首个章节之前的改动没有标题，line_number 为 0
+    title: Option<String>,
+    line_number: usize,
+    added: usize,
+    removed: usize,
+    hunks: Vec<HistoryDiffHunk>,
+}
 
-def add_direct_title_pair(cipher_title, plain_title, global_counts, font_counts, weight=4):
-    cipher_chars = normalize_plain_chars(cipher_title)
-    plain_chars = normalize_plain_chars(plain_title)
-    if len(cipher_chars) != len(plain_chars):
-        return 0
-    added = 0
-    for cipher, plain in zip(cipher_chars, plain_chars):
-        if cipher == plain:
-            continue
-        if is_mapping_candidate(cipher) and is_mapping_candidate(plain):
-            global_counts[cipher][plain] += weight
-            added += weight
-    return added
+#[derive(Serialize)]
+struct HistoryDiffReport {
+    base_revision: String,
+    target_revision: String,
+    hunk_count: usize,
+    added: usize,
+    removed: usize,
+    chapters: Vec<HistoryDiffChapter>,
+}
+
+// 0 起的半开区间
+#[derive(Debug, PartialEq)]
+struct LineDiffRange {
+    old_start: usize,
+    old_end: usize,
+    new_start: usize,
+    new_end: usize,
+}
+
+fn push_line_diff_range(out: &mut Vec<LineDiffRange>, range: LineDiffRange) {
+    if range.old_start == range.old_end && range.new_start == range.new_end {
+        return;
+    }
+    if let Some(last) = out.last_mut() {
+        if last.old_end == range.old_start && last.new_end == range.new_start {
+            last.old_end = range.old_end;
+            last.new_end = range.new_end;
+            return;
+        }
+    }
+    out.push(range);
+}
 
-def build_txt_alignment_tables(cipher_records, plain_chars, direct_title_pairs=None):
-    global_counts = defaultdict(Counter)
-    font_counts = defaultdict(Counter)
-    ci = 0
-    pi = 0
-    pairs = 0
-    stable_matches = 0
-    skipped_cipher = 0
-    skipped_plain = 0
-    while ci < len(cipher_records) and pi < len(plain_chars):
-        cipher, font_key = cipher_records[ci]
-        plain = plain_chars[pi]
-        cipher_stable = is_alignment_stable(cipher)
-        plain_stable = is_alignment_stable(plain)
-        if cipher_stable or plain_stable:
-            if cipher_stable and plain_stable and cipher == plain:
-                ci += 1
-                pi += 1
-                stable_matches += 1
-                continue
-            resync = find_resync(cipher_records, ci, plain_chars, pi)
-            if resync and (resync[0] > ci or resync[1] > pi):
-                pairs += add_segment_pairs(
-                    cipher_records, ci, resync[0], plain_chars, pi, resync[1], global_counts, font_counts
-                )
-                skipped_cipher += max(0, resync[0] - ci)
-                skipped_plain += max(0, resync[1] - pi)
-                ci, pi = resync
-                continue
-            if cipher_stable and not plain_stable:
-                ci += 1
-                skipped_cipher += 1
-            elif plain_stable and not cipher_stable:
-                pi += 1
-                skipped_plain += 1
-            else:
-                ci += 1
-                pi += 1
-                skipped_cipher += 1
-                skipped_plain += 1
-            continue
-        if is_mapping_candidate(cipher) and is_mapping_candidate(plain):
-            global_counts[cipher][plain] += 1
-            if font_key:
-                font_counts[(font_key, cipher)][plain] += 1
-            pairs += 1
-        ci += 1
-        pi += 1
-    title_pairs = 0
-    title_votes = 0
-    for cipher_title, plain_title in direct_title_pairs or []:
-        added = add_direct_title_pair(cipher_title, plain_title, global_counts, font_counts)
-        if added:
-            title_pairs += 1
-            title_votes += added
-    global_table, global_conflicts = choose_mapping_table(global_counts)
-    font_table, font_conflicts = choose_mapping_table(font_counts)
-    stats = {
-        "pairs": pairs,
-        "stable": stable_matches,
-        "skippedCipher": skipped_cipher,
-        "skippedPlain": skipped_plain,
-        "globalChars": len(global_table),
-        "fontChars": len(font_table),
-        "globalConflicts": global_conflicts,
-        "fontConflicts": font_conflicts,
-        "titlePairs": title_pairs,
-        "titleVotes": title_votes,
+// 两侧都只出现一次的行，按旧侧顺序取新侧下标的最长递增子序列
+fn unique_line_anchors<S: AsRef<str>>(
+    a: &[S],
+    b: &[S],
+    a_range: (usize, usize),
+    b_range: (usize, usize),
+) -> Vec<(usize, usize)> {
+    let mut seen: HashMap<&str, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, line) in a.iter().enumerate().take(a_range.1).skip(a_range.0) {
+        let entry = seen.entry(line.as_ref()).or_insert((0, i, 0, 0));
+        entry.0 += 1;
+    }
+    for (j, line) in b.iter().enumerate().take(b_range.1).skip(b_range.0) {
+        if let Some(entry) = seen.get_mut(line.as_ref()) {
+            entry.2 += 1;
+            entry.3 = j;
+        }
+    }
+    let mut pairs: Vec<(usize, usize)> = seen
+        .into_values()
+        .filter(|(count_a, _, count_b, _)| *count_a == 1 && *count_b == 1)
+        .map(|(_, i, _, j)| (i, j))
+        .collect();
+    pairs.sort_unstable();
+
+    let mut tails: Vec<usize> = Vec::new();
+    let mut prev: Vec<Option<usize>> = vec![None; pairs.len()];
+    for (index, (_, j)) in pairs.iter().enumerate() {
+        let pos = tails.partition_point(|&t| pairs[t].1 < *j);
+        if pos > 0 {
+            prev[index] = Some(tails[pos - 1]);
+        }
+        if pos == tails.len() {
+            tails.push(index);
+        } else {
+            tails[pos] = index;
+        }
+    }
+    let mut anchors = Vec::with_capacity(tails.len());
+    let mut cursor = tails.last().copied();
+    while let Some(index) = cursor {
+        anchors.push(pairs[index]);
+        cursor = prev[index];
+    }
+    anchors.reverse();
+    anchors
+}
+
+fn lcs_line_ranges<S: AsRef<str>>(
+    a: &[S],
+    b: &[S],
+    a_range: (usize, usize),
+    b_range: (usize, usize),
+    out: &mut Vec<LineDiffRange>,
+) {
+    let (n, m) = (a_range.1 - a_range.0, b_range.1 - b_range.0);
+    if n.saturating_mul(m) > HISTORY_DIFF_LCS_LIMIT {
+        push_line_diff_range(
+            out,
+            LineDiffRange {
+                old_start: a_range.0,
+                old_end: a_range.1,
+                new_start: b_range.0,
+                new_end: b_range.1,
+            },
+        );
+        return;
+    }
+    let width = m + 1;
+    let mut table = vec![0u32; (n + 1) * width];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i * width + j] = if a[a_range.0 + i].as_ref() == b[b_range.0 + j].as_ref() {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                table[(i + 1) * width + j].max(table[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0usize, 0usize);
+    let (mut pending_i, mut pending_j) = (0usize, 0usize);
+    while i < n && j < m {
+        if a[a_range.0 + i].as_ref() == b[b_range.0 + j].as_ref() {
+            push_line_diff_range(
+                out,
+                LineDiffRange {
+                    old_start: a_range.0 + pending_i,
+                    old_end: a_range.0 + i,
+                    new_start: b_range.0 + pending_j,
+                    new_end: b_range.0 + j,
+                },
+            );
+            i += 1;
+            j += 1;
+            pending_i = i;
+            pending_j = j;
+        } else if table[(i + 1) * width + j] >= table[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    push_line_diff_range(
+        out,
+        LineDiffRange {
+            old_start: a_range.0 + pending_i,
+            old_end: a_range.1,
+            new_start: b_range.0 + pending_j,
+            new_end: b_range.1,
+        },
+    );
+}
+
+fn diff_line_ranges_into<S: AsRef<str>>(
+    a: &[S],
+    b: &[S],
+    a_range: (usize, usize),
+    b_range: (usize, usize),
+    out: &mut Vec<LineDiffRange>,
+) {
+    let ((mut a_lo, mut a_hi), (mut b_lo, mut b_hi)) = (a_range, b_range);
+    while a_lo < a_hi && b_lo < b_hi && a[a_lo].as_ref() == b[b_lo].as_ref() {
+        a_lo += 1;
+        b_lo += 1;
+    }
+    while a_lo < a_hi && b_lo < b_hi && a[a_hi - 1].as_ref() == b[b_hi - 1].as_ref() {
+        a_hi -= 1;
+        b_hi -= 1;
+    }
+    if a_lo == a_hi || b_lo == b_hi {
+        push_line_diff_range(
+            out,
+            LineDiffRange {
+                old_start: a_lo,
+                old_end: a_hi,
+                new_start: b_lo,
+                new_end: b_hi,
+            },
+        );
+        return;
+    }
+    let anchors = unique_line_anchors(a, b, (a_lo, a_hi), (b_lo, b_hi));
+    if anchors.is_empty() {
+        lcs_line_ranges(a, b, (a_lo, a_hi), (b_lo, b_hi), out);
+        return;
+    }
+    let (mut prev_a, mut prev_b) = (a_lo, b_lo);
+    for (i, j) in anchors {
+        diff_line_ranges_into(a, b, (prev_a, i), (prev_b, j), out);
+        prev_a = i + 1;
+        prev_b = j + 1;
+    }
+    diff_line_ranges_into(a, b, (prev_a, a_hi), (prev_b, b_hi), out);
+}
+
+fn diff_line_ranges<S: AsRef<str>>(a: &[S], b: &[S]) -> Vec<LineDiffRange> {
+    let mut out = Vec::new();
+    diff_line_ranges_into(a, b, (0, a.len()), (0, b.len()), &mut out);
+    out
+}
+
+fn history_diff_hunk(
+    id: usize,
+    range: &LineDiffRange,
+    base: &[String],
+    target: &[String],
+) -> HistoryDiffHunk {
+    HistoryDiffHunk {
+        id,
+        old_start: range.old_start + 1,
+        new_start: range.new_start + 1,
+        old_lines: base[range.old_start..range.old_end].to_vec(),
+        new_lines: target[range.new_start..range.new_end].to_vec(),
+        context_before: target
+            [range.new_start.saturating_sub(HISTORY_DIFF_CONTEXT_LINES)..range.new_start]
+            .to_vec(),
+        context_after: target
+            [range.new_end..(range.new_end + HISTORY_DIFF_CONTEXT_LINES).min(target.len())]
+            .to_vec(),
+    }
+}
+
+fn history_diff_report(
+    base: &[String],
+    target: &[String],
+    chapter_rules: Vec<RegexRule>,
+) -> HistoryDiffReport {
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    scan_chapter_lines(
+        target.iter().map(String::as_str),
+        chapter_rules,
+        |chapter| {
+            headings.push((chapter.line_number, chapter.title));
+        },
+    );
+    let mut report = HistoryDiffReport {
+        base_revision: text_revision(&base.join("\n")),
+        target_revision: text_revision(&target.join("\n")),
+        hunk_count: 0,
+        added: 0,
+        removed: 0,
+        chapters: Vec::new(),
+    };
+    for (id, range) in diff_line_ranges(base, target).into_iter().enumerate() {
+        let new_start = range.new_start + 1;
+        // 纯删除归到删除位置之前的那一行所在章节
+        let anchor_line = if range.new_end > range.new_start {
+            new_start
+        } else {
+            range.new_start
+        };
+        let heading = headings
+            .iter()
+            .take_while(|(line, _)| *line <= anchor_line)
+            .last();
+        let line_number = heading.map_or(0, |(line, _)| *line);
+        if report
+            .chapters
+            .last()
+            .is_none_or(|chapter| chapter.line_number != line_number)
+        {
+            report.chapters.push(HistoryDiffChapter {
+                title: heading.map(|(_, title)| title.clone()),
+                line_number,
+                added: 0,
+                removed: 0,
+                hunks: Vec::new(),
+            });
+        }
+        let hunk = history_diff_hunk(id, &range, base, target);
+        let chapter = report.chapters.last_mut().expect("chapter pushed above");
+        chapter.added += hunk.new_lines.len();
+        chapter.removed += hunk.old_lines.len();
+        report.added += hunk.new_lines.len();
+        report.removed += hunk.old_lines.len();
+        report.hunk_count += 1;
+        chapter.hunks.push(hunk);
+    }
+    report
+}
+
+// 把选中的块（或整章的块）在目标中换回旧内容，返回与选择性替换相同的结果结构。
+fn restore_history_hunks_impl(
+    base: &[String],
+    target: &[String],
+    hunk_ids: &[usize],
+    chapter_lines: &[usize],
+    revision: Option<&str>,
+    chapter_rules: Vec<RegexRule>,
+) -> Result<ReplaceApplyResult, String> {
+    let report = history_diff_report(base, target, chapter_rules);
+    if revision.is_some_and(|rev| rev != report.target_revision) {
+        return Err("内容已变化，请重新比较历史版本".to_string());
+    }
+    let selected: HashSet<usize> = hunk_ids.iter().copied().collect();
+    let chapters: HashSet<usize> = chapter_lines.iter().copied().collect();
+    let mut lines = target.to_vec();
+    let mut patches = Vec::new();
+    let mut inverse_patches = Vec::new();
+    let mut line_delta = 0isize;
+    for chapter in &report.chapters {
+        let whole_chapter = chapters.contains(&chapter.line_number);
+        for hunk in &chapter.hunks {
+            if !whole_chapter && !selected.contains(&hunk.id) {
+                continue;
+            }
+            inverse_patches.push(TextBufferPatch {
+                start_line: (hunk.new_start as isize + line_delta) as usize,
+                delete_count: hunk.old_lines.len(),
+                lines: hunk.new_lines.clone(),
+            });
+            line_delta += hunk.old_lines.len() as isize - hunk.new_lines.len() as isize;
+            patches.push(TextBufferPatch {
+                start_line: hunk.new_start,
+                delete_count: hunk.new_lines.len(),
+                lines: hunk.old_lines.clone(),
+            });
+        }
+    }
+    let applied = patches.len();
+    apply_text_patches(&mut lines, patches)?;
+    let content = lines.join("\n");
+    Ok(ReplaceApplyResult {
+        revision: text_revision(&content),
+        content,
+        applied,
+        inverse_patches,
+    })
+}
+
+// 只允许读取本文件在历史目录下的快照，防止借对比接口读取任意文件。
+fn resolve_history_snapshot(
+    app: &tauri::AppHandle,
+    original_path: &str,
+    snapshot_path: &str,
+) -> Result<PathBuf, String> {
+    let history_dir = fs::canonicalize(get_history_base_dir(Some(app)))
+        .map_err(|e| format!("历史目录不可用: {}", e))?;
+    let path = fs::canonicalize(snapshot_path).map_err(|e| format!("历史版本不存在: {}", e))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if path.parent() != Some(history_dir.as_path())
+        || !is_history_snapshot_name(original_path, &name)
+    {
+        return Err("不是当前文件的历史版本".to_string());
     }
-    return global_table, font_table, stats
+    Ok(path)
+}
 
-def neutralize_css_fonts(text):
-    text = re.sub(r"(?is)@font-face\s*\{.*?\}", "", text)
-    text = re.sub(r"(?is)font-family\s*:\s*[^;}{]+;?", "font-family: serif;", text)
-    return text
+fn load_history_diff_source(
+    app: &tauri::AppHandle,
+    original_path: &str,
+    source: HistoryDiffSource,
+) -> Result<Arc<Vec<String>>, String> {
+    let content = match source {
+        HistoryDiffSource::Buffer { buffer_id } => return text_buffer_lines(&buffer_id),
+        HistoryDiffSource::Content { content } => content,
+        HistoryDiffSource::Snapshot { path } => {
+            read_history_snapshot_file(&resolve_history_snapshot(app, original_path, &path)?)?
+        }
+    };
+    Ok(Arc::new(
+        normalize_line_endings(content)
+            .split('\n')
+            .map(str::to_string)
+            .collect(),
+    ))
+}
 
-def neutralize_html_inline_fonts(text):
-    return re.sub(r"(?is)font-family\s*:\s*[^;\"'}]+;?", "font-family: serif;", text)
+#[tauri::command]
+async fn diff_history(
+    app: tauri::AppHandle,
+    original_path: String,
+    base: HistoryDiffSource,
+    target: HistoryDiffSource,
+    chapter_rules: Option<Vec<RegexRule>>,
+) -> Result<HistoryDiffReport, String> {
+    let base = load_history_diff_source(&app, &original_path, base)?;
+    let target = load_history_diff_source(&app, &original_path, target)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        history_diff_report(
+            &base,
+            &target,
+            chapter_rules.unwrap_or_else(mobile_default_chapter_rules),
+        )
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
+}
 
-def private_chars(count, excluded=None):
-    excluded = set(excluded or ())
-    ranges = [(0xE000, 0xF8FF), (0xF0000, 0xFFFFD), (0x100000, 0x10FFFD)]
-    available = []
-    for start, end in ranges:
-        for code in range(start, end + 1):
-            ch = chr(code)
-            if ch not in excluded:
-                available.append(ch)
-    if len(available) >= count:
-        random.SystemRandom().shuffle(available)
-        return available[:count]
-    raise RuntimeError("可用私用区字符不足")
+// target 只能是当前内容：content 直接返回恢复后的全文，buffer 写回缓冲且不回传全文。
+// chapter_lines 为 diff_history 返回的章节 line_number，整章恢复。
+#[allow(clippy::too_many_arguments)]
+#[tauri::command]
+async fn restore_history_hunks(
+    app: tauri::AppHandle,
+    original_path: String,
+    base: HistoryDiffSource,
+    target: HistoryDiffSource,
+    hunk_ids: Vec<usize>,
+    chapter_lines: Option<Vec<usize>>,
+    revision: Option<String>,
+    chapter_rules: Option<Vec<RegexRule>>,
+) -> Result<ReplaceApplyResult, String> {
+    let buffer_id = match &target {
+        HistoryDiffSource::Snapshot { .. } => {
+            return Err("只能把历史版本恢复到当前内容".to_string());
+        }
+        HistoryDiffSource::Buffer { buffer_id } => Some(buffer_id.clone()),
+        HistoryDiffSource::Content { .. } => None,
+    };
+    let base = load_history_diff_source(&app, &original_path, base)?;
+    let target = load_history_diff_source(&app, &original_path, target)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut result = restore_history_hunks_impl(
+            &base,
+            &target,
+            &hunk_ids,
+            &chapter_lines.unwrap_or_default(),
+            revision.as_deref(),
+            chapter_rules.unwrap_or_else(mobile_default_chapter_rules),
+        )?;
+        if let Some(buffer_id) = buffer_id {
+            if result.applied > 0 {
+                let mut buffers = lock_text_buffers()?;
+                let buffer = buffers
+                    .get_mut(&buffer_id)
+                    .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+                if !Arc::ptr_eq(&buffer.lines, &target) {
+                    return Err("内容已变化，请重新比较历史版本".to_string());
+                }
+                buffer.lines = Arc::new(result.content.split('\n').map(str::to_string).collect());
+                buffer.modified = true;
+            }
+            result.content = String::new();
+        }
+        Ok(result)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-def collect_font_chars(font_items):
-    from fontTools.ttLib import TTFont
+#[cfg(test)]
+mod history_retention_tests {
+    use super::*;
 
-    supported = set()
-    occupied_private = set()
-    coverage_by_name = {}
-    for name, font_data in font_items:
-        try:
-            font = TTFont(BytesIO(font_data))
-            best = font.getBestCmap() or {}
-            covered = set()
-            for code in best.keys():
-                ch = chr(code)
-                if should_map_char(ch):
-                    supported.add(ch)
-                    covered.add(ch)
-                if is_private_use_char(ch):
-                    occupied_private.add(ch)
-            coverage_by_name[name.lower()] = len(covered)
-        except Exception:
-            continue
-    return supported, occupied_private, coverage_by_name
+    fn snapshot(path: &str, timestamp: u64, size: u64) -> HistoryMeta {
+        HistoryMeta {
+            filename: path.to_string(),
+            path: path.to_string(),
+            timestamp,
+            size,
+            date_str: String::new(),
+            reason: String::new(),
+        }
+    }
 
-def split_font_families(value):
-    families = []
-    current = []
-    quote = None
-    for ch in value:
-        if quote:
-            current.append(ch)
-            if ch == quote:
-                quote = None
-        elif ch in ("'", '"'):
-            quote = ch
-            current.append(ch)
-        elif ch == ",":
-            item = "".join(current).strip()
-            if item:
-                families.append(item)
-            current = []
-        else:
-            current.append(ch)
-    item = "".join(current).strip()
-    if item:
-        families.append(item)
-    return families
+    #[test]
+    fn prune_applies_count_age_and_size_but_keeps_latest() {
+        let day = 24 * 60 * 60;
+        let now = 300 * day;
+        let snapshots = vec![
+            snapshot("a", now - 200 * day, 3 << 20),
+            snapshot("b", now - day, 1 << 20),
+            snapshot("c", now - 3 * day, 1 << 20),
+            snapshot("d", now - 10 * day, 1 << 20),
+        ];
+        let unlimited = HistoryRetentionConfig {
+            max_count: 0,
+            max_age_days: 0,
+            max_total_mb: 0,
+        };
+        assert!(prune_history_snapshots(&snapshots, &unlimited, now).is_empty());
+        let by_count = HistoryRetentionConfig {
+            max_count: 2,
+            ..unlimited.clone()
+        };
+        assert_eq!(
+            prune_history_snapshots(&snapshots, &by_count, now),
+            vec!["c", "d"]
+        );
+        let by_age = HistoryRetentionConfig {
+            max_age_days: 5,
+            ..unlimited.clone()
+        };
+        // 最新一份即使过期也保留
+        assert_eq!(prune_history_snapshots(&snapshots, &by_age, now), vec!["d"]);
+        let by_size = HistoryRetentionConfig {
+            max_total_mb: 4,
+            ..unlimited
+        };
+        assert_eq!(
+            prune_history_snapshots(&snapshots, &by_size, now),
+            vec!["c", "d"]
+        );
+    }
 
-def unquote_font_family(value):
-    value = value.strip()
-    if len(value) >= 2 and value[0] == value[-1] and value[0] in ("'", '"'):
-        value = value[1:-1]
-    return value
+    #[test]
+    fn snapshots_are_compressed_deduplicated_and_labelled() -> Result<(), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let original = "/books/小说.txt";
+        let retention = HistoryRetentionConfig {
+            max_count: 2,
+            ..Default::default()
+        };
+        let text = "第一章\n正文".repeat(200);
+        assert!(save_history_snapshot(
+            dir.path(),
+            original,
+            &text,
+            "manual",
+            &retention
+        )?);
+        assert!(!save_history_snapshot(
+            dir.path(),
+            original,
+            &text,
+            "manual",
+            &retention
+        )?);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(save_history_snapshot(
+            dir.path(),
+            original,
+            "改过的正文",
+            "before batch-replace",
+            &retention
+        )?);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(save_history_snapshot(
+            dir.path(),
+            original,
+            "再改",
+            "before_ai_proofing",
+            &retention
+        )?);
+
+        let list = list_history_snapshots(dir.path(), original);
+        let reasons: Vec<&str> = list.iter().map(|m| m.reason.as_str()).collect();
+        assert_eq!(reasons, vec!["before_ai_proofing", "before_batch_replace"]);
+        assert!(list[0].filename.ends_with(".bak.gz"));
+        assert_eq!(
+            read_history_snapshot_file(Path::new(&list[1].path))?,
+            "改过的正文"
+        );
 
-def normalize_font_family(value):
-    return unquote_font_family(value).lower()
+        // 旧版未压缩快照仍能列出与读取
+        let (_, prefix) = history_file_prefix(original);
+        let legacy = dir.path().join(format!("{}.1700000000.bak", prefix));
+        fs::write(&legacy, "旧版").map_err(|e| e.to_string())?;
+        let list = list_history_snapshots(dir.path(), original);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[2].reason, "");
+        assert_eq!(read_history_snapshot_file(&legacy)?, "旧版");
+        Ok(())
+    }
+}
 
-def css_font_family(value):
-    return '"' + value.replace("\\", "\\\\").replace('"', '\\"') + '"'
+#[cfg(test)]
+mod history_diff_tests {
+    use super::*;
 
-def append_font_fallbacks(value, fallback_families):
-    important = ""
-    important_match = re.search(r"\s*!important\s*$", value, flags=re.I)
-    if important_match:
-        important = important_match.group(0)
-        value = value[:important_match.start()]
-    families = split_font_families(value)
-    existing = {normalize_font_family(family) for family in families}
-    additions = [css_font_family(family) for family in fallback_families if family.lower() not in existing]
-    if not additions:
-        return value + important
-    generics = {
-        "serif", "sans-serif", "monospace", "cursive", "fantasy", "system-ui", "ui-serif",
-        "ui-sans-serif", "ui-monospace", "ui-rounded", "emoji", "math", "fangsong"
+    fn lines(text: &str) -> Vec<String> {
+        text.split('\n').map(str::to_string).collect()
     }
-    insert_at = next((index for index, family in enumerate(families) if normalize_font_family(family) in generics), len(families))
-    families[insert_at:insert_at] = additions
-    return ", ".join(families) + important
 
-def rewrite_css_font_fallbacks(source, fallback_families):
-    if not fallback_families:
-        return source
-    font_faces = []
-    def mask_face(match):
-        marker = "__TEPUB_FONT_FACE_{}__".format(len(font_faces))
-        font_faces.append(match.group(0))
-        return marker
-    masked = re.sub(r"(?is)@font-face\s*\{[^{}]*\}", mask_face, source)
-    rewritten = re.sub(
-        r"(?is)(font-family\s*:\s*)([^;}{]+)",
-        lambda match: match.group(1) + append_font_fallbacks(match.group(2), fallback_families),
-        masked,
-    )
-    return re.sub(
-        r"__TEPUB_FONT_FACE_(\d+)__",
-        lambda match: font_faces[int(match.group(1))] if int(match.group(1)) < len(font_faces) else "",
-        rewritten,
-    )
+    #[test]
+    fn diff_aligns_unique_lines_and_handles_repeated_ones() {
+        let a = ["甲", "乙", "", "丙", "", "丁"];
+        let b = ["甲", "乙改", "", "丙", "新行", "", "丁"];
+        assert_eq!(
+            diff_line_ranges(&a, &b),
+            vec![
+                LineDiffRange {
+                    old_start: 1,
+                    old_end: 2,
+                    new_start: 1,
+                    new_end: 2
+                },
+                LineDiffRange {
+                    old_start: 4,
+                    old_end: 4,
+                    new_start: 4,
+                    new_end: 5
+                },
+            ]
+        );
+        // 没有唯一行时退回 LCS
+        let a = ["x", "y", "x", "y"];
+        let b = ["x", "x", "y"];
+        let ranges = diff_line_ranges(&a, &b);
+        let removed: usize = ranges.iter().map(|r| r.old_end - r.old_start).sum();
+        let added: usize = ranges.iter().map(|r| r.new_end - r.new_start).sum();
+        assert_eq!((removed, added), (1, 0));
+        assert!(diff_line_ranges(&a, &a).is_empty());
+    }
 
-def rewrite_inline_font_fallbacks(source, fallback_families):
-    if not fallback_families:
-        return source
-    def rewrite_style(match):
-        style = re.sub(
-            r"(?is)(font-family\s*:\s*)([^;]+)",
-            lambda declaration: declaration.group(1) + append_font_fallbacks(declaration.group(2), fallback_families),
-            match.group(3),
-        )
-        return match.group(1) + match.group(2) + style + match.group(2)
-    return re.sub(r"(?is)(style\s*=\s*)(['\"])(.*?)\2", rewrite_style, source)
+    #[test]
+    fn hunks_group_by_chapter_and_restore_selected_ones() -> Result<(), String> {
+        let base =
+            lines("序言\n\n第一章 起\n\n张三说：你好\n李四说：再见\n\n第二章 承\n\n王五说：明天");
+        let target = lines(
+            "序言改\n\n第一章 起\n\n张三道：你好\n李四道：再见\n\n第二章 承\n\n王五道：明天\n完。",
+        );
+        let report = history_diff_report(&base, &target, mobile_default_chapter_rules());
+        assert_eq!(report.hunk_count, 3);
+        let titles: Vec<Option<&str>> =
+            report.chapters.iter().map(|c| c.title.as_deref()).collect();
+        assert_eq!(titles, vec![None, Some("第一章 起"), Some("第二章 承")]);
+        assert_eq!(
+            report.chapters[1].hunks[0].old_lines,
+            vec!["张三说：你好", "李四说：再见"]
+        );
+        assert_eq!(
+            report.chapters[2].hunks[0].new_lines,
+            vec!["王五道：明天", "完。"]
+        );
+        assert_eq!((report.added, report.removed), (5, 4));
+
+        // 只恢复第一章
+        let result = restore_history_hunks_impl(
+            &base,
+            &target,
+            &[],
+            &[report.chapters[1].line_number],
+            Some(&report.target_revision),
+            mobile_default_chapter_rules(),
+        )?;
+        assert_eq!(result.applied, 1);
+        assert_eq!(
+            result.content,
+            "序言改\n\n第一章 起\n\n张三说：你好\n李四说：再见\n\n第二章 承\n\n王五道：明天\n完。"
+        );
 
-def inject_obfuscated_text_compatibility(source, fallback_families):
-    if re.search(r"data-tepub-font-obfuscation\s*=", source, flags=re.I):
-        return source
-    default_family = ""
-    if fallback_families:
-        default_family = "html { font-family: " + ", ".join(css_font_family(family) for family in fallback_families) + ", serif; }\n"
-    style = '<style type="text/css" data-tepub-font-obfuscation="1">' + default_family + 'html, body { overflow-wrap: anywhere; word-break: break-all; }</style>'
-    if re.search(r"</head\s*>", source, flags=re.I):
-        return re.sub(r"</head\s*>", lambda _match: style + "</head>", source, count=1, flags=re.I)
-    return style + source
+        // 恢复开头与第二章的块，逆补丁可以撤销
+        let result = restore_history_hunks_impl(
+            &base,
+            &target,
+            &[0, 2],
+            &[],
+            None,
+            mobile_default_chapter_rules(),
+        )?;
+        assert_eq!(
+            result.content,
+            "序言\n\n第一章 起\n\n张三道：你好\n李四道：再见\n\n第二章 承\n\n王五说：明天"
+        );
+        let mut undone = lines(&result.content);
+        apply_text_patches(&mut undone, result.inverse_patches)?;
+        assert_eq!(undone, target);
+
+        let stale = restore_history_hunks_impl(
+            &base,
+            &target,
+            &[0],
+            &[],
+            Some("stale"),
+            mobile_default_chapter_rules(),
+        );
+        assert!(stale.is_err());
+        Ok(())
+    }
+}
 
-def collect_embedded_font_families(infos, contents, coverage_by_name):
-    ranked = []
-    seen = set()
-    for info in infos:
-        name = info.filename
-        if not name.lower().endswith(".css") or name.endswith("/"):
-            continue
-        source = decode_text(contents.get(name, b""))
-        for face in re.finditer(r"(?is)@font-face\s*\{([^{}]*)\}", source):
-            body = face.group(1)
-            family_match = re.search(r"(?is)font-family\s*:\s*([^;}{]+)", body)
-            if not family_match:
-                continue
-            family = unquote_font_family(family_match.group(1))
-            family_key = family.lower()
-            if not family or family_key in seen:
-                continue
-            scores = []
-            for url_match in re.finditer(r"(?is)url\(\s*(['\"]?)(.*?)\1\s*\)", body):
-                target = zip_join(zip_parent(name), url_match.group(2))
-                if target.lower() in coverage_by_name:
-                    scores.append(coverage_by_name[target.lower()])
-            if scores:
-                seen.add(family_key)
-                ranked.append((family, max(scores), len(ranked)))
-    ranked.sort(key=lambda item: (-item[1], item[2]))
-    return [family for family, _score, _order in ranked]
+#[tauri::command]
+fn list_library_fonts(app: tauri::AppHandle) -> Result<Vec<LibraryFontInfo>, String> {
+    let dir = library_fonts_dir(&app)?;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut fonts = Vec::new();
+    let entries = fs::read_dir(&dir).map_err(|e| format!("读取字体目录失败: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("读取字体目录项失败: {}", e))?;
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let ext = path
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        if !matches!(ext.as_str(), "ttf" | "otf" | "woff" | "woff2") {
+            continue;
+        }
+        if let Ok(info) = build_library_font_info(&app, &path) {
+            fonts.push(info);
+        }
+    }
 
-def add_private_cmap(font_data, mapping):
-    from fontTools.ttLib import TTFont
+    fonts.sort_by(|a, b| a.family.to_lowercase().cmp(&b.family.to_lowercase()));
+    Ok(fonts)
+}
 
-    font = TTFont(BytesIO(font_data))
-    best = font.getBestCmap() or {}
-    if "cmap" not in font:
-        return font_data
-    added = 0
-    for plain, shadow in mapping.items():
-        glyph = best.get(ord(plain))
-        if not glyph:
-            continue
-        for table in font["cmap"].tables:
-            if table.isUnicode():
-                table.cmap[ord(shadow)] = glyph
-                added += 1
-    if added == 0:
-        return font_data
-    out = BytesIO()
-    font.save(out)
-    return out.getvalue()
+#[tauri::command]
+fn import_library_font(app: tauri::AppHandle, path: String) -> Result<LibraryFontInfo, String> {
+    let normalized = normalize_local_file_path(&path);
+    let source = PathBuf::from(&normalized);
+    if !source.exists() || !source.is_file() {
+        return Err("字体文件不存在".to_string());
+    }
 
-def write_entries(output_path, infos, contents, extra_map=None, skip_map=False):
-    with zipfile.ZipFile(output_path, "w") as zout:
-        for info in infos:
-            if info.filename == "mimetype":
-                zi = zipfile.ZipInfo(info.filename)
-                zi.compress_type = zipfile.ZIP_STORED
-                zout.writestr(zi, contents[info.filename])
-                break
-        for info in infos:
-            name = info.filename
-            if name == "mimetype":
-                continue
-            if skip_map and name == MAP_NAME:
-                continue
-            zi = zipfile.ZipInfo(name)
-            zi.compress_type = info.compress_type if info.compress_type is not None else zipfile.ZIP_DEFLATED
-            zi.external_attr = info.external_attr
-            zi.date_time = info.date_time
-            if name.endswith("/"):
-                zout.writestr(zi, b"")
-            else:
-                zout.writestr(zi, contents[name])
-        if extra_map is not None:
-            zi = zipfile.ZipInfo(MAP_NAME)
-            zi.compress_type = zipfile.ZIP_DEFLATED
-            zout.writestr(zi, json.dumps(extra_map, ensure_ascii=False, separators=(",", ":")).encode("utf-8"))
+    let ext = source
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    if !matches!(ext.as_str(), "ttf" | "otf" | "woff" | "woff2") {
+        return Err("仅支持导入 ttf / otf / woff / woff2 字体".to_string());
+    }
 
-BODY_ENCRYPTION_CLASS = "tepub-font-encrypted-body"
-BODY_ENCRYPTION_FAMILY = "TEpubEncryptedBodyFont"
+    let font_data = fs::read(&source).map_err(|e| format!("读取字体失败: {}", e))?;
+    let family = pick_font_family_name(&font_data, &source);
+    let file_stem = sanitize_filename_part(&family);
+    let safe_stem = if file_stem.is_empty() {
+        "font".to_string()
+    } else {
+        file_stem
+    };
 
-def update_body_tag_class(tag, add):
-    match = re.search(r"\bclass\s*=\s*(['\"])(.*?)\1", tag, flags=re.I | re.S)
-    if match:
-        classes = [name for name in match.group(2).split() if name != BODY_ENCRYPTION_CLASS]
-        if add:
-            classes.append(BODY_ENCRYPTION_CLASS)
-        if not classes:
-            return tag[:match.start()] + tag[match.end():]
-        replacement = "class=" + match.group(1) + " ".join(classes) + match.group(1)
-        return tag[:match.start()] + replacement + tag[match.end():]
-    if not add:
-        return tag
-    return re.sub(r"\s*(/?)>$", ' class="' + BODY_ENCRYPTION_CLASS + r'"\1>', tag)
+    let target_dir = library_fonts_dir(&app)?;
+    ensure_dir(&target_dir)?;
 
-def is_title_like_tag(tag, name):
-    if re.fullmatch(r"h[1-6]", name or "") or name in ("title", "header", "nav"):
-        return True
-    attrs = parse_attrs(tag)
-    marker = (attrs.get("class", "") + " " + attrs.get("id", "")).lower()
-    return bool(
-        re.search(r"(^|[\s_-])(title|subtitle|heading|headline)([\s_-]|$)", marker)
-        or re.search(r"(^|[\s_-])(chapter|volume|part|section)[\s_-]*(title|subtitle|number|name)([\s_-]|$)", marker)
-    )
+    let mut target = target_dir.join(format!("{}.{}", safe_stem, ext));
+    let mut seq = 2usize;
+    while target.exists() {
+        let existing = fs::read(&target).unwrap_or_default();
+        if existing == font_data {
+            return build_library_font_info(&app, &target);
+        }
+        target = target_dir.join(format!("{}-{}.{}", safe_stem, seq, ext));
+        seq += 1;
+    }
 
-def transform_body_paragraphs(source, mapping, mode="encrypt"):
-    out = []
-    paragraph_depth = 0
-    title_depth = 0
-    element_stack = []
-    raw_tag = None
-    i = 0
-    while i < len(source):
-        if source[i] == "<":
-            end = source.find(">", i + 1)
-            if end < 0:
-                out.append(source[i:])
-                break
-            tag = source[i:end + 1]
-            closing, name = tag_identity(tag)
-            self_closing = bool(re.search(r"/\s*>$", tag))
-            class_match = re.search(r"\bclass\s*=\s*(['\"])(.*?)\1", tag, flags=re.I | re.S)
-            marked = bool(class_match and BODY_ENCRYPTION_CLASS in class_match.group(2).split())
-            if closing:
-                while element_stack:
-                    popped_name, popped_paragraph, popped_title = element_stack.pop()
-                    paragraph_depth -= int(popped_paragraph)
-                    title_depth -= int(popped_title)
-                    if popped_name == name:
-                        break
-            if not closing and not self_closing:
-                title_like = is_title_like_tag(tag, name)
-                target = (name == "p" and title_depth == 0 and not title_like) if mode == "encrypt" else marked
-                if target:
-                    paragraph_depth += 1
-                    tag = update_body_tag_class(tag, mode == "encrypt")
-                if title_like:
-                    title_depth += 1
-                element_stack.append((name, target, title_like))
-            if name in ("script", "style"):
-                raw_tag = None if closing else name
-            out.append(tag)
-            i = end + 1
-            continue
-        if not raw_tag and source[i] == "&":
-            end = source.find(";", i + 1, i + 16)
-            if end >= 0:
-                out.append(source[i:end + 1])
-                i = end + 1
-                continue
-        ch = source[i]
-        out.append(mapping.get(ch, ch) if not raw_tag and paragraph_depth > 0 else ch)
-        i += 1
-    return "".join(out)
+    fs::write(&target, &font_data).map_err(|e| format!("写入字体失败: {}", e))?;
+    build_library_font_info(&app, &target)
+}
 
-def collect_body_and_protected_chars(source):
-    body_chars = []
-    protected_chars = []
-    paragraph_depth = 0
-    title_depth = 0
-    element_stack = []
-    raw_tag = None
-    i = 0
-    while i < len(source):
-        if source[i] == "<":
-            end = source.find(">", i + 1)
-            if end < 0:
-                break
-            tag = source[i:end + 1]
-            closing, name = tag_identity(tag)
-            self_closing = bool(re.search(r"/\s*>$", tag))
-            if closing:
-                while element_stack:
-                    popped_name, popped_paragraph, popped_title = element_stack.pop()
-                    paragraph_depth -= int(popped_paragraph)
-                    title_depth -= int(popped_title)
-                    if popped_name == name:
-                        break
-            elif not self_closing:
-                title_like = is_title_like_tag(tag, name)
-                paragraph = name == "p" and title_depth == 0 and not title_like
-                paragraph_depth += int(paragraph)
-                title_depth += int(title_like)
-                element_stack.append((name, paragraph, title_like))
-            if name in ("script", "style"):
-                raw_tag = None if closing else name
-            i = end + 1
-            continue
-        if not raw_tag and source[i] == "&":
-            end = source.find(";", i + 1, i + 16)
-            if end >= 0:
-                i = end + 1
-                continue
-        ch = source[i]
-        if not raw_tag and should_map_char(ch):
-            if paragraph_depth > 0 and title_depth == 0:
-                body_chars.append(ch)
-            else:
-                protected_chars.append(ch)
-        i += 1
-    return body_chars, protected_chars
+#[tauri::command]
+fn rename_library_font(
+    app: tauri::AppHandle,
+    file_name: String,
+    family: String,
+) -> Result<LibraryFontInfo, String> {
+    let trimmed_family = family.trim();
+    if trimmed_family.is_empty() {
+        return Err("字体名称不能为空".to_string());
+    }
 
-def collect_spine_html_names(infos, contents):
-    names = {info.filename for info in infos}
-    for info in infos:
-        opf_path = info.filename
-        if not opf_path.lower().endswith(".opf"):
-            continue
-        opf = decode_text(contents.get(opf_path, b""))
-        opf_dir = zip_parent(opf_path)
-        manifest = {}
-        for item in re.finditer(r"(?is)<item\b([^>]*)>", opf):
-            attrs = parse_attrs(item.group(1))
-            if attrs.get("id") and attrs.get("href"):
-                manifest[attrs["id"]] = zip_join(opf_dir, attrs["href"])
-        spine = []
-        for itemref in re.finditer(r"(?is)<itemref\b([^>]*)>", opf):
-            target = manifest.get(parse_attrs(itemref.group(1)).get("idref", ""), "")
-            if target in names and is_html_name(target):
-                spine.append(target)
-        if spine:
-            return spine
-    return [info.filename for info in infos if is_html_name(info.filename)]
+    let font_path = library_fonts_dir(&app)?.join(&file_name);
+    if !font_path.exists() || !font_path.is_file() {
+        return Err("字体文件不存在".to_string());
+    }
+
+    let mut aliases = read_library_font_aliases(&app)?;
+    aliases.insert(file_name.clone(), trimmed_family.to_string());
+    write_library_font_aliases(&app, &aliases)?;
+    build_library_font_info(&app, &font_path)
+}
+
+#[tauri::command]
+fn delete_library_font(app: tauri::AppHandle, file_name: String) -> Result<(), String> {
+    let font_path = library_fonts_dir(&app)?.join(&file_name);
+    if !font_path.exists() || !font_path.is_file() {
+        return Err("字体文件不存在".to_string());
+    }
 
-def relative_zip_path(from_file, target):
-    source_parts = [part for part in zip_parent(from_file).split("/") if part]
-    target_parts = [part for part in target.replace("\\", "/").split("/") if part]
-    while source_parts and target_parts and source_parts[0].lower() == target_parts[0].lower():
-        source_parts.pop(0)
-        target_parts.pop(0)
-    return "/".join([".."] * len(source_parts) + target_parts)
+    fs::remove_file(&font_path).map_err(|e| format!("删除字体失败: {}", e))?;
+    let mut aliases = read_library_font_aliases(&app)?;
+    if aliases.remove(&file_name).is_some() {
+        write_library_font_aliases(&app, &aliases)?;
+    }
+    Ok(())
+}
 
-def inject_body_font_style(source, html_path, font_path):
-    source = re.sub(
-        r"(?is)<style[^>]*data-tepub-body-font-encryption\s*=\s*(['\"])1\1[^>]*>.*?</style>",
-        "",
-        source,
-    )
-    font_url = relative_zip_path(html_path, font_path).replace('"', "%22")
-    style = (
-        '<style type="text/css" data-tepub-body-font-encryption="1">'
-        '@font-face { font-family: "' + BODY_ENCRYPTION_FAMILY + '"; src: url("' + font_url + '"); } '
-        '.' + BODY_ENCRYPTION_CLASS + ', .' + BODY_ENCRYPTION_CLASS + ' * { font-family: "' + BODY_ENCRYPTION_FAMILY + '" !important; }'
-        '</style>'
-    )
-    if re.search(r"</head\s*>", source, flags=re.I):
-        return re.sub(r"</head\s*>", lambda _match: style + "</head>", source, count=1, flags=re.I)
-    return style + source
+#[tauri::command]
+fn list_style_templates(app: tauri::AppHandle) -> Result<Vec<StyleTemplateInfo>, String> {
+    let root = style_templates_root(&app)?;
+    ensure_dir(&root)?;
 
-def random_derangement(chars):
-    shuffled = list(chars)
-    random_source = random.SystemRandom()
-    for index in range(len(shuffled) - 1, 0, -1):
-        target = random_source.randrange(index)
-        shuffled[index], shuffled[target] = shuffled[target], shuffled[index]
-    return shuffled
+    let builtin_path = builtin_style_template_path(&app)?;
+    let mut templates = vec![build_style_template_info(&builtin_path, true)?];
 
-def permute_font_cmap(font_data, mapping):
-    from fontTools.ttLib import TTFont
+    let entries = fs::read_dir(&root).map_err(|e| format!("读取样式模板目录失败: {}", e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("读取样式模板目录项失败: {}", e))?;
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let ext = path
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        if ext != "css" {
+            continue;
+        }
+        if path.file_name().and_then(|s| s.to_str()) == Some("builtin.css") {
+            continue;
+        }
+        templates.push(build_style_template_info(&path, false)?);
+    }
 
-    font = TTFont(BytesIO(font_data))
-    best = font.getBestCmap() or {}
-    if "cmap" not in font:
-        return font_data
-    plain_codes = {ord(ch) for ch in mapping}
-    changed = 0
-    for table in font["cmap"].tables:
-        if not table.isUnicode():
-            continue
-        for code in plain_codes:
-            table.cmap.pop(code, None)
-        for plain, cipher in mapping.items():
-            glyph = best.get(ord(plain))
-            cipher_code = ord(cipher)
-            if glyph and (table.format != 4 or cipher_code <= 0xFFFF):
-                table.cmap[cipher_code] = glyph
-                changed += 1
-    if not changed:
-        return font_data
-    out = BytesIO()
-    font.save(out)
-    return out.getvalue()
+    let mut imported = templates.split_off(1);
+    imported.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+    templates.extend(imported);
+    Ok(templates)
+}
 
-def encrypt_epub_body_permutation(input_path, output_path):
-    from fontTools.ttLib import TTFont
+#[tauri::command]
+fn read_style_template(app: tauri::AppHandle, id: String) -> Result<StyleTemplateContent, String> {
+    let is_builtin = id == "builtin";
+    let path = if is_builtin {
+        builtin_style_template_path(&app)?
+    } else {
+        style_template_path(&app, &id)?
+    };
+    let main_css = if path.exists() {
+        let css = fs::read_to_string(&path).map_err(|e| format!("读取样式模板失败: {}", e))?;
+        if is_builtin && is_stale_builtin_style_template_css(&css) {
+            fs::remove_file(&path).map_err(|e| format!("升级内置样式模板失败: {}", e))?;
+            String::new()
+        } else {
+            css
+        }
+    } else {
+        String::new()
+    };
+    Ok(StyleTemplateContent {
+        id: if is_builtin {
+            "builtin".to_string()
+        } else {
+            id
+        },
+        name: if is_builtin {
+            "内置模板".to_string()
+        } else {
+            style_template_name_from_path(&path)
+        },
+        main_css,
+        is_builtin,
+    })
+}
 
-    with zipfile.ZipFile(input_path, "r") as zin:
-        infos = zin.infolist()
-        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
-        for info in infos:
-            if info.filename.endswith("/"):
-                contents[info.filename] = b""
-        font_names = [info.filename for info in infos if is_font_name(info.filename)]
-        html_names = collect_spine_html_names(infos, contents)
-        if not font_names:
-            raise RuntimeError("EPUB 内未找到字体文件")
-        if not html_names:
-            raise RuntimeError("EPUB 内未找到正文 HTML/XHTML 文件")
-        html_sources = {name: decode_text(contents[name]) for name in html_names}
-        body_chars = set()
-        protected_chars = set()
-        for source in html_sources.values():
-            body, protected = collect_body_and_protected_chars(source)
-            body_chars.update(body)
-            protected_chars.update(protected)
-        body_chars.difference_update(protected_chars)
-        if len(body_chars) < 2:
-            raise RuntimeError("未找到可加密的正文段落汉字")
-        candidates = []
-        for name in font_names:
-            try:
-                font = TTFont(BytesIO(contents[name]))
-                supported = {chr(code) for code in (font.getBestCmap() or {}) if is_cjk_ideograph(chr(code))}
-                chars = [ch for ch in body_chars if ch in supported]
-                candidates.append((len(chars), name, chars))
-            except Exception:
-                continue
-        if not candidates:
-            raise RuntimeError("正文字体中未找到可加密汉字")
-        _score, selected_font, chars = max(candidates, key=lambda item: item[0])
-        if len(chars) < 2:
-            raise RuntimeError("正文字体中未找到足够的可加密汉字")
-        chars = sorted(chars)
-        mapping = dict(zip(chars, random_derangement(chars)))
-        changed_files = 0
-        for name, source in html_sources.items():
-            transformed = transform_body_paragraphs(source, mapping, "encrypt")
-            if transformed != source:
-                contents[name] = inject_body_font_style(transformed, name, selected_font).encode("utf-8")
-                changed_files += 1
-        if not changed_files:
-            raise RuntimeError("未发现可加密的正文段落")
-        new_font = permute_font_cmap(contents[selected_font], mapping)
-        if new_font == contents[selected_font]:
-            raise RuntimeError("正文字体 cmap 未能完成随机置换")
-        contents[selected_font] = new_font
-        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
-        return len(mapping), 1
+#[tauri::command]
+fn import_style_template(app: tauri::AppHandle, path: String) -> Result<StyleTemplateInfo, String> {
+    let normalized = normalize_local_file_path(&path);
+    let source = PathBuf::from(&normalized);
+    if !source.exists() || !source.is_file() {
+        return Err("样式模板文件不存在".to_string());
+    }
 
-def encrypt_epub(input_path, output_path):
-    with zipfile.ZipFile(input_path, "r") as zin:
-        infos = zin.infolist()
-        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
-        for info in infos:
-            if info.filename.endswith("/"):
-                contents[info.filename] = b""
-        font_names = [info.filename for info in infos if is_font_name(info.filename)]
-        html_names = [info.filename for info in infos if is_html_name(info.filename)]
-        if not font_names:
-            raise RuntimeError("EPUB 内未找到字体文件")
-        if not html_names:
-            raise RuntimeError("EPUB 内未找到 HTML/XHTML 文件")
-        supported_chars, existing_private_chars, coverage_by_name = collect_font_chars(
-            (name, contents[name]) for name in font_names
-        )
-        fallback_families = collect_embedded_font_families(infos, contents, coverage_by_name)
-        if not supported_chars:
-            raise RuntimeError("内嵌字体未发现可加密汉字")
-        chars = []
-        seen = set()
-        for name in html_names:
-            html = decode_text(contents[name])
-            existing_private_chars.update(ch for ch in html if is_private_use_char(ch))
-            for ch in iter_text_chars(html):
-                if ch in supported_chars and ch not in seen:
-                    seen.add(ch)
-                    chars.append(ch)
-        if not chars:
-            raise RuntimeError("未找到可加密的正文汉字")
-        shadows = private_chars(len(chars), existing_private_chars)
-        mapping = dict(zip(chars, shadows))
-        for name in html_names:
-            transformed = transform_text(decode_text(contents[name]), mapping)
-            transformed = rewrite_inline_font_fallbacks(transformed, fallback_families)
-            transformed = inject_obfuscated_text_compatibility(transformed, fallback_families)
-            contents[name] = transformed.encode("utf-8")
-        for info in infos:
-            name = info.filename
-            if name.lower().endswith(".css") and not name.endswith("/"):
-                contents[name] = rewrite_css_font_fallbacks(
-                    decode_text(contents[name]), fallback_families
-                ).encode("utf-8")
-        changed_fonts = 0
-        for name in font_names:
-            new_data = add_private_cmap(contents[name], mapping)
-            if new_data != contents[name]:
-                changed_fonts += 1
-                contents[name] = new_data
-        if changed_fonts == 0:
-            raise RuntimeError("字体 cmap 未能写入私用区映射，无法完成字体加密")
-        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
-        return len(chars), changed_fonts
+    let ext = source
+        .extension()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    if ext != "css" {
+        return Err("仅支持导入 CSS 样式模板".to_string());
+    }
+
+    let css = fs::read_to_string(&source).map_err(|e| format!("读取样式模板失败: {}", e))?;
+    let root = style_templates_root(&app)?;
+    ensure_dir(&root)?;
 
-def decrypt_epub_with_saved_map(input_path, output_path):
-    with zipfile.ZipFile(input_path, "r") as zin:
-        infos = zin.infolist()
-        names = [info.filename for info in infos]
-        if MAP_NAME not in names:
-            raise RuntimeError("未找到字体加密映射文件，无法自动解密")
-        payload = json.loads(zin.read(MAP_NAME).decode("utf-8"))
-        pairs = payload.get("map") or []
-        reverse = {shadow: plain for plain, shadow in pairs}
-        if not reverse:
-            raise RuntimeError("字体加密映射为空")
-        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
-        for info in infos:
-            if info.filename.endswith("/"):
-                contents[info.filename] = b""
-        restored = 0
-        for info in infos:
-            name = info.filename
-            if is_html_name(name):
-                text = decode_text(contents[name])
-                new_text = transform_text(text, reverse)
-                if new_text != text:
-                    restored += 1
-                    contents[name] = new_text.encode("utf-8")
-        if restored == 0:
-            raise RuntimeError("未发现可恢复的字体加密正文")
-        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
-        return {"mode": "saved-map", "files": restored, "chars": len(reverse)}
+    let source_stem = source
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("style-template");
+    let safe_stem = {
+        let candidate = sanitize_filename_part(source_stem);
+        if candidate.is_empty() {
+            "style-template".to_string()
+        } else {
+            candidate
+        }
+    };
 
-def decrypt_epub_with_txt_alignment(input_path, output_path, txt_path):
-    if not txt_path:
-        raise RuntimeError("未找到 TEpub 字体映射文件，请选择与 EPUB 对应的明文 TXT 进行对齐解密")
-    with zipfile.ZipFile(input_path, "r") as zin:
-        infos = zin.infolist()
-        contents = {info.filename: zin.read(info.filename) for info in infos if not info.filename.endswith("/")}
-        for info in infos:
-            if info.filename.endswith("/"):
-                contents[info.filename] = b""
-        html_names = [info.filename for info in infos if is_html_name(info.filename)]
-        if not html_names:
-            raise RuntimeError("EPUB 内未找到 HTML/XHTML 文件")
-        nav_paths, ncx_paths, title_by_target = collect_navigation_data(infos, contents)
-        nav_path_keys = {path.lower() for path in nav_paths}
-        content_html_names = [name for name in html_names if name.lower() not in nav_path_keys]
-        direct_title_pairs = collect_title_pairs(contents, title_by_target)
-        selector_fonts = build_selector_font_rules(infos, contents)
-        cipher_records = []
-        for name in content_html_names:
-            cipher_records.extend(iter_text_records(decode_text(contents[name]), selector_fonts))
-        plain_chars = normalize_plain_chars(decode_txt_file(txt_path))
-        if len(cipher_records) < 50 or len(plain_chars) < 50:
-            raise RuntimeError("EPUB 或 TXT 文本过短，无法可靠对齐")
-        global_table, font_table, stats = build_txt_alignment_tables(cipher_records, plain_chars, direct_title_pairs)
-        if stats["pairs"] < 30 or (not global_table and not font_table):
-            raise RuntimeError(
-                "TXT 与 EPUB 对齐失败，未能生成可靠映射。请确认 TXT 与 EPUB 是同一版本。"
-            )
-        changed = 0
-        for info in infos:
-            name = info.filename
-            if name.endswith("/"):
-                continue
-            lower = name.lower()
-            if is_html_name(name) and name.lower() not in nav_path_keys:
-                old_text = decode_text(contents[name])
-                new_text = transform_text_font_aware(old_text, global_table, font_table, selector_fonts)
-                new_text = neutralize_html_inline_fonts(new_text)
-                if new_text != old_text:
-                    contents[name] = new_text.encode("utf-8")
-                    changed += 1
-            elif lower.endswith(".css"):
-                old_text = decode_text(contents[name])
-                new_text = neutralize_css_fonts(old_text)
-                if new_text != old_text:
-                    contents[name] = new_text.encode("utf-8")
-        if changed == 0:
-            raise RuntimeError("未发现可替换的字体混淆正文")
-        write_entries(output_path, infos, contents, extra_map=None, skip_map=True)
-        stats["mode"] = "txt-alignment-font-aware" if font_table else "txt-alignment-global"
-        stats["files"] = changed
-        stats["skippedNavFiles"] = len(nav_paths) + len(ncx_paths)
-        return stats
+    let mut target = root.join(format!("{}.css", safe_stem));
+    let mut seq = 2usize;
+    while target.exists() {
+        let existing = fs::read_to_string(&target).unwrap_or_default();
+        if existing == css {
+            return build_style_template_info(&target, false);
+        }
+        target = root.join(format!("{}-{}.css", safe_stem, seq));
+        seq += 1;
+    }
 
-def decrypt_epub(input_path, output_path, txt_path=None):
-    with zipfile.ZipFile(input_path, "r") as zin:
-        names = zin.namelist()
-    if MAP_NAME in names:
-        return decrypt_epub_with_saved_map(input_path, output_path)
-    return decrypt_epub_with_txt_alignment(input_path, output_path, txt_path)
+    fs::write(&target, css).map_err(|e| format!("保存样式模板失败: {}", e))?;
+    build_style_template_info(&target, false)
+}
 
-def main():
-    if len(sys.argv) not in (4, 5):
-        raise SystemExit("usage: font_obfuscation.py encrypt|decrypt input.epub output.epub [plain.txt]")
-    mode, input_path, output_path = sys.argv[1:4]
-    txt_path = sys.argv[4] if len(sys.argv) == 5 else None
-    if mode == "encrypt":
-        chars, fonts = encrypt_epub_body_permutation(input_path, output_path)
-        print(json.dumps({"chars": chars, "fonts": fonts}, ensure_ascii=False))
-    elif mode == "decrypt":
-        result = decrypt_epub(input_path, output_path, txt_path)
-        print(json.dumps(result, ensure_ascii=False))
-    else:
-        raise SystemExit("unknown mode: " + mode)
+#[tauri::command]
+fn save_style_template(
+    app: tauri::AppHandle,
+    id: String,
+    main_css: String,
+) -> Result<StyleTemplateInfo, String> {
+    let path = if id == "builtin" {
+        builtin_style_template_path(&app)?
+    } else {
+        style_template_path(&app, &id)?
+    };
+    let root = style_templates_root(&app)?;
+    ensure_dir(&root)?;
+    fs::write(&path, main_css).map_err(|e| format!("保存样式模板失败: {}", e))?;
+    build_style_template_info(&path, id == "builtin")
+}
 
-if __name__ == "__main__":
-    main()
-"##;
+#[tauri::command]
+fn restore_builtin_style_template(app: tauri::AppHandle) -> Result<(), String> {
+    let path = builtin_style_template_path(&app)?;
+    if path.exists() {
+        fs::remove_file(&path).map_err(|e| format!("恢复内置样式失败: {}", e))?;
+    }
+    Ok(())
+}
 
-fn run_toolbox_font_obfuscation(
-    source: &Path,
-    output_path: &Path,
-    mode: &str,
-    txt_path: Option<&Path>,
-) -> Result<String, String> {
-    if mode == "encrypt" {
-        ensure_fonttools_available()?;
+fn is_stale_builtin_style_template_css(css: &str) -> bool {
+    let normalized = css.trim();
+    if normalized.is_empty() {
+        return false;
     }
-    let temp_dir = tempfile::tempdir().map_err(|e| format!("创建字体工具临时目录失败: {}", e))?;
-    let script_path = temp_dir.path().join("tepub_font_obfuscation.py");
-    fs::write(&script_path, TOOLBOX_FONT_OBFUSCATION_SCRIPT)
-        .map_err(|e| format!("写入字体工具脚本失败: {}", e))?;
-    let mut command = hidden_process_command("python");
-    command
-        .arg(&script_path)
-        .arg(mode)
-        .arg(source)
-        .arg(output_path);
-    if let Some(txt_path) = txt_path {
-        command.arg(txt_path);
+
+    normalized.contains("TEpub template schema: 1") && !normalized.contains(".te-volume-subtitle")
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+pub struct RegexRule {
+    pub level: u8,
+    pub pattern: String,
+    // 命中任一排除规则的行不算标题（如以“第三章”开头的对白）
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    #[serde(default)]
+    pub max_title_length: Option<usize>,
+    #[serde(default)]
+    pub require_blank_before: bool,
+    #[serde(default)]
+    pub require_blank_after: bool,
+    // 数值大的规则先匹配，相同优先级按传入顺序
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct ChapterInfo {
+    pub title: String,
+    pub line_number: usize,
+    pub level: u8,
+    pub is_meta: bool,
+    pub word_count: usize,
+    // 命中的规则在传入 rules 中的下标
+    #[serde(default)]
+    pub rule_index: Option<usize>,
+}
+
+struct CompiledChapterRule {
+    index: usize,
+    level: u8,
+    regex: Regex,
+    excludes: Vec<Regex>,
+    max_title_length: Option<usize>,
+    require_blank_before: bool,
+    require_blank_after: bool,
+}
+
+impl CompiledChapterRule {
+    fn matches(&self, line: &str, line_trim: &str, blank_before: bool, blank_after: bool) -> bool {
+        if self.require_blank_before && !blank_before {
+            return false;
+        }
+        if self.require_blank_after && !blank_after {
+            return false;
+        }
+        if let Some(max_len) = self.max_title_length {
+            if line_trim.chars().count() > max_len {
+                return false;
+            }
+        }
+        if !self.regex.is_match(line).unwrap_or(false) {
+            return false;
+        }
+        !self
+            .excludes
+            .iter()
+            .any(|exclude| exclude.is_match(line).unwrap_or(false))
     }
-    let output = command
-        .output()
-        .map_err(|e| format!("无法启动 Python 字体工具: {}", e))?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        return Err(if stderr.is_empty() { stdout } else { stderr });
+}
+
+// 非法的正则（含排除规则）直接跳过，与旧版行为一致。
+fn compile_chapter_rules(rules: Vec<RegexRule>) -> Vec<CompiledChapterRule> {
+    let mut compiled: Vec<(i32, CompiledChapterRule)> = rules
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, rule)| {
+            let regex = Regex::new(&rule.pattern).ok()?;
+            let excludes = rule
+                .exclude_patterns
+                .iter()
+                .filter(|p| !p.trim().is_empty())
+                .filter_map(|p| Regex::new(p).ok())
+                .collect();
+            Some((
+                rule.priority,
+                CompiledChapterRule {
+                    index,
+                    level: rule.level,
+                    regex,
+                    excludes,
+                    max_title_length: rule.max_title_length.filter(|len| *len > 0),
+                    require_blank_before: rule.require_blank_before,
+                    require_blank_after: rule.require_blank_after,
+                },
+            ))
+        })
+        .collect();
+    compiled.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+    compiled.into_iter().map(|(_, rule)| rule).collect()
+}
+
+// 逐行匹配章节规则，每确定一个章节（字数统计完成）就交给 on_chapter。
+// scan_chapters 收集成列表，大文件缓冲则分批通过事件推送。
+fn scan_chapter_lines<'a, I, F>(lines: I, rules: Vec<RegexRule>, mut on_chapter: F)
+where
+    I: Iterator<Item = &'a str>,
+    F: FnMut(ChapterInfo),
+{
+    // Compile regex rules safely
+    let compiled_rules = compile_chapter_rules(rules);
+
+    let mut heading_count = 0usize;
+    let mut current_chapter: Option<ChapterInfo> = None;
+    // 文件开头视为空行，文件末尾同理
+    let mut prev_is_empty = true;
+    let mut lines = lines.enumerate().peekable();
+    while let Some((index, line)) = lines.next() {
+        let line_trim = line.trim();
+        let char_count = line_trim.chars().count();
+        let is_empty = line_trim.is_empty();
+
+        let mut matched_rule = None;
+        if !is_empty {
+            let next_is_empty = lines.peek().is_none_or(|(_, next)| next.trim().is_empty());
+            matched_rule = compiled_rules
+                .iter()
+                .find(|rule| rule.matches(line, line_trim, prev_is_empty, next_is_empty));
+        }
+        prev_is_empty = is_empty;
+
+        if let Some(rule) = matched_rule {
+            let lvl = rule.level;
+            if let Some(prev) = current_chapter.take() {
+                on_chapter(prev);
+            }
+            // Auto detect meta to prevent folding chapters into introductions
+            // But ensure Volumes (Level 1 containing 卷/部) are NOT treated as meta
+            // And only auto-detect meta for Level 1 items (Chapters at Level 3 should not be meta)
+            let is_vol_keyword = line_trim.contains("卷") || line_trim.contains("部");
+            let is_first_heading = heading_count == 0;
+            let is_meta = !is_vol_keyword
+                && mobile_is_meta_title(line_trim)
+                && (lvl == 1 || is_first_heading);
+            heading_count += 1;
+
+            current_chapter = Some(ChapterInfo {
+                title: line_trim.to_string(),
+                line_number: index + 1,
+                level: lvl,
+                is_meta,
+                word_count: 0,
+                rule_index: Some(rule.index),
+            });
+        } else if let Some(ref mut chapter) = current_chapter {
+            if !is_empty {
+                chapter.word_count += char_count;
+            }
+        }
+    }
+    if let Some(last) = current_chapter {
+        on_chapter(last);
     }
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
-fn toolbox_font_encrypt_impl(source: &Path) -> Result<ToolboxEpubToolResult, String> {
-    if !source.exists() {
-        return Err(format!("文件不存在: {}", source.to_string_lossy()));
-    }
-    let output_path = build_processed_epub_path(source, "_font_encrypt");
-    let details = run_toolbox_font_obfuscation(source, &output_path, "encrypt", None)?;
-    Ok(toolbox_epub_tool_result(
-        source,
-        &output_path,
-        true,
-        "font_encrypt",
-        if details.is_empty() {
-            "字体加密完成".to_string()
-        } else {
-            format!("字体加密完成: {}", details)
-        },
-    ))
+#[tauri::command]
+async fn scan_chapters(content: String, rules: Vec<RegexRule>) -> Vec<ChapterInfo> {
+    // Normalize line endings to ensure consistency with CodeMirror's line counting
+    // CodeMirror treats \r, \n, and \r\n all as line separators
+    // Rust's .lines() only recognizes \n and \r\n
+    let content = normalize_line_endings(content);
+
+    let mut chapters = Vec::new();
+    scan_chapter_lines(content.lines(), rules, |chapter| chapters.push(chapter));
+    chapters
 }
 
-fn toolbox_font_decrypt_impl(
-    source: &Path,
-    txt_path: Option<&Path>,
-) -> Result<ToolboxEpubToolResult, String> {
-    if !source.exists() {
-        return Err(format!("文件不存在: {}", source.to_string_lossy()));
+#[cfg(test)]
+mod chapter_rule_tests {
+    use super::*;
+
+    fn scan(lines: &[&str], rules: Vec<RegexRule>) -> Vec<ChapterInfo> {
+        let mut chapters = Vec::new();
+        scan_chapter_lines(lines.iter().copied(), rules, |chapter| {
+            chapters.push(chapter)
+        });
+        chapters
     }
-    if let Some(txt_path) = txt_path {
-        if !txt_path.exists() {
-            return Err(format!("TXT 文件不存在: {}", txt_path.to_string_lossy()));
-        }
+
+    #[test]
+    fn exclusions_and_max_length_skip_dialogue_lines() {
+        let lines = [
+            "第三章 风起",
+            "正文",
+            "第三章写得真好，他笑着说道。",
+            "第四章 云涌",
+        ];
+        let chapters = scan(
+            &lines,
+            vec![RegexRule {
+                level: 3,
+                pattern: r"^\s*第[一二三四五六七八九十]+章".to_string(),
+                exclude_patterns: vec!["[，。！？]".to_string()],
+                max_title_length: Some(20),
+                ..Default::default()
+            }],
+        );
+        let titles: Vec<&str> = chapters.iter().map(|c| c.title.as_str()).collect();
+        assert_eq!(titles, vec!["第三章 风起", "第四章 云涌"]);
+        assert_eq!(
+            chapters[0].word_count,
+            "正文第三章写得真好，他笑着说道。".chars().count()
+        );
     }
-    let output_path = build_processed_epub_path(source, "_font_decrypt");
-    let details = run_toolbox_font_obfuscation(source, &output_path, "decrypt", txt_path)?;
-    Ok(toolbox_epub_tool_result(
-        source,
-        &output_path,
-        true,
-        "font_decrypt",
-        if details.is_empty() {
-            "字体解密完成".to_string()
-        } else {
-            format!("字体解密完成: {}", details)
-        },
-    ))
-}
 
-#[tauri::command]
-fn toolbox_file_encrypt(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
-    toolbox_file_encrypt_impl(&PathBuf::from(epub_path))
-}
+    #[test]
+    fn blank_line_guards_are_honoured() {
+        let lines = ["前文", "第一章 甲", "", "第二章 乙", "", "正文"];
+        let chapters = scan(
+            &lines,
+            vec![RegexRule {
+                level: 3,
+                pattern: "^第.章".to_string(),
+                require_blank_before: true,
+                require_blank_after: true,
+                ..Default::default()
+            }],
+        );
+        assert_eq!(chapters.len(), 1);
+        assert_eq!(chapters[0].title, "第二章 乙");
+    }
 
-#[tauri::command]
-fn toolbox_file_decrypt(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
-    toolbox_file_decrypt_impl(&PathBuf::from(epub_path))
+    #[test]
+    fn higher_priority_rule_wins_and_is_recorded() {
+        let lines = ["第一卷 风起"];
+        let chapters = scan(
+            &lines,
+            vec![
+                RegexRule {
+                    level: 3,
+                    pattern: "^第".to_string(),
+                    ..Default::default()
+                },
+                RegexRule {
+                    level: 1,
+                    pattern: "^第.卷".to_string(),
+                    priority: 10,
+                    ..Default::default()
+                },
+            ],
+        );
+        assert_eq!(chapters[0].level, 1);
+        assert_eq!(chapters[0].rule_index, Some(1));
+    }
 }
 
-#[tauri::command]
-fn toolbox_font_encrypt(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
-    toolbox_font_encrypt_impl(&PathBuf::from(epub_path))
+// --- 书籍统计 ---
+// 字数口径与 ChapterInfo.word_count 相同（非空白字符），章节标题行不计入正文。
+// 对白按成对引号内的字符计，引号在段末未闭合时截止到段末。
+
+const STATS_DEFAULT_CJK_PER_MINUTE: f64 = 400.0;
+const STATS_DEFAULT_WORDS_PER_MINUTE: f64 = 200.0;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+struct StatisticsOptions {
+    // 阅读速度，缺省按每分钟 400 字 / 200 个英文单词估算
+    cjk_chars_per_minute: Option<f64>,
+    latin_words_per_minute: Option<f64>,
 }
 
-#[tauri::command]
-fn toolbox_font_decrypt(
-    epub_path: String,
-    txt_path: Option<String>,
-) -> Result<ToolboxEpubToolResult, String> {
-    let txt_path_buf = txt_path.map(PathBuf::from);
-    toolbox_font_decrypt_impl(&PathBuf::from(epub_path), txt_path_buf.as_deref())
+#[derive(Serialize, Default, Clone, Debug, PartialEq)]
+struct TextStatistics {
+    chars: usize,
+    cjk_chars: usize,
+    latin_words: usize,
+    punctuation: usize,
+    paragraphs: usize,
+    dialogue_chars: usize,
+    dialogue_ratio: f64,
+    reading_minutes: f64,
 }
 
-#[tauri::command]
-fn toolbox_epub_reformat(epub_path: String) -> Result<ToolboxEpubToolResult, String> {
-    toolbox_epub_reformat_impl(&PathBuf::from(epub_path))
+#[derive(Serialize)]
+struct ChapterStatistics {
+    // TXT 给出标题行号，EPUB 给出文件路径；TXT 开头标题之前的内容 title 为空
+    title: Option<String>,
+    line_number: Option<usize>,
+    path: Option<String>,
+    is_meta: bool,
+    #[serde(flatten)]
+    stats: TextStatistics,
 }
 
-#[tauri::command]
-fn toolbox_image_convert(
-    epub_path: String,
-    image_format: Option<String>,
-) -> Result<ToolboxEpubToolResult, String> {
-    toolbox_image_convert_impl(&PathBuf::from(epub_path), image_format.as_deref())
+#[derive(Serialize)]
+struct BookStatistics {
+    total: TextStatistics,
+    // 以下只统计非 meta 且有正文的章节，longest/shortest 为 chapters 中的下标
+    ranked_chapters: usize,
+    average_chapter_chars: usize,
+    longest: Option<usize>,
+    shortest: Option<usize>,
+    chapters: Vec<ChapterStatistics>,
+}
+
+fn is_text_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || matches!(c as u32,
+            0x00A1 | 0x00A7 | 0x00AB | 0x00B6 | 0x00B7 | 0x00BB | 0x00BF
+            | 0x2010..=0x2027 | 0x2030..=0x205E | 0x3001..=0x3003 | 0x3008..=0x3011
+            | 0x3014..=0x301F | 0x30FB | 0xFE10..=0xFE19 | 0xFE30..=0xFE6B
+            | 0xFF01..=0xFF0F | 0xFF1A..=0xFF20 | 0xFF3B..=0xFF40 | 0xFF5B..=0xFF65)
+}
+
+fn dialogue_close_quote(c: char) -> Option<char> {
+    match c {
+        '“' => Some('”'),
+        '「' => Some('」'),
+        '『' => Some('』'),
+        '"' => Some('"'),
+        _ => None,
+    }
 }
 
-const AI_IMAGE_MAX_INPUT_BYTES: usize = 16 * 1024 * 1024;
-const AI_IMAGE_MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
-const AI_IMAGE_USER_AGENT: &str = "TEpub-Editor/image-tools";
+impl TextStatistics {
+    fn add_paragraph(&mut self, paragraph: &str) {
+        let chars: Vec<char> = paragraph.chars().collect();
+        let visible = chars.iter().filter(|c| !c.is_whitespace()).count();
+        if visible == 0 {
+            return;
+        }
+        self.paragraphs += 1;
+        self.chars += visible;
+        // 引号栈：“他说「好」”按最外层计
+        let mut quotes: Vec<char> = Vec::new();
+        let mut in_word = false;
+        for (i, &c) in chars.iter().enumerate() {
+            if quotes.last() == Some(&c) {
+                quotes.pop();
+            } else if let Some(close) = dialogue_close_quote(c) {
+                quotes.push(close);
+            } else if !quotes.is_empty() && !c.is_whitespace() {
+                self.dialogue_chars += 1;
+            }
+            // don't / rock-n-roll 之类词内的撇号、连字符算作单词的一部分
+            let joins_word = in_word
+                && matches!(c, '\'' | '’' | '-')
+                && chars
+                    .get(i + 1)
+                    .is_some_and(|n| n.is_alphanumeric() && !is_cjk_char(*n));
+            if is_cjk_char(c) {
+                self.cjk_chars += 1;
+            } else if is_text_punctuation(c) && !joins_word {
+                self.punctuation += 1;
+            }
+            let word_char = c.is_alphanumeric() && !is_cjk_char(c);
+            if word_char && !in_word {
+                self.latin_words += 1;
+            }
+            in_word = word_char || joins_word;
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct ToolboxAiImageRequest {
-    base_url: String,
-    api_key: String,
-    model: String,
-    target: String,
-    title: Option<String>,
-    author: Option<String>,
-    prompt: String,
-    size: String,
-    reference_data: Option<Vec<u8>>,
+    fn merge(&mut self, other: &TextStatistics) {
+        self.chars += other.chars;
+        self.cjk_chars += other.cjk_chars;
+        self.latin_words += other.latin_words;
+        self.punctuation += other.punctuation;
+        self.paragraphs += other.paragraphs;
+        self.dialogue_chars += other.dialogue_chars;
+    }
+
+    fn finish(&mut self, options: &StatisticsOptions) {
+        let per_minute = |value: Option<f64>, default: f64| {
+            value
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .unwrap_or(default)
+        };
+        self.dialogue_ratio = if self.chars == 0 {
+            0.0
+        } else {
+            self.dialogue_chars as f64 / self.chars as f64
+        };
+        let minutes = self.cjk_chars as f64
+            / per_minute(options.cjk_chars_per_minute, STATS_DEFAULT_CJK_PER_MINUTE)
+            + self.latin_words as f64
+                / per_minute(
+                    options.latin_words_per_minute,
+                    STATS_DEFAULT_WORDS_PER_MINUTE,
+                );
+        self.reading_minutes = (minutes * 10.0).round() / 10.0;
+    }
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct ToolboxAiImageResult {
-    bytes: Vec<u8>,
-    mime: String,
-    extension: String,
-    file_name: String,
-    prompt: String,
-    size: String,
+fn book_statistics(
+    mut chapters: Vec<ChapterStatistics>,
+    options: &StatisticsOptions,
+) -> BookStatistics {
+    let mut total = TextStatistics::default();
+    for chapter in &mut chapters {
+        total.merge(&chapter.stats);
+        chapter.stats.finish(options);
+    }
+    total.finish(options);
+    let ranked: Vec<(usize, usize)> = chapters
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| !c.is_meta && c.stats.chars > 0)
+        .map(|(i, c)| (i, c.stats.chars))
+        .collect();
+    // 长度相同时取靠前的章节
+    let longest = ranked
+        .iter()
+        .rev()
+        .max_by_key(|(_, chars)| *chars)
+        .map(|(i, _)| *i);
+    let shortest = ranked
+        .iter()
+        .min_by_key(|(_, chars)| *chars)
+        .map(|(i, _)| *i);
+    let ranked_total: usize = ranked.iter().map(|(_, chars)| chars).sum();
+    BookStatistics {
+        total,
+        ranked_chapters: ranked.len(),
+        average_chapter_chars: ranked_total.checked_div(ranked.len()).unwrap_or(0),
+        longest,
+        shortest,
+        chapters,
+    }
 }
 
-fn normalize_ai_base_url(value: &str) -> String {
-    value.trim().trim_end_matches('/').to_string()
+fn text_line_statistics(
+    lines: &[&str],
+    rules: Vec<RegexRule>,
+    options: &StatisticsOptions,
+) -> BookStatistics {
+    let mut headings = Vec::new();
+    scan_chapter_lines(lines.iter().copied(), rules, |chapter| {
+        headings.push(chapter)
+    });
+    let mut chapters = Vec::with_capacity(headings.len() + 1);
+    let first_heading = headings.first().map_or(lines.len(), |h| h.line_number - 1);
+    let mut preface = TextStatistics::default();
+    for line in &lines[..first_heading] {
+        preface.add_paragraph(line);
+    }
+    if preface.chars > 0 {
+        chapters.push(ChapterStatistics {
+            title: None,
+            line_number: None,
+            path: None,
+            is_meta: true,
+            stats: preface,
+        });
+    }
+    for (i, heading) in headings.iter().enumerate() {
+        let end = headings
+            .get(i + 1)
+            .map_or(lines.len(), |next| next.line_number - 1);
+        let mut stats = TextStatistics::default();
+        for line in &lines[heading.line_number..end] {
+            stats.add_paragraph(line);
+        }
+        chapters.push(ChapterStatistics {
+            title: Some(heading.title.clone()),
+            line_number: Some(heading.line_number),
+            path: None,
+            is_meta: heading.is_meta,
+            stats,
+        });
+    }
+    book_statistics(chapters, options)
 }
 
-fn remove_suffix_ignore_case(value: &str, suffix: &str) -> String {
-    if value
-        .to_ascii_lowercase()
-        .ends_with(&suffix.to_ascii_lowercase())
-    {
-        value[..value.len() - suffix.len()].to_string()
-    } else {
-        value.to_string()
+static HTML_STATS_SKIP_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)<(head|script|style|h[1-6])\b[^>]*>.*?</\1\s*>")
+        .expect("valid html stats skip regex")
+});
+static HTML_BLOCK_BREAK_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)<br\b[^>]*>|</(p|div|li|blockquote|dd|dt|tr|section|aside)\s*>")
+        .expect("valid html block break regex")
+});
+
+// 标题取第一个 h1-h6，没有时取 <title>；段落按块级元素切分。
+fn xhtml_chapter_statistics(path: &str, content: &str) -> ChapterStatistics {
+    let title = extract_html_heading_title(content);
+    let body = HTML_STATS_SKIP_RE.replace_all(content, "");
+    let body = HTML_BLOCK_BREAK_RE.replace_all(&body, "\n");
+    let text = decode_basic_html_entities(&strip_html_tags(&body));
+    let mut stats = TextStatistics::default();
+    for paragraph in text.lines() {
+        stats.add_paragraph(paragraph);
+    }
+    ChapterStatistics {
+        title,
+        line_number: None,
+        path: Some(path.to_string()),
+        is_meta: false,
+        stats,
     }
 }
 
-fn is_versioned_ai_base_url(base_url: &str) -> bool {
-    let lower = base_url.to_ascii_lowercase();
-    let Some((prefix, tail)) = lower.rsplit_once("/api/v") else {
-        return false;
-    };
-    !prefix.is_empty() && !tail.is_empty() && tail.chars().all(|c| c.is_ascii_digit())
+#[tauri::command]
+async fn text_statistics(
+    content: String,
+    rules: Vec<RegexRule>,
+    options: Option<StatisticsOptions>,
+) -> Result<BookStatistics, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = normalize_line_endings(content);
+        let lines: Vec<&str> = content.lines().collect();
+        text_line_statistics(&lines, rules, &options.unwrap_or_default())
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
 }
 
-fn ai_image_generation_url(base_url: &str) -> Result<String, String> {
-    let base = normalize_ai_base_url(base_url);
-    if base.is_empty() {
-        return Err("Base URL 不能为空".to_string());
-    }
-    let lower = base.to_ascii_lowercase();
-    Ok(if lower.ends_with("/images/generations") {
-        base
-    } else if lower.ends_with("/chat/completions") {
-        remove_suffix_ignore_case(&base, "/chat/completions") + "/images/generations"
-    } else if lower.ends_with("/v1") || is_versioned_ai_base_url(&base) {
-        format!("{}/images/generations", base)
-    } else {
-        format!("{}/v1/images/generations", base)
+#[tauri::command]
+async fn text_buffer_statistics(
+    buffer_id: String,
+    rules: Vec<RegexRule>,
+    options: Option<StatisticsOptions>,
+) -> Result<BookStatistics, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        text_line_statistics(&lines, rules, &options.unwrap_or_default())
     })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
 }
 
-fn ai_image_edits_url(base_url: &str) -> Result<String, String> {
-    let base = normalize_ai_base_url(base_url);
-    if base.is_empty() {
-        return Err("Base URL 不能为空".to_string());
-    }
-    let lower = base.to_ascii_lowercase();
-    Ok(if lower.ends_with("/images/edits") {
-        base
-    } else if lower.ends_with("/images/generations") {
-        remove_suffix_ignore_case(&base, "/images/generations") + "/images/edits"
-    } else if lower.ends_with("/chat/completions") {
-        remove_suffix_ignore_case(&base, "/chat/completions") + "/images/edits"
-    } else if lower.ends_with("/v1") || is_versioned_ai_base_url(&base) {
-        format!("{}/images/edits", base)
-    } else {
-        format!("{}/v1/images/edits", base)
+// files 为按书脊顺序排列的正文文件，与 search_in_files_detailed 一样由前端给出。
+#[tauri::command]
+async fn epub_statistics(
+    epub_path: String,
+    files: Vec<String>,
+    options: Option<StatisticsOptions>,
+) -> Result<BookStatistics, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let temp_path = cached_epub_temp_path(&epub_path)?;
+        let chapters = files
+            .iter()
+            .filter_map(|path| {
+                let content = fs::read_to_string(temp_path.join(path)).ok()?;
+                Some(xhtml_chapter_statistics(path, &content))
+            })
+            .collect();
+        Ok(book_statistics(chapters, &options.unwrap_or_default()))
     })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
-fn ai_image_mime_from_bytes(bytes: &[u8]) -> (&'static str, &'static str) {
-    match detect_image_ext(bytes) {
-        "png" => ("png", "image/png"),
-        "webp" => ("webp", "image/webp"),
-        "gif" => ("gif", "image/gif"),
-        "jpg" | "jpeg" => ("jpg", "image/jpeg"),
-        _ => ("jpg", "image/jpeg"),
+#[cfg(test)]
+mod statistics_tests {
+    use super::*;
+
+    fn rules() -> Vec<RegexRule> {
+        vec![RegexRule {
+            level: 3,
+            pattern: r"^第.+章".to_string(),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn counts_cjk_words_punctuation_and_dialogue() {
+        let mut stats = TextStatistics::default();
+        stats.add_paragraph("他说：“我不知道 don't know。”");
+        stats.add_paragraph("　　");
+        stats.add_paragraph("「走吧」，Alice 答道。");
+        stats.finish(&StatisticsOptions::default());
+        assert_eq!(stats.paragraphs, 2);
+        assert_eq!(stats.cjk_chars, 10);
+        assert_eq!(stats.latin_words, 3);
+        // ：“。” 「」，。，don't 的撇号不算
+        assert_eq!(stats.punctuation, 8);
+        // 我不知道don'tknow。 + 走吧
+        assert_eq!(stats.dialogue_chars, 16);
+        assert_eq!(stats.chars, 32);
+    }
+
+    #[test]
+    fn txt_report_splits_chapters_and_ranks_outliers() {
+        let text = "序言一句。\n第一章 起\n甲乙丙丁\n\n戊己\n第二章 承\n短\n第三章 转\n一二三四五六七八九十";
+        let lines: Vec<&str> = text.lines().collect();
+        let report = text_line_statistics(&lines, rules(), &StatisticsOptions::default());
+        let summary: Vec<(Option<&str>, usize, usize)> = report
+            .chapters
+            .iter()
+            .map(|c| (c.title.as_deref(), c.stats.chars, c.stats.paragraphs))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (None, 5, 1),
+                (Some("第一章 起"), 6, 2),
+                (Some("第二章 承"), 1, 1),
+                (Some("第三章 转"), 10, 1),
+            ]
+        );
+        assert_eq!(report.total.chars, 22);
+        assert_eq!(report.ranked_chapters, 3);
+        assert_eq!(report.longest, Some(3));
+        assert_eq!(report.shortest, Some(2));
+        assert_eq!(report.average_chapter_chars, 5);
+    }
+
+    #[test]
+    fn reading_time_uses_configured_speed() {
+        let lines = ["第一章 起", &"字".repeat(600), "one two three four"];
+        let options = StatisticsOptions {
+            cjk_chars_per_minute: Some(300.0),
+            latin_words_per_minute: Some(40.0),
+        };
+        let report = text_line_statistics(&lines, rules(), &options);
+        assert_eq!(report.total.reading_minutes, 2.1);
+        assert_eq!(report.chapters[0].stats.reading_minutes, 2.1);
     }
-}
 
-fn validate_ai_image_bytes(bytes: &[u8], label: &str) -> Result<(), String> {
-    if bytes.is_empty() {
-        return Err(format!("{}为空", label));
+    #[test]
+    fn xhtml_statistics_skip_head_and_headings() {
+        let content = r#"<html><head><title>卷首</title><style>p{}</style></head>
+<body><h2>第一章</h2><p>“你好。”</p><p>他点头<br/>离开。</p><div><img src="a.png"/></div></body></html>"#;
+        let chapter = xhtml_chapter_statistics("Text/c1.xhtml", content);
+        assert_eq!(chapter.title.as_deref(), Some("第一章"));
+        assert_eq!(chapter.stats.paragraphs, 3);
+        assert_eq!(chapter.stats.cjk_chars, 7);
+        assert_eq!(chapter.stats.dialogue_chars, 3);
     }
-    if bytes.len() > AI_IMAGE_MAX_RESPONSE_BYTES {
-        return Err(format!("{}过大", label));
+}
+
+// --- 拼音与异体字搜索 ---
+// 拼音表只收不带声调的音节；简繁与全角字母数字按单字折叠，折叠前后字符位置一一对应。
+
+const PINYIN_DICT: &str = include_str!("../dict/pinyin.txt");
+
+static PINYIN_TABLE: Lazy<HashMap<char, Vec<&'static str>>> = Lazy::new(|| {
+    let mut table: HashMap<char, Vec<&'static str>> = HashMap::new();
+    for line in PINYIN_DICT.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some((syllable, chars)) = line.split_once('\t') else {
+            continue;
+        };
+        for c in chars.chars() {
+            table.entry(c).or_default().push(syllable);
+        }
     }
-    image::load_from_memory(bytes)
-        .map(|_| ())
-        .map_err(|_| format!("无法识别{}的图片内容", label))
+    table
+});
+
+static SEARCH_VARIANT_FOLD: Lazy<HashMap<char, char>> = Lazy::new(|| build_t2s_table().chars);
+
+#[derive(Deserialize, Default, Clone, Copy)]
+struct FuzzySearchOptions {
+    // 按拼音全拼或首字母匹配汉字，如 zjj 匹配“张家界”
+    #[serde(default)]
+    pinyin: bool,
+    // 简繁、全角半角字母数字视为相同
+    #[serde(default)]
+    variant_insensitive: bool,
 }
 
-fn decode_ai_base64_image(value: &str) -> Option<Vec<u8>> {
-    let trimmed = value.trim();
-    let encoded = if trimmed.to_ascii_lowercase().starts_with("data:image/") {
-        trimmed.split_once(',')?.1.trim()
-    } else {
-        trimmed
+fn fold_variant_char(c: char) -> char {
+    let c = match c {
+        '０'..='９' | 'Ａ'..='Ｚ' | 'ａ'..='ｚ' => {
+            char::from_u32(c as u32 - 0xFEE0).unwrap_or(c)
+        }
+        _ => c,
     };
-    if encoded.len() < 80 || encoded.contains(char::is_whitespace) {
-        return None;
-    }
-    let bytes = general_purpose::STANDARD.decode(encoded).ok()?;
-    validate_ai_image_bytes(&bytes, "返回图片").ok()?;
-    Some(bytes)
+    SEARCH_VARIANT_FOLD.get(&c).copied().unwrap_or(c)
 }
 
-fn decode_ai_data_url(value: &str) -> Option<Vec<u8>> {
-    if !value.trim().to_ascii_lowercase().starts_with("data:image/") {
-        return None;
+fn fold_variant_text(text: &str) -> Cow<'_, str> {
+    if text.is_ascii() {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(text.chars().map(fold_variant_char).collect())
     }
-    decode_ai_base64_image(value)
 }
 
-fn is_remote_ai_url(value: &str) -> bool {
-    let lower = value.trim().to_ascii_lowercase();
-    lower.starts_with("https://") || lower.starts_with("http://")
+// 查询中的字母按拼音匹配汉字，其余字符按原样匹配；空格与隔音符号忽略。
+fn pinyin_query_chars(pattern: &str) -> Vec<char> {
+    pattern
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '\'')
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
 }
 
-fn ai_json_error_preview(text: &str) -> String {
-    serde_json::from_str::<serde_json::Value>(text)
-        .ok()
-        .and_then(|value| {
-            value
-                .get("error")
-                .and_then(|error| error.get("message").or(Some(error)))
-                .and_then(|message| message.as_str().map(|s| s.to_string()))
-        })
-        .unwrap_or_else(|| text.chars().take(240).collect())
+struct PinyinMatcher<'a> {
+    query: &'a [char],
+    text: &'a [char],
+    // 失败状态与起点无关，整行共享
+    failed: HashSet<(usize, usize)>,
 }
 
-fn image_size_candidates(requested: &str, target: &str) -> Vec<String> {
-    let fallback = match target {
-        "banner" => "1536x768",
-        "standard" => "1200x1600",
-        _ => "1024x1792",
-    };
-    let secondary_fallback = match target {
-        "banner" => "1280x640",
-        "standard" => "1024x1365",
-        _ => "1400x2400",
-    };
-    let mut values = vec![
-        requested.trim().to_string(),
-        fallback.to_string(),
-        secondary_fallback.to_string(),
-        String::new(),
-    ];
-    values.dedup();
-    values
+impl PinyinMatcher<'_> {
+    fn match_from(&mut self, t: usize, q: usize) -> Option<usize> {
+        if q == self.query.len() {
+            return Some(t);
+        }
+        if t == self.text.len() || self.failed.contains(&(t, q)) {
+            return None;
+        }
+        let c = self.text[t];
+        if self.query[q].is_ascii_lowercase() {
+            for syllable in PINYIN_TABLE.get(&c).into_iter().flatten() {
+                let common = syllable
+                    .chars()
+                    .zip(&self.query[q..])
+                    .take_while(|(a, b)| a == *b)
+                    .count();
+                // 长前缀优先：zhangjiajie 先按全拼试，再退到首字母
+                for used in (1..=common).rev() {
+                    if let Some(end) = self.match_from(t + 1, q + used) {
+                        return Some(end);
+                    }
+                }
+            }
+        } else if self.query[q] == c {
+            return self.match_from(t + 1, q + 1);
+        }
+        self.failed.insert((t, q));
+        None
+    }
 }
 
-fn render_ai_image_prompt(template: &str, title: Option<&str>, author: Option<&str>) -> String {
-    let title = title.unwrap_or("").trim();
-    let title = if title.is_empty() {
-        "当前书籍"
-    } else {
-        title
+// 返回行内不重叠匹配的字符区间。
+fn pinyin_match_ranges(line: &str, query: &[char]) -> Vec<(usize, usize)> {
+    let text: Vec<char> = line.chars().collect();
+    let mut matcher = PinyinMatcher {
+        query,
+        text: &text,
+        failed: HashSet::new(),
     };
-    let raw_author = author.unwrap_or("").trim();
-    let mut prompt = template.trim().to_string();
-    if raw_author.is_empty() {
-        for marker in [
-            "作者{author}的",
-            "作者{{author}}的",
-            "作者 {author} 的",
-            "作者 {{author}} 的",
-        ] {
-            prompt = prompt.replace(marker, "");
+    let mut ranges = Vec::new();
+    let mut start = 0usize;
+    while start < text.len() {
+        match matcher.match_from(start, 0) {
+            Some(end) if end > start => {
+                ranges.push((start, end));
+                start = end;
+            }
+            _ => start += 1,
         }
     }
-    let author = if raw_author.is_empty() {
-        ""
+    ranges
+}
+
+fn search_text_lines<'a, I>(
+    lines: I,
+    pattern: &str,
+    is_regex: bool,
+    options: &FuzzySearchOptions,
+) -> SearchResult
+where
+    I: Iterator<Item = &'a str>,
+{
+    if pattern.is_empty() {
+        return SearchResult {
+            found: false,
+            count: 0,
+            matches: vec![],
+        };
+    }
+    let pattern = if options.variant_insensitive {
+        fold_variant_text(pattern)
     } else {
-        raw_author
+        Cow::Borrowed(pattern)
     };
-    let replaced = prompt
-        .replace("{title}", title)
-        .replace("{{title}}", title)
-        .replace("{{text}}", title)
-        .replace("{author}", author)
-        .replace("{{author}}", author);
-    if prompt.contains("{title}")
-        || prompt.contains("{{title}}")
-        || prompt.contains("{{text}}")
-        || prompt.contains("{author}")
-        || prompt.contains("{{author}}")
-    {
-        replaced
-    } else {
-        let book_info = if author.is_empty() {
-            title.to_string()
+    let lines = lines.map(|line| {
+        if options.variant_insensitive {
+            fold_variant_text(line)
         } else {
-            format!("{} / {}", title, author)
-        };
-        format!("{}\n\n书籍信息：{}", replaced, book_info)
-    }
-}
-
-fn build_ai_image_request_bodies(
-    model: &str,
-    prompt: &str,
-    size: &str,
-    reference_data_url: Option<&str>,
-) -> Vec<serde_json::Value> {
-    fn base_body(
-        model: &str,
-        prompt: &str,
-        size: &str,
-    ) -> serde_json::Map<String, serde_json::Value> {
-        let mut map = serde_json::Map::new();
-        map.insert(
-            "model".to_string(),
-            serde_json::Value::String(model.to_string()),
-        );
-        map.insert(
-            "prompt".to_string(),
-            serde_json::Value::String(prompt.to_string()),
-        );
-        map.insert("n".to_string(), serde_json::Value::Number(1.into()));
-        if !size.trim().is_empty() {
-            map.insert(
-                "size".to_string(),
-                serde_json::Value::String(size.trim().to_string()),
-            );
+            Cow::Borrowed(line)
         }
-        map
-    }
-
-    let mut bodies = Vec::new();
-    if let Some(reference) = reference_data_url.filter(|value| !value.trim().is_empty()) {
-        for variant in [
-            "image_array",
-            "image_string",
-            "image_urls_array",
-            "image_array_no_format",
-            "image_string_no_format",
-            "image_urls_array_no_format",
-        ] {
-            let mut map = base_body(model, prompt, size);
-            match variant {
-                "image_array" | "image_array_no_format" => {
-                    map.insert("image".to_string(), serde_json::json!([reference]));
-                }
-                "image_string" | "image_string_no_format" => {
-                    map.insert(
-                        "image".to_string(),
-                        serde_json::Value::String(reference.to_string()),
-                    );
+    });
+    let mut matches_vec = Vec::new();
+    if options.pinyin {
+        let query = pinyin_query_chars(&pattern);
+        if !query.is_empty() {
+            for (i, line) in lines.enumerate() {
+                for (start_char, end_char) in pinyin_match_ranges(&line, &query) {
+                    matches_vec.push(MatchLocation {
+                        line: i + 1,
+                        start_char,
+                        end_char,
+                    });
                 }
-                _ => {
-                    map.insert("image_urls".to_string(), serde_json::json!([reference]));
+            }
+        }
+    } else if is_regex {
+        if let Ok(re) = Regex::new(&pattern) {
+            for (i, line) in lines.enumerate() {
+                for match_obj in re.find_iter(&line).flatten() {
+                    matches_vec.push(MatchLocation {
+                        line: i + 1,
+                        start_char: line[..match_obj.start()].chars().count(),
+                        end_char: line[..match_obj.start()].chars().count()
+                            + line[match_obj.start()..match_obj.end()].chars().count(),
+                    });
                 }
             }
-            if !variant.ends_with("_no_format") {
-                map.insert(
-                    "response_format".to_string(),
-                    serde_json::Value::String("b64_json".to_string()),
-                );
+        }
+    } else {
+        for (i, line) in lines.enumerate() {
+            for (byte_idx, part) in line.match_indices(pattern.as_ref()) {
+                matches_vec.push(MatchLocation {
+                    line: i + 1,
+                    start_char: line[..byte_idx].chars().count(),
+                    end_char: line[..byte_idx].chars().count() + part.chars().count(),
+                });
             }
-            bodies.push(serde_json::Value::Object(map));
         }
     }
+    let count = matches_vec.len();
+    SearchResult {
+        found: count > 0,
+        count,
+        matches: matches_vec,
+    }
+}
+
+#[tauri::command]
+async fn advanced_search(
+    content: String,
+    pattern: String,
+    is_regex: bool,
+    options: Option<FuzzySearchOptions>,
+) -> SearchResult {
+    search_text_lines(
+        content.lines(),
+        &pattern,
+        is_regex,
+        &options.unwrap_or_default(),
+    )
+}
+
+#[tauri::command]
+async fn advanced_replace(
+    content: String,
+    pattern: String,
+    replacement: String,
+    is_regex: bool,
+) -> Result<String, String> {
+    if is_regex {
+        let re = Regex::new(&pattern).map_err(|e| format!("Regex Error: {}", e))?;
+        Ok(re.replace_all(&content, &replacement).to_string())
+    } else {
+        Ok(content.replace(&pattern, &replacement))
+    }
+}
+
+#[cfg(test)]
+mod fuzzy_search_tests {
+    use super::*;
+
+    fn ranges(
+        text: &str,
+        pattern: &str,
+        options: FuzzySearchOptions,
+    ) -> Vec<(usize, usize, usize)> {
+        search_text_lines(text.lines(), pattern, false, &options)
+            .matches
+            .iter()
+            .map(|m| (m.line, m.start_char, m.end_char))
+            .collect()
+    }
+
+    #[test]
+    fn pinyin_matches_initials_full_syllables_and_mixed_queries() {
+        let options = FuzzySearchOptions {
+            pinyin: true,
+            ..Default::default()
+        };
+        let text = "我们去张家界。\n张家界的山，长江边。";
+        assert_eq!(ranges(text, "zjj", options), vec![(1, 3, 6), (2, 0, 3)]);
+        assert_eq!(
+            ranges(text, "zhangjiajie", options),
+            vec![(1, 3, 6), (2, 0, 3)]
+        );
+        assert_eq!(
+            ranges(text, "zhang jia j", options),
+            vec![(1, 3, 6), (2, 0, 3)]
+        );
+        assert_eq!(ranges(text, "张jj", options), vec![(1, 3, 6), (2, 0, 3)]);
+        // 多音字按任一读音匹配
+        assert_eq!(ranges(text, "cj", options), vec![(2, 6, 8)]);
+        // 字母只匹配汉字，不匹配原文中的拉丁字母
+        assert!(ranges("zjj abc", "zjj", options).is_empty());
+    }
 
-    let mut with_format = base_body(model, prompt, size);
-    with_format.insert(
-        "response_format".to_string(),
-        serde_json::Value::String("b64_json".to_string()),
-    );
-    bodies.push(serde_json::Value::Object(with_format));
-    bodies.push(serde_json::Value::Object(base_body(model, prompt, size)));
+    #[test]
+    fn variant_insensitive_folds_traditional_and_full_width() {
+        let options = FuzzySearchOptions {
+            variant_insensitive: true,
+            ..Default::default()
+        };
+        let text = "張家界門票１２０元\nＥＰＵＢ与epub";
+        assert_eq!(ranges(text, "张家界门票", options), vec![(1, 0, 5)]);
+        assert_eq!(ranges(text, "120", options), vec![(1, 5, 8)]);
+        assert_eq!(ranges(text, "EPUB", options), vec![(2, 0, 4)]);
+        assert_eq!(ranges(text, "ｅｐｕｂ", options), vec![(2, 5, 9)]);
+        assert!(ranges(text, "张家界", FuzzySearchOptions::default()).is_empty());
+
+        let regex = search_text_lines(text.lines(), r"门票\d+", true, &options);
+        assert_eq!(regex.count, 1);
+        assert_eq!(regex.matches[0].end_char, 8);
+
+        let both = FuzzySearchOptions {
+            pinyin: true,
+            variant_insensitive: true,
+        };
+        assert_eq!(ranges(text, "zjjmp", both), vec![(1, 0, 5)]);
+    }
+}
 
-    let mut seen = HashSet::new();
-    bodies
-        .into_iter()
-        .filter(|body| seen.insert(body.to_string()))
-        .collect()
+// --- 跨行正则搜索 ---
+
+const MULTILINE_SEARCH_DEFAULT_CONTEXT: usize = 40;
+const MULTILINE_SEARCH_DEFAULT_LIMIT: usize = 10000;
+
+#[derive(Deserialize, Clone)]
+struct MultilineSearchOptions {
+    #[serde(default = "default_true")]
+    is_regex: bool,
+    #[serde(default)]
+    case_insensitive: bool,
+    // . 是否匹配换行；^ $ 始终按行匹配
+    #[serde(default)]
+    dot_all: bool,
+    // 匹配前后各取多少个字符作为上下文
+    #[serde(default)]
+    context_chars: Option<usize>,
+    #[serde(default)]
+    max_matches: Option<usize>,
 }
 
-async fn download_ai_image_url(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, String> {
-    let response = client
-        .get(url.trim())
-        .header("Accept", "image/*,*/*;q=0.8")
-        .send()
-        .await
-        .map_err(|e| format!("下载生成图片失败: {}", e))?;
-    let status = response.status();
-    let bytes = response
-        .bytes()
-        .await
-        .map_err(|e| format!("读取生成图片失败: {}", e))?;
-    if !status.is_success() {
-        return Err(format!("下载生成图片返回 {}", status));
+impl Default for MultilineSearchOptions {
+    fn default() -> Self {
+        Self {
+            is_regex: true,
+            case_insensitive: false,
+            dot_all: false,
+            context_chars: None,
+            max_matches: None,
+        }
     }
-    let bytes = bytes.to_vec();
-    validate_ai_image_bytes(&bytes, "生成图片")?;
-    Ok(bytes)
 }
 
-const REMOTE_IMAGE_MAX_BYTES: usize = 32 * 1024 * 1024;
+// 行号从 1 开始，列为行内字符偏移（与 MatchLocation 一致）。
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+struct TextSpan {
+    start_line: usize,
+    start_column: usize,
+    end_line: usize,
+    end_column: usize,
+}
+
+#[derive(Serialize, Clone)]
+struct MultilineCapture {
+    index: usize,
+    name: Option<String>,
+    text: String,
+    span: TextSpan,
+}
+
+#[derive(Serialize, Clone)]
+struct MultilineMatch {
+    span: TextSpan,
+    text: String,
+    captures: Vec<MultilineCapture>,
+    context_before: String,
+    context_after: String,
+}
 
 #[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct RemoteImageDownloadResponse {
-    data_base64: String,
-    content_type: String,
-    final_url: String,
+struct MultilineSearchResult {
+    found: bool,
+    count: usize,
+    truncated: bool,
+    matches: Vec<MultilineMatch>,
 }
 
-fn validate_remote_image_url(url: &reqwest::Url) -> Result<(), String> {
-    if url.scheme() != "http" && url.scheme() != "https" {
-        return Err("仅支持 HTTP 或 HTTPS 图片地址".to_string());
-    }
-    if !url.username().is_empty() || url.password().is_some() {
-        return Err("图片地址不能包含用户名或密码".to_string());
+// 字节偏移与行列位置的换算表
+struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        Self { line_starts }
     }
-    let host = url
-        .host_str()
-        .ok_or_else(|| "图片地址缺少主机名".to_string())?
-        .trim_end_matches('.')
-        .to_ascii_lowercase();
-    if host == "localhost" || host.ends_with(".localhost") || host.ends_with(".local") {
-        return Err("不允许下载本机或局域网图片地址".to_string());
+
+    fn position(&self, text: &str, byte: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|start| *start <= byte) - 1;
+        let column = text[self.line_starts[line]..byte].chars().count();
+        (line + 1, column)
     }
-    if let Ok(ip) = host.parse::<IpAddr>() {
-        let blocked = match ip {
-            IpAddr::V4(ip) => {
-                ip.is_private()
-                    || ip.is_loopback()
-                    || ip.is_link_local()
-                    || ip.is_broadcast()
-                    || ip.is_documentation()
-                    || ip.is_unspecified()
-            }
-            IpAddr::V6(ip) => {
-                ip.is_loopback()
-                    || ip.is_unspecified()
-                    || (ip.segments()[0] & 0xfe00) == 0xfc00
-                    || (ip.segments()[0] & 0xffc0) == 0xfe80
-                    || ip.to_ipv4().map_or(false, |mapped| {
-                        mapped.is_private()
-                            || mapped.is_loopback()
-                            || mapped.is_link_local()
-                            || mapped.is_broadcast()
-                            || mapped.is_documentation()
-                            || mapped.is_unspecified()
-                    })
-            }
-        };
-        if blocked {
-            return Err("不允许下载本机、私有网络或链路本地图片地址".to_string());
+
+    fn span(&self, text: &str, start: usize, end: usize) -> TextSpan {
+        let (start_line, start_column) = self.position(text, start);
+        let (end_line, end_column) = self.position(text, end);
+        TextSpan {
+            start_line,
+            start_column,
+            end_line,
+            end_column,
         }
     }
-    Ok(())
 }
 
-fn raster_image_content_type(bytes: &[u8]) -> Option<&'static str> {
-    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
-        return Some("image/jpeg");
+fn match_context(text: &str, start: usize, end: usize, chars: usize) -> (String, String) {
+    let before: Vec<char> = text[..start].chars().rev().take(chars).collect();
+    (
+        before.into_iter().rev().collect(),
+        text[end..].chars().take(chars).collect(),
+    )
+}
+
+fn build_multiline_regex(pattern: &str, options: &MultilineSearchOptions) -> Result<Regex, String> {
+    let body = if options.is_regex {
+        pattern.to_string()
+    } else {
+        fancy_regex::escape(pattern).into_owned()
+    };
+    let mut flags = String::from("m");
+    if options.case_insensitive {
+        flags.push('i');
     }
-    if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]) {
-        return Some("image/png");
+    if options.dot_all {
+        flags.push('s');
     }
-    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
-        return Some("image/gif");
+    Regex::new(&format!("(?{}){}", flags, body)).map_err(|e| format!("Regex Error: {}", e))
+}
+
+fn multiline_search_impl(
+    text: &str,
+    pattern: &str,
+    options: &MultilineSearchOptions,
+) -> Result<MultilineSearchResult, String> {
+    if pattern.is_empty() {
+        return Ok(MultilineSearchResult {
+            found: false,
+            count: 0,
+            truncated: false,
+            matches: vec![],
+        });
     }
-    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
-        return Some("image/webp");
+    let re = build_multiline_regex(pattern, options)?;
+    let names: Vec<Option<String>> = re
+        .capture_names()
+        .map(|name| name.map(str::to_string))
+        .collect();
+    let context = options
+        .context_chars
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_CONTEXT);
+    let limit = options
+        .max_matches
+        .filter(|v| *v > 0)
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_LIMIT);
+    let index = LineIndex::new(text);
+    let mut matches = Vec::new();
+    let mut truncated = false;
+    for caps in re.captures_iter(text) {
+        let caps = caps.map_err(|e| format!("Regex Error: {}", e))?;
+        let Some(whole) = caps.get(0) else {
+            continue;
+        };
+        if matches.len() >= limit {
+            truncated = true;
+            break;
+        }
+        let captures = (1..caps.len())
+            .filter_map(|group| {
+                caps.get(group).map(|m| MultilineCapture {
+                    index: group,
+                    name: names.get(group).cloned().flatten(),
+                    text: m.as_str().to_string(),
+                    span: index.span(text, m.start(), m.end()),
+                })
+            })
+            .collect();
+        let (context_before, context_after) =
+            match_context(text, whole.start(), whole.end(), context);
+        matches.push(MultilineMatch {
+            span: index.span(text, whole.start(), whole.end()),
+            text: whole.as_str().to_string(),
+            captures,
+            context_before,
+            context_after,
+        });
     }
-    None
+    Ok(MultilineSearchResult {
+        found: !matches.is_empty(),
+        count: matches.len(),
+        truncated,
+        matches,
+    })
 }
 
 #[tauri::command]
-async fn download_remote_image(url: String) -> Result<RemoteImageDownloadResponse, String> {
-    let mut current = reqwest::Url::parse(url.trim())
-        .map_err(|e| format!("图片地址无效: {}", e))?;
-    validate_remote_image_url(&current)?;
+async fn advanced_search_multiline(
+    content: String,
+    pattern: String,
+    options: Option<MultilineSearchOptions>,
+) -> Result<MultilineSearchResult, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = normalize_line_endings(content);
+        multiline_search_impl(&content, &pattern, &options.unwrap_or_default())
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-    let client = reqwest::Client::builder()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) TEpub-Editor/1.0")
-        .timeout(Duration::from_secs(30))
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .map_err(|e| format!("初始化图片下载失败: {}", e))?;
+#[tauri::command]
+async fn search_text_buffer_multiline(
+    buffer_id: String,
+    pattern: String,
+    options: Option<MultilineSearchOptions>,
+) -> Result<MultilineSearchResult, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = lines.join("\n");
+        multiline_search_impl(&content, &pattern, &options.unwrap_or_default())
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-    for redirect_count in 0..=5 {
-        validate_remote_image_url(&current)?;
-        let mut referer = current.clone();
-        referer.set_path("/");
-        referer.set_query(None);
-        referer.set_fragment(None);
-        let response = client
-            .get(current.clone())
-            .header(
-                reqwest::header::ACCEPT,
-                "image/avif,image/webp,image/png,image/jpeg,image/gif,image/*;q=0.8,*/*;q=0.5",
-            )
-            .header(reqwest::header::REFERER, referer.as_str())
-            .send()
-            .await
-            .map_err(|e| format!("下载图片失败: {}", e))?;
+#[cfg(test)]
+mod multiline_search_tests {
+    use super::*;
 
-        if response.status().is_redirection() {
-            if redirect_count == 5 {
-                return Err("图片地址重定向次数超过 5 次".to_string());
+    #[test]
+    fn matches_across_lines_with_captures_and_context() -> Result<(), String> {
+        let text = "第一章 风起\n他说：“走吧。\n我们回家。”\n尾声";
+        let result = multiline_search_impl(
+            text,
+            r"“(?<first>[^”\n]*)\n([^”]*)”",
+            &MultilineSearchOptions {
+                context_chars: Some(3),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(result.count, 1);
+        let found = &result.matches[0];
+        assert_eq!(
+            found.span,
+            TextSpan {
+                start_line: 2,
+                start_column: 3,
+                end_line: 3,
+                end_column: 6,
             }
-            let location = response
-                .headers()
-                .get(reqwest::header::LOCATION)
-                .and_then(|value| value.to_str().ok())
-                .ok_or_else(|| format!("图片服务器返回了无目标的重定向 {}", response.status()))?;
-            current = current
-                .join(location)
-                .map_err(|e| format!("图片重定向地址无效: {}", e))?;
-            continue;
-        }
+        );
+        assert_eq!(found.captures.len(), 2);
+        assert_eq!(found.captures[0].name.as_deref(), Some("first"));
+        assert_eq!(found.captures[0].text, "走吧。");
+        assert_eq!(found.captures[1].index, 2);
+        assert_eq!(found.captures[1].span.start_line, 3);
+        assert_eq!(found.captures[1].span.start_column, 0);
+        assert_eq!(found.context_before, "他说：");
+        assert_eq!(found.context_after, "\n尾声");
+        Ok(())
+    }
 
-        if !response.status().is_success() {
-            return Err(format!("图片服务器返回 {}", response.status()));
-        }
-        if response.content_length().unwrap_or(0) > REMOTE_IMAGE_MAX_BYTES as u64 {
-            return Err("图片超过 32 MB 限制".to_string());
-        }
+    #[test]
+    fn literal_anchored_and_limited_searches() -> Result<(), String> {
+        let text = "a.b\nA.B\na.b";
+        let literal = MultilineSearchOptions {
+            is_regex: false,
+            case_insensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(multiline_search_impl(text, "a.b", &literal)?.count, 3);
+        let anchored = multiline_search_impl(text, "^a", &MultilineSearchOptions::default())?;
+        assert_eq!(anchored.count, 2);
+        assert_eq!(anchored.matches[1].span.start_line, 3);
+        let limited = multiline_search_impl(
+            text,
+            "(?i)a",
+            &MultilineSearchOptions {
+                max_matches: Some(2),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(limited.count, 2);
+        assert!(limited.truncated);
+        assert!(multiline_search_impl(text, "(", &MultilineSearchOptions::default()).is_err());
+        Ok(())
+    }
+}
 
-        let final_url = response.url().to_string();
-        let mut response = response;
-        let mut bytes = Vec::new();
-        while let Some(chunk) = response
-            .chunk()
-            .await
-            .map_err(|e| format!("读取图片响应失败: {}", e))?
-        {
-            if bytes.len() + chunk.len() > REMOTE_IMAGE_MAX_BYTES {
-                return Err("图片超过 32 MB 限制".to_string());
-            }
-            bytes.extend_from_slice(&chunk);
-        }
-        if bytes.is_empty() {
-            return Err("图片响应为空".to_string());
+// --- 选择性替换 ---
+// 两步：先预览全部匹配及替换结果，再按 id 应用选中的项；id 即匹配序号，revision 用来确认内容未变。
+
+#[derive(Serialize, Clone)]
+struct ReplaceCandidate {
+    id: usize,
+    span: TextSpan,
+    text: String,
+    replacement: String,
+    context_before: String,
+    context_after: String,
+}
+
+#[derive(Serialize)]
+struct ReplacePreview {
+    revision: String,
+    count: usize,
+    truncated: bool,
+    matches: Vec<ReplaceCandidate>,
+}
+
+// inverse_patches 以替换后的行号为坐标，交给 apply_text_buffer_patches 即可精确撤销。
+#[derive(Serialize)]
+struct ReplaceApplyResult {
+    content: String,
+    revision: String,
+    applied: usize,
+    inverse_patches: Vec<TextBufferPatch>,
+}
+
+struct ReplaceMatch {
+    start: usize,
+    end: usize,
+    replacement: String,
+}
+
+fn text_revision(text: &str) -> String {
+    format!("{:x}", md5::compute(text.as_bytes()))
+}
+
+// 正则模式下 replacement 支持 $1 / ${name}，与 advanced_replace 一致；纯文本模式原样替换。
+fn collect_replace_matches(
+    text: &str,
+    pattern: &str,
+    replacement: &str,
+    options: &MultilineSearchOptions,
+) -> Result<(Vec<ReplaceMatch>, bool), String> {
+    if pattern.is_empty() {
+        return Ok((Vec::new(), false));
+    }
+    let re = build_multiline_regex(pattern, options)?;
+    let limit = options
+        .max_matches
+        .filter(|v| *v > 0)
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_LIMIT);
+    let mut matches = Vec::new();
+    for caps in re.captures_iter(text) {
+        let caps = caps.map_err(|e| format!("Regex Error: {}", e))?;
+        let Some(whole) = caps.get(0) else {
+            continue;
+        };
+        if matches.len() >= limit {
+            return Ok((matches, true));
         }
-        let content_type = raster_image_content_type(&bytes).ok_or_else(|| {
-            "响应不是支持的 JPG、PNG、WebP 或 GIF 图片".to_string()
-        })?;
-        return Ok(RemoteImageDownloadResponse {
-            data_base64: general_purpose::STANDARD.encode(bytes),
-            content_type: content_type.to_string(),
-            final_url,
+        let replacement = if options.is_regex {
+            let mut expanded = String::new();
+            caps.expand(replacement, &mut expanded);
+            expanded
+        } else {
+            replacement.to_string()
+        };
+        matches.push(ReplaceMatch {
+            start: whole.start(),
+            end: whole.end(),
+            replacement,
         });
     }
-
-    Err("图片下载失败".to_string())
+    Ok((matches, false))
 }
 
-async fn extract_ai_image_bytes(
-    client: &reqwest::Client,
-    value: &serde_json::Value,
-    key_hint: Option<&str>,
-) -> Option<Vec<u8>> {
-    match value {
-        serde_json::Value::String(text) => {
-            if let Some(bytes) = decode_ai_data_url(text).or_else(|| decode_ai_base64_image(text)) {
-                return Some(bytes);
-            }
-            let key = key_hint.unwrap_or("").to_ascii_lowercase();
-            if (key.contains("url") || key == "uri" || key == "href") && is_remote_ai_url(text) {
-                return download_ai_image_url(client, text).await.ok();
-            }
-            None
-        }
-        serde_json::Value::Array(items) => {
-            for item in items {
-                if let Some(bytes) = Box::pin(extract_ai_image_bytes(client, item, key_hint)).await
-                {
-                    return Some(bytes);
-                }
+fn preview_replace_impl(
+    text: &str,
+    pattern: &str,
+    replacement: &str,
+    options: &MultilineSearchOptions,
+) -> Result<ReplacePreview, String> {
+    let (matches, truncated) = collect_replace_matches(text, pattern, replacement, options)?;
+    let context = options
+        .context_chars
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_CONTEXT);
+    let index = LineIndex::new(text);
+    let matches: Vec<ReplaceCandidate> = matches
+        .into_iter()
+        .enumerate()
+        .map(|(id, m)| {
+            let (context_before, context_after) = match_context(text, m.start, m.end, context);
+            ReplaceCandidate {
+                id,
+                span: index.span(text, m.start, m.end),
+                text: text[m.start..m.end].to_string(),
+                replacement: m.replacement,
+                context_before,
+                context_after,
             }
-            None
-        }
-        serde_json::Value::Object(map) => {
-            for key in [
-                "b64_json",
-                "base64",
-                "image_base64",
-                "data",
-                "content",
-                "url",
-                "image_url",
-                "uri",
-                "href",
-            ] {
-                if let Some(item) = map.get(key) {
-                    if let Some(bytes) =
-                        Box::pin(extract_ai_image_bytes(client, item, Some(key))).await
-                    {
-                        return Some(bytes);
-                    }
-                }
+        })
+        .collect();
+    Ok(ReplacePreview {
+        revision: text_revision(text),
+        count: matches.len(),
+        truncated,
+        matches,
+    })
+}
+
+// 选中的匹配按所在行合并成若干段，逐段重写；每段生成一条逆向补丁（恢复原来的整行）。
+fn apply_selected_replacements(
+    text: &str,
+    matches: &[ReplaceMatch],
+    accepted: &HashSet<usize>,
+) -> (String, usize, Vec<TextBufferPatch>) {
+    let index = LineIndex::new(text);
+    let line_of = |byte: usize| index.line_starts.partition_point(|start| *start <= byte) - 1;
+    let line_end = |line: usize| {
+        index
+            .line_starts
+            .get(line + 1)
+            .map_or(text.len(), |next| next - 1)
+    };
+
+    let mut groups: Vec<(usize, usize, Vec<&ReplaceMatch>)> = Vec::new();
+    for m in matches
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| accepted.contains(id))
+        .map(|(_, m)| m)
+    {
+        let (first, last) = (line_of(m.start), line_of(m.end));
+        match groups.last_mut() {
+            Some(group) if first <= group.1 => {
+                group.1 = group.1.max(last);
+                group.2.push(m);
             }
-            for (key, item) in map {
-                if let Some(bytes) = Box::pin(extract_ai_image_bytes(client, item, Some(key))).await
-                {
-                    return Some(bytes);
-                }
+            _ => groups.push((first, last, vec![m])),
+        }
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut copied = 0usize;
+    let mut applied = 0usize;
+    let mut line_delta = 0isize;
+    let mut inverse = Vec::new();
+    for (first, last, group) in groups {
+        let start = index.line_starts[first];
+        let end = line_end(last);
+        out.push_str(&text[copied..start]);
+        let mut segment = String::new();
+        let mut cursor = start;
+        for m in group {
+            segment.push_str(&text[cursor..m.start]);
+            segment.push_str(&m.replacement);
+            cursor = m.end;
+            applied += 1;
+        }
+        segment.push_str(&text[cursor..end]);
+        let new_line_count = segment.split('\n').count();
+        inverse.push(TextBufferPatch {
+            start_line: (first as isize + line_delta) as usize + 1,
+            delete_count: new_line_count,
+            lines: text[start..end].split('\n').map(str::to_string).collect(),
+        });
+        line_delta += new_line_count as isize - (last - first + 1) as isize;
+        out.push_str(&segment);
+        copied = end;
+    }
+    out.push_str(&text[copied..]);
+    (out, applied, inverse)
+}
+
+fn apply_replace_impl(
+    text: &str,
+    pattern: &str,
+    replacement: &str,
+    accepted_ids: &[usize],
+    revision: Option<&str>,
+    options: &MultilineSearchOptions,
+) -> Result<ReplaceApplyResult, String> {
+    if revision.is_some_and(|rev| rev != text_revision(text)) {
+        return Err("内容已变化，请重新预览替换".to_string());
+    }
+    let (matches, _) = collect_replace_matches(text, pattern, replacement, options)?;
+    let accepted: HashSet<usize> = accepted_ids.iter().copied().collect();
+    let (content, applied, inverse_patches) =
+        apply_selected_replacements(text, &matches, &accepted);
+    Ok(ReplaceApplyResult {
+        revision: text_revision(&content),
+        content,
+        applied,
+        inverse_patches,
+    })
+}
+
+#[tauri::command]
+async fn preview_replace(
+    content: String,
+    pattern: String,
+    replacement: String,
+    options: Option<MultilineSearchOptions>,
+) -> Result<ReplacePreview, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = normalize_line_endings(content);
+        preview_replace_impl(
+            &content,
+            &pattern,
+            &replacement,
+            &options.unwrap_or_default(),
+        )
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+#[tauri::command]
+async fn apply_replace(
+    content: String,
+    pattern: String,
+    replacement: String,
+    accepted_ids: Vec<usize>,
+    revision: Option<String>,
+    options: Option<MultilineSearchOptions>,
+) -> Result<ReplaceApplyResult, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = normalize_line_endings(content);
+        apply_replace_impl(
+            &content,
+            &pattern,
+            &replacement,
+            &accepted_ids,
+            revision.as_deref(),
+            &options.unwrap_or_default(),
+        )
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+#[tauri::command]
+async fn preview_text_buffer_replace(
+    buffer_id: String,
+    pattern: String,
+    replacement: String,
+    options: Option<MultilineSearchOptions>,
+) -> Result<ReplacePreview, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        preview_replace_impl(
+            &lines.join("\n"),
+            &pattern,
+            &replacement,
+            &options.unwrap_or_default(),
+        )
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+// 缓冲版本不回传全文，撤销时把 inverse_patches 交给 apply_text_buffer_patches。
+#[tauri::command]
+async fn apply_text_buffer_replace(
+    buffer_id: String,
+    pattern: String,
+    replacement: String,
+    accepted_ids: Vec<usize>,
+    revision: Option<String>,
+    options: Option<MultilineSearchOptions>,
+) -> Result<ReplaceApplyResult, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut result = apply_replace_impl(
+            &lines.join("\n"),
+            &pattern,
+            &replacement,
+            &accepted_ids,
+            revision.as_deref(),
+            &options.unwrap_or_default(),
+        )?;
+        if result.applied > 0 {
+            let mut buffers = lock_text_buffers()?;
+            let buffer = buffers
+                .get_mut(&buffer_id)
+                .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+            if !Arc::ptr_eq(&buffer.lines, &lines) {
+                return Err("内容已变化，请重新预览替换".to_string());
             }
-            None
+            buffer.lines = Arc::new(result.content.split('\n').map(str::to_string).collect());
+            buffer.modified = true;
         }
-        _ => None,
-    }
+        result.content = String::new();
+        Ok(result)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
-async fn request_ai_image_generation(
-    client: &reqwest::Client,
-    request: &ToolboxAiImageRequest,
-    body: &serde_json::Value,
-) -> Result<Vec<u8>, String> {
-    let url = ai_image_generation_url(&request.base_url)?;
-    let response = client
-        .post(url)
-        .bearer_auth(request.api_key.trim())
-        .header("Accept", "application/json,image/*")
-        .json(body)
-        .send()
-        .await
-        .map_err(|e| format!("请求生图接口失败: {}", e))?;
-    let status = response.status();
-    let content_type = response
-        .headers()
-        .get(reqwest::header::CONTENT_TYPE)
-        .and_then(|value| value.to_str().ok())
-        .unwrap_or("")
-        .to_string();
-    let bytes = response
-        .bytes()
-        .await
-        .map_err(|e| format!("读取生图响应失败: {}", e))?;
-    if bytes.len() > AI_IMAGE_MAX_RESPONSE_BYTES {
-        return Err("生图响应过大".to_string());
-    }
-    if !status.is_success() {
-        let text = String::from_utf8_lossy(&bytes).to_string();
-        return Err(format!(
-            "生图接口返回 {}: {}",
-            status,
-            ai_json_error_preview(&text)
-        ));
+#[cfg(test)]
+mod selective_replace_tests {
+    use super::*;
+
+    #[test]
+    fn preview_expands_groups_and_apply_takes_selected_ids() -> Result<(), String> {
+        let text = "张三说：你好\n李四说：再见\n王五说：明天\n见";
+        let options = MultilineSearchOptions::default();
+        let preview = preview_replace_impl(text, r"(\p{Han}{2})说：", "${1}道：", &options)?;
+        assert_eq!(preview.count, 3);
+        assert_eq!(preview.matches[1].text, "李四说：");
+        assert_eq!(preview.matches[1].replacement, "李四道：");
+        assert_eq!(preview.matches[1].span.start_line, 2);
+
+        let result = apply_replace_impl(
+            text,
+            r"(\p{Han}{2})说：",
+            "${1}道：",
+            &[0, 2],
+            Some(&preview.revision),
+            &options,
+        )?;
+        assert_eq!(result.applied, 2);
+        assert_eq!(
+            result.content,
+            "张三道：你好\n李四说：再见\n王五道：明天\n见"
+        );
+
+        let stale = apply_replace_impl(text, "说", "道", &[0], Some("stale"), &options);
+        assert!(stale.is_err());
+        Ok(())
     }
-    if content_type.starts_with("image/") {
-        let out = bytes.to_vec();
-        validate_ai_image_bytes(&out, "生成图片")?;
-        return Ok(out);
+
+    #[test]
+    fn inverse_patches_restore_multi_line_edits() -> Result<(), String> {
+        let text = "一\n二\n三\n四\n五\n六";
+        let options = MultilineSearchOptions::default();
+        let result = apply_replace_impl(text, r"二\n三", "二三", &[0], None, &options)?;
+        let result_second =
+            apply_replace_impl(&result.content, "五", "五\n五又", &[0], None, &options)?;
+        assert_eq!(result_second.content, "一\n二三\n四\n五\n五又\n六");
+        assert_eq!(
+            result_second.inverse_patches,
+            vec![TextBufferPatch {
+                start_line: 4,
+                delete_count: 2,
+                lines: vec!["五".to_string()],
+            }]
+        );
+
+        let both = apply_replace_impl(text, r"二\n三|五", "X", &[0, 1], None, &options)?;
+        assert_eq!(both.content, "一\nX\n四\nX\n六");
+        let mut lines: Vec<String> = both.content.split('\n').map(str::to_string).collect();
+        apply_text_patches(&mut lines, both.inverse_patches)?;
+        assert_eq!(lines.join("\n"), text);
+        Ok(())
     }
-    let text = String::from_utf8_lossy(&bytes).to_string();
-    let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| {
-        format!(
-            "生图接口未返回有效 JSON: {}; {}",
-            e,
-            text.chars().take(180).collect::<String>()
-        )
-    })?;
-    extract_ai_image_bytes(client, &value, None)
-        .await
-        .ok_or_else(|| "生图接口返回中没有图片数据".to_string())
 }
 
-async fn request_ai_image_edit(
-    client: &reqwest::Client,
-    request: &ToolboxAiImageRequest,
-    prompt: &str,
-    size: &str,
-    reference_bytes: &[u8],
-) -> Result<Vec<u8>, String> {
-    let url = ai_image_edits_url(&request.base_url)?;
-    let (ext, mime) = ai_image_mime_from_bytes(reference_bytes);
-    let mut form = reqwest::multipart::Form::new()
-        .text("model", request.model.trim().to_string())
-        .text("prompt", prompt.to_string())
-        .text("n", "1")
-        .text("response_format", "b64_json");
-    if !size.trim().is_empty() {
-        form = form.text("size", size.trim().to_string());
-    }
-    form = form.part(
-        "image",
-        reqwest::multipart::Part::bytes(reference_bytes.to_vec())
-            .file_name(format!("reference.{}", ext))
-            .mime_str(mime)
-            .map_err(|e| format!("构造参考图失败: {}", e))?,
-    );
+// --- 净化规则集 ---
+// 规则集按顺序保存在书库 _data/replace_rules.json（与 library.json 同目录），规则逐条依次执行。
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ReplaceRule {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    name: String,
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+    #[serde(default = "default_true")]
+    is_regex: bool,
+    #[serde(default)]
+    case_insensitive: bool,
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// 作用范围：all（默认）、txt、epub（EPUB 文本节点）、title（仅标题）
+    #[serde(default)]
+    scope: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ReplaceRuleSet {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    rules: Vec<ReplaceRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReplaceRuleSetImport {
+    Many(Vec<ReplaceRuleSet>),
+    One(ReplaceRuleSet),
+}
+
+#[derive(Serialize, Clone)]
+struct ReplaceRuleHit {
+    rule_id: String,
+    name: String,
+    hits: usize,
+}
+
+#[derive(Serialize)]
+struct ReplaceRulesReport {
+    content: String,
+    total_hits: usize,
+    changed_files: Vec<String>,
+    rules: Vec<ReplaceRuleHit>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReplaceRuleTarget {
+    Txt,
+    Epub,
+}
+
+struct CompiledReplaceRule {
+    re: Regex,
+    replacement: String,
+    expand: bool,
+    scope: String,
+}
 
-    let response = client
-        .post(url)
-        .bearer_auth(request.api_key.trim())
-        .header("Accept", "application/json,image/*")
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| format!("请求图片编辑接口失败: {}", e))?;
-    let status = response.status();
-    let content_type = response
-        .headers()
-        .get(reqwest::header::CONTENT_TYPE)
-        .and_then(|value| value.to_str().ok())
-        .unwrap_or("")
-        .to_string();
-    let bytes = response
-        .bytes()
-        .await
-        .map_err(|e| format!("读取图片编辑响应失败: {}", e))?;
-    if bytes.len() > AI_IMAGE_MAX_RESPONSE_BYTES {
-        return Err("图片编辑响应过大".to_string());
+impl CompiledReplaceRule {
+    fn applies_to(&self, target: ReplaceRuleTarget, is_title: bool) -> bool {
+        match self.scope.as_str() {
+            "txt" => target == ReplaceRuleTarget::Txt,
+            "epub" => target == ReplaceRuleTarget::Epub,
+            "title" => is_title,
+            "" | "all" => true,
+            _ => false,
+        }
     }
-    if !status.is_success() {
-        let text = String::from_utf8_lossy(&bytes).to_string();
-        return Err(format!(
-            "图片编辑接口返回 {}: {}",
-            status,
-            ai_json_error_preview(&text)
-        ));
+}
+
+fn replace_rule_sets_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
+    Ok(library_data_dir(app)?.join("replace_rules.json"))
+}
+
+fn read_replace_rule_sets(app: &tauri::AppHandle) -> Result<Vec<ReplaceRuleSet>, String> {
+    let path = replace_rule_sets_path(app)?;
+    if !path.exists() {
+        return Ok(Vec::new());
     }
-    if content_type.starts_with("image/") {
-        let out = bytes.to_vec();
-        validate_ai_image_bytes(&out, "生成图片")?;
-        return Ok(out);
+    let bytes = fs::read(&path).map_err(|e| format!("读取净化规则失败: {}", e))?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("解析净化规则失败: {}", e))
+}
+
+fn write_replace_rule_sets(app: &tauri::AppHandle, sets: &[ReplaceRuleSet]) -> Result<(), String> {
+    let path = replace_rule_sets_path(app)?;
+    if let Some(parent) = path.parent() {
+        ensure_dir(parent)?;
     }
-    let text = String::from_utf8_lossy(&bytes).to_string();
-    let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| {
-        format!(
-            "图片编辑接口未返回有效 JSON: {}; {}",
-            e,
-            text.chars().take(180).collect::<String>()
-        )
-    })?;
-    extract_ai_image_bytes(client, &value, None)
-        .await
-        .ok_or_else(|| "图片编辑接口返回中没有图片数据".to_string())
+    let bytes =
+        serde_json::to_vec_pretty(sets).map_err(|e| format!("序列化净化规则失败: {}", e))?;
+    fs::write(path, bytes).map_err(|e| format!("保存净化规则失败: {}", e))
 }
 
-#[tauri::command]
-async fn toolbox_generate_ai_image(
-    request: ToolboxAiImageRequest,
-) -> Result<ToolboxAiImageResult, String> {
-    if request.base_url.trim().is_empty()
-        || request.api_key.trim().is_empty()
-        || request.model.trim().is_empty()
-    {
-        return Err("请先填写 Base URL、API Key 和模型".to_string());
+fn replace_rule_label(rule: &ReplaceRule) -> &str {
+    if rule.name.trim().is_empty() {
+        &rule.pattern
+    } else {
+        &rule.name
     }
-    if request.prompt.trim().is_empty() {
-        return Err("提示词不能为空".to_string());
+}
+
+// 作用范围只接受已知值，拼错的范围直接报错，而不是当成 all
+fn replace_rule_scope(rule: &ReplaceRule) -> Result<String, String> {
+    let scope = rule.scope.trim().to_ascii_lowercase();
+    match scope.as_str() {
+        "" | "all" | "txt" | "epub" | "title" => Ok(scope),
+        _ => Err(format!(
+            "规则「{}」的作用范围无效: {}",
+            replace_rule_label(rule),
+            rule.scope
+        )),
     }
-    let target = match request.target.trim() {
-        "duokan" | "cover" => "duokan",
-        "standard" => "standard",
-        "banner" => "banner",
-        _ => "duokan",
+}
+
+fn compile_replace_rule(rule: &ReplaceRule) -> Result<CompiledReplaceRule, String> {
+    let options = MultilineSearchOptions {
+        is_regex: rule.is_regex,
+        case_insensitive: rule.case_insensitive,
+        ..Default::default()
     };
-    let reference_data = request.reference_data.as_deref();
-    if let Some(bytes) = reference_data {
-        if bytes.len() > AI_IMAGE_MAX_INPUT_BYTES {
-            return Err("参考封面图片过大".to_string());
+    let scope = replace_rule_scope(rule)?;
+    let re = build_multiline_regex(&rule.pattern, &options)
+        .map_err(|e| format!("规则「{}」无效: {}", replace_rule_label(rule), e))?;
+    Ok(CompiledReplaceRule {
+        re,
+        replacement: rule.replacement.clone(),
+        expand: rule.is_regex,
+        scope,
+    })
+}
+
+// 补齐缺失的 id 并校验正则，保存和导入前都走一遍。
+fn normalize_replace_rule_sets(sets: &mut [ReplaceRuleSet]) -> Result<(), String> {
+    for set in sets.iter_mut() {
+        if set.id.trim().is_empty() {
+            set.id = uuid::Uuid::new_v4().to_string();
+        }
+        for rule in &mut set.rules {
+            if rule.id.trim().is_empty() {
+                rule.id = uuid::Uuid::new_v4().to_string();
+            }
+            if rule.pattern.is_empty() {
+                replace_rule_scope(rule)?;
+            } else {
+                compile_replace_rule(rule)?;
+            }
         }
-        validate_ai_image_bytes(bytes, "参考封面")?;
     }
+    Ok(())
+}
 
-    let prompt = render_ai_image_prompt(
-        &request.prompt,
-        request.title.as_deref(),
-        request.author.as_deref(),
-    );
-    let client = reqwest::Client::builder()
-        .user_agent(AI_IMAGE_USER_AGENT)
-        .connect_timeout(Duration::from_secs(20))
-        .timeout(Duration::from_secs(180))
-        .build()
-        .map_err(|e| format!("初始化生图客户端失败: {}", e))?;
+// 启用且非空的规则，与原规则下标一一对应（None 表示跳过）
+fn compile_replace_rule_set(
+    set: &ReplaceRuleSet,
+) -> Result<Vec<Option<CompiledReplaceRule>>, String> {
+    set.rules
+        .iter()
+        .map(|rule| {
+            if rule.enabled && !rule.pattern.is_empty() {
+                compile_replace_rule(rule).map(Some)
+            } else {
+                Ok(None)
+            }
+        })
+        .collect()
+}
 
-    let reference_data_url = reference_data.map(|bytes| {
-        let (_, mime) = ai_image_mime_from_bytes(bytes);
-        format!(
-            "data:{};base64,{}",
-            mime,
-            general_purpose::STANDARD.encode(bytes)
-        )
-    });
-    let sizes = image_size_candidates(&request.size, target);
-    let is_gpt_image = request.model.to_ascii_lowercase().contains("gpt-image");
-    let mut last_error = None;
+fn replace_all_counted(
+    re: &Regex,
+    text: &str,
+    replacement: &str,
+    expand: bool,
+) -> Result<(String, usize), String> {
+    let mut out = String::new();
+    let mut copied = 0usize;
+    let mut hits = 0usize;
+    for caps in re.captures_iter(text) {
+        let caps = caps.map_err(|e| format!("Regex Error: {}", e))?;
+        let Some(whole) = caps.get(0) else {
+            continue;
+        };
+        out.push_str(&text[copied..whole.start()]);
+        if expand {
+            caps.expand(replacement, &mut out);
+        } else {
+            out.push_str(replacement);
+        }
+        copied = whole.end();
+        hits += 1;
+    }
+    if hits == 0 {
+        return Ok((text.to_string(), 0));
+    }
+    out.push_str(&text[copied..]);
+    Ok((out, hits))
+}
 
-    if is_gpt_image {
-        if let Some(bytes) = reference_data {
-            for size in &sizes {
-                match request_ai_image_edit(&client, &request, &prompt, size, bytes).await {
-                    Ok(out) => {
-                        let (ext, mime) = ai_image_mime_from_bytes(&out);
-                        let stem =
-                            sanitize_filename_part(request.title.as_deref().unwrap_or(target));
-                        let stem = if stem.is_empty() {
-                            target.to_string()
-                        } else {
-                            stem
-                        };
-                        let file_name = format!("{}-{}.{}", stem, target, ext);
-                        return Ok(ToolboxAiImageResult {
-                            bytes: out,
-                            mime: mime.to_string(),
-                            extension: ext.to_string(),
-                            file_name,
-                            prompt,
-                            size: size.clone(),
-                        });
-                    }
-                    Err(e) => last_error = Some(e),
+fn replace_rule_hits(set: &ReplaceRuleSet, hits: &[usize]) -> Vec<ReplaceRuleHit> {
+    set.rules
+        .iter()
+        .zip(hits)
+        .filter(|(rule, _)| rule.enabled && !rule.pattern.is_empty())
+        .map(|(rule, hits)| ReplaceRuleHit {
+            rule_id: rule.id.clone(),
+            name: rule.name.clone(),
+            hits: *hits,
+        })
+        .collect()
+}
+
+// TXT：普通规则作用于全文（可跨行），title 规则只改标题行。
+fn apply_replace_rules_to_text(
+    content: &str,
+    rules: &[Option<CompiledReplaceRule>],
+    chapter_rules: Vec<RegexRule>,
+    hits: &mut [usize],
+) -> Result<String, String> {
+    let compiled = compile_chapter_rules(chapter_rules);
+    let mut text = content.to_string();
+    for (index, rule) in rules.iter().enumerate() {
+        let Some(rule) = rule else {
+            continue;
+        };
+        if rule.scope == "title" {
+            let mut lines: Vec<String> = text.split('\n').map(str::to_string).collect();
+            for line in lines.iter_mut() {
+                let trim = line.trim();
+                if trim.is_empty()
+                    || !compiled
+                        .iter()
+                        .any(|chapter_rule| chapter_rule.matches(line, trim, true, true))
+                {
+                    continue;
                 }
+                let (next, count) =
+                    replace_all_counted(&rule.re, line, &rule.replacement, rule.expand)?;
+                *line = next;
+                hits[index] += count;
             }
+            text = lines.join("\n");
+        } else if rule.applies_to(ReplaceRuleTarget::Txt, false) {
+            let (next, count) =
+                replace_all_counted(&rule.re, &text, &rule.replacement, rule.expand)?;
+            text = next;
+            hits[index] += count;
         }
     }
+    Ok(text)
+}
 
-    for size in &sizes {
-        for body in build_ai_image_request_bodies(
-            &request.model,
-            &prompt,
-            size,
-            reference_data_url.as_deref(),
-        ) {
-            match request_ai_image_generation(&client, &request, &body).await {
-                Ok(out) => {
-                    let (ext, mime) = ai_image_mime_from_bytes(&out);
-                    let stem = sanitize_filename_part(request.title.as_deref().unwrap_or(target));
-                    let stem = if stem.is_empty() {
-                        target.to_string()
-                    } else {
-                        stem
-                    };
-                    let file_name = format!("{}-{}.{}", stem, target, ext);
-                    return Ok(ToolboxAiImageResult {
-                        bytes: out,
-                        mime: mime.to_string(),
-                        extension: ext.to_string(),
-                        file_name,
-                        prompt,
-                        size: size.clone(),
-                    });
+// EPUB：逐个文本节点执行；h1-h6/title 内以及 NCX 中的文本视为标题。
+fn apply_replace_rules_to_markup(
+    markup: &str,
+    is_toc: bool,
+    rules: &[Option<CompiledReplaceRule>],
+    hits: &mut [usize],
+) -> Result<String, String> {
+    let mut error: Option<String> = None;
+    let output = map_markup_text_nodes_in_context(markup, |node, _, elements| {
+        let is_title = is_toc
+            || elements.iter().any(|e| {
+                matches!(
+                    e.name.as_str(),
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "title"
+                )
+            });
+        // 规则作用于解码后的文字，命中后再整体转义；未命中的节点保持原有实体写法。
+        // 无法解码的实体先换成私用区占位符，避免被规则改动或被二次转义。
+        let (decoded, _, opaque) = decode_text_node_entities(node);
+        let mut text = String::with_capacity(decoded.len());
+        let mut kept: Vec<(char, &str)> = Vec::new();
+        let mut copied = 0usize;
+        for (start, end) in opaque {
+            let Some(placeholder) = char::from_u32(0xF0000 + kept.len() as u32) else {
+                return node.to_string();
+            };
+            text.push_str(&decoded[copied..start]);
+            text.push(placeholder);
+            kept.push((placeholder, &decoded[start..end]));
+            copied = end;
+        }
+        text.push_str(&decoded[copied..]);
+        let mut node_hits = 0usize;
+        for (index, rule) in rules.iter().enumerate() {
+            let Some(rule) = rule else {
+                continue;
+            };
+            if error.is_some() || !rule.applies_to(ReplaceRuleTarget::Epub, is_title) {
+                continue;
+            }
+            match replace_all_counted(&rule.re, &text, &rule.replacement, rule.expand) {
+                Ok((next, count)) => {
+                    text = next;
+                    hits[index] += count;
+                    node_hits += count;
                 }
-                Err(e) => last_error = Some(e),
+                Err(e) => error = Some(e),
             }
         }
+        if node_hits == 0 {
+            return node.to_string();
+        }
+        let mut escaped = escape_xml(&text);
+        for (placeholder, entity) in kept {
+            escaped = escaped.replace(placeholder, entity);
+        }
+        escaped
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(output),
     }
+}
 
-    Err(last_error.unwrap_or_else(|| "未获取到生图结果".to_string()))
+fn find_replace_rule_set(app: &tauri::AppHandle, set_id: &str) -> Result<ReplaceRuleSet, String> {
+    read_replace_rule_sets(app)?
+        .into_iter()
+        .find(|set| set.id == set_id)
+        .ok_or_else(|| "净化规则集不存在".to_string())
+}
+
+#[tauri::command]
+fn list_replace_rule_sets(app: tauri::AppHandle) -> Result<Vec<ReplaceRuleSet>, String> {
+    read_replace_rule_sets(&app)
+}
+
+// 整体保存：列表顺序即规则集顺序，也用于排序和删除。
+#[tauri::command]
+fn save_replace_rule_sets(
+    app: tauri::AppHandle,
+    mut sets: Vec<ReplaceRuleSet>,
+) -> Result<Vec<ReplaceRuleSet>, String> {
+    normalize_replace_rule_sets(&mut sets)?;
+    write_replace_rule_sets(&app, &sets)?;
+    Ok(sets)
+}
+
+#[tauri::command]
+fn delete_replace_rule_set(
+    app: tauri::AppHandle,
+    set_id: String,
+) -> Result<Vec<ReplaceRuleSet>, String> {
+    let mut sets = read_replace_rule_sets(&app)?;
+    sets.retain(|set| set.id != set_id);
+    write_replace_rule_sets(&app, &sets)?;
+    Ok(sets)
+}
+
+// untagged 会把任意 JSON 对象解析成空规则集，这里要求每个规则集至少有名称或规则。
+fn parse_replace_rule_set_import(bytes: &[u8]) -> Result<Vec<ReplaceRuleSet>, String> {
+    let sets = match serde_json::from_slice::<ReplaceRuleSetImport>(bytes)
+        .map_err(|e| format!("解析规则文件失败: {}", e))?
+    {
+        ReplaceRuleSetImport::Many(sets) => sets,
+        ReplaceRuleSetImport::One(set) => vec![set],
+    };
+    if sets.is_empty()
+        || sets
+            .iter()
+            .any(|set| set.name.trim().is_empty() && set.rules.is_empty())
+    {
+        return Err("规则文件中没有有效的规则集".to_string());
+    }
+    Ok(sets)
+}
+
+// 导入单个规则集或规则集数组；id 相同的覆盖原位置，其余追加到末尾。
+#[tauri::command]
+fn import_replace_rule_sets(
+    app: tauri::AppHandle,
+    path: String,
+) -> Result<Vec<ReplaceRuleSet>, String> {
+    let bytes = fs::read(&path).map_err(|e| format!("读取规则文件失败: {}", e))?;
+    let mut imported = parse_replace_rule_set_import(&bytes)?;
+    normalize_replace_rule_sets(&mut imported)?;
+    let mut sets = read_replace_rule_sets(&app)?;
+    for set in imported {
+        match sets.iter_mut().find(|existing| existing.id == set.id) {
+            Some(existing) => *existing = set,
+            None => sets.push(set),
+        }
+    }
+    write_replace_rule_sets(&app, &sets)?;
+    Ok(sets)
+}
+
+// set_ids 为空时导出全部，返回导出的规则集数量。
+#[tauri::command]
+fn export_replace_rule_sets(
+    app: tauri::AppHandle,
+    path: String,
+    set_ids: Option<Vec<String>>,
+) -> Result<usize, String> {
+    let sets: Vec<ReplaceRuleSet> = read_replace_rule_sets(&app)?
+        .into_iter()
+        .filter(|set| set_ids.as_ref().is_none_or(|ids| ids.contains(&set.id)))
+        .collect();
+    let bytes =
+        serde_json::to_vec_pretty(&sets).map_err(|e| format!("序列化净化规则失败: {}", e))?;
+    fs::write(&path, bytes).map_err(|e| format!("写入规则文件失败: {}", e))?;
+    Ok(sets.len())
+}
+
+#[tauri::command]
+async fn apply_replace_rule_set_to_text(
+    app: tauri::AppHandle,
+    set_id: String,
+    content: String,
+    chapter_rules: Option<Vec<RegexRule>>,
+) -> Result<ReplaceRulesReport, String> {
+    let set = find_replace_rule_set(&app, &set_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let rules = compile_replace_rule_set(&set)?;
+        let mut hits = vec![0usize; rules.len()];
+        let content = apply_replace_rules_to_text(
+            &normalize_line_endings(content),
+            &rules,
+            chapter_rules.unwrap_or_else(mobile_default_chapter_rules),
+            &mut hits,
+        )?;
+        Ok(ReplaceRulesReport {
+            content,
+            total_hits: hits.iter().sum(),
+            changed_files: Vec::new(),
+            rules: replace_rule_hits(&set, &hits),
+        })
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+#[tauri::command]
+async fn apply_replace_rule_set_to_text_buffer(
+    app: tauri::AppHandle,
+    set_id: String,
+    buffer_id: String,
+    chapter_rules: Option<Vec<RegexRule>>,
+) -> Result<ReplaceRulesReport, String> {
+    let set = find_replace_rule_set(&app, &set_id)?;
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let rules = compile_replace_rule_set(&set)?;
+        let mut hits = vec![0usize; rules.len()];
+        let content = apply_replace_rules_to_text(
+            &lines.join("\n"),
+            &rules,
+            chapter_rules.unwrap_or_else(mobile_default_chapter_rules),
+            &mut hits,
+        )?;
+        let total_hits: usize = hits.iter().sum();
+        if total_hits > 0 {
+            let mut buffers = lock_text_buffers()?;
+            let buffer = buffers
+                .get_mut(&buffer_id)
+                .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+            if !Arc::ptr_eq(&buffer.lines, &lines) {
+                return Err("内容已变化，请重新应用净化规则".to_string());
+            }
+            buffer.lines = Arc::new(content.split('\n').map(str::to_string).collect());
+            buffer.modified = true;
+        }
+        Ok(ReplaceRulesReport {
+            content: String::new(),
+            total_hits,
+            changed_files: Vec::new(),
+            rules: replace_rule_hits(&set, &hits),
+        })
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
 #[tauri::command]
-fn toolbox_epub_diagnose(epub_path: String) -> Result<ToolboxEpubDiagnosticResult, String> {
-    let source = PathBuf::from(&epub_path);
-    if !source.exists() {
-        return Err(format!("File does not exist: {}", source.to_string_lossy()));
-    }
+async fn apply_replace_rule_set_to_epub(
+    app: tauri::AppHandle,
+    set_id: String,
+    epub_path: String,
+) -> Result<ReplaceRulesReport, String> {
+    let set = find_replace_rule_set(&app, &set_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let rules = compile_replace_rule_set(&set)?;
+        let mut hits = vec![0usize; rules.len()];
+        let mut error: Option<String> = None;
+        let updated = collect_cached_epub_text_rewrites(&epub_path, |path, text| {
+            if error.is_some() || !is_markup_text_entry(path) {
+                return None;
+            }
+            let is_toc = path.to_ascii_lowercase().ends_with(".ncx");
+            match apply_replace_rules_to_markup(text, is_toc, &rules, &mut hits) {
+                Ok(next) => (next != text).then_some(next),
+                Err(e) => {
+                    error = Some(e);
+                    None
+                }
+            }
+        })?;
+        // 任何规则出错都不落盘，避免规则集只应用了一半
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let changed_files = commit_cached_epub_text_rewrites(&epub_path, updated)?;
+        Ok(ReplaceRulesReport {
+            content: String::new(),
+            total_hits: hits.iter().sum(),
+            changed_files,
+            rules: replace_rule_hits(&set, &hits),
+        })
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-    let bytes = fs::read(&source).map_err(|e| format!("Read EPUB failed: {}", e))?;
-    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes.clone()))
-        .map_err(|e| format!("Read EPUB failed: {}", e))?;
-    let mut issues = Vec::new();
-    let mut entry_names = Vec::new();
+#[cfg(test)]
+mod replace_rule_set_tests {
+    use super::*;
 
-    for i in 0..archive.len() {
-        let file = archive
-            .by_index(i)
-            .map_err(|e| format!("Read ZIP entry failed: {}", e))?;
-        entry_names.push(file.name().replace('\\', "/"));
+    fn rule(pattern: &str, replacement: &str, scope: &str) -> ReplaceRule {
+        ReplaceRule {
+            id: String::new(),
+            name: pattern.to_string(),
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            is_regex: true,
+            case_insensitive: false,
+            enabled: true,
+            scope: scope.to_string(),
+        }
     }
 
-    let entry_set: HashSet<String> = entry_names.iter().cloned().collect();
-    let mut lower_to_names: HashMap<String, Vec<String>> = HashMap::new();
-    for name in &entry_names {
-        lower_to_names
-            .entry(name.to_ascii_lowercase())
-            .or_default()
-            .push(name.clone());
+    fn sample_set() -> ReplaceRuleSet {
+        let mut disabled = rule("正文", "X", "all");
+        disabled.enabled = false;
+        ReplaceRuleSet {
+            id: "clean".to_string(),
+            name: "清理".to_string(),
+            rules: vec![
+                rule(r"^.*(?i:www\.\w+\.com).*\n", "", "all"),
+                rule(r"\s*【[^】]*】$", "", "title"),
+                rule("。。", "。", "txt"),
+                disabled,
+                rule("旧", "新", "epub"),
+            ],
+        }
     }
 
-    if !entry_set.contains("mimetype") {
-        push_diag_issue(
-            &mut issues,
-            "warning",
-            "missing-mimetype",
-            Some("mimetype".to_string()),
-            "EPUB has no top-level mimetype entry.",
-        );
+    #[test]
+    fn applies_rules_in_order_with_scopes_and_hits() -> Result<(), String> {
+        let mut sets = vec![sample_set()];
+        normalize_replace_rule_sets(&mut sets)?;
+        assert!(sets[0].rules.iter().all(|rule| !rule.id.is_empty()));
+        let rules = compile_replace_rule_set(&sets[0])?;
+        let mut hits = vec![0usize; rules.len()];
+        let text = "第一章 开始【求票】\n正文。。旧\nWWW.ABC.COM 广告\n结尾【注】";
+        let output =
+            apply_replace_rules_to_text(text, &rules, mobile_default_chapter_rules(), &mut hits)?;
+        assert_eq!(output, "第一章 开始\n正文。旧\n结尾【注】");
+        assert_eq!(hits, vec![1, 1, 1, 0, 0]);
+        let report = replace_rule_hits(&sets[0], &hits);
+        assert_eq!(report.len(), 4);
+        assert_eq!(report[3].hits, 0);
+        Ok(())
     }
 
-    if !entry_set.contains("META-INF/container.xml") {
-        if let Some(matches) = case_mismatch_names(&lower_to_names, "META-INF/container.xml") {
-            push_diag_issue(
-                &mut issues,
-                "warning",
-                "case-mismatch",
-                Some("META-INF/container.xml".to_string()),
-                format!(
-                    "container.xml exists with different case: {}",
-                    matches.join(", ")
-                ),
-            );
-        } else {
-            push_diag_issue(
-                &mut issues,
-                "error",
-                "missing-container",
-                Some("META-INF/container.xml".to_string()),
-                "EPUB has no META-INF/container.xml entry.",
-            );
-        }
+    #[test]
+    fn epub_scope_touches_text_nodes_and_titles() -> Result<(), String> {
+        let rules = compile_replace_rule_set(&sample_set())?;
+        let mut hits = vec![0usize; rules.len()];
+        let html = r#"<h1>第一章 开始【求票】</h1><p title="旧">旧文。。</p>"#;
+        let output = apply_replace_rules_to_markup(html, false, &rules, &mut hits)?;
+        assert_eq!(output, r#"<h1>第一章 开始</h1><p title="旧">新文。。</p>"#);
+        assert_eq!(hits, vec![0, 1, 0, 0, 1]);
+
+        let import = parse_replace_rule_set_import(
+            r#"{"name":"单个","rules":[{"pattern":"a","scope":"txt"}]}"#.as_bytes(),
+        )?;
+        assert!(import.len() == 1 && import[0].rules[0].enabled);
+        assert!(parse_replace_rule_set_import(br#"{}"#).is_err());
+        assert!(parse_replace_rule_set_import(br#"{"version":2}"#).is_err());
+        assert!(parse_replace_rule_set_import(br#"[]"#).is_err());
+        let mut invalid = vec![ReplaceRuleSet {
+            id: String::new(),
+            name: String::new(),
+            rules: vec![rule("(", "", "all")],
+        }];
+        assert!(normalize_replace_rule_sets(&mut invalid).is_err());
+        let mut misspelled = vec![ReplaceRuleSet {
+            id: String::new(),
+            name: "拼错".to_string(),
+            rules: vec![rule("a", "b", "ePub"), rule("", "", "titel")],
+        }];
+        assert!(normalize_replace_rule_sets(&mut misspelled)
+            .is_err_and(|e| e.contains("作用范围无效: titel")));
+        assert!(compile_replace_rule(&rule("a", "b", "epubs")).is_err());
+        Ok(())
     }
 
-    let opf_path = match find_opf_path_in_epub_bytes(&bytes) {
-        Ok(path) => path,
-        Err(e) => {
-            push_diag_issue(
-                &mut issues,
-                "error",
-                "missing-opf",
-                None,
-                format!("Cannot locate OPF: {}", e),
-            );
-            let error_count = issues.iter().filter(|issue| issue.level == "error").count();
-            let warning_count = issues
-                .iter()
-                .filter(|issue| issue.level == "warning")
-                .count();
-            return Ok(ToolboxEpubDiagnosticResult {
-                source_path: source.to_string_lossy().to_string(),
-                opf_path: None,
-                total_entries: entry_names.len(),
-                manifest_items: 0,
-                error_count,
-                warning_count,
-                issues,
-            });
-        }
-    };
+    #[test]
+    fn epub_rules_match_decoded_text_and_escape_replacements() -> Result<(), String> {
+        let set = ReplaceRuleSet {
+            id: "amp".to_string(),
+            name: "实体".to_string(),
+            rules: vec![rule("甲乙", "A & B", "epub"), rule("&", "与", "epub")],
+        };
+        let rules = compile_replace_rule_set(&set)?;
+        let mut hits = vec![0usize; rules.len()];
+        let html = "<p>甲乙</p><p>猫 &amp; 狗&#160;&copy;</p><p>无关 &amp; 内容</p>";
+        let output = apply_replace_rules_to_markup(html, false, &rules, &mut hits)?;
+        // 第二条规则把第一条写入的 & 也替换掉，说明两条规则都作用在解码后的文字上
+        assert_eq!(
+            output,
+            "<p>A 与 B</p><p>猫 与 狗\u{a0}&copy;</p><p>无关 与 内容</p>"
+        );
+        assert_eq!(hits, vec![1, 3]);
 
-    if !entry_set.contains(&opf_path) {
-        if let Some(matches) = case_mismatch_names(&lower_to_names, &opf_path) {
-            push_diag_issue(
-                &mut issues,
-                "warning",
-                "case-mismatch",
-                Some(opf_path.clone()),
-                format!("OPF exists with different case: {}", matches.join(", ")),
-            );
-        } else {
-            push_diag_issue(
-                &mut issues,
-                "error",
-                "missing-opf",
-                Some(opf_path.clone()),
-                "container.xml points to an OPF entry that is not in the ZIP.",
-            );
-        }
+        let mut hits = vec![0usize; 1];
+        let rules = compile_replace_rule_set(&ReplaceRuleSet {
+            rules: vec![rule("甲乙", "A & B <c>", "epub")],
+            ..set
+        })?;
+        let output = apply_replace_rules_to_markup(
+            "<p>甲乙 &amp;</p><p>&lt;</p>",
+            false,
+            &rules,
+            &mut hits,
+        )?;
+        assert_eq!(output, "<p>A &amp; B &lt;c&gt; &amp;</p><p>&lt;</p>");
+        Ok(())
     }
+}
 
-    let manifest_items = match parse_toolbox_manifest_items(&bytes, &opf_path) {
-        Ok(items) => items,
-        Err(e) => {
-            push_diag_issue(
-                &mut issues,
-                "error",
-                "manifest-parse",
-                Some(opf_path.clone()),
-                format!("Parse OPF manifest failed: {}", e),
-            );
-            let error_count = issues.iter().filter(|issue| issue.level == "error").count();
-            let warning_count = issues
-                .iter()
-                .filter(|issue| issue.level == "warning")
-                .count();
-            return Ok(ToolboxEpubDiagnosticResult {
-                source_path: source.to_string_lossy().to_string(),
-                opf_path: Some(opf_path),
-                total_entries: entry_names.len(),
-                manifest_items: 0,
-                error_count,
-                warning_count,
-                issues,
-            });
-        }
-    };
+// --- 章节编号连续性检查 ---
 
-    let manifest_set: HashSet<String> = manifest_items
-        .iter()
-        .map(|item| item.abs_path.clone())
-        .collect();
-    let mut required_entries: HashSet<String> = HashSet::new();
-    required_entries.insert("mimetype".to_string());
-    required_entries.insert("META-INF/container.xml".to_string());
-    required_entries.insert(opf_path.clone());
+#[derive(Serialize, Clone)]
+struct ChapterNumberingIssue {
+    kind: String, // gap, duplicate, out_of_order, reset
+    level: u8,
+    line_number: usize,
+    title: String,
+    number: u64,
+    previous: Option<u64>,
+    expected: Option<u64>,
+    message: String,
+}
 
-    for item in &manifest_items {
-        if entry_set.contains(&item.abs_path) {
-            continue;
-        }
-        if let Some(matches) = case_mismatch_names(&lower_to_names, &item.abs_path) {
-            push_diag_issue(
-                &mut issues,
-                "warning",
-                "case-mismatch",
-                Some(item.abs_path.clone()),
-                format!(
-                    "Manifest item '{}' exists with different case: {}",
-                    item.id,
-                    matches.join(", ")
-                ),
-            );
-        } else {
-            push_diag_issue(
-                &mut issues,
-                "error",
-                "manifest-missing",
-                Some(item.abs_path.clone()),
-                format!("Manifest item '{}' points to a missing resource.", item.id),
-            );
-        }
-    }
+#[derive(Serialize)]
+struct ChapterNumberingReport {
+    issues: Vec<ChapterNumberingIssue>,
+    numbered_count: usize,
+    unnumbered_count: usize,
+    gap_count: usize,
+    duplicate_count: usize,
+    out_of_order_count: usize,
+    reset_count: usize,
+}
+
+#[derive(Default)]
+struct ChapterNumberingRun {
+    previous: Option<u64>,
+    seen: HashSet<u64>,
+    // 上一个编号之后出现过更高层级的标题（如新的一卷），允许从 1 重新编号
+    after_parent: bool,
+}
+
+// 每个层级单独成一条编号序列；遇到更高层级标题时，下级序列允许重置。
+fn check_chapter_numbering_impl(chapters: &[ChapterInfo]) -> ChapterNumberingReport {
+    let mut runs: HashMap<u8, ChapterNumberingRun> = HashMap::new();
+    let mut issues = Vec::new();
+    let mut numbered_count = 0usize;
+    let mut unnumbered_count = 0usize;
 
-    for name in &entry_names {
-        if manifest_set.contains(name) || required_entries.contains(name) {
+    for chapter in chapters {
+        if chapter.is_meta {
             continue;
         }
-        if is_diagnostic_resource_entry(name) {
-            push_diag_issue(
-                &mut issues,
-                "warning",
-                "unregistered-resource",
-                Some(name.clone()),
-                "Resource exists in ZIP but is not registered in OPF manifest.",
-            );
+        for (level, run) in runs.iter_mut() {
+            if *level > chapter.level {
+                run.after_parent = true;
+            }
         }
-    }
-
-    let mut seen_refs = HashSet::new();
-    for name in &entry_names {
-        if !is_text_like_entry(name) || name.ends_with('/') {
+        let Some((number, unit)) = chapter_number_from_title(&chapter.title) else {
+            unnumbered_count += 1;
             continue;
-        }
-        let data = match read_zip_entry_bytes(&bytes, name) {
-            Ok(data) => data,
-            Err(e) => {
-                push_diag_issue(
-                    &mut issues,
-                    "warning",
-                    "read-entry",
-                    Some(name.clone()),
-                    format!("Cannot read text-like entry: {}", e),
-                );
-                continue;
-            }
         };
-        let text = String::from_utf8_lossy(&data).to_string();
-        for raw_ref in collect_diagnostic_refs(&text) {
-            let Some(abs_ref) = resolve_epub_ref(name, &raw_ref) else {
-                continue;
-            };
-            if !seen_refs.insert((name.clone(), raw_ref.clone(), abs_ref.clone())) {
-                continue;
+        numbered_count += 1;
+        let unit = if unit.is_empty() {
+            "章".to_string()
+        } else {
+            unit
+        };
+        let run = runs.entry(chapter.level).or_default();
+        let mut push_issue = |kind: &str, expected: Option<u64>, message: String| {
+            issues.push(ChapterNumberingIssue {
+                kind: kind.to_string(),
+                level: chapter.level,
+                line_number: chapter.line_number,
+                title: chapter.title.clone(),
+                number,
+                previous: run.previous,
+                expected,
+                message,
+            });
+        };
+
+        match run.previous {
+            None => {
+                if number > 1 && !run.after_parent {
+                    push_issue(
+                        "gap",
+                        Some(1),
+                        format!(
+                            "编号从第 {} {} 开始，缺少前面的 {} 个",
+                            number,
+                            unit,
+                            number - 1
+                        ),
+                    );
+                }
             }
-            if entry_set.contains(&abs_ref) {
-                continue;
+            Some(previous) if number == previous + 1 => {}
+            Some(previous) if number == 1 && previous > 1 => {
+                let message = if run.after_parent {
+                    format!("新的上级标题后编号从 1 重新开始（上一编号 {}）", previous)
+                } else {
+                    format!("编号从 {} 重置为 1，中间没有上级标题", previous)
+                };
+                push_issue("reset", None, message);
+                run.seen.clear();
             }
-            if let Some(matches) = case_mismatch_names(&lower_to_names, &abs_ref) {
-                push_diag_issue(
-                    &mut issues,
-                    "warning",
-                    "ref-case-mismatch",
-                    Some(name.clone()),
-                    format!(
-                        "Reference '{}' resolves to '{}' but ZIP contains different case: {}",
-                        raw_ref,
-                        abs_ref,
-                        matches.join(", ")
-                    ),
+            // 只有一章的上一卷之后重新从 1 开始，不算重复
+            Some(previous) if run.after_parent && number == 1 => {
+                push_issue(
+                    "reset",
+                    None,
+                    format!("新的上级标题后编号从 1 重新开始（上一编号 {}）", previous),
                 );
-            } else {
-                push_diag_issue(
-                    &mut issues,
-                    "error",
-                    "missing-reference",
-                    Some(name.clone()),
-                    format!(
-                        "Reference '{}' resolves to missing resource '{}'.",
-                        raw_ref, abs_ref
-                    ),
+                run.seen.clear();
+            }
+            Some(previous) if number == previous || run.seen.contains(&number) => {
+                push_issue(
+                    "duplicate",
+                    Some(previous + 1),
+                    format!("第 {} {} 重复出现", number, unit),
+                );
+            }
+            Some(previous) if number > previous => {
+                let message = if number - previous == 2 {
+                    format!("缺少第 {} {}", previous + 1, unit)
+                } else {
+                    format!("缺少第 {}–{} {}", previous + 1, number - 1, unit)
+                };
+                push_issue("gap", Some(previous + 1), message);
+            }
+            Some(previous) => {
+                push_issue(
+                    "out_of_order",
+                    Some(previous + 1),
+                    format!("第 {} {} 出现在第 {} {} 之后", number, unit, previous, unit),
                 );
             }
         }
-    }
 
-    let error_count = issues.iter().filter(|issue| issue.level == "error").count();
-    let warning_count = issues
-        .iter()
-        .filter(|issue| issue.level == "warning")
-        .count();
+        run.seen.insert(number);
+        run.after_parent = false;
+        // 乱序的旧编号不作为后续比较基准，避免一处错位引出一串误报
+        if run
+            .previous
+            .is_none_or(|previous| number > previous || number == 1)
+        {
+            run.previous = Some(number);
+        }
+    }
 
-    Ok(ToolboxEpubDiagnosticResult {
-        source_path: source.to_string_lossy().to_string(),
-        opf_path: Some(opf_path),
-        total_entries: entry_names.len(),
-        manifest_items: manifest_items.len(),
-        error_count,
-        warning_count,
+    let count_kind = |kind: &str| issues.iter().filter(|i| i.kind == kind).count();
+    ChapterNumberingReport {
+        gap_count: count_kind("gap"),
+        duplicate_count: count_kind("duplicate"),
+        out_of_order_count: count_kind("out_of_order"),
+        reset_count: count_kind("reset"),
         issues,
-    })
+        numbered_count,
+        unnumbered_count,
+    }
 }
 
-fn toolbox_epub_diagnose_tool_result(source: &Path) -> Result<ToolboxEpubToolResult, String> {
-    let result = toolbox_epub_diagnose(source.to_string_lossy().to_string())?;
-    let action = if result.error_count > 0 || result.warning_count > 0 {
-        "epub_diagnose_issue"
-    } else {
-        "epub_diagnose_clean"
-    };
-    let message = if result.error_count == 0 && result.warning_count == 0 {
-        format!(
-            "EPUB diagnosis completed: no structural issues found; {} ZIP entries, {} manifest items",
-            result.total_entries, result.manifest_items
-        )
-    } else {
-        let mut lines = vec![format!(
-            "EPUB diagnosis completed: {} errors, {} warnings; {} ZIP entries, {} manifest items",
-            result.error_count, result.warning_count, result.total_entries, result.manifest_items
-        )];
-        for issue in result.issues.iter().take(6) {
-            let path = issue
-                .path
-                .as_deref()
-                .filter(|value| !value.is_empty())
-                .map(|value| format!(" [{}]", value))
-                .unwrap_or_default();
-            lines.push(format!(
-                "{} {}{}: {}",
-                issue.level, issue.kind, path, issue.message
-            ));
-        }
-        if result.issues.len() > 6 {
-            lines.push(format!("... and {} more issues", result.issues.len() - 6));
+// 直接接收 scan_chapters 的结果，TXT 字符串与大文件缓冲两种来源都能用。
+#[tauri::command]
+async fn check_chapter_numbering(chapters: Vec<ChapterInfo>) -> ChapterNumberingReport {
+    check_chapter_numbering_impl(&chapters)
+}
+
+#[cfg(test)]
+mod chapter_numbering_tests {
+    use super::*;
+
+    fn heading(title: &str, level: u8, line_number: usize) -> ChapterInfo {
+        ChapterInfo {
+            title: title.to_string(),
+            line_number,
+            level,
+            is_meta: false,
+            word_count: 0,
+            rule_index: None,
         }
-        lines.join("\n")
-    };
+    }
 
-    Ok(toolbox_epub_tool_result(
-        source,
-        source,
-        false,
-        action,
-        message,
-    ))
+    #[test]
+    fn parses_chinese_and_mixed_numerals() {
+        assert_eq!(parse_cjk_number("一百零三"), Some(103));
+        assert_eq!(parse_cjk_number("两千零五"), Some(2005));
+        assert_eq!(parse_cjk_number("十二"), Some(12));
+        assert_eq!(parse_cjk_number("一〇三"), Some(103));
+        assert_eq!(parse_cjk_number("１２"), Some(12));
+        assert_eq!(parse_cjk_number("三万零一"), Some(30001));
+        assert_eq!(parse_cjk_number("十2"), Some(12));
+        assert_eq!(parse_cjk_number("1百零3"), Some(103));
+        assert_eq!(
+            chapter_number_from_title("第一百零三章 归来"),
+            Some((103, "章".to_string()))
+        );
+        assert_eq!(
+            chapter_number_from_title("Chapter 7 Home"),
+            Some((7, String::new()))
+        );
+        assert_eq!(chapter_number_from_title("楔子"), None);
+    }
+
+    #[test]
+    fn reports_gaps_duplicates_and_out_of_order() {
+        let chapters = vec![
+            heading("第一章 甲", 3, 1),
+            heading("第二章 乙", 3, 5),
+            heading("第五章 丙", 3, 9),
+            heading("第五章 丙", 3, 13),
+            heading("第四章 丁", 3, 17),
+            heading("第六章 戊", 3, 21),
+        ];
+        let report = check_chapter_numbering_impl(&chapters);
+        let kinds: Vec<&str> = report.issues.iter().map(|i| i.kind.as_str()).collect();
+        assert_eq!(kinds, vec!["gap", "duplicate", "out_of_order"]);
+        assert_eq!(report.issues[0].expected, Some(3));
+        assert_eq!(report.issues[0].line_number, 9);
+        assert_eq!(report.numbered_count, 6);
+    }
+
+    #[test]
+    fn volume_boundaries_allow_resets() {
+        let chapters = vec![
+            heading("第一卷 起", 1, 1),
+            heading("第一章 甲", 3, 2),
+            heading("第二章 乙", 3, 4),
+            heading("第二卷 承", 1, 6),
+            heading("第一章 丙", 3, 7),
+            heading("第二章 丁", 3, 9),
+            heading("第一章 戊", 3, 11),
+        ];
+        let report = check_chapter_numbering_impl(&chapters);
+        assert_eq!(report.reset_count, 2);
+        assert!(report.issues[0].message.contains("新的上级标题"));
+        assert!(report.issues[1].message.contains("没有上级标题"));
+        assert_eq!(report.gap_count, 0);
+    }
+
+    #[test]
+    fn one_chapter_volume_is_not_a_duplicate() {
+        let chapters = vec![
+            heading("第一卷 起", 1, 1),
+            heading("第一章 甲", 3, 2),
+            heading("第二卷 承", 1, 4),
+            heading("第一章 乙", 3, 5),
+            heading("第二章 丙", 3, 7),
+            heading("第三卷 转", 1, 9),
+            heading("第一章 丁", 3, 10),
+        ];
+        let report = check_chapter_numbering_impl(&chapters);
+        assert_eq!(report.duplicate_count, 0);
+        assert_eq!(report.gap_count, 0);
+        assert_eq!(report.reset_count, 2);
+        assert_eq!(report.issues[0].line_number, 5);
+    }
+}
+
+// --- 章节标题规范化 ---
+
+#[derive(Deserialize, Debug, Clone)]
+struct ChapterTitleNormalizeOptions {
+    #[serde(default = "default_numeral_style")]
+    numeral_style: String, // keep, arabic, chinese
+    #[serde(default)]
+    zero_pad: usize,
+    #[serde(default = "default_title_separator")]
+    separator: String,
+    #[serde(default = "default_true")]
+    fix_width: bool,
 }
 
-#[tauri::command]
-async fn toolbox_scan_batch_inputs(
-    input_paths: Vec<String>,
-    output_dir: Option<String>,
-) -> Result<ToolboxBatchScanResult, String> {
-    tauri::async_runtime::spawn_blocking(move || {
-        let resolved_output_dir = resolve_batch_output_dir(&input_paths, output_dir);
-        let sources = list_epub_sources(&input_paths, &resolved_output_dir)?;
-        Ok(ToolboxBatchScanResult {
-            input_paths: sources
-                .into_iter()
-                .map(|path| path.to_string_lossy().to_string())
-                .collect(),
-            output_dir: resolved_output_dir.to_string_lossy().to_string(),
+fn default_numeral_style() -> String {
+    "keep".to_string()
+}
+
+fn default_title_separator() -> String {
+    " ".to_string()
+}
+
+#[derive(Serialize, Clone)]
+struct ChapterTitleChange {
+    line_number: usize,
+    before: String,
+    after: String,
+}
+
+#[derive(Serialize)]
+struct ChapterTitleNormalizeResult {
+    content: String,
+    chapters: Vec<ChapterInfo>,
+    changed_count: usize,
+    changes: Vec<ChapterTitleChange>,
+}
+
+static CHAPTER_TITLE_PARTS_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^第\s*([0-9０-９零〇一二两三四五六七八九十百千万亿]+)\s*([卷部集章回节])\s*[:：、.．\-—]*\s*(.*)$",
+    )
+    .expect("valid chapter title parts regex")
+});
+static CHAPTER_TITLE_EN_PARTS_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^(chapter)\s*(\d+)\s*[:：.．\-—]*\s*(.*)$")
+        .expect("valid english chapter title parts regex")
+});
+
+// 全角字母数字转半角，全角空格与连续空白合并为一个半角空格。
+fn fix_title_width(title: &str) -> String {
+    let converted: String = title
+        .chars()
+        .map(|c| match c {
+            '０'..='９' | 'Ａ'..='Ｚ' | 'ａ'..='ｚ' => {
+                char::from_u32(c as u32 - 0xFEE0).unwrap_or(c)
+            }
+            '\u{3000}' => ' ',
+            _ => c,
         })
-    })
-    .await
-    .map_err(|e| format!("批量目录扫描失败: {}", e))?
+        .collect();
+    converted.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn render_chapter_number(raw: &str, options: &ChapterTitleNormalizeOptions) -> String {
+    let parsed = parse_cjk_number(raw);
+    match (options.numeral_style.as_str(), parsed) {
+        ("arabic", Some(n)) => format!("{:0width$}", n, width = options.zero_pad),
+        ("chinese", Some(n)) => format_cjk_number(n),
+        _ => raw.to_string(),
+    }
+}
+
+fn normalize_chapter_title(title: &str, options: &ChapterTitleNormalizeOptions) -> String {
+    let base = if options.fix_width {
+        fix_title_width(title)
+    } else {
+        title.trim().to_string()
+    };
+    let join_name = |head: String, name: &str| {
+        let name = name.trim();
+        if name.is_empty() {
+            head
+        } else {
+            format!("{}{}{}", head, options.separator, name)
+        }
+    };
+
+    if let Ok(Some(caps)) = CHAPTER_TITLE_PARTS_RE.captures(&base) {
+        let raw = caps.get(1).map_or("", |m| m.as_str());
+        let unit = caps.get(2).map_or("", |m| m.as_str());
+        let name = caps.get(3).map_or("", |m| m.as_str());
+        return join_name(
+            format!("第{}{}", render_chapter_number(raw, options), unit),
+            name,
+        );
+    }
+    if let Ok(Some(caps)) = CHAPTER_TITLE_EN_PARTS_RE.captures(&base) {
+        let word = caps.get(1).map_or("Chapter", |m| m.as_str());
+        let raw = caps.get(2).map_or("", |m| m.as_str());
+        let name = caps.get(3).map_or("", |m| m.as_str());
+        // 英文标题保持阿拉伯数字，只应用补零
+        let number = match parse_cjk_number(raw) {
+            Some(n) if options.numeral_style != "keep" => {
+                format!("{:0width$}", n, width = options.zero_pad)
+            }
+            _ => raw.to_string(),
+        };
+        return join_name(format!("{} {}", word, number), name);
+    }
+    base
+}
+
+// 按 chapters 的行号改写标题行；标题行与当前内容不一致（章节列表已过期）时跳过。
+fn normalize_chapter_title_lines(
+    lines: &mut [String],
+    chapters: &mut [ChapterInfo],
+    options: &ChapterTitleNormalizeOptions,
+) -> Vec<ChapterTitleChange> {
+    let mut changes = Vec::new();
+    for chapter in chapters.iter_mut() {
+        let Some(line) = chapter
+            .line_number
+            .checked_sub(1)
+            .and_then(|index| lines.get_mut(index))
+        else {
+            continue;
+        };
+        if line.trim() != chapter.title.trim() {
+            continue;
+        }
+        let normalized = normalize_chapter_title(&chapter.title, options);
+        let trimmed = line.trim();
+        if normalized != trimmed {
+            changes.push(ChapterTitleChange {
+                line_number: chapter.line_number,
+                before: trimmed.to_string(),
+                after: normalized.clone(),
+            });
+            // 只替换标题本身，保留行首缩进与行尾空白
+            let start = line.len() - line.trim_start().len();
+            let end = start + trimmed.len();
+            line.replace_range(start..end, &normalized);
+        }
+        chapter.title = normalized;
+    }
+    changes
 }
 
 #[tauri::command]
-fn toolbox_cancel_batch(task_id: String) -> Result<(), String> {
-    if task_id.trim().is_empty() {
-        return Err("缺少批量任务 ID".to_string());
+async fn normalize_chapter_titles(
+    content: String,
+    mut chapters: Vec<ChapterInfo>,
+    options: ChapterTitleNormalizeOptions,
+) -> ChapterTitleNormalizeResult {
+    let content = normalize_line_endings(content);
+    let mut lines: Vec<String> = content.split('\n').map(str::to_string).collect();
+    let changes = normalize_chapter_title_lines(&mut lines, &mut chapters, &options);
+    ChapterTitleNormalizeResult {
+        content: if changes.is_empty() {
+            content
+        } else {
+            lines.join("\n")
+        },
+        chapters,
+        changed_count: changes.len(),
+        changes,
     }
-    request_toolbox_batch_cancel(&task_id);
-    Ok(())
 }
 
+// 大文件缓冲版本：直接改写缓冲中的标题行，返回的 content 为空。
 #[tauri::command]
-async fn toolbox_run_batch(
-    app: tauri::AppHandle,
-    task_id: String,
-    tool: String,
-    input_paths: Vec<String>,
-    image_format: Option<String>,
-    output_dir: Option<String>,
-    output_mode: Option<String>,
-    conflict_mode: Option<String>,
-) -> Result<ToolboxBatchSummary, String> {
-    tauri::async_runtime::spawn_blocking(move || {
-        run_toolbox_batch_impl(
-            app,
-            task_id,
-            tool,
-            input_paths,
-            image_format,
-            output_dir,
-            output_mode,
-            conflict_mode,
-        )
+fn normalize_text_buffer_chapter_titles(
+    buffer_id: String,
+    mut chapters: Vec<ChapterInfo>,
+    options: ChapterTitleNormalizeOptions,
+) -> Result<ChapterTitleNormalizeResult, String> {
+    let mut buffers = lock_text_buffers()?;
+    let buffer = buffers
+        .get_mut(&buffer_id)
+        .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+    let changes = normalize_chapter_title_lines(
+        Arc::make_mut(&mut buffer.lines).as_mut_slice(),
+        &mut chapters,
+        &options,
+    );
+    if !changes.is_empty() {
+        buffer.modified = true;
+    }
+    Ok(ChapterTitleNormalizeResult {
+        content: String::new(),
+        chapters,
+        changed_count: changes.len(),
+        changes,
     })
-    .await
-    .map_err(|e| format!("批量任务执行失败: {}", e))?
 }
 
-// ============================================================
-// ===== App Data Layout (绿色版 vs 安装版自动路由) =====
-// ============================================================
-//
-// 判定方式：exe 同级目录存在 `portable.txt` 标记文件即视为绿色版。
-// 绿色版数据目录：<exe_dir>/data/
-// 安装版数据目录：Tauri app_data_dir (%APPDATA%/<bundle-id>)
-//
-// 凡是落到这个目录的内容：app 配置、TXT 编辑器 .history、library.pointer 等。
-// 书库的 epub 副本与封面是另一回事，由用户在书库设置里指定的 customWorkDir 决定。
+#[cfg(test)]
+mod chapter_title_normalize_tests {
+    use super::*;
 
-fn portable_marker_path() -> Option<PathBuf> {
-    let exe = std::env::current_exe().ok()?;
-    let dir = exe.parent()?;
-    Some(dir.join("portable.txt"))
-}
+    fn options(style: &str, zero_pad: usize) -> ChapterTitleNormalizeOptions {
+        ChapterTitleNormalizeOptions {
+            numeral_style: style.to_string(),
+            zero_pad,
+            separator: " ".to_string(),
+            fix_width: true,
+        }
+    }
 
-fn is_portable() -> bool {
-    portable_marker_path().map(|p| p.exists()).unwrap_or(false)
-}
+    #[test]
+    fn formats_chinese_numerals() {
+        assert_eq!(format_cjk_number(103), "一百零三");
+        assert_eq!(format_cjk_number(12), "十二");
+        assert_eq!(format_cjk_number(110), "一百一十");
+        assert_eq!(format_cjk_number(2005), "二千零五");
+        assert_eq!(format_cjk_number(10001), "一万零一");
+        for n in [1, 10, 19, 101, 1010, 99999, 120_000_305] {
+            assert_eq!(parse_cjk_number(&format_cjk_number(n)), Some(n));
+        }
+    }
 
-fn portable_data_root() -> Option<PathBuf> {
-    let exe = std::env::current_exe().ok()?;
-    let dir = exe.parent()?;
-    Some(dir.join("data"))
-}
+    #[test]
+    fn normalizes_numbers_spacing_and_width() {
+        assert_eq!(
+            normalize_chapter_title("第一百零三章：归来", &options("arabic", 4)),
+            "第0103章 归来"
+        );
+        assert_eq!(
+            normalize_chapter_title("第１２章　　风起", &options("chinese", 0)),
+            "第十二章 风起"
+        );
+        assert_eq!(
+            normalize_chapter_title("第 3 卷", &options("keep", 0)),
+            "第3卷"
+        );
+        assert_eq!(
+            normalize_chapter_title("Chapter 7-Home", &options("arabic", 2)),
+            "Chapter 07 Home"
+        );
+        assert_eq!(
+            normalize_chapter_title("楔子", &options("arabic", 0)),
+            "楔子"
+        );
 
-fn app_data_root(app: &tauri::AppHandle) -> Result<PathBuf, String> {
-    if is_portable() {
-        if let Some(p) = portable_data_root() {
-            return Ok(p);
-        }
+        let keep_width = ChapterTitleNormalizeOptions {
+            fix_width: false,
+            ..options("arabic", 0)
+        };
+        assert_eq!(
+            normalize_chapter_title("第１２章：风起", &keep_width),
+            "第12章 风起"
+        );
+        assert_eq!(
+            normalize_chapter_title("Chapter ７", &keep_width),
+            "Chapter 7"
+        );
+    }
+
+    #[test]
+    fn rewrites_heading_lines_and_skips_stale_chapters() {
+        let mut lines: Vec<String> = ["第一章风起", "正文", "第2章 云涌"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut chapters = vec![
+            ChapterInfo {
+                title: "第一章风起".to_string(),
+                line_number: 1,
+                level: 3,
+                is_meta: false,
+                word_count: 2,
+                rule_index: None,
+            },
+            ChapterInfo {
+                title: "第二章 过期".to_string(),
+                line_number: 3,
+                level: 3,
+                is_meta: false,
+                word_count: 0,
+                rule_index: None,
+            },
+        ];
+        let changes =
+            normalize_chapter_title_lines(&mut lines, &mut chapters, &options("arabic", 0));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(lines[0], "第1章 风起");
+        assert_eq!(chapters[0].title, "第1章 风起");
+        assert_eq!(lines[2], "第2章 云涌");
     }
-    use tauri::Manager;
-    app.path()
-        .app_data_dir()
-        .map_err(|e| format!("无法获取 app_data_dir: {}", e))
-}
 
-fn epub_templates_root(app: &tauri::AppHandle) -> Result<PathBuf, String> {
-    Ok(app_data_root(app)?.join("epub-templates"))
+    #[test]
+    fn keeps_indentation_around_rewritten_titles() {
+        let mut lines = vec!["\u{3000}\u{3000}第一章：风起  ".to_string()];
+        let mut chapters = vec![ChapterInfo {
+            title: "第一章：风起".to_string(),
+            line_number: 1,
+            level: 3,
+            is_meta: false,
+            word_count: 0,
+            rule_index: None,
+        }];
+        let changes =
+            normalize_chapter_title_lines(&mut lines, &mut chapters, &options("arabic", 0));
+        assert_eq!(changes[0].before, "第一章：风起");
+        assert_eq!(lines[0], "\u{3000}\u{3000}第1章 风起  ");
+    }
 }
 
-fn epub_template_repositories_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
-    Ok(epub_templates_root(app)?.join("repositories.json"))
-}
+// --- TXT 硬换行重排 ---
 
-fn style_templates_root(app: &tauri::AppHandle) -> Result<PathBuf, String> {
-    Ok(app_data_root(app)?.join("style-templates"))
-}
+const REFLOW_WIDTH_TOLERANCE: usize = 2;
+const REFLOW_MIN_WIDTH: usize = 20;
+const REFLOW_MIN_LINES: usize = 8;
+const REFLOW_SAMPLE_LIMIT: usize = 20;
 
-fn builtin_style_template_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
-    Ok(style_templates_root(app)?.join("builtin.css"))
+#[derive(Deserialize, Clone, Default)]
+struct TextReflowOptions {
+    #[serde(default)]
+    dry_run: bool,
+    // 不填则自动识别折行宽度（半角列数，全角字符按 2 列计）
+    #[serde(default)]
+    wrap_width: Option<usize>,
+    // keep, fullwidth（两个全角空格）, none
+    #[serde(default)]
+    indent: Option<String>,
+    // 标题行不参与合并；为空时使用默认章节规则
+    #[serde(default)]
+    rules: Vec<RegexRule>,
 }
 
-fn style_template_path(app: &tauri::AppHandle, id: &str) -> Result<PathBuf, String> {
-    let safe_id = sanitize_filename_part(id);
-    if safe_id.is_empty() {
-        return Err("模板 ID 无效".to_string());
-    }
-    Ok(style_templates_root(app)?.join(format!("{}.css", safe_id)))
+#[derive(Serialize, Clone)]
+struct TextReflowSample {
+    line_number: usize,
+    line_count: usize,
+    text: String,
 }
 
-fn library_font_aliases_path(app: &tauri::AppHandle) -> Result<PathBuf, String> {
-    Ok(library_data_dir(app)?.join("font-aliases.json"))
+#[derive(Serialize)]
+struct TextReflowReport {
+    content: String,
+    wrap_width: Option<usize>,
+    paragraphs_before: usize,
+    paragraphs_after: usize,
+    changed_paragraphs: usize,
+    merged_lines: usize,
+    samples: Vec<TextReflowSample>,
 }
 
-fn read_library_font_aliases(app: &tauri::AppHandle) -> Result<LibraryFontAliasMap, String> {
-    let path = library_font_aliases_path(app)?;
-    if !path.exists() {
-        return Ok(HashMap::new());
-    }
-    let bytes = fs::read(&path).map_err(|e| format!("读取字体别名失败: {}", e))?;
-    serde_json::from_slice(&bytes).map_err(|e| format!("解析字体别名失败: {}", e))
+fn text_display_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| if (c as u32) < 0x1100 { 1 } else { 2 })
+        .sum()
 }
 
-fn write_library_font_aliases(
-    app: &tauri::AppHandle,
-    aliases: &LibraryFontAliasMap,
-) -> Result<(), String> {
-    let dir = library_data_dir(app)?;
-    ensure_dir(&dir)?;
-    let path = library_font_aliases_path(app)?;
-    let bytes =
-        serde_json::to_vec_pretty(aliases).map_err(|e| format!("序列化字体别名失败: {}", e))?;
-    fs::write(&path, bytes).map_err(|e| format!("保存字体别名失败: {}", e))
+// 折行宽度：大部分非标题行落在 [w-2, w] 区间，且几乎没有更长的行。
+fn detect_hard_wrap_width(widths: &[usize]) -> Option<usize> {
+    if widths.len() < REFLOW_MIN_LINES {
+        return None;
+    }
+    let mut histogram: HashMap<usize, usize> = HashMap::new();
+    for width in widths {
+        *histogram.entry(*width).or_insert(0) += 1;
+    }
+    let band = |w: usize| -> usize {
+        (w.saturating_sub(REFLOW_WIDTH_TOLERANCE)..=w)
+            .map(|x| histogram.get(&x).copied().unwrap_or(0))
+            .sum()
+    };
+    let (best, best_band) = histogram
+        .keys()
+        .filter(|w| **w >= REFLOW_MIN_WIDTH)
+        .map(|w| (*w, band(*w)))
+        .max_by_key(|(w, count)| (*count, *w))?;
+    let wider = widths
+        .iter()
+        .filter(|w| **w > best + REFLOW_WIDTH_TOLERANCE)
+        .count();
+    if best_band * 100 >= widths.len() * 35 && wider * 100 <= widths.len() * 5 {
+        Some(best)
+    } else {
+        None
+    }
 }
 
-fn style_template_name_from_path(path: &Path) -> String {
-    path.file_stem()
-        .and_then(|s| s.to_str())
-        .map(|s| s.replace('_', " ").replace('-', " "))
-        .filter(|s| !s.trim().is_empty())
-        .unwrap_or_else(|| "样式模板".to_string())
+fn ends_with_sentence_terminal(text: &str) -> bool {
+    text.trim_end().chars().last().is_some_and(|c| {
+        matches!(
+            c,
+            '。' | '！' | '？' | '…' | '”' | '」' | '』' | '.' | '!' | '?' | '"'
+        )
+    })
 }
 
-fn build_style_template_info(path: &Path, is_builtin: bool) -> Result<StyleTemplateInfo, String> {
-    let file_name = path
-        .file_name()
-        .and_then(|s| s.to_str())
-        .ok_or_else(|| "模板文件名无效".to_string())?
-        .to_string();
-    let id = if is_builtin {
-        "builtin".to_string()
+fn join_wrapped_line(paragraph: &mut String, next: &str) {
+    let next = next.trim();
+    let needs_space = paragraph
+        .chars()
+        .last()
+        .is_some_and(|c| c.is_ascii_alphanumeric() || c == ',' || c == '.')
+        && next
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric());
+    let trimmed_len = paragraph.trim_end().len();
+    paragraph.truncate(trimmed_len);
+    if needs_space {
+        paragraph.push(' ');
+    }
+    paragraph.push_str(next);
+}
+
+fn apply_paragraph_indent(line: &str, indent: &str) -> String {
+    let body = line.trim_start();
+    match indent {
+        "fullwidth" => format!("\u{3000}\u{3000}{}", body),
+        "none" => body.to_string(),
+        _ => line.to_string(),
+    }
+}
+
+fn reflow_text_lines(
+    lines: &[&str],
+    options: &TextReflowOptions,
+) -> (Vec<String>, TextReflowReport) {
+    let rules = if options.rules.is_empty() {
+        mobile_default_chapter_rules()
     } else {
-        path.file_stem()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_string())
-            .ok_or_else(|| "模板 ID 无效".to_string())?
+        options.rules.clone()
     };
-    Ok(StyleTemplateInfo {
-        id,
-        name: if is_builtin {
-            "内置模板".to_string()
-        } else {
-            style_template_name_from_path(path)
-        },
-        file_name,
-        path: path.to_string_lossy().to_string(),
-        is_builtin,
-    })
-}
-
-fn github_owner_repo(url: &str) -> Result<(String, String), String> {
-    let trimmed = url.trim().trim_end_matches('/');
-    let parsed = reqwest::Url::parse(trimmed).map_err(|e| format!("模板仓库 URL 无效: {}", e))?;
-    let host = parsed.host_str().unwrap_or_default().to_lowercase();
-    if host != "github.com" {
-        return Err("当前模板仓库仅支持 github.com".to_string());
-    }
-    let parts: Vec<&str> = parsed
-        .path_segments()
-        .map(|segments| segments.collect())
-        .unwrap_or_else(Vec::new);
-    if parts.len() < 2 {
-        return Err("GitHub 仓库 URL 需要包含 owner/repo".to_string());
-    }
-    let owner = parts[0].to_string();
-    let repo = parts[1].trim_end_matches(".git").to_string();
-    if owner.is_empty() || repo.is_empty() {
-        return Err("GitHub 仓库 URL 需要包含 owner/repo".to_string());
+    let compiled = compile_chapter_rules(rules);
+    // 标题判定放宽空行要求，只用来保护标题不被并入正文
+    let is_heading: Vec<bool> = lines
+        .iter()
+        .map(|line| {
+            let trim = line.trim();
+            !trim.is_empty()
+                && compiled
+                    .iter()
+                    .any(|rule| rule.matches(line, trim, true, true))
+        })
+        .collect();
+    let widths: Vec<usize> = lines
+        .iter()
+        .zip(&is_heading)
+        .filter(|(line, heading)| !**heading && !line.trim().is_empty())
+        .map(|(line, _)| text_display_width(line.trim_end()))
+        .collect();
+    let wrap_width = options
+        .wrap_width
+        .filter(|w| *w > 0)
+        .or_else(|| detect_hard_wrap_width(&widths));
+    // 有缩进的文本靠缩进判断段首；没有缩进时才退而参考句末标点
+    let uses_indent = lines
+        .iter()
+        .filter(|line| line.starts_with(char::is_whitespace) && !line.trim().is_empty())
+        .count()
+        >= 2;
+    let indent = options.indent.as_deref().unwrap_or("keep");
+
+    let mut output: Vec<String> = Vec::with_capacity(lines.len());
+    let mut samples = Vec::new();
+    let mut changed_paragraphs = 0usize;
+    let mut merged_lines = 0usize;
+    let mut paragraphs_after = 0usize;
+    let mut index = 0usize;
+    while index < lines.len() {
+        let line = lines[index];
+        if line.trim().is_empty() || is_heading[index] {
+            output.push(line.to_string());
+            index += 1;
+            continue;
+        }
+        let start = index;
+        let mut paragraph = line.trim_end().to_string();
+        let mut last = line;
+        index += 1;
+        if let Some(width) = wrap_width {
+            while index < lines.len() {
+                let next = lines[index];
+                let can_merge = !next.trim().is_empty()
+                    && !is_heading[index]
+                    && !next.starts_with(char::is_whitespace)
+                    && text_display_width(last.trim_end()) + REFLOW_WIDTH_TOLERANCE >= width
+                    && !is_ellipsis_paragraph(last)
+                    && !is_ellipsis_paragraph(next)
+                    && (uses_indent || !ends_with_sentence_terminal(last));
+                if !can_merge {
+                    break;
+                }
+                join_wrapped_line(&mut paragraph, next);
+                last = next;
+                index += 1;
+            }
+        }
+        let line_count = index - start;
+        let result = apply_paragraph_indent(&paragraph, indent);
+        if line_count > 1 || result != line {
+            changed_paragraphs += 1;
+            if samples.len() < REFLOW_SAMPLE_LIMIT {
+                samples.push(TextReflowSample {
+                    line_number: start + 1,
+                    line_count,
+                    text: result.chars().take(120).collect(),
+                });
+            }
+        }
+        merged_lines += line_count - 1;
+        paragraphs_after += 1;
+        output.push(result);
     }
-    Ok((owner, repo))
-}
 
-fn raw_github_url(repo: &EpubTemplateRepository, path: &str) -> Result<String, String> {
-    let (owner, name) = github_owner_repo(&repo.url)?;
-    let clean_path = path.trim_start_matches('/');
-    Ok(format!(
-        "https://raw.githubusercontent.com/{}/{}/{}/{}",
-        owner, name, repo.branch, clean_path
-    ))
+    let report = TextReflowReport {
+        content: String::new(),
+        wrap_width,
+        paragraphs_before: paragraphs_after + merged_lines,
+        paragraphs_after,
+        changed_paragraphs,
+        merged_lines,
+        samples,
+    };
+    (output, report)
 }
 
-fn github_tree_url(repo: &EpubTemplateRepository, dir: &str) -> Result<String, String> {
-    let (owner, name) = github_owner_repo(&repo.url)?;
-    let clean_dir = dir.trim_matches('/');
-    Ok(format!(
-        "https://api.github.com/repos/{}/{}/git/trees/{}:{}?recursive=1",
-        owner, name, repo.branch, clean_dir
-    ))
+#[tauri::command]
+async fn reflow_text(
+    content: String,
+    options: TextReflowOptions,
+) -> Result<TextReflowReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = normalize_line_endings(content);
+        let lines: Vec<&str> = content.split('\n').collect();
+        let (output, mut report) = reflow_text_lines(&lines, &options);
+        if !options.dry_run {
+            report.content = output.join("\n");
+        }
+        report
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
 }
 
-fn read_template_repositories(
-    app: &tauri::AppHandle,
-) -> Result<Vec<EpubTemplateRepository>, String> {
-    let path = epub_template_repositories_path(app)?;
-    if !path.exists() {
-        return Ok(Vec::new());
-    }
-    let bytes = fs::read(&path).map_err(|e| format!("读取模板仓库配置失败: {}", e))?;
-    serde_json::from_slice(&bytes).map_err(|e| format!("解析模板仓库配置失败: {}", e))
+// 大文件缓冲版本：非预演时直接替换缓冲内容，行号会变化，需重新扫描章节。
+#[tauri::command]
+async fn reflow_text_buffer(
+    buffer_id: String,
+    options: TextReflowOptions,
+) -> Result<TextReflowReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let lines = text_buffer_lines(&buffer_id)?;
+        let refs: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let (output, report) = reflow_text_lines(&refs, &options);
+        if !options.dry_run && report.changed_paragraphs > 0 {
+            let mut buffers = lock_text_buffers()?;
+            let buffer = buffers
+                .get_mut(&buffer_id)
+                .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+            if !Arc::ptr_eq(&buffer.lines, &lines) {
+                return Err("内容已变化，请重新执行段落重排".to_string());
+            }
+            buffer.lines = Arc::new(output);
+            buffer.modified = true;
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
-fn write_template_repositories(
-    app: &tauri::AppHandle,
-    repos: &[EpubTemplateRepository],
-) -> Result<(), String> {
-    let root = epub_templates_root(app)?;
-    ensure_dir(&root)?;
-    let path = epub_template_repositories_path(app)?;
-    let bytes =
-        serde_json::to_vec_pretty(repos).map_err(|e| format!("序列化模板仓库配置失败: {}", e))?;
-    fs::write(path, bytes).map_err(|e| format!("保存模板仓库配置失败: {}", e))
-}
+#[cfg(test)]
+mod text_reflow_tests {
+    use super::*;
 
-fn get_history_base_dir(app: Option<&tauri::AppHandle>) -> PathBuf {
-    // 优先使用书库工作目录下的 _data/history（书库已配置时）
-    if let Some(app) = app {
-        if let Ok(p) = library_history_dir(app) {
-            return p;
-        }
-    }
-    // 回退：绿色版 exe_dir/data/history
-    if is_portable() {
-        if let Some(p) = portable_data_root() {
-            return p.join("history");
-        }
-    }
-    // 回退：安装版 app_data_root/history
-    if let Some(app) = app {
-        if let Ok(p) = app_data_root(app) {
-            return p.join("history");
-        }
+    fn wrapped_sample() -> Vec<&'static str> {
+        vec![
+            "第一章 风起",
+            "　　天色将晚，山道上行人渐稀，远处的钟声一",
+            "下一下地传过来，像是在催促着什么人回家去，",
+            "又像是在送别。",
+            "　　他停下脚步，抬头望了一眼山顶的古寺，心",
+            "里默默地数着台阶，一级，两级，三级，直到数",
+            "不清楚为止。",
+            "",
+            "　　夜里下起了雨，檐下的灯笼被风吹得摇摇晃",
+            "晃，映得满院子都是碎光，像是撒了一地的金子",
+            "。",
+            "第二章 云涌",
+        ]
     }
-    // 终极兜底
-    PathBuf::from(".history")
-}
 
-fn history_key_for_path(original_path: &str) -> String {
-    // Keep names deterministic and avoid collisions for files that share the same stem.
-    let digest = format!("{:x}", md5::compute(original_path.as_bytes()));
-    digest[..8].to_string()
-}
+    #[test]
+    fn detects_width_and_merges_cjk_wraps() {
+        let (output, report) = reflow_text_lines(&wrapped_sample(), &TextReflowOptions::default());
+        assert_eq!(report.wrap_width, Some(42));
+        assert_eq!(report.paragraphs_after, 3);
+        assert_eq!(report.merged_lines, 6);
+        assert_eq!(output[0], "第一章 风起");
+        assert_eq!(
+            output[1],
+            "　　天色将晚，山道上行人渐稀，远处的钟声一下一下地传过来，像是在催促着什么人回家去，又像是在送别。"
+        );
+        assert_eq!(output[3], "");
+        assert!(output[4].ends_with("撒了一地的金子。"));
+        assert_eq!(output.last().map(String::as_str), Some("第二章 云涌"));
+    }
 
-fn pick_font_family_name(font_data: &[u8], source_path: &Path) -> String {
-    if let Some(name) = pick_preferred_font_name(parse_font_internal_names(font_data)) {
-        return name;
+    #[test]
+    fn leaves_unwrapped_text_alone_and_normalizes_indent() {
+        let lines = ["  第一段。", "第二段。", "第三段。"];
+        let options = TextReflowOptions {
+            indent: Some("fullwidth".to_string()),
+            ..Default::default()
+        };
+        let (output, report) = reflow_text_lines(&lines, &options);
+        assert_eq!(report.wrap_width, None);
+        assert_eq!(report.merged_lines, 0);
+        assert_eq!(report.changed_paragraphs, 3);
+        assert_eq!(output, vec!["　　第一段。", "　　第二段。", "　　第三段。"]);
     }
 
-    source_path
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .unwrap_or_else(|| "CustomFont".to_string())
+    #[test]
+    fn without_indentation_sentence_ends_break_paragraphs() {
+        let lines = [
+            "The quick brown fox jumps over the lazy",
+            "dog.",
+            "Another paragraph starts here and wraps at",
+            "the column limit.",
+        ];
+        let options = TextReflowOptions {
+            wrap_width: Some(40),
+            ..Default::default()
+        };
+        let (output, report) = reflow_text_lines(&lines, &options);
+        assert_eq!(report.merged_lines, 2);
+        assert_eq!(
+            output,
+            vec![
+                "The quick brown fox jumps over the lazy dog.",
+                "Another paragraph starts here and wraps at the column limit.",
+            ]
+        );
+    }
 }
 
-fn contains_cjk(text: &str) -> bool {
-    text.chars().any(|ch| {
-        matches!(
-            ch as u32,
-            0x3400..=0x4DBF
-                | 0x4E00..=0x9FFF
-                | 0xF900..=0xFAFF
-                | 0x20000..=0x2A6DF
-                | 0x2A700..=0x2B73F
-                | 0x2B740..=0x2B81F
-                | 0x2B820..=0x2CEAF
-                | 0x2CEB0..=0x2EBEF
-        )
-    })
+// --- 标点排版规范化 ---
+
+#[derive(Deserialize, Clone)]
+struct PunctuationNormalizeOptions {
+    // 中文语境下半角标点转全角：, . ; : ? ! ( )
+    #[serde(default = "default_true")]
+    full_width: bool,
+    // 直引号转弯引号；quote_style 为 curly（“”‘’）或 corner（「」『』）时统一为该风格，默认 keep
+    #[serde(default = "default_true")]
+    quotes: bool,
+    #[serde(default)]
+    quote_style: Option<String>,
+    // ... / 。。。 / ⋯ 等统一为 ……
+    #[serde(default = "default_true")]
+    ellipsis: bool,
+    // -- / — / ― 等统一为 ——
+    #[serde(default = "default_true")]
+    dash: bool,
+    // 删除中文字符之间的半角空格（全角空格视为有意排版，保留）
+    #[serde(default = "default_true")]
+    cjk_spacing: bool,
+    #[serde(default)]
+    dry_run: bool,
+    // 标题行不删空格；为空时使用默认章节规则
+    #[serde(default)]
+    rules: Vec<RegexRule>,
 }
 
-fn font_name_preference_score(name: &str) -> i32 {
-    let trimmed = name.trim();
-    if trimmed.is_empty() {
-        return i32::MIN;
+impl Default for PunctuationNormalizeOptions {
+    fn default() -> Self {
+        Self {
+            full_width: true,
+            quotes: true,
+            quote_style: None,
+            ellipsis: true,
+            dash: true,
+            cjk_spacing: true,
+            dry_run: false,
+            rules: Vec::new(),
+        }
     }
+}
 
-    let mut score = 0i32;
-    if contains_cjk(trimmed) {
-        score += 1000;
-    }
-    if trimmed.contains(' ') {
-        score += 40;
-    }
-    if trimmed.len() >= 2 {
-        score += 10;
-    }
-    if trimmed
-        .chars()
-        .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '+' | '.'))
-    {
-        score -= 120;
+#[derive(Serialize, Clone, Default)]
+struct PunctuationRuleCounts {
+    full_width: usize,
+    quotes: usize,
+    ellipsis: usize,
+    dash: usize,
+    cjk_spacing: usize,
+}
+
+impl PunctuationRuleCounts {
+    fn total(&self) -> usize {
+        self.full_width + self.quotes + self.ellipsis + self.dash + self.cjk_spacing
     }
-    if trimmed.eq_ignore_ascii_case("regular")
-        || trimmed.eq_ignore_ascii_case("bold")
-        || trimmed.eq_ignore_ascii_case("italic")
-    {
-        score -= 400;
+
+    fn add(&mut self, other: &PunctuationRuleCounts) {
+        self.full_width += other.full_width;
+        self.quotes += other.quotes;
+        self.ellipsis += other.ellipsis;
+        self.dash += other.dash;
+        self.cjk_spacing += other.cjk_spacing;
     }
-    score - trimmed.len() as i32 / 4
 }
 
-fn pick_preferred_font_name(names: Vec<String>) -> Option<String> {
-    names
-        .into_iter()
-        .filter(|name| !name.trim().is_empty())
-        .max_by_key(|name| font_name_preference_score(name))
+#[derive(Serialize)]
+struct PunctuationNormalizeReport {
+    content: String,
+    changed_lines: usize,
+    total_changes: usize,
+    counts: PunctuationRuleCounts,
 }
 
-fn build_library_font_info(
-    app: &tauri::AppHandle,
-    font_path: &Path,
-) -> Result<LibraryFontInfo, String> {
-    let font_data = fs::read(font_path).map_err(|e| format!("读取字体失败: {}", e))?;
-    let file_name = font_path
-        .file_name()
-        .and_then(|s| s.to_str())
-        .ok_or_else(|| "字体文件名无效".to_string())?
-        .to_string();
-    let aliases = read_library_font_aliases(app).unwrap_or_default();
-    let family = aliases
-        .get(&file_name)
-        .cloned()
-        .filter(|name| !name.trim().is_empty())
-        .unwrap_or_else(|| pick_font_family_name(&font_data, font_path));
-    Ok(LibraryFontInfo {
-        css_value: format!(r#""{}", serif"#, family),
-        family,
-        file_name,
-        path: font_path.to_string_lossy().to_string(),
-    })
+#[derive(Serialize)]
+struct EpubPunctuationReport {
+    changed_files: Vec<String>,
+    total_changes: usize,
+    counts: PunctuationRuleCounts,
 }
 
-#[tauri::command]
-fn list_epub_template_repositories(
-    app: tauri::AppHandle,
-) -> Result<Vec<EpubTemplateRepository>, String> {
-    read_template_repositories(&app)
+fn is_cjk_char(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F)
 }
 
-#[tauri::command]
-fn add_epub_template_repository(
-    app: tauri::AppHandle,
-    name: String,
-    url: String,
-    branch: Option<String>,
-) -> Result<Vec<EpubTemplateRepository>, String> {
-    let (owner, repo_name) = github_owner_repo(&url)?;
-    let fallback_name = format!("{}-{}", owner, repo_name);
-    let display_name = if name.trim().is_empty() {
-        fallback_name.clone()
-    } else {
-        name.trim().to_string()
-    };
-    let mut repo_id = sanitize_filename_part(&display_name);
-    if repo_id.trim().is_empty() {
-        repo_id = sanitize_filename_part(&fallback_name);
-    }
-    let mut branch_name = branch.unwrap_or_else(default_template_branch);
-    if branch_name.trim().is_empty() {
-        branch_name = default_template_branch();
-    }
+fn is_cjk_context_char(c: char) -> bool {
+    is_cjk_char(c)
+        || matches!(c as u32, 0x3000..=0x303F | 0xFF00..=0xFFEF)
+        || matches!(c, '“' | '”' | '‘' | '’' | '…' | '—')
+}
 
-    let mut repos = read_template_repositories(&app)?;
-    let repo = EpubTemplateRepository {
-        id: repo_id,
-        name: display_name,
-        url: url.trim().to_string(),
-        branch: branch_name.trim().to_string(),
-        last_synced: String::new(),
-    };
-    repos.retain(|item| item.id != repo.id && item.url != repo.url);
-    repos.push(repo);
-    write_template_repositories(&app, &repos)?;
-    Ok(repos)
+fn is_stray_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\u{a0}')
 }
 
-#[tauri::command]
-async fn sync_epub_template_repository(
-    app: tauri::AppHandle,
-    repository_id: String,
-) -> Result<EpubTemplateRepositoryIndex, String> {
-    let mut repos = read_template_repositories(&app)?;
-    let repo = repos
+fn prev_visible(chars: &[char], index: usize) -> Option<char> {
+    chars[..index]
         .iter()
-        .find(|item| item.id == repository_id)
-        .cloned()
-        .ok_or_else(|| "模板仓库不存在".to_string())?;
-    let index_url = raw_github_url(&repo, "index.json")?;
-    let client = reqwest::Client::builder()
-        .user_agent("TEpub-Editor")
-        .build()
-        .map_err(|e| format!("创建 HTTP 客户端失败: {}", e))?;
-    let text = client
-        .get(index_url)
-        .send()
-        .await
-        .map_err(|e| format!("拉取模板索引失败: {}", e))?
-        .error_for_status()
-        .map_err(|e| format!("模板索引响应异常: {}", e))?
-        .text()
-        .await
-        .map_err(|e| format!("读取模板索引失败: {}", e))?;
-    let index: EpubTemplateRepositoryIndex =
-        serde_json::from_str(&text).map_err(|e| format!("解析模板索引失败: {}", e))?;
-    for item in &mut repos {
-        if item.id == repository_id {
-            item.last_synced = chrono::Local::now().to_rfc3339();
-        }
-    }
-    write_template_repositories(&app, &repos)?;
-    Ok(index)
+        .rev()
+        .find(|c| !is_stray_space(**c))
+        .copied()
 }
 
-#[tauri::command]
-async fn install_remote_epub_template(
-    app: tauri::AppHandle,
-    repository_id: String,
-    template: EpubRemoteTemplate,
-) -> Result<EpubTemplateInstallResult, String> {
-    let repos = read_template_repositories(&app)?;
-    let repo = repos
+fn next_visible(chars: &[char], index: usize) -> Option<char> {
+    chars[index..]
         .iter()
-        .find(|item| item.id == repository_id)
-        .cloned()
-        .ok_or_else(|| "模板仓库不存在".to_string())?;
-    let template_dir = Path::new(&template.path)
-        .parent()
-        .and_then(|p| p.to_str())
-        .ok_or_else(|| "模板 path 需要指向 template.json".to_string())?
-        .replace('\\', "/");
-    let tree_url = github_tree_url(&repo, &template_dir)?;
-    let client = reqwest::Client::builder()
-        .user_agent("TEpub-Editor")
-        .build()
-        .map_err(|e| format!("创建 HTTP 客户端失败: {}", e))?;
-    let tree_text = client
-        .get(tree_url)
-        .send()
-        .await
-        .map_err(|e| format!("拉取模板文件树失败: {}", e))?
-        .error_for_status()
-        .map_err(|e| format!("模板文件树响应异常: {}", e))?
-        .text()
-        .await
-        .map_err(|e| format!("读取模板文件树失败: {}", e))?;
-    let tree_json: serde_json::Value =
-        serde_json::from_str(&tree_text).map_err(|e| format!("解析模板文件树失败: {}", e))?;
+        .find(|c| !is_stray_space(**c))
+        .copied()
+}
+
+fn normalize_ellipsis_runs(chars: &[char]) -> (Vec<char>, usize) {
+    let mut out = Vec::with_capacity(chars.len());
+    let mut count = 0usize;
+    let mut index = 0usize;
+    while index < chars.len() {
+        if !is_ellipsis_char(chars[index]) {
+            out.push(chars[index]);
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < chars.len() && is_ellipsis_char(chars[index]) {
+            index += 1;
+        }
+        let run: String = chars[start..index].iter().collect();
+        // 混有 。 的串（如“好。……”）按句号切开，句号原样保留
+        let segments: Vec<&str> = if run.chars().all(|c| c == '。') {
+            vec![run.as_str()]
+        } else {
+            run.split('。').collect()
+        };
+        for (i, segment) in segments.into_iter().enumerate() {
+            if i > 0 {
+                out.push('。');
+            }
+            if !is_ellipsis_run(segment) {
+                out.extend(segment.chars());
+                continue;
+            }
+            // 纯 … 的长串保留长度（补成偶数），其余一律为两个 …
+            let len = if segment.chars().all(|c| c == '…' || c == '⋯') {
+                segment.chars().count().div_ceil(2) * 2
+            } else {
+                2
+            };
+            let target = "…".repeat(len);
+            if target != segment {
+                count += 1;
+            }
+            out.extend(target.chars());
+        }
+    }
+    (out, count)
+}
 
-    let install_root = epub_templates_root(&app)?
-        .join("github")
-        .join(sanitize_filename_part(&repository_id))
-        .join(sanitize_filename_part(&template.id));
-    ensure_dir(&install_root)?;
-    let entries = tree_json
-        .get("tree")
-        .and_then(|v| v.as_array())
-        .ok_or_else(|| "GitHub tree 响应缺少 tree 字段".to_string())?;
+fn is_dash_char(c: char) -> bool {
+    matches!(c, '-' | '－' | '—' | '―' | '─')
+}
 
-    let mut file_count = 0usize;
-    for entry in entries {
-        if entry.get("type").and_then(|v| v.as_str()) != Some("blob") {
+fn normalize_dash_runs(chars: &[char]) -> (Vec<char>, usize) {
+    let mut out = Vec::with_capacity(chars.len());
+    let mut count = 0usize;
+    let mut index = 0usize;
+    while index < chars.len() {
+        if !is_dash_char(chars[index]) {
+            out.push(chars[index]);
+            index += 1;
             continue;
         }
-        let Some(path) = entry.get("path").and_then(|v| v.as_str()) else {
-            continue;
-        };
-        let relative = path
-            .trim_start_matches(&template_dir)
-            .trim_start_matches('/')
-            .replace('\\', "/");
-        if relative.is_empty() || relative.contains("..") {
+        let start = index;
+        while index < chars.len() && is_dash_char(chars[index]) {
+            index += 1;
+        }
+        let run = &chars[start..index];
+        let is_em_pairs = run.iter().all(|c| *c == '—') && run.len().is_multiple_of(2);
+        // 单个半角连字符是正常的连字用法，不处理
+        let is_hyphen = run == ['-'];
+        let cjk_side = start
+            .checked_sub(1)
+            .map(|i| is_cjk_context_char(chars[i]))
+            .unwrap_or(true)
+            || chars.get(index).is_none_or(|c| is_cjk_context_char(*c));
+        if is_em_pairs || is_hyphen || !cjk_side {
+            out.extend_from_slice(run);
             continue;
         }
-        let raw_url = raw_github_url(&repo, path)?;
-        let bytes = client
-            .get(raw_url)
-            .send()
-            .await
-            .map_err(|e| format!("下载模板文件失败 {}: {}", relative, e))?
-            .error_for_status()
-            .map_err(|e| format!("模板文件响应异常 {}: {}", relative, e))?
-            .bytes()
-            .await
-            .map_err(|e| format!("读取模板文件失败 {}: {}", relative, e))?;
-        let target = install_root.join(relative);
-        if let Some(parent) = target.parent() {
-            ensure_dir(parent)?;
+        out.extend(['—', '—']);
+        count += 1;
+    }
+    (out, count)
+}
+
+fn normalize_full_width_punctuation(chars: &[char]) -> (Vec<char>, usize) {
+    let mut out = Vec::with_capacity(chars.len());
+    let mut count = 0usize;
+    let mut parens: Vec<bool> = Vec::new();
+    let mut index = 0usize;
+    while index < chars.len() {
+        let c = chars[index];
+        let prev = prev_visible(chars, index);
+        let next = next_visible(chars, index + 1);
+        let prev_cjk = prev.is_some_and(is_cjk_context_char);
+        let next_cjk = next.is_some_and(is_cjk_context_char);
+        let converted = match c {
+            ',' | ';' | ':' | '?' | '!'
+                if prev_cjk || (next_cjk && !prev.is_some_and(|p| p.is_ascii_alphanumeric())) =>
+            {
+                Some(match c {
+                    ',' => '，',
+                    ';' => '；',
+                    ':' => '：',
+                    '?' => '？',
+                    _ => '！',
+                })
+            }
+            '.' if prev.is_some_and(is_cjk_char)
+                && !chars
+                    .get(index + 1)
+                    .is_some_and(|n| n.is_ascii_alphanumeric()) =>
+            {
+                Some('。')
+            }
+            '(' => {
+                let cjk = next.is_some_and(is_cjk_char);
+                parens.push(cjk);
+                cjk.then_some('（')
+            }
+            ')' => {
+                let opened = parens.pop().unwrap_or(false);
+                (opened || prev.is_some_and(is_cjk_char)).then_some('）')
+            }
+            _ => None,
+        };
+        match converted {
+            Some(full) => {
+                out.push(full);
+                count += 1;
+                index += 1;
+                // 全角标点自带间距，吞掉其后的半角空格
+                if full != '（' && (next_cjk || next.is_none()) {
+                    while index < chars.len() && is_stray_space(chars[index]) {
+                        index += 1;
+                    }
+                }
+            }
+            None => {
+                out.push(c);
+                index += 1;
+            }
         }
-        fs::write(&target, &bytes).map_err(|e| format!("写入模板文件失败: {}", e))?;
-        file_count += 1;
     }
-
-    Ok(EpubTemplateInstallResult {
-        template_id: template.id,
-        local_path: install_root.to_string_lossy().to_string(),
-        file_count,
-    })
+    (out, count)
 }
 
-// --- 指令区域 ---
-
-#[tauri::command]
-fn exit_app() {
-    process::exit(0);
+fn normalize_quotes(chars: &[char], style: &str) -> (Vec<char>, usize) {
+    let (double_open, double_close, single_open, single_close) = if style == "corner" {
+        ('「', '」', '『', '』')
+    } else {
+        ('“', '”', '‘', '’')
+    };
+    // 夹在两个西文字母之间的 ’ 是撇号（don’t），不算作引号
+    let is_apostrophe = |index: usize| {
+        index > 0
+            && chars[index - 1].is_ascii_alphabetic()
+            && chars
+                .get(index + 1)
+                .is_some_and(|n| n.is_ascii_alphabetic())
+    };
+    let single_closes = (0..chars.len())
+        .filter(|i| chars[*i] == '’' && !is_apostrophe(*i))
+        .count();
+    let single_balanced = chars.iter().filter(|c| **c == '‘').count() == single_closes;
+    let mut out = Vec::with_capacity(chars.len());
+    let mut count = 0usize;
+    let mut straight_open = true;
+    for (index, c) in chars.iter().copied().enumerate() {
+        let converted = match (style, c) {
+            (_, '"') => {
+                let quote = if straight_open {
+                    double_open
+                } else {
+                    double_close
+                };
+                straight_open = !straight_open;
+                Some(quote)
+            }
+            ("curly", '「') | ("corner", '“') => Some(double_open),
+            ("curly", '」') | ("corner", '”') => Some(double_close),
+            ("curly", '『') => Some(single_open),
+            ("curly", '』') => Some(single_close),
+            ("corner", '‘') if single_balanced => Some(single_open),
+            ("corner", '’') if single_balanced && !is_apostrophe(index) => Some(single_close),
+            _ => None,
+        };
+        match converted {
+            Some(quote) if quote != c => {
+                out.push(quote);
+                count += 1;
+            }
+            _ => out.push(c),
+        }
+    }
+    (out, count)
 }
 
-#[tauri::command]
-fn read_text_file(path: String) -> Result<String, String> {
-    let mut file = fs::File::open(&path).map_err(|e| format!("无法打开: {}", e))?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .map_err(|e| format!("读取失败: {}", e))?;
-
-    // 策略：尝试多种编码，选取"乱码"（替换字符 ）最少的一个
-    let candidates = vec![
-        ("utf-8", encoding_rs::UTF_8),
-        ("gb18030", encoding_rs::GB18030),
-        ("utf-16le", encoding_rs::UTF_16LE),
-        ("utf-16be", encoding_rs::UTF_16BE),
-        ("big5", encoding_rs::BIG5),
-    ];
-
-    // 1. 优先尝试 UTF-8 (严格)
-    if let Ok(s) = String::from_utf8(buffer.clone()) {
-        return Ok(normalize_line_endings(s));
+fn strip_cjk_spacing(chars: &[char]) -> (Vec<char>, usize) {
+    let mut out = Vec::with_capacity(chars.len());
+    let mut count = 0usize;
+    let mut index = 0usize;
+    while index < chars.len() {
+        if !is_stray_space(chars[index]) || out.is_empty() {
+            out.push(chars[index]);
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < chars.len() && is_stray_space(chars[index]) {
+            index += 1;
+        }
+        let between_cjk = out.last().copied().is_some_and(is_cjk_context_char)
+            && chars.get(index).copied().is_some_and(is_cjk_context_char);
+        if between_cjk {
+            count += 1;
+        } else {
+            out.extend_from_slice(&chars[start..index]);
+        }
     }
+    (out, count)
+}
 
-    // 2. Chardetng 检测作为基准
-    let mut detector = EncodingDetector::new();
-    detector.feed(&buffer, true);
-    let detected_encoding = detector.guess(Some(b"cn"), true);
-
-    let (cow_detected, _, malformed_detected) = detected_encoding.decode(&buffer);
-    let errors_detected = cow_detected.chars().filter(|&c| c == '\u{FFFD}').count();
-
-    let mut best_content = cow_detected.into_owned();
-    let mut min_errors = if malformed_detected {
-        errors_detected
+// 单行处理；标点规则只在含中文的行上生效，避免误改纯西文内容。
+fn normalize_punctuation_line(
+    line: &str,
+    options: &PunctuationNormalizeOptions,
+    is_heading: bool,
+    counts: &mut PunctuationRuleCounts,
+) -> String {
+    if !line.chars().any(is_cjk_char) {
+        return line.to_string();
+    }
+    let mut chars: Vec<char> = line.chars().collect();
+    if options.ellipsis {
+        let (next, count) = normalize_ellipsis_runs(&chars);
+        chars = next;
+        counts.ellipsis += count;
+    }
+    if options.dash {
+        let (next, count) = normalize_dash_runs(&chars);
+        chars = next;
+        counts.dash += count;
+    }
+    if options.full_width {
+        let (next, count) = normalize_full_width_punctuation(&chars);
+        chars = next;
+        counts.full_width += count;
+    }
+    if options.quotes {
+        let style = options.quote_style.as_deref().unwrap_or("keep");
+        let (next, count) = normalize_quotes(&chars, style);
+        chars = next;
+        counts.quotes += count;
+    }
+    if options.cjk_spacing && !is_heading {
+        let (next, count) = strip_cjk_spacing(&chars);
+        chars = next;
+        counts.cjk_spacing += count;
+    }
+    chars.into_iter().collect()
+}
+
+fn normalize_punctuation_lines(
+    lines: &[&str],
+    options: &PunctuationNormalizeOptions,
+) -> (Vec<String>, PunctuationNormalizeReport) {
+    let rules = if options.rules.is_empty() {
+        mobile_default_chapter_rules()
     } else {
-        0
+        options.rules.clone()
+    };
+    let compiled = compile_chapter_rules(rules);
+    let mut counts = PunctuationRuleCounts::default();
+    let mut changed_lines = 0usize;
+    let output: Vec<String> = lines
+        .iter()
+        .map(|line| {
+            let trim = line.trim();
+            let is_heading = !trim.is_empty()
+                && compiled
+                    .iter()
+                    .any(|rule| rule.matches(line, trim, true, true));
+            let next = normalize_punctuation_line(line, options, is_heading, &mut counts);
+            if next != *line {
+                changed_lines += 1;
+            }
+            next
+        })
+        .collect();
+    let report = PunctuationNormalizeReport {
+        content: String::new(),
+        changed_lines,
+        total_changes: counts.total(),
+        counts,
     };
-    let mut best_encoding = detected_encoding.name();
+    (output, report)
+}
 
-    // 如果检测结果完美且不是 windows-1252 (容易误判)，直接返回
-    if min_errors == 0 && best_encoding != "windows-1252" && best_encoding != "ISO-8859-1" {
-        return Ok(normalize_line_endings(best_content));
-    }
+#[tauri::command]
+async fn normalize_punctuation(
+    content: String,
+    options: PunctuationNormalizeOptions,
+) -> Result<PunctuationNormalizeReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let content = normalize_line_endings(content);
+        let lines: Vec<&str> = content.split('\n').collect();
+        let (output, mut report) = normalize_punctuation_lines(&lines, &options);
+        if !options.dry_run {
+            report.content = output.join("\n");
+        }
+        report
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
+}
 
-    // 3. 遍历候选编码打擂台
-    for (name, enc) in candidates {
-        let (cow, _, _) = enc.decode(&buffer);
-        let content = cow.into_owned();
-        let errors = content.chars().filter(|&c| c == '\u{FFFD}').count();
+#[tauri::command]
+async fn normalize_text_buffer_punctuation(
+    buffer_id: String,
+    options: PunctuationNormalizeOptions,
+) -> Result<PunctuationNormalizeReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let lines = text_buffer_lines(&buffer_id)?;
+        let refs: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let (output, report) = normalize_punctuation_lines(&refs, &options);
+        if !options.dry_run && report.changed_lines > 0 {
+            let mut buffers = lock_text_buffers()?;
+            let buffer = buffers
+                .get_mut(&buffer_id)
+                .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+            if !Arc::ptr_eq(&buffer.lines, &lines) {
+                return Err("内容已变化，请重新执行标点规范化".to_string());
+            }
+            buffer.lines = Arc::new(output);
+            buffer.modified = true;
+        }
+        Ok(report)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-        // 优选错误更少的。
-        // 特判：如果 best 是 windows-1252 (常见误判)，只要 candidates 里有 reasonably low error (<10%) 的中文编码，就替换它
-        let is_current_bad_guess = best_encoding == "windows-1252" || best_encoding == "ISO-8859-1";
+const PUNCTUATION_SKIP_ELEMENTS: [&str; 4] = ["code", "pre", "kbd", "samp"];
 
-        if errors < min_errors || (is_current_bad_guess && errors < buffer.len() / 20) {
-            min_errors = errors;
-            best_content = content;
-            best_encoding = name;
+// EPUB 只处理 XHTML 正文文本节点；标题元素内不删空格，代码类元素整体跳过。
+fn normalize_markup_punctuation(
+    markup: &str,
+    options: &PunctuationNormalizeOptions,
+    counts: &mut PunctuationRuleCounts,
+) -> String {
+    map_markup_text_nodes_in_context(markup, |text, _, elements| {
+        if elements
+            .iter()
+            .any(|e| PUNCTUATION_SKIP_ELEMENTS.contains(&e.name.as_str()))
+        {
+            return text.to_string();
         }
-    }
-
-    // println!("Selected encoding: {} (errors: {})", best_encoding, min_errors);
-    Ok(normalize_line_endings(best_content))
+        let is_heading = elements.iter().any(|e| {
+            matches!(
+                e.name.as_str(),
+                "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "title"
+            )
+        });
+        text.split('\n')
+            .map(|line| normalize_punctuation_line(line, options, is_heading, counts))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
 }
 
 #[tauri::command]
-fn prepare_epub_for_open(
-    app: tauri::AppHandle,
+async fn normalize_epub_punctuation(
     epub_path: String,
-) -> Result<EpubPrepareResult, String> {
-    let source = PathBuf::from(&epub_path);
-    if !source.exists() {
-        return Err(format!("文件不存在: {}", epub_path));
-    }
+    options: PunctuationNormalizeOptions,
+) -> Result<EpubPunctuationReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut counts = PunctuationRuleCounts::default();
+        let updated = collect_cached_epub_text_rewrites(&epub_path, |path, text| {
+            let lower = path.to_ascii_lowercase();
+            if lower.ends_with(".ncx") || !is_markup_text_entry(path) {
+                return None;
+            }
+            let mut file_counts = PunctuationRuleCounts::default();
+            let next = normalize_markup_punctuation(text, &options, &mut file_counts);
+            counts.add(&file_counts);
+            (next != text).then_some(next)
+        })?;
+        // 预览与实际写入走同一遍历，预览只是不落盘
+        let changed_files = if options.dry_run {
+            let mut files: Vec<String> = updated.into_iter().map(|(path, _)| path).collect();
+            files.sort();
+            files
+        } else {
+            commit_cached_epub_text_rewrites(&epub_path, updated)?
+        };
+        Ok(EpubPunctuationReport {
+            changed_files,
+            total_changes: counts.total(),
+            counts,
+        })
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-    emit_epub_prepare_stage(&app, &source, "准备 EPUB 文件");
+#[cfg(test)]
+mod punctuation_normalize_tests {
+    use super::*;
 
-    // Case 1: valid ZIP/EPUB, only repair pseudo-encryption bit if needed.
-    if is_zip_archive_path(&source) {
-        emit_epub_prepare_stage(&app, &source, "读取 EPUB 文件");
-        let mut bytes = read_epub_bytes(&source)?;
-        emit_epub_prepare_stage(&app, &source, "检查伪加密标记");
-        let fixed_encryption_flag = clear_zip_encryption_flags(&mut bytes);
+    fn normalize(
+        line: &str,
+        options: &PunctuationNormalizeOptions,
+    ) -> (String, PunctuationRuleCounts) {
+        let mut counts = PunctuationRuleCounts::default();
+        let out = normalize_punctuation_line(line, options, false, &mut counts);
+        (out, counts)
+    }
 
-        let rebuilt = {
-            let mut progress = |message: &str| {
-                emit_epub_prepare_stage(&app, &source, message);
-            };
-            rebuild_epub_with_sanitized_names_from_bytes_with_progress(
-                &source,
-                &bytes,
-                "_deobf",
-                &mut progress,
-            )?
-        };
+    #[test]
+    fn converts_half_width_punctuation_in_cjk_context() {
+        let options = PunctuationNormalizeOptions::default();
+        let (out, counts) = normalize("你好, 世界!他说:(真的)吗? 版本 v1.2, ok.", &options);
+        assert_eq!(out, "你好，世界！他说：（真的）吗？版本 v1.2, ok.");
+        assert_eq!(counts.full_width, 6);
+        let (out, _) = normalize("plain, english.", &options);
+        assert_eq!(out, "plain, english.");
+    }
 
-        if let Some(out_path) = rebuilt {
-            return Ok(EpubPrepareResult {
-                source_path: source.to_string_lossy().to_string(),
-                processed_path: out_path.to_string_lossy().to_string(),
-                changed: true,
-                action: if fixed_encryption_flag {
-                    "已自动解除伪加密并修复文件名混淆".to_string()
-                } else {
-                    "已自动修复文件名混淆".to_string()
-                },
-            });
-        }
+    #[test]
+    fn normalizes_ellipsis_dash_and_spacing() {
+        let options = PunctuationNormalizeOptions::default();
+        let (out, counts) = normalize("他想了想...又说。。。 不 对⋯就这样--走吧—", &options);
+        assert_eq!(out, "他想了想……又说……不对……就这样——走吧——");
+        assert_eq!(counts.ellipsis, 3);
+        assert_eq!(counts.dash, 2);
+        assert_eq!(counts.cjk_spacing, 2);
+        let (out, counts) = normalize("等等…………还有——", &options);
+        assert_eq!(out, "等等…………还有——");
+        assert_eq!(counts.total(), 0);
+        assert!(is_ellipsis_run("。。。"));
+        assert!(!is_ellipsis_run("。。"));
+        assert!(!is_ellipsis_run("。……"));
+        let (out, counts) = normalize("好。……再说。...", &options);
+        assert_eq!(out, "好。……再说。……");
+        assert_eq!(counts.ellipsis, 1);
+    }
 
-        if fixed_encryption_flag {
-            emit_epub_prepare_stage(&app, &source, "写出伪加密修复文件");
-            let out_path = build_processed_epub_path(&source, "_decrypted");
-            fs::write(&out_path, &bytes).map_err(|e| format!("写入修复文件失败: {}", e))?;
-            return Ok(EpubPrepareResult {
-                source_path: source.to_string_lossy().to_string(),
-                processed_path: out_path.to_string_lossy().to_string(),
-                changed: true,
-                action: "已自动解除伪加密".to_string(),
-            });
-        }
-        emit_epub_prepare_stage(&app, &source, "EPUB 无需预处理");
-        return Ok(EpubPrepareResult {
-            source_path: source.to_string_lossy().to_string(),
-            processed_path: source.to_string_lossy().to_string(),
-            changed: false,
-            action: "无需处理".to_string(),
-        });
+    #[test]
+    fn unifies_quote_styles() {
+        let mut options = PunctuationNormalizeOptions::default();
+        let (out, _) = normalize("他说\"走吧\"，「好」。", &options);
+        assert_eq!(out, "他说“走吧”，「好」。");
+        options.quote_style = Some("curly".to_string());
+        let (out, counts) = normalize("他说「走吧『嗯』」。", &options);
+        assert_eq!(out, "他说“走吧‘嗯’”。");
+        assert_eq!(counts.quotes, 4);
+        options.quote_style = Some("corner".to_string());
+        let (out, counts) = normalize("他说“走吧‘嗯’”，don’t 中文", &options);
+        assert_eq!(out, "他说「走吧『嗯』」，don’t 中文");
+        assert_eq!(counts.quotes, 4);
     }
 
-    // Case 2: try simple XOR obfuscation recovery.
-    emit_epub_prepare_stage(&app, &source, "检测非标准混淆格式");
-    let bytes = read_epub_bytes(&source)?;
-    let mut candidate_keys: Vec<u8> = vec![0xFF, 0xA5, 0x5A];
-    if bytes.len() >= 4 {
-        let k0 = bytes[0] ^ 0x50;
-        if (bytes[1] ^ 0x4B) == k0 && (bytes[2] ^ 0x03) == k0 && (bytes[3] ^ 0x04) == k0 {
-            if !candidate_keys.contains(&k0) {
-                candidate_keys.insert(0, k0);
-            }
-        }
+    #[test]
+    fn respects_toggles_headings_and_markup() {
+        let options = PunctuationNormalizeOptions {
+            full_width: false,
+            dash: false,
+            ..PunctuationNormalizeOptions::default()
+        };
+        let lines = ["第一章 风起", "你好, 世界--再见", "他 说"];
+        let (output, report) = normalize_punctuation_lines(&lines, &options);
+        assert_eq!(output, vec!["第一章 风起", "你好, 世界--再见", "他说"]);
+        assert_eq!(report.changed_lines, 1);
+        assert_eq!(report.total_changes, 1);
+
+        let mut counts = PunctuationRuleCounts::default();
+        let html = r#"<h2>第一章 风起</h2><p class="a b">他 说...<code>a ... b 中</code></p>"#;
+        let out = normalize_markup_punctuation(
+            html,
+            &PunctuationNormalizeOptions::default(),
+            &mut counts,
+        );
+        assert_eq!(
+            out,
+            r#"<h2>第一章 风起</h2><p class="a b">他说……<code>a ... b 中</code></p>"#
+        );
+        assert_eq!(counts.total(), 2);
     }
+}
 
-    for key in candidate_keys {
-        emit_epub_prepare_stage(&app, &source, "尝试 XOR 解混淆");
-        let mut decoded = xor_bytes(&bytes, key);
-        emit_epub_prepare_stage(&app, &source, "检查解混淆后的 ZIP 标记");
-        let fixed_encryption_flag = clear_zip_encryption_flags(&mut decoded);
-        if is_zip_archive_bytes(&decoded) {
-            let suffix = if fixed_encryption_flag {
-                "_deobf_decrypted"
-            } else {
-                "_deobf"
-            };
+// --- 章节规则推断 ---
 
-            let rebuilt = {
-                let mut progress = |message: &str| {
-                    emit_epub_prepare_stage(&app, &source, message);
-                };
-                rebuild_epub_with_sanitized_names_from_bytes_with_progress(
-                    &source,
-                    &decoded,
-                    suffix,
-                    &mut progress,
-                )?
-            };
+const CHAPTER_INFER_MAX_LINE_CHARS: usize = 40;
+const CHAPTER_INFER_SAMPLE_LIMIT: usize = 5;
+const CHAPTER_NUM_CLASS: &str = "[0-9０-９零〇一二两三四五六七八九十百千万]";
 
-            if let Some(out_path) = rebuilt {
-                return Ok(EpubPrepareResult {
-                    source_path: source.to_string_lossy().to_string(),
-                    processed_path: out_path.to_string_lossy().to_string(),
-                    changed: true,
-                    action: "已自动解混淆并修复可读格式".to_string(),
-                });
-            }
+struct ChapterShape {
+    key: &'static str,
+    label: &'static str,
+    pattern: String,
+    level: u8,
+    min_count: usize,
+}
 
-            emit_epub_prepare_stage(&app, &source, "写出解混淆后的 EPUB");
-            let out_path = build_processed_epub_path(&source, suffix);
-            fs::write(&out_path, &decoded).map_err(|e| format!("写入解混淆文件失败: {}", e))?;
-            return Ok(EpubPrepareResult {
-                source_path: source.to_string_lossy().to_string(),
-                processed_path: out_path.to_string_lossy().to_string(),
-                changed: true,
-                action: "已自动解混淆并修复可读格式".to_string(),
-            });
-        }
-    }
+fn chapter_shapes() -> Vec<ChapterShape> {
+    let n = CHAPTER_NUM_CLASS;
+    vec![
+        ChapterShape {
+            key: "intro",
+            label: "简介/感言",
+            pattern: r"^\s*(?:内容简介|本书相关|完本感言)\s*(?:[:：].*)?$".to_string(),
+            level: 1,
+            min_count: 1,
+        },
+        ChapterShape {
+            key: "volume",
+            label: "第N卷 / 卷N",
+            pattern: format!(
+                r"^\s*(?:第\s*{n}+\s*[卷部集]|卷\s*{n}+)(?:\s|[:：、.．\-—]|$)",
+                n = n
+            ),
+            level: 1,
+            min_count: 1,
+        },
+        ChapterShape {
+            key: "prologue",
+            label: "序章/楔子/后记",
+            pattern: r"^\s*(?:序(?:章|言)?|前言|楔子|后记|尾声|终章)\s*(?:[:：\s].*)?$".to_string(),
+            level: 3,
+            min_count: 1,
+        },
+        ChapterShape {
+            key: "chapter",
+            label: "第N章 / 第N回",
+            pattern: format!(r"^\s*第\s*{n}+\s*[章回]", n = n),
+            level: 3,
+            min_count: 2,
+        },
+        ChapterShape {
+            key: "section",
+            label: "第N节",
+            pattern: format!(r"^\s*第\s*{n}+\s*节", n = n),
+            level: 3,
+            min_count: 2,
+        },
+        ChapterShape {
+            key: "chapter_en",
+            label: "Chapter N",
+            pattern: r"^\s*(?i:chapter)\s*\d+".to_string(),
+            level: 3,
+            min_count: 2,
+        },
+        ChapterShape {
+            key: "extra",
+            label: "【番外】",
+            pattern: r"^\s*(?:【\s*(?:番外|后日谈)[^】]*】|(?:番外|后日谈)(?:\s|[:：、.．\-—]))"
+                .to_string(),
+            level: 3,
+            min_count: 1,
+        },
+        ChapterShape {
+            key: "numbered",
+            label: "001. 编号行",
+            pattern: r"^\s*\d{1,4}\s*[.．、]\s*\S".to_string(),
+            level: 3,
+            min_count: 3,
+        },
+    ]
+}
 
-    Err("该 EPUB 可能经过非通用加密/混淆，当前版本暂无法自动处理".to_string())
+#[derive(Serialize, Clone)]
+struct ChapterShapeSample {
+    line_number: usize,
+    text: String,
 }
 
-// 入库前自动检测并修复 EPUB 加密 / 混淆 / 错误文件名。
-// 处理结果落到系统临时目录，调用方负责通过 IngestTempFile 回收。
-// 返回值：
-//   Ok(None)  ：源文件已是合法 EPUB，无需处理
-//   Ok(Some)  ：(临时清洗文件 path, 动作描述)
-//   Err       ：检测到非 ZIP 且无法用通用密钥解混淆
-fn try_prepare_epub_for_ingest(source: &Path) -> Result<Option<(PathBuf, String)>, String> {
-    let bytes = read_epub_bytes(source)?;
+#[derive(Serialize, Clone)]
+struct ChapterShapeReport {
+    shape: String,
+    label: String,
+    level: u8,
+    count: usize,
+    score: f32,
+    samples: Vec<ChapterShapeSample>,
+}
 
-    // 在系统临时目录构造一个"虚拟 source"，使 build_processed_epub_path /
-    // rebuild_epub_with_sanitized_names_from_bytes 把输出落在临时目录而非源目录。
-    let temp_dir = std::env::temp_dir();
-    let stem = source
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("book");
-    let virtual_source = temp_dir.join(format!(
-        "tepub_ingest_{}_{}.epub",
-        uuid::Uuid::new_v4(),
-        stem
-    ));
+#[derive(Serialize)]
+struct ChapterRuleInference {
+    rules: Vec<RegexRule>,
+    shapes: Vec<ChapterShapeReport>,
+    total_lines: usize,
+    short_lines: usize,
+}
 
-    // Case 1：源已是 ZIP，检查伪加密位 + 文件名混淆
-    if is_zip_archive_bytes(&bytes) {
-        let mut bytes_mut = bytes;
-        let fixed_encryption_flag = clear_zip_encryption_flags(&mut bytes_mut);
+struct ChapterShapeStats {
+    count: usize,
+    blank_neighbor_count: usize,
+    max_chars: usize,
+    samples: Vec<ChapterShapeSample>,
+    leading_numbers: Vec<u32>,
+}
 
-        if let Some(out_path) =
-            rebuild_epub_with_sanitized_names_from_bytes(&virtual_source, &bytes_mut, "")?
-        {
-            let action = if fixed_encryption_flag {
-                "已自动解除伪加密并修复文件名混淆"
-            } else {
-                "已自动修复文件名混淆"
-            };
-            return Ok(Some((out_path, action.to_string())));
-        }
+fn leading_ascii_number(text: &str) -> Option<u32> {
+    let digits: String = text
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+// 编号行最容易误判（正文中的列表），要求编号大体递增。
+fn numbers_mostly_increase(numbers: &[u32]) -> bool {
+    if numbers.len() < 2 {
+        return false;
+    }
+    let increasing = numbers.windows(2).filter(|w| w[1] > w[0]).count();
+    increasing * 5 >= (numbers.len() - 1) * 4
+}
 
-        if fixed_encryption_flag {
-            let out_path = build_processed_epub_path(&virtual_source, "");
-            fs::write(&out_path, &bytes_mut).map_err(|e| format!("写入修复文件失败: {}", e))?;
-            return Ok(Some((out_path, "已自动解除伪加密".to_string())));
+// 只看短行：统计各种常见标题形态的出现次数、前后空行比例与样例，
+// 按得分给出带层级的规则建议。得分 = 次数 × (0.5 + 0.5 × 前后有空行的比例)。
+fn infer_chapter_rules_from_lines<'a, I>(lines: I) -> ChapterRuleInference
+where
+    I: Iterator<Item = &'a str>,
+{
+    let shapes = chapter_shapes();
+    let compiled: Vec<Option<Regex>> = shapes.iter().map(|s| Regex::new(&s.pattern).ok()).collect();
+    let mut stats: Vec<ChapterShapeStats> = shapes
+        .iter()
+        .map(|_| ChapterShapeStats {
+            count: 0,
+            blank_neighbor_count: 0,
+            max_chars: 0,
+            samples: Vec::new(),
+            leading_numbers: Vec::new(),
+        })
+        .collect();
+
+    let all_lines: Vec<&str> = lines.collect();
+    let mut short_lines = 0usize;
+    for (index, line) in all_lines.iter().enumerate() {
+        let trimmed = line.trim();
+        let chars = trimmed.chars().count();
+        if chars == 0 || chars > CHAPTER_INFER_MAX_LINE_CHARS {
+            continue;
         }
-        return Ok(None);
-    }
+        short_lines += 1;
+        // 以“。”“；”结尾的是正文句子，不参与推断
+        if trimmed.ends_with('。') || trimmed.ends_with('；') {
+            continue;
+        }
+        let blank_before = index == 0 || all_lines[index - 1].trim().is_empty();
+        let blank_after = all_lines
+            .get(index + 1)
+            .is_none_or(|next| next.trim().is_empty());
 
-    // Case 2：源非 ZIP，尝试 XOR 解混淆
-    let mut candidate_keys: Vec<u8> = vec![0xFF, 0xA5, 0x5A];
-    if bytes.len() >= 4 {
-        let k0 = bytes[0] ^ 0x50;
-        if (bytes[1] ^ 0x4B) == k0 && (bytes[2] ^ 0x03) == k0 && (bytes[3] ^ 0x04) == k0 {
-            if !candidate_keys.contains(&k0) {
-                candidate_keys.insert(0, k0);
+        // 每行只归入第一个命中的形态，避免“第一卷”同时计入章节
+        let hit = compiled.iter().position(|re| {
+            re.as_ref()
+                .is_some_and(|re| re.is_match(trimmed).unwrap_or(false))
+        });
+        if let Some(shape_index) = hit {
+            let stat = &mut stats[shape_index];
+            stat.count += 1;
+            if blank_before || blank_after {
+                stat.blank_neighbor_count += 1;
             }
-        }
-    }
-    for key in candidate_keys {
-        let mut decoded = xor_bytes(&bytes, key);
-        let _ = clear_zip_encryption_flags(&mut decoded);
-        if is_zip_archive_bytes(&decoded) {
-            if let Some(out_path) =
-                rebuild_epub_with_sanitized_names_from_bytes(&virtual_source, &decoded, "")?
-            {
-                return Ok(Some((out_path, "已自动解混淆并修复可读格式".to_string())));
+            stat.max_chars = stat.max_chars.max(chars);
+            if stat.samples.len() < CHAPTER_INFER_SAMPLE_LIMIT {
+                stat.samples.push(ChapterShapeSample {
+                    line_number: index + 1,
+                    text: trimmed.to_string(),
+                });
+            }
+            if shapes[shape_index].key == "numbered" {
+                if let Some(number) = leading_ascii_number(trimmed) {
+                    stat.leading_numbers.push(number);
+                }
             }
-            let out_path = build_processed_epub_path(&virtual_source, "");
-            fs::write(&out_path, &decoded).map_err(|e| format!("写入解混淆文件失败: {}", e))?;
-            return Ok(Some((out_path, "已自动解混淆并修复可读格式".to_string())));
         }
     }
 
-    Err("该 EPUB 可能经过非通用加密/混淆，当前版本暂无法自动处理".to_string())
-}
+    let has_chapters = shapes
+        .iter()
+        .zip(stats.iter())
+        .any(|(shape, stat)| shape.key == "chapter" && stat.count >= shape.min_count);
 
-// RAII：函数结束时自动删除入库流程产生的临时清洗文件
-struct IngestTempFile(Option<PathBuf>);
-impl Drop for IngestTempFile {
-    fn drop(&mut self) {
-        if let Some(p) = self.0.take() {
-            let _ = fs::remove_file(p);
+    let mut accepted: Vec<(ChapterShapeReport, RegexRule)> = Vec::new();
+    for (shape, stat) in shapes.iter().zip(stats) {
+        if stat.count < shape.min_count {
+            continue;
+        }
+        if shape.key == "numbered" && !numbers_mostly_increase(&stat.leading_numbers) {
+            continue;
+        }
+        // 已有“第N章”时，节与编号行属于章内小标题；目录规则只支持 1、3 级，不再建议
+        if has_chapters && (shape.key == "section" || shape.key == "numbered") {
+            continue;
         }
+        let level = shape.level;
+        let blank_ratio = stat.blank_neighbor_count as f32 / stat.count as f32;
+        let score = stat.count as f32 * (0.5 + 0.5 * blank_ratio);
+        let rule = RegexRule {
+            level,
+            pattern: shape.pattern.clone(),
+            exclude_patterns: vec![r"[。；]\s*$".to_string()],
+            max_title_length: Some((stat.max_chars + 10).min(CHAPTER_INFER_MAX_LINE_CHARS + 10)),
+            ..Default::default()
+        };
+        accepted.push((
+            ChapterShapeReport {
+                shape: shape.key.to_string(),
+                label: shape.label.to_string(),
+                level,
+                count: stat.count,
+                score,
+                samples: stat.samples,
+            },
+            rule,
+        ));
+    }
+
+    // 卷、简介等一级标题通常远少于章节，排序按得分，但优先级保证一级规则先匹配
+    accepted.sort_by(|a, b| b.0.score.total_cmp(&a.0.score));
+    let (shapes, mut rules): (Vec<_>, Vec<_>) = accepted.into_iter().unzip();
+    for rule in rules.iter_mut() {
+        rule.priority = match rule.level {
+            1 => 20,
+            3 => 10,
+            _ => 0,
+        };
+    }
+
+    ChapterRuleInference {
+        rules,
+        shapes,
+        total_lines: all_lines.len(),
+        short_lines,
     }
 }
 
 #[tauri::command]
-async fn save_text_file(path: String, content: String) -> Result<(), String> {
-    let mut file = fs::File::create(&path).map_err(|e| format!("无法创建: {}", e))?;
-    file.write_all(content.as_bytes())
-        .map_err(|e| format!("写入失败: {}", e))?;
-    Ok(())
+async fn infer_chapter_rules(content: String) -> ChapterRuleInference {
+    let content = normalize_line_endings(content);
+    infer_chapter_rules_from_lines(content.lines())
 }
 
 #[tauri::command]
-fn read_binary_file(path: String) -> Result<Vec<u8>, String> {
-    fs::read(&path).map_err(|e| format!("读取失败: {}", e))
+async fn infer_text_buffer_chapter_rules(
+    buffer_id: String,
+) -> Result<ChapterRuleInference, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        infer_chapter_rules_from_lines(lines.iter().map(String::as_str))
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
 }
 
-#[derive(Serialize, Clone)]
-struct CoverSearchResult {
-    id: String,
-    title: String,
-    author: String,
-    image_url: String,
-    page_url: String,
-    source: String,
-    preferred: bool,
-}
+#[cfg(test)]
+mod chapter_infer_tests {
+    use super::*;
 
-fn percent_encode_component(input: &str) -> String {
-    let mut out = String::new();
-    for b in input.as_bytes() {
-        match *b {
-            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
-                out.push(*b as char)
-            }
-            b' ' => out.push_str("%20"),
-            _ => out.push_str(&format!("%{:02X}", b)),
-        }
+    #[test]
+    fn infers_volume_chapter_and_extra_shapes_with_samples() {
+        let text = "内容简介\n一个故事。\n\n第一卷 风起\n\n第一章 开端\n正文。\n\n第二章 相遇\n他说：“第三章写完了吗？”\n\n第三章 离别\n正文。\n\n【番外】 旧事\n正文。\n";
+        let inference = infer_chapter_rules_from_lines(text.lines());
+        let keys: Vec<&str> = inference.shapes.iter().map(|s| s.shape.as_str()).collect();
+
+        assert!(keys.contains(&"intro"), "{:?}", keys);
+        assert!(keys.contains(&"volume"), "{:?}", keys);
+        assert!(keys.contains(&"extra"), "{:?}", keys);
+        assert_eq!(keys[0], "chapter", "{:?}", keys);
+
+        let chapter = &inference.shapes[0];
+        assert_eq!(chapter.count, 3);
+        assert_eq!(chapter.samples[0].line_number, 6);
+        let volume_rule = inference
+            .rules
+            .iter()
+            .find(|r| r.level == 1 && r.pattern.contains("卷"));
+        assert!(volume_rule.is_some_and(|r| r.priority > inference.rules[0].priority));
     }
-    out
-}
 
-fn normalize_cover_url(raw: &str) -> String {
-    let mut url = raw
-        .replace("\\/", "/")
-        .replace("&amp;", "&")
-        .replace("&quot;", "\"")
-        .trim()
-        .to_string();
-    if url.starts_with("//") {
-        url = format!("https:{}", url);
+    #[test]
+    fn numbered_lines_need_an_increasing_sequence() {
+        let numbered = "001. 出发\n正文\n002. 路上\n正文\n003. 到达\n正文\n";
+        let inference = infer_chapter_rules_from_lines(numbered.lines());
+        assert_eq!(inference.shapes.len(), 1);
+        assert_eq!(inference.shapes[0].shape, "numbered");
+
+        let list = "3. 苹果\n1. 香蕉\n2. 橘子\n1. 西瓜\n";
+        let inference = infer_chapter_rules_from_lines(list.lines());
+        assert!(inference.shapes.is_empty());
+    }
+
+    #[test]
+    fn inferred_rules_scan_the_same_headings() {
+        let text = "第一章 开端\n正文\n第二章 相遇\n正文\n";
+        let inference = infer_chapter_rules_from_lines(text.lines());
+        let mut chapters = Vec::new();
+        scan_chapter_lines(text.lines(), inference.rules, |c| chapters.push(c));
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].title, "第二章 相遇");
+    }
+
+    #[test]
+    fn sections_under_chapters_are_not_suggested() {
+        let text = "第一章 开端\n第一节 甲\n正文\n第二节 乙\n正文\n第二章 相遇\n第一节 丙\n正文\n";
+        let inference = infer_chapter_rules_from_lines(text.lines());
+        let keys: Vec<&str> = inference.shapes.iter().map(|s| s.shape.as_str()).collect();
+        assert_eq!(keys, vec!["chapter"]);
+        assert!(inference.rules.iter().all(|r| r.level == 1 || r.level == 3));
     }
-    url
 }
 
-fn html_unescape_basic(raw: &str) -> String {
-    raw.replace("&quot;", "\"")
-        .replace("&#34;", "\"")
-        .replace("&#x22;", "\"")
-        .replace("&#39;", "'")
-        .replace("&#x27;", "'")
-        .replace("&lt;", "<")
-        .replace("&gt;", ">")
-        .replace("&amp;", "&")
+// --- TXT 大文件缓冲命令 ---
+
+const TEXT_BUFFER_CHAPTER_BATCH: usize = 200;
+
+#[derive(Serialize, Clone)]
+struct TextBufferInfo {
+    buffer_id: String,
+    path: String,
+    line_count: usize,
+    byte_len: u64,
+    modified: bool,
+    report: TextEncodingReport,
 }
 
-fn compact_match_key(text: &str) -> String {
-    text.chars()
-        .filter(|c| !c.is_whitespace() && *c != '《' && *c != '》')
-        .collect::<String>()
-        .to_lowercase()
+#[derive(Serialize)]
+struct TextBufferChunk {
+    start_line: usize,
+    lines: Vec<String>,
+    total_lines: usize,
 }
 
-fn is_preferred_cover_source(image_url: &str, page_url: &str, source: &str) -> bool {
-    let image = image_url.to_lowercase();
-    let page = page_url.to_lowercase();
-    let source = source.to_lowercase();
-    image.contains("bookcover.yuewen.com")
-        || image.contains("icode.qq.com")
-        || image.contains("p9-novel-sign.byteimg.com")
-        || image.contains("fanqienovel.com")
-        || page.contains("m.qidian.com")
-        || page.contains("icode.qq.com")
-        || page.contains("bookcover.yuewen.com")
-        || page.contains("fanqienovel.com")
-        || page.contains("p9-novel-sign.byteimg.com")
-        || source.contains("m.qidian.com")
-        || source.contains("icode.qq.com")
-        || source.contains("bookcover.yuewen.com")
-        || source.contains("fanqienovel.com")
-        || source.contains("p9-novel-sign.byteimg.com")
+#[derive(Serialize, Clone)]
+struct TextBufferChapterEvent {
+    buffer_id: String,
+    task_id: String,
+    chapters: Vec<ChapterInfo>,
+    done: bool,
+    total: usize,
 }
 
-fn cover_download_referer(url: &str) -> &'static str {
-    let lower = url.to_lowercase();
-    if lower.contains("byteimg.com") || lower.contains("fanqienovel.com") {
-        "https://fanqienovel.com/"
-    } else {
-        "https://m.qidian.com/"
+// 以 1 起始的行号为坐标：删除 start_line 起的 delete_count 行，再在该处插入 lines。
+// delete_count 为 0 即纯插入；lines 为空即纯删除。
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct TextBufferPatch {
+    start_line: usize,
+    #[serde(default)]
+    delete_count: usize,
+    #[serde(default)]
+    lines: Vec<String>,
+}
+
+fn lock_text_buffers() -> Result<std::sync::MutexGuard<'static, HashMap<String, TextBuffer>>, String>
+{
+    TEXT_BUFFERS
+        .lock()
+        .map_err(|_| "TXT 缓冲已被中毒，无法访问".to_string())
+}
+
+fn text_buffer_info(buffer_id: &str, buffer: &TextBuffer) -> TextBufferInfo {
+    TextBufferInfo {
+        buffer_id: buffer_id.to_string(),
+        path: buffer.path.clone(),
+        line_count: buffer.lines.len(),
+        byte_len: buffer.byte_len,
+        modified: buffer.modified,
+        report: buffer.report.clone(),
     }
 }
 
-fn host_from_url(url: &str) -> String {
-    reqwest::Url::parse(url)
-        .ok()
-        .and_then(|u| u.host_str().map(|h| h.to_string()))
-        .unwrap_or_default()
+// 取出行数据的共享引用后立即释放锁，长时间扫描/搜索不阻塞分块读取。
+fn text_buffer_lines(buffer_id: &str) -> Result<Arc<Vec<String>>, String> {
+    let buffers = lock_text_buffers()?;
+    buffers
+        .get(buffer_id)
+        .map(|buffer| Arc::clone(&buffer.lines))
+        .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())
 }
 
-fn json_num(value: &serde_json::Value, keys: &[&str]) -> Option<f64> {
-    for key in keys {
-        if let Some(number) = value.get(*key).and_then(|v| v.as_f64()) {
-            return Some(number);
+// 按行号从大到小应用补丁，先整体校验越界与重叠，任何一个补丁不合法都不改动缓冲。
+fn apply_text_patches(
+    lines: &mut Vec<String>,
+    mut patches: Vec<TextBufferPatch>,
+) -> Result<(), String> {
+    patches.sort_by_key(|patch| std::cmp::Reverse(patch.start_line));
+    let mut next_start = lines.len() + 1;
+    for patch in &patches {
+        if patch.start_line == 0 {
+            return Err("补丁行号从 1 开始".to_string());
+        }
+        let end = patch.start_line - 1 + patch.delete_count;
+        if end > lines.len() {
+            return Err(format!(
+                "补丁超出范围: 第 {} 行起删除 {} 行，共 {} 行",
+                patch.start_line,
+                patch.delete_count,
+                lines.len()
+            ));
         }
-        if let Some(text) = value.get(*key).and_then(|v| v.as_str()) {
-            if let Ok(number) = text.parse::<f64>() {
-                return Some(number);
-            }
+        if end >= next_start {
+            return Err(format!("补丁区间重叠: 第 {} 行", patch.start_line));
         }
+        next_start = patch.start_line;
     }
-    None
+
+    for patch in patches {
+        let start = patch.start_line - 1;
+        lines.splice(start..start + patch.delete_count, patch.lines);
+    }
+    Ok(())
 }
 
 #[tauri::command]
-async fn search_book_covers(
-    title: String,
-    author: String,
-) -> Result<Vec<CoverSearchResult>, String> {
-    let clean_title = title.trim();
-    let clean_author = author.trim();
-    if clean_title.is_empty() || clean_title == "书名" || clean_title == "涔﹀悕" {
-        return Err("请先填写书名".to_string());
-    }
+async fn open_text_buffer(
+    path: String,
+    encoding: Option<String>,
+) -> Result<TextBufferInfo, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let buffer = read_text_file_bytes(&path)?;
+        let byte_len = buffer.len() as u64;
+        let (content, report) = decode_text_bytes(&buffer, encoding.as_deref())?;
+        drop(buffer);
+        let lines: Vec<String> = content.split('\n').map(str::to_string).collect();
+        drop(content);
+
+        let buffer_id = uuid::Uuid::new_v4().to_string();
+        let text_buffer = TextBuffer {
+            path,
+            lines: Arc::new(lines),
+            report,
+            byte_len,
+            modified: false,
+        };
+        let info = text_buffer_info(&buffer_id, &text_buffer);
+        lock_text_buffers()?.insert(buffer_id, text_buffer);
+        Ok(info)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
+
+#[tauri::command]
+fn get_text_buffer_info(buffer_id: String) -> Result<TextBufferInfo, String> {
+    let buffers = lock_text_buffers()?;
+    buffers
+        .get(&buffer_id)
+        .map(|buffer| text_buffer_info(&buffer_id, buffer))
+        .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())
+}
+
+#[tauri::command]
+fn read_text_buffer_lines(
+    buffer_id: String,
+    start_line: usize,
+    line_count: usize,
+) -> Result<TextBufferChunk, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    let start = start_line.max(1) - 1;
+    let end = start.saturating_add(line_count).min(lines.len());
+    let chunk = if start < end {
+        lines[start..end].to_vec()
+    } else {
+        Vec::new()
+    };
+    Ok(TextBufferChunk {
+        start_line: start + 1,
+        lines: chunk,
+        total_lines: lines.len(),
+    })
+}
+
+// 章节扫描结果按批通过 text-buffer-chapters 事件推送，最后一批 done=true；返回章节总数。
+#[tauri::command]
+async fn scan_text_buffer_chapters(
+    app: tauri::AppHandle,
+    buffer_id: String,
+    task_id: String,
+    rules: Vec<RegexRule>,
+) -> Result<usize, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut total = 0usize;
+        let mut batch: Vec<ChapterInfo> = Vec::with_capacity(TEXT_BUFFER_CHAPTER_BATCH);
+        let emit_batch = |chapters: Vec<ChapterInfo>, done: bool, total: usize| {
+            let _ = app.emit(
+                "text-buffer-chapters",
+                TextBufferChapterEvent {
+                    buffer_id: buffer_id.clone(),
+                    task_id: task_id.clone(),
+                    chapters,
+                    done,
+                    total,
+                },
+            );
+        };
+
+        scan_chapter_lines(lines.iter().map(String::as_str), rules, |chapter| {
+            total += 1;
+            batch.push(chapter);
+            if batch.len() >= TEXT_BUFFER_CHAPTER_BATCH {
+                emit_batch(std::mem::take(&mut batch), false, total);
+            }
+        });
+        emit_batch(batch, true, total);
+        total
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
+}
+
+#[tauri::command]
+async fn search_text_buffer(
+    buffer_id: String,
+    pattern: String,
+    is_regex: bool,
+    options: Option<FuzzySearchOptions>,
+) -> Result<SearchResult, String> {
+    let lines = text_buffer_lines(&buffer_id)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        search_text_lines(
+            lines.iter().map(String::as_str),
+            &pattern,
+            is_regex,
+            &options.unwrap_or_default(),
+        )
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))
+}
+
+#[tauri::command]
+fn apply_text_buffer_patches(
+    buffer_id: String,
+    patches: Vec<TextBufferPatch>,
+) -> Result<TextBufferInfo, String> {
+    let mut buffers = lock_text_buffers()?;
+    let buffer = buffers
+        .get_mut(&buffer_id)
+        .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+    if !patches.is_empty() {
+        // 扫描/搜索任务仍持有旧 Arc 时这里会复制一份，不影响正在进行的任务
+        apply_text_patches(Arc::make_mut(&mut buffer.lines), patches)?;
+        buffer.modified = true;
+    }
+    Ok(text_buffer_info(&buffer_id, buffer))
+}
+
+// 写回磁盘；path 为空时写回原文件，options 为空时沿用打开时识别到的编码、BOM 与换行风格。
+#[tauri::command]
+async fn save_text_buffer(
+    buffer_id: String,
+    path: Option<String>,
+    options: Option<TextSaveOptions>,
+) -> Result<TextBufferInfo, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (lines, source_path, report) = {
+            let buffers = lock_text_buffers()?;
+            let buffer = buffers
+                .get(&buffer_id)
+                .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+            (
+                Arc::clone(&buffer.lines),
+                buffer.path.clone(),
+                buffer.report.clone(),
+            )
+        };
+        let target = path.filter(|p| !p.trim().is_empty()).unwrap_or(source_path);
+        let options = options.unwrap_or_else(|| TextSaveOptions {
+            encoding: Some(report.encoding.clone()),
+            has_bom: report.has_bom,
+            line_ending: Some(report.line_ending.clone()),
+        });
+
+        let bytes = encode_text_for_save(&lines.join("\n"), &options)?;
+        let mut file = fs::File::create(&target).map_err(|e| format!("无法创建: {}", e))?;
+        file.write_all(&bytes)
+            .map_err(|e| format!("写入失败: {}", e))?;
+
+        let mut buffers = lock_text_buffers()?;
+        let buffer = buffers
+            .get_mut(&buffer_id)
+            .ok_or_else(|| "TXT 缓冲不存在或已关闭".to_string())?;
+        buffer.path = target;
+        buffer.byte_len = bytes.len() as u64;
+        // 保存期间没有新补丁才清除修改标记
+        if Arc::ptr_eq(&buffer.lines, &lines) {
+            buffer.modified = false;
+        }
+        Ok(text_buffer_info(&buffer_id, buffer))
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-    let client = reqwest::Client::builder()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) TEpub-Editor/0.5")
-        .build()
-        .map_err(|e| format!("初始化封面搜索失败: {}", e))?;
-    let image_meta_re =
-        Regex::new(r#"m="([^"]+)""#).map_err(|e| format!("封面结果解析失败: {}", e))?;
-    let mut results: Vec<(i32, CoverSearchResult)> = Vec::new();
-    let mut seen = HashSet::new();
-    let title_key = compact_match_key(clean_title);
-    let author_key = compact_match_key(clean_author);
+#[tauri::command]
+fn close_text_buffer(buffer_id: String) -> Result<(), String> {
+    lock_text_buffers()?.remove(&buffer_id);
+    Ok(())
+}
 
-    let mut query_texts = vec![clean_title.to_string()];
-    if !clean_author.is_empty() && clean_author != "作者" && clean_author != "浣滆€?" {
-        query_texts.push(format!("{} {}", clean_title, clean_author));
+#[cfg(test)]
+mod text_buffer_tests {
+    use super::*;
+
+    fn sample_lines() -> Vec<String> {
+        ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
     }
-    query_texts.push(format!("{} 小说封面", clean_title));
 
-    for (query_idx, query_text) in query_texts.iter().enumerate() {
-        let query = percent_encode_component(query_text);
-        let search_url = format!(
-            "https://cn.bing.com/images/search?q={}&form=HDRSC2&first=1",
-            query
-        );
-        let html = client
-            .get(&search_url)
-            .header("Referer", "https://cn.bing.com/images")
-            .header("Accept-Language", "zh-CN,zh;q=0.9")
-            .send()
-            .await
-            .map_err(|e| format!("搜索封面失败: {}", e))?
-            .text()
-            .await
-            .map_err(|e| format!("读取封面搜索结果失败: {}", e))?;
+    #[test]
+    fn patches_apply_against_original_line_numbers() -> Result<(), String> {
+        let mut lines = sample_lines();
+        apply_text_patches(
+            &mut lines,
+            vec![
+                TextBufferPatch {
+                    start_line: 2,
+                    delete_count: 1,
+                    lines: vec!["B1".to_string(), "B2".to_string()],
+                },
+                TextBufferPatch {
+                    start_line: 5,
+                    delete_count: 1,
+                    lines: vec![],
+                },
+                TextBufferPatch {
+                    start_line: 6,
+                    delete_count: 0,
+                    lines: vec!["f".to_string()],
+                },
+            ],
+        )?;
+        assert_eq!(lines, vec!["a", "B1", "B2", "c", "d", "f"]);
+        Ok(())
+    }
 
-        for (idx, item) in image_meta_re.captures_iter(&html).enumerate() {
-            let captures = match item {
-                Ok(c) => c,
-                Err(_) => continue,
-            };
-            let raw_meta = captures.get(1).map(|m| m.as_str()).unwrap_or("");
-            let meta_text = html_unescape_basic(raw_meta);
-            let meta = match serde_json::from_str::<serde_json::Value>(&meta_text) {
-                Ok(value) => value,
-                Err(_) => continue,
-            };
-            let image_url = match meta
-                .get("murl")
-                .and_then(|value| value.as_str())
-                .or_else(|| meta.get("turl").and_then(|value| value.as_str()))
-            {
-                Some(url) => normalize_cover_url(url),
-                None => continue,
-            };
-            if image_url.is_empty()
-                || !(image_url.starts_with("https://") || image_url.starts_with("http://"))
-                || !seen.insert(image_url.clone())
-            {
-                continue;
-            }
+    #[test]
+    fn overlapping_or_out_of_range_patches_leave_lines_untouched() {
+        let mut lines = sample_lines();
+        let overlap = apply_text_patches(
+            &mut lines,
+            vec![
+                TextBufferPatch {
+                    start_line: 1,
+                    delete_count: 3,
+                    lines: vec![],
+                },
+                TextBufferPatch {
+                    start_line: 3,
+                    delete_count: 1,
+                    lines: vec![],
+                },
+            ],
+        );
+        assert!(overlap.is_err());
+
+        let out_of_range = apply_text_patches(
+            &mut lines,
+            vec![TextBufferPatch {
+                start_line: 5,
+                delete_count: 2,
+                lines: vec![],
+            }],
+        );
+        assert!(out_of_range.is_err());
+        assert_eq!(lines, sample_lines());
+    }
 
-            let page_url = meta
-                .get("purl")
-                .and_then(|value| value.as_str())
-                .map(normalize_cover_url)
-                .unwrap_or_default();
-            let result_title = meta
-                .get("t")
-                .and_then(|value| value.as_str())
-                .map(html_unescape_basic)
-                .unwrap_or_else(|| clean_title.to_string());
-            let id = meta
-                .get("md5")
-                .and_then(|value| value.as_str())
-                .or_else(|| meta.get("cid").and_then(|value| value.as_str()))
-                .map(|value| value.to_string())
-                .unwrap_or_else(|| format!("{}-{}", query_idx, idx));
-            let source = {
-                let page_host = host_from_url(&page_url);
-                if page_host.is_empty() {
-                    host_from_url(&image_url)
-                } else {
-                    page_host
-                }
-            };
-            let preferred = is_preferred_cover_source(&image_url, &page_url, &source);
+    #[test]
+    fn chapter_scan_over_lines_matches_scan_chapters() {
+        let lines = ["第一章 起", "正文一", "", "第二章 承", "正文二二"];
+        let mut chapters = Vec::new();
+        scan_chapter_lines(
+            lines.iter().copied(),
+            vec![RegexRule {
+                level: 3,
+                pattern: "^第.+章".to_string(),
+                ..Default::default()
+            }],
+            |chapter| chapters.push(chapter),
+        );
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].line_number, 4);
+        assert_eq!(chapters[0].word_count, 3);
+        assert_eq!(chapters[1].word_count, 4);
+    }
+}
 
-            let result_title_key = compact_match_key(&result_title);
-            let page_key = compact_match_key(&page_url);
-            let source_key = compact_match_key(&source);
-            let image_key = compact_match_key(&image_url);
-            let combined_key = format!(
-                "{}{}{}{}",
-                result_title_key, page_key, source_key, image_key
-            );
-            let has_title_match = !title_key.is_empty() && combined_key.contains(&title_key);
+// --- EPUB 导出 ---
 
-            let mut score = if preferred { 180 } else { 0 };
-            score += match query_idx {
-                0 => 70,
-                1 => 30,
-                _ => 10,
-            };
-            if !title_key.is_empty() && result_title_key == title_key {
-                score += 150;
-            } else if has_title_match {
-                score += 100;
-            } else if !title_key.is_empty() && result_title_key.contains(&title_key) {
-                score += 70;
-            } else {
-                score -= 80;
-            }
-            if !author_key.is_empty()
-                && (result_title_key.contains(&author_key) || page_key.contains(&author_key))
+// 目录层级：卷下挂章，meta 章节（序、后记等）始终在顶层。NCX 与 nav.xhtml 共用。
+fn export_toc_depths(chapters: &[ChapterInfo]) -> Vec<usize> {
+    let mut stack: Vec<u8> = Vec::new();
+    chapters
+        .iter()
+        .map(|chapter| {
+            while stack
+                .last()
+                .is_some_and(|top| *top >= chapter.level || chapter.is_meta)
             {
-                score += 20;
+                stack.pop();
             }
-            if source.contains("icode.qq.com") {
-                score += 90;
+            let depth = stack.len();
+            if !chapter.is_meta {
+                stack.push(chapter.level);
             }
-            if let (Some(width), Some(height)) = (
-                json_num(&meta, &["w", "width", "ow", "imgw"]),
-                json_num(&meta, &["h", "height", "oh", "imgh"]),
-            ) {
-                if width > 0.0 && height > 0.0 {
-                    let ratio = width / height;
-                    if (0.62..=0.82).contains(&ratio) {
-                        score += 35;
-                    } else if ratio >= 1.0 {
-                        score -= 45;
-                    }
+            depth
+        })
+        .collect()
+}
+
+// entries 为（层级，已转义的标题，href），层级相邻两项最多加深一级
+fn build_nav_ol(entries: &[(usize, String, String)], indent: &str) -> String {
+    let mut out = format!("{}<ol>\n", indent);
+    let mut current: Option<usize> = None;
+    for (depth, label, href) in entries {
+        match current {
+            Some(prev) if *depth > prev => out.push_str("<ol>\n"),
+            Some(prev) => {
+                out.push_str("</li>\n");
+                for _ in *depth..prev {
+                    out.push_str("</ol></li>\n");
                 }
             }
-            score -= (query_idx as i32 * 20) + idx as i32;
-
-            results.push((
-                score,
-                CoverSearchResult {
-                    id,
-                    title: if result_title.trim().is_empty() {
-                        clean_title.to_string()
-                    } else {
-                        result_title
-                    },
-                    author: source.clone(),
-                    image_url,
-                    page_url,
-                    source,
-                    preferred,
-                },
-            ));
+            None => {}
         }
+        out.push_str(&format!(
+            "{}  <li><a href=\"{}\">{}</a>",
+            indent, href, label
+        ));
+        current = Some(*depth);
     }
-
-    results.sort_by(|a, b| b.0.cmp(&a.0));
-    Ok(results
-        .into_iter()
-        .take(12)
-        .map(|(_, result)| result)
-        .collect())
-}
-#[tauri::command]
-async fn download_cover_to_temp(image_url: String, title: String) -> Result<String, String> {
-    let url = normalize_cover_url(&image_url);
-    if !(url.starts_with("https://") || url.starts_with("http://")) {
-        return Err("封面地址无效".to_string());
+    if let Some(prev) = current {
+        out.push_str("</li>\n");
+        for _ in 0..prev {
+            out.push_str("</ol></li>\n");
+        }
     }
+    out.push_str(&format!("{}</ol>", indent));
+    out
+}
 
-    let client = reqwest::Client::builder()
-        .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) TEpub-Editor/0.5")
-        .build()
-        .map_err(|e| format!("初始化封面下载失败: {}", e))?;
-    let bytes = client
-        .get(&url)
-        .header("Referer", cover_download_referer(&url))
-        .send()
-        .await
-        .map_err(|e| format!("下载封面失败: {}", e))?
-        .bytes()
-        .await
-        .map_err(|e| format!("读取封面数据失败: {}", e))?;
-    if bytes.is_empty() {
-        return Err("下载到的封面为空".to_string());
-    }
-    if bytes.len() > 12 * 1024 * 1024 {
-        return Err("封面图片过大".to_string());
-    }
+fn build_epub3_nav(
+    title: &str,
+    language: &str,
+    toc: &[(usize, String, String)],
+    landmarks: &[(&str, &str, String)],
+) -> String {
+    let landmark_items: String = landmarks
+        .iter()
+        .map(|(kind, label, href)| {
+            format!(
+                "      <li><a epub:type=\"{}\" href=\"{}\">{}</a></li>\n",
+                kind, href, label
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{lang}" lang="{lang}">
+<head>
+  <title>{title}</title>
+  <link href="Styles/main.css" type="text/css" rel="stylesheet"/>
+</head>
+<body>
+  <nav epub:type="toc" id="toc">
+    <h1>目录</h1>
+{toc}
+  </nav>
+  <nav epub:type="landmarks" id="landmarks" hidden="hidden">
+    <ol>
+{landmarks}    </ol>
+  </nav>
+</body>
+</html>"#,
+        lang = escape_xml(language),
+        title = title,
+        toc = build_nav_ol(toc, "    "),
+        landmarks = landmark_items
+    )
+}
 
-    let ext = detect_image_ext(&bytes);
-    let base = sanitize_filename_part(&title);
-    let stem = if base.is_empty() {
-        "cover".to_string()
-    } else {
-        base
+fn chapter_xhtml_document(
+    title: &str,
+    class_attr: &str,
+    body: &str,
+    epub3_language: Option<&str>,
+) -> String {
+    let head = match epub3_language {
+        Some(language) => format!(
+            r#"<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{lang}" lang="{lang}">"#,
+            lang = escape_xml(language)
+        ),
+        None => r#"<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml">"#
+            .to_string(),
     };
-    let dir = std::env::temp_dir().join("TEpub-Editor").join("covers");
-    fs::create_dir_all(&dir).map_err(|e| format!("创建封面缓存目录失败: {}", e))?;
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(|e| format!("生成封面缓存文件名失败: {}", e))?
-        .as_millis();
-    let path = dir.join(format!("{}-{}.{}", stem, now, ext));
-    fs::write(&path, &bytes).map_err(|e| format!("保存封面失败: {}", e))?;
-    Ok(path.to_string_lossy().to_string())
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+{}
+<head>
+  <title>{}</title>
+  <link href="../Styles/font.css" type="text/css" rel="stylesheet"/>
+  <link href="../Styles/main.css" type="text/css" rel="stylesheet"/>
+</head>
+<body class="{}">
+{}
+</body>
+</html>"#,
+        head, title, class_attr, body
+    )
+}
+
+const DEFAULT_NOTE_REFERENCE_PATTERN: &str = r"【注(\d+)】";
+const DEFAULT_NOTE_INLINE_PATTERN: &str = r"〔注[:：]([^〔〕]+)〕";
+
+static FRAGMENT_HREF_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r##"href="#([^"]+)""##).expect("valid fragment href regex"));
+static ELEMENT_ID_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r##"\sid="([^"]+)""##).expect("valid element id regex"));
+
+/// 导出时的注释识别设置。style 为 "footnote"（EPUB 3 noteref / aside）或 "duokan"（多看弹注），留空不处理。
+/// 两个正则都取第 1 个捕获组：引用式取编号，行内式取注释正文；匹配对象是反转义后的段落原文。
+#[derive(Deserialize, Debug, Default, Clone)]
+struct NoteExportOptions {
+    #[serde(default)]
+    style: String,
+    #[serde(default)]
+    reference_pattern: String,
+    #[serde(default)]
+    inline_pattern: String,
 }
 
-#[tauri::command]
-fn calculate_md5(content: String) -> String {
-    format!("{:x}", md5::compute(content.as_bytes()))
+struct NoteRules {
+    duokan: bool,
+    reference: Regex,
+    inline: Regex,
 }
 
-#[tauri::command]
-async fn save_history(
-    app: tauri::AppHandle,
-    original_path: String,
-    content: String,
-) -> Result<(), String> {
-    let path = Path::new(&original_path);
-    let file_stem = path.file_stem().unwrap().to_string_lossy();
-    let history_dir = get_history_base_dir(Some(&app));
-    if !history_dir.exists() {
-        fs::create_dir_all(&history_dir).map_err(|e| e.to_string())?;
-    }
-    let now = SystemTime::now();
-    let timestamp = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let path_key = history_key_for_path(&original_path);
-    let file_prefix = format!("{}-{}", file_stem, path_key);
-    let backup_name = format!("{}.{}.bak", file_prefix, timestamp);
-    let backup_path = history_dir.join(backup_name);
-    let mut file = fs::File::create(&backup_path).map_err(|e| e.to_string())?;
-    file.write_all(content.as_bytes())
-        .map_err(|e| e.to_string())?;
-    if let Ok(entries) = fs::read_dir(&history_dir) {
-        let mut backups: Vec<_> = entries
-            .filter_map(|e| e.ok())
-            .filter(|e| {
-                let name = e.file_name().to_string_lossy().to_string();
-                // Read hashed names first; keep compatibility with old naming.
-                name.starts_with(&file_prefix)
-                    || (name.starts_with(&format!("{}.", file_stem)) && name.ends_with(".bak"))
-            })
-            .collect();
-        backups.sort_by_key(|e| {
-            e.metadata()
-                .and_then(|m| m.modified())
-                .unwrap_or(SystemTime::UNIX_EPOCH)
-        });
-        if backups.len() > 10 {
-            for entry in backups.iter().take(backups.len() - 10) {
-                let _ = fs::remove_file(entry.path());
-            }
-        }
+impl NoteRules {
+    fn from_options(options: &NoteExportOptions) -> Result<Option<Self>, String> {
+        let duokan = match options.style.trim() {
+            "" => return Ok(None),
+            "footnote" => false,
+            "duokan" => true,
+            other => return Err(format!("未知的注释样式: {}", other)),
+        };
+        let compile = |pattern: &str, fallback: &str| {
+            let pattern = if pattern.trim().is_empty() {
+                fallback
+            } else {
+                pattern
+            };
+            Regex::new(pattern).map_err(|e| format!("注释正则无效: {} ({})", pattern, e))
+        };
+        Ok(Some(Self {
+            duokan,
+            reference: compile(&options.reference_pattern, DEFAULT_NOTE_REFERENCE_PATTERN)?,
+            inline: compile(&options.inline_pattern, DEFAULT_NOTE_INLINE_PATTERN)?,
+        }))
     }
-    Ok(())
 }
 
-#[tauri::command]
-async fn get_history_list(app: tauri::AppHandle, original_path: String) -> Vec<HistoryMeta> {
-    let path = Path::new(&original_path);
-    let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
-    let history_dir = get_history_base_dir(Some(&app));
-    let file_prefix = format!("{}-{}", file_stem, history_key_for_path(&original_path));
-    let mut list = Vec::new();
-    if let Ok(entries) = fs::read_dir(history_dir) {
-        for entry in entries.filter_map(|e| e.ok()) {
-            let fname = entry.file_name().to_string_lossy().to_string();
-            if (fname.starts_with(&file_prefix) || fname.starts_with(&format!("{}.", file_stem)))
-                && fname.ends_with(".bak")
-            {
-                if let Ok(meta) = entry.metadata() {
-                    list.push(HistoryMeta {
-                        filename: fname,
-                        path: entry.path().to_string_lossy().to_string(),
-                        timestamp: meta
-                            .modified()
-                            .unwrap_or(SystemTime::now())
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap_or_default()
-                            .as_secs(),
-                        size: meta.len(),
-                        date_str: "".to_string(),
-                    });
-                }
-            }
-        }
-    }
-    list.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-    list
+fn note_capture<'t>(caps: &fancy_regex::Captures<'t>) -> &'t str {
+    caps.get(1)
+        .or_else(|| caps.get(0))
+        .map(|m| m.as_str().trim())
+        .unwrap_or("")
 }
 
-#[tauri::command]
-fn list_library_fonts(app: tauri::AppHandle) -> Result<Vec<LibraryFontInfo>, String> {
-    let dir = library_fonts_dir(&app)?;
-    if !dir.exists() {
-        return Ok(Vec::new());
+/// 把章节正文里的注释标记换成引用链接，注释统一收集到本章末尾并带回链。
+/// 引用式注释的正文取自以同一标记开头的段落（如“【注1】……”），该段落随之移除；找不到正文的标记保持原样。
+fn apply_chapter_notes(body: &str, chapter: usize, rules: &NoteRules, epub3: bool) -> String {
+    const PARAGRAPH_OPEN: &str = "  <p class=\"te-paragraph\">";
+    fn paragraph_text(line: &str) -> Option<&str> {
+        line.strip_prefix(PARAGRAPH_OPEN)
+            .and_then(|rest| rest.trim_end().strip_suffix("</p>"))
     }
+    let lines: Vec<&str> = body.split_inclusive('\n').collect();
 
-    let mut fonts = Vec::new();
-    let entries = fs::read_dir(&dir).map_err(|e| format!("读取字体目录失败: {}", e))?;
-    for entry in entries {
-        let entry = entry.map_err(|e| format!("读取字体目录项失败: {}", e))?;
-        let path = entry.path();
-        if !path.is_file() {
+    let mut definitions: HashMap<String, (usize, String)> = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+        let Some(text) = paragraph_text(line).map(xml_unescape) else {
             continue;
-        }
-        let ext = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("")
-            .to_lowercase();
-        if !matches!(ext.as_str(), "ttf" | "otf" | "woff" | "woff2") {
+        };
+        let Ok(Some(caps)) = rules.reference.captures(&text) else {
+            continue;
+        };
+        let Some(whole) = caps
+            .get(0)
+            .filter(|m| m.start() == 0 && !m.as_str().is_empty())
+        else {
             continue;
+        };
+        let note = text[whole.end()..]
+            .trim_start_matches([':', '：'])
+            .trim_matches(|c: char| c.is_whitespace());
+        if !note.is_empty() {
+            definitions
+                .entry(note_capture(&caps).to_string())
+                .or_insert((index, note.to_string()));
         }
-        if let Ok(info) = build_library_font_info(&app, &path) {
-            fonts.push(info);
+    }
+    let definition_lines: HashMap<usize, &str> = definitions
+        .iter()
+        .map(|(key, (index, _))| (*index, key.as_str()))
+        .collect();
+
+    let noteref_type = if epub3 { " epub:type=\"noteref\"" } else { "" };
+    let ref_class = if rules.duokan {
+        "duokan-footnote te-noteref"
+    } else {
+        "te-noteref"
+    };
+    let mut notes: Vec<String> = Vec::new();
+    let mut numbered: HashMap<String, usize> = HashMap::new();
+    let mut rewritten: Vec<String> = Vec::with_capacity(lines.len());
+    for (index, line) in lines.iter().enumerate() {
+        // 段落由 escape_xml 生成，反转义后匹配，写回时再转义
+        let text = match paragraph_text(line) {
+            Some(text) if !definition_lines.contains_key(&index) => xml_unescape(text),
+            _ => {
+                rewritten.push(line.to_string());
+                continue;
+            }
+        };
+        let mut out = String::new();
+        let mut pos = 0;
+        loop {
+            let reference = rules.reference.captures_from_pos(&text, pos).ok().flatten();
+            let inline = rules.inline.captures_from_pos(&text, pos).ok().flatten();
+            let (caps, is_inline) = match (reference, inline) {
+                (Some(r), Some(n)) if n.get(0).map(|m| m.start()) < r.get(0).map(|m| m.start()) => {
+                    (n, true)
+                }
+                (Some(r), _) => (r, false),
+                (None, Some(n)) => (n, true),
+                (None, None) => break,
+            };
+            let Some(whole) = caps.get(0).filter(|m| !m.as_str().is_empty()) else {
+                break;
+            };
+            out.push_str(&escape_xml(&text[pos..whole.start()]));
+            pos = whole.end();
+            let captured = note_capture(&caps);
+            let (number, first) = if is_inline {
+                if captured.is_empty() {
+                    out.push_str(&escape_xml(whole.as_str()));
+                    continue;
+                }
+                notes.push(captured.to_string());
+                (notes.len(), true)
+            } else if let Some(&number) = numbered.get(captured) {
+                (number, false)
+            } else if let Some((_, note)) = definitions.get(captured) {
+                notes.push(note.clone());
+                numbered.insert(captured.to_string(), notes.len());
+                (notes.len(), true)
+            } else {
+                out.push_str(&escape_xml(whole.as_str()));
+                continue;
+            };
+            // 同一编号多次引用时只有第一处带 id，回链指向它
+            let id_attr = if first {
+                format!(" id=\"noteref-{}-{}\"", chapter, number)
+            } else {
+                String::new()
+            };
+            out.push_str(&format!(
+                "<sup><a class=\"{}\"{} href=\"#note-{}-{}\"{}>[{}]</a></sup>",
+                ref_class, noteref_type, chapter, number, id_attr, number
+            ));
         }
+        out.push_str(&escape_xml(&text[pos..]));
+        rewritten.push(format!("{}{}</p>\n", PARAGRAPH_OPEN, out));
     }
 
-    fonts.sort_by(|a, b| a.family.to_lowercase().cmp(&b.family.to_lowercase()));
-    Ok(fonts)
+    let mut result = String::new();
+    for (index, line) in rewritten.iter().enumerate() {
+        let consumed = definition_lines
+            .get(&index)
+            .is_some_and(|key| numbered.contains_key(*key));
+        if !consumed {
+            result.push_str(line);
+        }
+    }
+    if notes.is_empty() {
+        return result;
+    }
+    if rules.duokan {
+        result.push_str("  <ol class=\"duokan-footnote-content te-footnotes\">\n");
+        for (offset, note) in notes.iter().enumerate() {
+            let number = offset + 1;
+            result.push_str(&format!(
+                "    <li class=\"duokan-footnote-item\" id=\"note-{c}-{n}\"><a class=\"duokan-footnote-item-link te-footnote-backlink\" href=\"#noteref-{c}-{n}\">[{n}]</a> {note}</li>\n",
+                c = chapter,
+                n = number,
+                note = escape_xml(note)
+            ));
+        }
+        result.push_str("  </ol>\n");
+    } else {
+        let (tag, note_type) = if epub3 {
+            ("aside", " epub:type=\"footnote\"")
+        } else {
+            ("div", "")
+        };
+        for (offset, note) in notes.iter().enumerate() {
+            let number = offset + 1;
+            result.push_str(&format!(
+                "  <{tag} class=\"te-footnote\"{note_type} id=\"note-{c}-{n}\"><p class=\"te-footnote-text\"><a class=\"te-footnote-backlink\" href=\"#noteref-{c}-{n}\">[{n}]</a> {note}</p></{tag}>\n",
+                tag = tag,
+                note_type = note_type,
+                c = chapter,
+                n = number,
+                note = escape_xml(note)
+            ));
+        }
+    }
+    result
 }
 
-#[tauri::command]
-fn import_library_font(app: tauri::AppHandle, path: String) -> Result<LibraryFontInfo, String> {
-    let normalized = normalize_local_file_path(&path);
-    let source = PathBuf::from(&normalized);
-    if !source.exists() || !source.is_file() {
-        return Err("字体文件不存在".to_string());
+/// 章节拆分后，把指向其他续页中 id 的 "#xxx" 链接改成带文件名的链接。
+fn link_split_parts(parts: &mut [String], file_names: &[String]) {
+    if parts.len() < 2 {
+        return;
     }
-
-    let ext = source
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("")
-        .to_lowercase();
-    if !matches!(ext.as_str(), "ttf" | "otf" | "woff" | "woff2") {
-        return Err("仅支持导入 ttf / otf / woff / woff2 字体".to_string());
+    let mut owners: HashMap<String, usize> = HashMap::new();
+    for (index, part) in parts.iter().enumerate() {
+        for caps in ELEMENT_ID_RE.captures_iter(part).flatten() {
+            if let Some(id) = caps.get(1) {
+                owners.entry(id.as_str().to_string()).or_insert(index);
+            }
+        }
+    }
+    for (index, part) in parts.iter_mut().enumerate() {
+        let linked = FRAGMENT_HREF_RE.replace_all(part, |caps: &fancy_regex::Captures| {
+            let id = caps.get(1).map(|m| m.as_str()).unwrap_or("");
+            match (owners.get(id), caps.get(0)) {
+                (Some(&owner), _) if owner != index => {
+                    format!("href=\"{}#{}\"", file_names[owner], id)
+                }
+                (_, Some(whole)) => whole.as_str().to_string(),
+                _ => String::new(),
+            }
+        });
+        *part = linked.into_owned();
+    }
+}
+
+/// 按正文字节数或段落数把章节 body 切成若干段，只在段落边界处切，每段至少保留一个段落。
+fn split_chapter_body(body: &str, max_bytes: usize, max_paragraphs: usize) -> Vec<String> {
+    if max_bytes == 0 && max_paragraphs == 0 {
+        return vec![body.to_string()];
+    }
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut paragraphs = 0usize;
+    for line in body.split_inclusive('\n') {
+        let is_paragraph = line.starts_with("  <p class=\"te-paragraph\"")
+            || line.starts_with("  <p class=\"te-divider-line\"")
+            || line.starts_with("  <div class=\"te-divider-image\"")
+            || line.starts_with("  <div class=\"te-illustration\"");
+        if is_paragraph && paragraphs > 0 {
+            let over_bytes = max_bytes > 0 && current.len() + line.len() > max_bytes;
+            let over_paragraphs = max_paragraphs > 0 && paragraphs >= max_paragraphs;
+            if over_bytes || over_paragraphs {
+                parts.push(std::mem::take(&mut current));
+                paragraphs = 0;
+            }
+        }
+        if is_paragraph {
+            paragraphs += 1;
+        }
+        current.push_str(line);
     }
+    parts.push(current);
+    parts
+}
 
-    let font_data = fs::read(&source).map_err(|e| format!("读取字体失败: {}", e))?;
-    let family = pick_font_family_name(&font_data, &source);
-    let file_stem = sanitize_filename_part(&family);
-    let safe_stem = if file_stem.is_empty() {
-        "font".to_string()
+#[tauri::command]
+async fn export_epub(
+    save_path: String,
+    content: String,
+    chapters: Vec<ChapterInfo>,
+    metadata: EpubMetadata,
+) -> Result<Vec<String>, String> {
+    let epub3 = metadata.is_epub3();
+    let note_rules = NoteRules::from_options(&metadata.notes)?;
+    let path = Path::new(&save_path);
+    let file = fs::File::create(&path).map_err(|e| e.to_string())?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let options_store = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+    zip.start_file("mimetype", options_store)
+        .map_err(|e| e.to_string())?;
+    zip.write_all(b"application/epub+zip")
+        .map_err(|e| e.to_string())?;
+
+    zip.start_file("META-INF/container.xml", options)
+        .map_err(|e| e.to_string())?;
+    zip.write_all(
+        r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+   <rootfiles>
+      <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+   </rootfiles>
+</container>"#
+            .as_bytes(),
+    )
+    .map_err(|e| e.to_string())?;
+
+    zip.start_file("OEBPS/Styles/font.css", options)
+        .map_err(|e| e.to_string())?;
+    let font_css = if metadata.font_css.trim().is_empty() {
+        CSS_FONT
     } else {
-        file_stem
+        &metadata.font_css
     };
+    zip.write_all(font_css.as_bytes())
+        .map_err(|e| e.to_string())?;
 
-    let target_dir = library_fonts_dir(&app)?;
-    ensure_dir(&target_dir)?;
+    zip.start_file("OEBPS/Styles/main.css", options)
+        .map_err(|e| e.to_string())?;
+    let main_css = if metadata.main_css.trim().is_empty() {
+        CSS_MAIN
+    } else {
+        &metadata.main_css
+    };
+    let asset_slot_placements = parse_asset_slot_placements(main_css);
+    zip.write_all(main_css.as_bytes())
+        .map_err(|e| e.to_string())?;
 
-    let mut target = target_dir.join(format!("{}.{}", safe_stem, ext));
-    let mut seq = 2usize;
-    while target.exists() {
-        let existing = fs::read(&target).unwrap_or_default();
-        if existing == font_data {
-            return build_library_font_info(&app, &target);
-        }
-        target = target_dir.join(format!("{}-{}.{}", safe_stem, seq, ext));
-        seq += 1;
+    let mut has_cover = false;
+    let mut cover_ext = "jpg".to_string();
+    let normalized_cover_path = normalize_local_file_path(&metadata.cover_path);
+    if !normalized_cover_path.trim().is_empty() {
+        let img_bytes = fs::read(&normalized_cover_path)
+            .map_err(|e| format!("读取封面失败: {} ({})", normalized_cover_path, e))?;
+        cover_ext = Path::new(&normalized_cover_path)
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("jpg")
+            .to_lowercase();
+        let cover_filename = format!("OEBPS/Images/cover.{}", cover_ext);
+        zip.start_file(&cover_filename, options)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(&img_bytes).map_err(|e| e.to_string())?;
+        has_cover = true;
     }
 
-    fs::write(&target, &font_data).map_err(|e| format!("写入字体失败: {}", e))?;
-    build_library_font_info(&app, &target)
-}
+    let lines: Vec<&str> = content.lines().collect();
+    let mut manifest_items = String::new();
+    let mut spine_refs = String::new();
+    let mut ncx_navpoints = String::new();
+    let mut play_order = 1;
 
-#[tauri::command]
-fn rename_library_font(
-    app: tauri::AppHandle,
-    file_name: String,
-    family: String,
-) -> Result<LibraryFontInfo, String> {
-    let trimmed_family = family.trim();
-    if trimmed_family.is_empty() {
-        return Err("字体名称不能为空".to_string());
+    if has_cover {
+        let mime = image_mime_from_ext(&cover_ext);
+        manifest_items.push_str(&format!(r#"<item id="cover-image" href="Images/cover.{}" media-type="{}" properties="cover-image"/>"#, cover_ext, mime));
     }
 
-    let font_path = library_fonts_dir(&app)?.join(&file_name);
-    if !font_path.exists() || !font_path.is_file() {
-        return Err("字体文件不存在".to_string());
-    }
+    // 写入资产文件
+    let mut image_slot_hrefs: HashMap<String, String> = HashMap::new();
+    let mut image_asset_hrefs: HashMap<String, String> = HashMap::new();
+    let font_subset_text = if metadata.subset_fonts {
+        Some(build_font_subset_text(&content, &metadata))
+    } else {
+        None
+    };
+    for (i, asset) in metadata.assets.iter().enumerate() {
+        let normalized_asset_path = normalize_local_file_path(&asset.path);
+        if let Ok(mut asset_bytes) = fs::read(&normalized_asset_path) {
+            let sub_dir = match asset.category.as_str() {
+                "fonts" => "Fonts",
+                "images" => "Images",
+                _ => "Other",
+            };
+            let ext = Path::new(&asset.name)
+                .extension()
+                .and_then(|s| s.to_str())
+                .unwrap_or("")
+                .to_lowercase();
+            let safe_name = if !asset.role.trim().is_empty() && asset.category == "images" {
+                let stem = asset_slot_file_stem(&asset.role);
+                if ext.is_empty() {
+                    format!("{}.jpg", stem)
+                } else {
+                    format!("{}.{}", stem, ext)
+                }
+            } else {
+                let sanitized = sanitize_filename_part(&asset.name);
+                if sanitized.is_empty() {
+                    format!("asset_{}", i)
+                } else {
+                    sanitized
+                }
+            };
+
+            if asset.category == "fonts" {
+                if let Some(subset_text) = font_subset_text.as_deref() {
+                    match try_subset_font_bytes(&asset_bytes, &ext, subset_text) {
+                        Ok(subsetted) => asset_bytes = subsetted,
+                        Err(err) => eprintln!("字体子集化已回退原文件: {} ({})", asset.name, err),
+                    }
+                }
+            }
 
-    let mut aliases = read_library_font_aliases(&app)?;
-    aliases.insert(file_name.clone(), trimmed_family.to_string());
-    write_library_font_aliases(&app, &aliases)?;
-    build_library_font_info(&app, &font_path)
-}
+            let asset_filename = format!("OEBPS/{}/{}", sub_dir, safe_name);
+            zip.start_file(&asset_filename, options)
+                .map_err(|e| e.to_string())?;
+            zip.write_all(&asset_bytes).map_err(|e| e.to_string())?;
 
-#[tauri::command]
-fn delete_library_font(app: tauri::AppHandle, file_name: String) -> Result<(), String> {
-    let font_path = library_fonts_dir(&app)?.join(&file_name);
-    if !font_path.exists() || !font_path.is_file() {
-        return Err("字体文件不存在".to_string());
+            let href = format!("{}/{}", sub_dir, safe_name);
+            let mime = asset_manifest_mime(&safe_name);
+            if !asset.role.trim().is_empty() && asset.category == "images" {
+                image_slot_hrefs.insert(asset.role.clone(), href.clone());
+            }
+            if asset.category == "images" {
+                image_asset_hrefs.insert(asset.name.clone(), href.clone());
+            }
+            manifest_items.push_str(&format!(
+                r#"<item id="asset_{}" href="{}" media-type="{}"/>"#,
+                i, href, mime
+            ));
+        }
     }
 
-    fs::remove_file(&font_path).map_err(|e| format!("删除字体失败: {}", e))?;
-    let mut aliases = read_library_font_aliases(&app)?;
-    if aliases.remove(&file_name).is_some() {
-        write_library_font_aliases(&app, &aliases)?;
+    // 正文插图标记：先匹配素材列表中的图片，再到源 TXT 所在目录查找并写入 Images/
+    // 找不到或不合法的标记保留原文，名称作为警告返回给调用方
+    let mut illustration_hrefs: HashMap<String, String> = HashMap::new();
+    let mut warnings: Vec<String> = Vec::new();
+    let source_dir = Path::new(&normalize_local_file_path(&metadata.source_path))
+        .parent()
+        .map(Path::to_path_buf);
+    let mut used_image_names: HashSet<String> = image_asset_hrefs
+        .values()
+        .chain(image_slot_hrefs.values())
+        .map(|href| href.trim_start_matches("Images/").to_lowercase())
+        .collect();
+    if has_cover {
+        used_image_names.insert(format!("cover.{}", cover_ext).to_lowercase());
     }
-    Ok(())
-}
-
-#[tauri::command]
-fn list_style_templates(app: tauri::AppHandle) -> Result<Vec<StyleTemplateInfo>, String> {
-    let root = style_templates_root(&app)?;
-    ensure_dir(&root)?;
-
-    let builtin_path = builtin_style_template_path(&app)?;
-    let mut templates = vec![build_style_template_info(&builtin_path, true)?];
-
-    let entries = fs::read_dir(&root).map_err(|e| format!("读取样式模板目录失败: {}", e))?;
-    for entry in entries {
-        let entry = entry.map_err(|e| format!("读取样式模板目录项失败: {}", e))?;
-        let path = entry.path();
-        if !path.is_file() {
+    for (i, name) in illustration_marker_names(&content).into_iter().enumerate() {
+        let file_name = Path::new(&name)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or(&name)
+            .to_string();
+        if let Some(href) = image_asset_hrefs
+            .get(&name)
+            .or_else(|| image_asset_hrefs.get(&file_name))
+        {
+            illustration_hrefs.insert(name, href.clone());
             continue;
         }
-        let ext = path
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("")
-            .to_lowercase();
-        if ext != "css" {
+        let Some(dir) = source_dir.as_ref() else {
+            warnings.push(format!("插图未找到，保留原标记: {}", name));
+            continue;
+        };
+        // 只接受源目录下的相对路径图片，拒绝绝对路径与 ..
+        let relative = Path::new(&name);
+        let is_plain_relative = relative
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if !is_plain_relative || !asset_manifest_mime(&file_name).starts_with("image/") {
+            warnings.push(format!("插图路径不合法，保留原标记: {}", name));
             continue;
         }
-        if path.file_name().and_then(|s| s.to_str()) == Some("builtin.css") {
+        let Ok(bytes) = fs::read(dir.join(relative)) else {
+            warnings.push(format!("插图未找到，保留原标记: {}", name));
             continue;
+        };
+        let sanitized = sanitize_filename_part(&file_name);
+        let base = if sanitized.is_empty() {
+            format!("illustration_{}.jpg", i)
+        } else {
+            sanitized
+        };
+        let mut safe_name = base.clone();
+        let mut n = 2;
+        while !used_image_names.insert(safe_name.to_lowercase()) {
+            let path = Path::new(&base);
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+            safe_name = match path.extension().and_then(|s| s.to_str()) {
+                Some(ext) => format!("{}_{}.{}", stem, n, ext),
+                None => format!("{}_{}", stem, n),
+            };
+            n += 1;
         }
-        templates.push(build_style_template_info(&path, false)?);
+        zip.start_file(format!("OEBPS/Images/{}", safe_name), options)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(&bytes).map_err(|e| e.to_string())?;
+        let href = format!("Images/{}", safe_name);
+        manifest_items.push_str(&format!(
+            r#"<item id="illustration_{}" href="{}" media-type="{}"/>"#,
+            i,
+            escape_xml(&href),
+            asset_manifest_mime(&safe_name)
+        ));
+        illustration_hrefs.insert(name, href);
     }
+    manifest_items
+        .push_str(r#"<item id="font.css" href="Styles/font.css" media-type="text/css"/>"#);
+    manifest_items
+        .push_str(r#"<item id="main.css" href="Styles/main.css" media-type="text/css"/>"#);
 
-    let mut imported = templates.split_off(1);
-    imported.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
-    templates.extend(imported);
-    Ok(templates)
-}
+    for (i, chapter) in chapters.iter().enumerate() {
+        let file_name_in_zip = format!("OEBPS/Text/chapter{}.xhtml", i);
+        let href_in_opf = format!("Text/chapter{}.xhtml", i);
+        let id = format!("chapter{}", i);
 
-#[tauri::command]
-fn read_style_template(app: tauri::AppHandle, id: String) -> Result<StyleTemplateContent, String> {
-    let is_builtin = id == "builtin";
-    let path = if is_builtin {
-        builtin_style_template_path(&app)?
-    } else {
-        style_template_path(&app, &id)?
-    };
-    let main_css = if path.exists() {
-        let css = fs::read_to_string(&path).map_err(|e| format!("读取样式模板失败: {}", e))?;
-        if is_builtin && is_stale_builtin_style_template_css(&css) {
-            fs::remove_file(&path).map_err(|e| format!("升级内置样式模板失败: {}", e))?;
-            String::new()
-        } else {
-            css
-        }
-    } else {
-        String::new()
-    };
-    Ok(StyleTemplateContent {
-        id: if is_builtin {
-            "builtin".to_string()
+        let start_line = chapter.line_number;
+        let end_line = if i + 1 < chapters.len() {
+            chapters[i + 1].line_number - 2 // line_number 指向标题的下一行，所以要减2
         } else {
-            id
-        },
-        name: if is_builtin {
-            "内置模板".to_string()
+            lines.len() - 1
+        };
+        let safe_end = end_line.min(lines.len() - 1);
+        let safe_start = start_line.min(safe_end);
+        let body_lines = if safe_start <= safe_end {
+            // `line_number` already points to the first body line (next line after title),
+            // so do not skip one more line here.
+            &lines[safe_start..=safe_end] // 使用 ..= 包含 safe_end
         } else {
-            style_template_name_from_path(&path)
-        },
-        main_css,
-        is_builtin,
-    })
-}
-
-#[tauri::command]
-fn import_style_template(app: tauri::AppHandle, path: String) -> Result<StyleTemplateInfo, String> {
-    let normalized = normalize_local_file_path(&path);
-    let source = PathBuf::from(&normalized);
-    if !source.exists() || !source.is_file() {
-        return Err("样式模板文件不存在".to_string());
-    }
-
-    let ext = source
-        .extension()
-        .and_then(|s| s.to_str())
-        .unwrap_or("")
-        .to_lowercase();
-    if ext != "css" {
-        return Err("仅支持导入 CSS 样式模板".to_string());
-    }
+            &[]
+        };
 
-    let css = fs::read_to_string(&source).map_err(|e| format!("读取样式模板失败: {}", e))?;
-    let root = style_templates_root(&app)?;
-    ensure_dir(&root)?;
+        let mut html_body = String::new();
+        let mut class_attr = "te-book-body te-chapter-page";
 
-    let source_stem = source
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("style-template");
-    let safe_stem = {
-        let candidate = sanitize_filename_part(source_stem);
-        if candidate.is_empty() {
-            "style-template".to_string()
+        let (chap_num_raw, chap_name_raw) = split_title(&chapter.title);
+        let safe_display_title = if !chap_num_raw.is_empty() && !chap_name_raw.is_empty() {
+            format!(
+                "{} {}",
+                escape_xml(&chap_num_raw),
+                escape_xml(&chap_name_raw)
+            )
         } else {
-            candidate
-        }
-    };
-
-    let mut target = root.join(format!("{}.css", safe_stem));
-    let mut seq = 2usize;
-    while target.exists() {
-        let existing = fs::read_to_string(&target).unwrap_or_default();
-        if existing == css {
-            return build_style_template_info(&target, false);
-        }
-        target = root.join(format!("{}-{}.css", safe_stem, seq));
-        seq += 1;
-    }
-
-    fs::write(&target, css).map_err(|e| format!("保存样式模板失败: {}", e))?;
-    build_style_template_info(&target, false)
-}
+            escape_xml(&chapter.title)
+        };
 
-#[tauri::command]
-fn save_style_template(
-    app: tauri::AppHandle,
-    id: String,
-    main_css: String,
-) -> Result<StyleTemplateInfo, String> {
-    let path = if id == "builtin" {
-        builtin_style_template_path(&app)?
-    } else {
-        style_template_path(&app, &id)?
-    };
-    let root = style_templates_root(&app)?;
-    ensure_dir(&root)?;
-    fs::write(&path, main_css).map_err(|e| format!("保存样式模板失败: {}", e))?;
-    build_style_template_info(&path, id == "builtin")
-}
+        if chapter.is_meta {
+            class_attr = "te-book-body te-intro-page";
+            if i == 0 && has_cover {
+                html_body.push_str(&format!(
+                    "  <div class=\"te-cover-wrap\"><img class=\"te-cover-image\" src=\"../Images/cover.{}\" alt=\"封面\" /><br/>\n    <span class=\"book-name\">{}</span>\n  </div>\n\n",
+                    cover_ext,
+                    escape_xml(&metadata.title)
+                ));
+            }
+            html_body.push_str(&format!(
+                "  <h1 class=\"te-intro-title\" title=\"{}\"><span><b>{}</b></span></h1>\n",
+                safe_display_title, safe_display_title
+            ));
+            append_text_body_lines(
+                &mut html_body,
+                body_lines,
+                &illustration_hrefs,
+                true,
+                first_image_slot_for_placement(
+                    &image_slot_hrefs,
+                    &asset_slot_placements,
+                    "replace-ellipsis",
+                    "dividerImage",
+                ),
+            );
+        } else {
+            match chapter.level {
+                1 => {
+                    class_attr = "te-book-body te-volume-page";
+                    let safe_vol_num = escape_xml(&chap_num_raw);
+                    let safe_vol_name = escape_xml(&chap_name_raw);
 
-#[tauri::command]
-fn restore_builtin_style_template(app: tauri::AppHandle) -> Result<(), String> {
-    let path = builtin_style_template_path(&app)?;
-    if path.exists() {
-        fs::remove_file(&path).map_err(|e| format!("恢复内置样式失败: {}", e))?;
-    }
-    Ok(())
-}
+                    // We only use the vertical number styling if there's actually a volume number parsed
+                    let vertical_num = if !safe_vol_num.is_empty() {
+                        format_vertical_volume(&safe_vol_num)
+                    } else {
+                        String::new()
+                    };
 
-fn is_stale_builtin_style_template_css(css: &str) -> bool {
-    let normalized = css.trim();
-    if normalized.is_empty() {
-        return false;
-    }
+                    let formatted_name = if !safe_vol_name.is_empty() {
+                        safe_vol_name
+                    } else {
+                        safe_display_title.clone()
+                    };
 
-    normalized.contains("TEpub template schema: 1") && !normalized.contains(".te-volume-subtitle")
-}
+                    let inserted_volume_head = if let Some((role, href)) =
+                        first_image_slot_for_placement(
+                            &image_slot_hrefs,
+                            &asset_slot_placements,
+                            "volume-before-title",
+                            "volumeHead",
+                        ) {
+                        html_body.push_str(&image_slot_html(role, href));
+                        true
+                    } else {
+                        false
+                    };
 
-#[derive(serde::Deserialize, Clone)]
-pub struct RegexRule {
-    pub level: u8,
-    pub pattern: String,
-}
+                    if !inserted_volume_head {
+                        class_attr = "te-book-body te-volume-page te-volume-page--no-image";
+                    }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone)]
-pub struct ChapterInfo {
-    pub title: String,
-    pub line_number: usize,
-    pub level: u8,
-    pub is_meta: bool,
-    pub word_count: usize,
-}
+                    html_body.push_str(&format!(
+                        "  <h1 class=\"te-volume-title\" title=\"{}\"><br /><br />\n  {}</h1>\n  <p class=\"te-volume-subtitle\">{}</p>\n",
+                        safe_display_title, vertical_num, formatted_name.trim()
+                    ));
 
-#[tauri::command]
-async fn scan_chapters(content: String, rules: Vec<RegexRule>) -> Vec<ChapterInfo> {
-    // Normalize line endings to ensure consistency with CodeMirror's line counting
-    // CodeMirror treats \r, \n, and \r\n all as line separators
-    // Rust's .lines() only recognizes \n and \r\n
-    let content = content
-        .replace("\r\n", "\n")
-        .replace('\r', "\n")
-        .replace('\u{2028}', "\n")
-        .replace('\u{2029}', "\n");
+                    // Add body content for Volume if they exist, to prevent loss of potential inner-body text
+                    append_text_body_lines(
+                        &mut html_body,
+                        body_lines,
+                        &illustration_hrefs,
+                        true,
+                        first_image_slot_for_placement(
+                            &image_slot_hrefs,
+                            &asset_slot_placements,
+                            "replace-ellipsis",
+                            "dividerImage",
+                        ),
+                    );
+                }
+                3 => {
+                    let safe_chap_num = escape_xml(&chap_num_raw);
+                    let safe_chap_name = escape_xml(&chap_name_raw);
 
-    let mut chapters = Vec::new();
+                    let inserted_chapter_head = if let Some((role, href)) =
+                        first_image_slot_for_placement(
+                            &image_slot_hrefs,
+                            &asset_slot_placements,
+                            "chapter-before-title",
+                            "chapterHead",
+                        ) {
+                        html_body.push_str(&image_slot_html(role, href));
+                        true
+                    } else {
+                        false
+                    };
 
-    // Compile regex rules safely
-    let compiled_rules: Vec<(u8, Regex)> = rules
-        .into_iter()
-        .filter_map(|r| Regex::new(&r.pattern).ok().map(|re| (r.level, re)))
-        .collect();
+                    if !inserted_chapter_head {
+                        class_attr = "te-book-body te-chapter-page te-chapter-page--no-image";
+                    }
 
-    let mut current_chapter: Option<ChapterInfo> = None;
-    for (index, line) in content.lines().enumerate() {
-        let line_trim = line.trim();
-        let char_count = line_trim.chars().count();
-        let is_empty = line_trim.is_empty();
+                    if !safe_chap_num.is_empty() {
+                        html_body.push_str(&format!(
+                            "  <h3 class=\"te-chapter-title\"><span class=\"te-chapter-number\">{}</span><br/><b class=\"te-chapter-name\">{}</b></h3>\n",
+                            safe_chap_num, safe_chap_name
+                        ));
+                    } else {
+                        html_body.push_str(&format!(
+                            "  <h3 class=\"te-chapter-title\">{}</h3>\n",
+                            safe_display_title
+                        ));
+                    }
 
-        let mut match_level = None;
-        if !is_empty {
-            for (level, re) in &compiled_rules {
-                if re.is_match(line).unwrap_or(false) {
-                    match_level = Some(*level);
-                    break;
+                    append_text_body_lines(
+                        &mut html_body,
+                        body_lines,
+                        &illustration_hrefs,
+                        true,
+                        first_image_slot_for_placement(
+                            &image_slot_hrefs,
+                            &asset_slot_placements,
+                            "replace-ellipsis",
+                            "dividerImage",
+                        ),
+                    );
+                }
+                _ => {
+                    html_body.push_str(&format!(
+                        "  <h{} class=\"te-chapter-title\">{}</h{}>\n",
+                        chapter.level, safe_display_title, chapter.level
+                    ));
+                    append_text_body_lines(
+                        &mut html_body,
+                        body_lines,
+                        &illustration_hrefs,
+                        true,
+                        first_image_slot_for_placement(
+                            &image_slot_hrefs,
+                            &asset_slot_placements,
+                            "replace-ellipsis",
+                            "dividerImage",
+                        ),
+                    );
                 }
             }
         }
 
-        if let Some(lvl) = match_level {
-            if let Some(prev) = current_chapter.take() {
-                chapters.push(prev);
-            }
-            // Auto detect meta to prevent folding chapters into introductions
-            // But ensure Volumes (Level 1 containing 卷/部) are NOT treated as meta
-            // And only auto-detect meta for Level 1 items (Chapters at Level 3 should not be meta)
-            let is_vol_keyword = line_trim.contains("卷") || line_trim.contains("部");
-            let is_first_heading = chapters.is_empty();
-            let is_meta = !is_vol_keyword
-                && mobile_is_meta_title(line_trim)
-                && (lvl == 1 || is_first_heading);
+        if let Some(rules) = note_rules.as_ref() {
+            html_body = apply_chapter_notes(&html_body, i, rules, epub3);
+        }
 
-            current_chapter = Some(ChapterInfo {
-                title: line_trim.to_string(),
-                line_number: index + 1,
-                level: lvl,
-                is_meta,
-                word_count: 0,
-            });
-        } else {
-            if let Some(ref mut chapter) = current_chapter {
-                if !is_empty {
-                    chapter.word_count += char_count;
-                }
-            }
+        // 续页沿用本章 class 并追加 te-continuation-page，只进 manifest / spine，不进目录
+        let mut parts = split_chapter_body(
+            &html_body,
+            metadata.split_max_kb.saturating_mul(1024),
+            metadata.split_max_paragraphs,
+        );
+        let part_file_names: Vec<String> = (0..parts.len())
+            .map(|part| match part {
+                0 => format!("chapter{}.xhtml", i),
+                _ => format!("chapter{}_{}.xhtml", i, part),
+            })
+            .collect();
+        link_split_parts(&mut parts, &part_file_names);
+        for (part, part_body) in parts.iter().enumerate() {
+            let (file_name_in_zip, href_in_opf, id, part_class) = if part == 0 {
+                (
+                    file_name_in_zip.clone(),
+                    href_in_opf.clone(),
+                    id.clone(),
+                    class_attr.to_string(),
+                )
+            } else {
+                (
+                    format!("OEBPS/Text/{}", part_file_names[part]),
+                    format!("Text/{}", part_file_names[part]),
+                    format!("chapter{}_{}", i, part),
+                    format!("{} te-continuation-page", class_attr),
+                )
+            };
+            let full_html = chapter_xhtml_document(
+                &safe_display_title,
+                &part_class,
+                part_body,
+                epub3.then(|| metadata.language_tag()),
+            );
+
+            zip.start_file(&file_name_in_zip, options)
+                .map_err(|e| e.to_string())?;
+            zip.write_all(full_html.as_bytes())
+                .map_err(|e| e.to_string())?;
+
+            manifest_items.push_str(&format!(
+                r#"<item id="{}" href="{}" media-type="application/xhtml+xml"/>"#,
+                id, href_in_opf
+            ));
+            spine_refs.push_str(&format!(r#"<itemref idref="{}"/>"#, id));
         }
     }
-    if let Some(last) = current_chapter {
-        chapters.push(last);
-    }
-    chapters
-}
 
-#[tauri::command]
-async fn advanced_search(content: String, pattern: String, is_regex: bool) -> SearchResult {
-    if pattern.is_empty() {
-        return SearchResult {
-            found: false,
-            count: 0,
-            matches: vec![],
+    let mut open_navpoints = 0usize;
+    let mut nav_entries: Vec<(usize, String, String)> = Vec::new();
+
+    for ((i, chapter), depth) in chapters
+        .iter()
+        .enumerate()
+        .zip(export_toc_depths(&chapters))
+    {
+        let href_in_opf = format!("Text/chapter{}.xhtml", i);
+        let (chap_num_raw, chap_name_raw) = split_title(&chapter.title);
+        let safe_display_title = if !chap_num_raw.is_empty() && !chap_name_raw.is_empty() {
+            format!(
+                "{} {}",
+                escape_xml(&chap_num_raw),
+                escape_xml(&chap_name_raw)
+            )
+        } else {
+            escape_xml(&chapter.title)
         };
-    }
-    let mut matches_vec = Vec::new();
-    if is_regex {
-        if let Ok(re) = Regex::new(&pattern) {
-            for (i, line) in content.lines().enumerate() {
-                for m in re.find_iter(line) {
-                    if let Ok(match_obj) = m {
-                        matches_vec.push(MatchLocation {
-                            line: i + 1,
-                            start_char: line[..match_obj.start()].chars().count(),
-                            end_char: line[..match_obj.start()].chars().count()
-                                + line[match_obj.start()..match_obj.end()].chars().count(),
-                        });
-                    }
-                }
-            }
-        }
-    } else {
-        for (i, line) in content.lines().enumerate() {
-            for (byte_idx, part) in line.match_indices(&pattern) {
-                matches_vec.push(MatchLocation {
-                    line: i + 1,
-                    start_char: line[..byte_idx].chars().count(),
-                    end_char: line[..byte_idx].chars().count() + part.chars().count(),
-                });
-            }
+
+        while open_navpoints > depth {
+            ncx_navpoints.push_str("</navPoint>\n");
+            open_navpoints -= 1;
         }
+
+        ncx_navpoints.push_str(&format!(
+            r#"<navPoint id="navPoint-{}" playOrder="{}"><navLabel><text>{}</text></navLabel><content src="{}"/>"#,
+            play_order, play_order, safe_display_title, href_in_opf
+        ));
+        ncx_navpoints.push('\n');
+        open_navpoints += 1;
+        nav_entries.push((depth, safe_display_title, href_in_opf));
+        play_order += 1;
     }
-    let count = matches_vec.len();
-    SearchResult {
-        found: count > 0,
-        count,
-        matches: matches_vec,
+
+    for _ in 0..open_navpoints {
+        ncx_navpoints.push_str("</navPoint>\n");
     }
-}
 
-#[tauri::command]
-async fn advanced_replace(
-    content: String,
-    pattern: String,
-    replacement: String,
-    is_regex: bool,
-) -> Result<String, String> {
-    if is_regex {
-        let re = Regex::new(&pattern).map_err(|e| format!("Regex Error: {}", e))?;
-        Ok(re.replace_all(&content, &replacement).to_string())
+    let date_str = match metadata.opf.date.trim() {
+        "" => chrono::Local::now().format("%Y-%m-%d").to_string(),
+        date => escape_xml(date),
+    };
+    let creators = if metadata.opf.creators.is_empty() {
+        vec![OpfContributor {
+            name: metadata.creator.clone(),
+            ..Default::default()
+        }]
     } else {
-        Ok(content.replace(&pattern, &replacement))
+        metadata.opf.creators.clone()
+    };
+    let creators_xml = render_opf_people("dc:creator", "creator", &creators, "aut", epub3);
+    let full_uuid = if metadata.uuid.starts_with("urn:uuid:") {
+        metadata.uuid.clone()
+    } else {
+        format!("urn:uuid:{}", metadata.uuid)
+    };
+
+    let mut extra_metadata = render_opf_metadata_ext(&metadata.opf, epub3);
+    for tag in &metadata.tags {
+        let trimmed = tag.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        extra_metadata.push_str(&format!(
+            "    <dc:subject>{}</dc:subject>\n",
+            escape_xml(trimmed)
+        ));
+    }
+    for (k, v) in &metadata.extra {
+        if epub3 {
+            // EPUB 3 不允许自定义 dc 元素，改写成兼容的 name/content meta
+            if !v.trim().is_empty() {
+                extra_metadata.push_str(&format!(
+                    "    <meta name=\"{}\" content=\"{}\" />\n",
+                    escape_xml(k),
+                    escape_xml(v)
+                ));
+            }
+            continue;
+        }
+        extra_metadata.push_str(&format!(
+            "    <dc:{} pub-type=\"zdy\">{}</dc:{}>\n",
+            escape_xml(k),
+            escape_xml(v),
+            escape_xml(k)
+        ));
     }
-}
-
-// --- EPUB 导出 ---
 
-#[tauri::command]
-async fn export_epub(
-    save_path: String,
-    content: String,
-    chapters: Vec<ChapterInfo>,
-    metadata: EpubMetadata,
-) -> Result<(), String> {
-    let path = Path::new(&save_path);
-    let file = fs::File::create(&path).map_err(|e| e.to_string())?;
-    let mut zip = zip::ZipWriter::new(file);
-    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
-    let options_store = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let opf_content = if epub3 {
+        let mut landmarks: Vec<(&str, &str, String)> = Vec::new();
+        if has_cover && chapters.first().is_some_and(|c| c.is_meta) {
+            landmarks.push(("cover", "封面", "Text/chapter0.xhtml".to_string()));
+        }
+        landmarks.push(("toc", "目录", "nav.xhtml#toc".to_string()));
+        if let Some(i) = chapters.iter().position(|c| !c.is_meta) {
+            landmarks.push(("bodymatter", "正文", format!("Text/chapter{}.xhtml", i)));
+        }
+        let nav_content = build_epub3_nav(
+            &escape_xml(&metadata.title),
+            metadata.language_tag(),
+            &nav_entries,
+            &landmarks,
+        );
+        zip.start_file("OEBPS/nav.xhtml", options)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(nav_content.as_bytes())
+            .map_err(|e| e.to_string())?;
+        manifest_items.push_str(
+            r#"<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>"#,
+        );
+        // 书脊中的非线性目录页供部分阅读器跳转显示；nav 与 landmarks 的目标本身不要求在书脊中
+        spine_refs.push_str(r#"<itemref idref="nav" linear="no"/>"#);
+
+        // EPUB 3 中 dc 元素不能为空，空字段直接省略
+        let mut dc_optional = creators_xml;
+        for (tag, value) in [
+            ("publisher", &metadata.publisher),
+            ("description", &metadata.description),
+        ] {
+            if !value.trim().is_empty() {
+                dc_optional.push_str(&format!(
+                    "    <dc:{tag}>{}</dc:{tag}>\n",
+                    escape_xml(value),
+                    tag = tag
+                ));
+            }
+        }
+        if has_cover {
+            dc_optional.push_str("    <meta name=\"cover\" content=\"cover-image\" />\n");
+        }
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId" version="3.0" xml:lang="{}">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="BookId">{}</dc:identifier>
+    <dc:title id="t1">{}</dc:title>
+    <dc:language>{}</dc:language>
+    <dc:date>{}</dc:date>
+{}    <meta property="dcterms:modified">{}</meta>
+    <meta name="reamicro:md5" content="{}" />
+{}  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    {}
+  </manifest>
+  <spine toc="ncx">
+    {}
+  </spine>
+</package>"#,
+            escape_xml(metadata.language_tag()),
+            full_uuid,
+            escape_xml(&metadata.title),
+            escape_xml(metadata.language_tag()),
+            date_str,
+            dc_optional,
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            metadata.md5,
+            extra_metadata,
+            manifest_items,
+            spine_refs
+        )
+    } else {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title id="t1">{}</dc:title>
+    <dc:language>{}</dc:language>
+{}    <dc:date>{}</dc:date>
+    <dc:publisher>{}</dc:publisher>
+    <dc:identifier opf:scheme="UUID" id="BookId">{}</dc:identifier>
+    <dc:description>{}</dc:description>
+    <meta name="cover" content="cover-image" />
+    <meta property="reamicro:md5" content="{}" />
+{}  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    {}
+  </manifest>
+  <spine toc="ncx">
+    {}
+  </spine>
+</package>"#,
+            escape_xml(&metadata.title),
+            escape_xml(metadata.language_tag()),
+            creators_xml,
+            date_str,
+            escape_xml(&metadata.publisher),
+            full_uuid,
+            escape_xml(&metadata.description),
+            metadata.md5,
+            extra_metadata,
+            manifest_items,
+            spine_refs
+        )
+    };
 
-    zip.start_file("mimetype", options_store)
+    zip.start_file("OEBPS/content.opf", options)
         .map_err(|e| e.to_string())?;
-    zip.write_all(b"application/epub+zip")
+    zip.write_all(opf_content.as_bytes())
         .map_err(|e| e.to_string())?;
 
-    zip.start_file("META-INF/container.xml", options)
-        .map_err(|e| e.to_string())?;
-    zip.write_all(
-        r#"<?xml version="1.0"?>
-<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
-   <rootfiles>
-      <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
-   </rootfiles>
-</container>"#
-            .as_bytes(),
-    )
-    .map_err(|e| e.to_string())?;
+    let ncx_content = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE ncx PUBLIC "-//NISO//DTD ncx 2005-1//EN" "http://www.daisy.org/z3986/2005/ncx-2005-1.dtd">
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head>
+    <meta name="dtb:uid" content="{}"/>
+    <meta name="dtb:depth" content="2"/>
+    <meta name="dtb:totalPageCount" content="0"/>
+    <meta name="dtb:maxPageNumber" content="0"/>
+  </head>
+  <docTitle><text>{}</text></docTitle>
+  <navMap>
+    {}
+  </navMap>
+</ncx>"#,
+        full_uuid,
+        escape_xml(&metadata.title),
+        ncx_navpoints
+    );
 
-    zip.start_file("OEBPS/Styles/font.css", options)
+    zip.start_file("OEBPS/toc.ncx", options)
         .map_err(|e| e.to_string())?;
-    let font_css = if metadata.font_css.trim().is_empty() {
-        CSS_FONT
-    } else {
-        &metadata.font_css
-    };
-    zip.write_all(font_css.as_bytes())
+    zip.write_all(ncx_content.as_bytes())
         .map_err(|e| e.to_string())?;
 
-    zip.start_file("OEBPS/Styles/main.css", options)
-        .map_err(|e| e.to_string())?;
-    let main_css = if metadata.main_css.trim().is_empty() {
-        CSS_MAIN
-    } else {
-        &metadata.main_css
-    };
-    let asset_slot_placements = parse_asset_slot_placements(main_css);
-    zip.write_all(main_css.as_bytes())
-        .map_err(|e| e.to_string())?;
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(warnings)
+}
 
-    let mut has_cover = false;
-    let mut cover_ext = "jpg".to_string();
-    let normalized_cover_path = normalize_local_file_path(&metadata.cover_path);
-    if !normalized_cover_path.trim().is_empty() {
-        let img_bytes = fs::read(&normalized_cover_path)
-            .map_err(|e| format!("读取封面失败: {} ({})", normalized_cover_path, e))?;
-        cover_ext = Path::new(&normalized_cover_path)
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("jpg")
-            .to_lowercase();
-        let cover_filename = format!("OEBPS/Images/cover.{}", cover_ext);
-        zip.start_file(&cover_filename, options)
-            .map_err(|e| e.to_string())?;
-        zip.write_all(&img_bytes).map_err(|e| e.to_string())?;
-        has_cover = true;
-    }
+#[cfg(test)]
+mod epub_export_tests {
+    use super::*;
 
-    let lines: Vec<&str> = content.lines().collect();
-    let mut manifest_items = String::new();
-    let mut spine_refs = String::new();
-    let mut ncx_navpoints = String::new();
-    let mut play_order = 1;
+    fn chapter(title: &str, line_number: usize, level: u8, is_meta: bool) -> ChapterInfo {
+        ChapterInfo {
+            title: title.to_string(),
+            line_number,
+            level,
+            is_meta,
+            word_count: 0,
+            rule_index: None,
+        }
+    }
 
-    if has_cover {
-        let mime = image_mime_from_ext(&cover_ext);
-        manifest_items.push_str(&format!(r#"<item id="cover-image" href="Images/cover.{}" media-type="{}" properties="cover-image"/>"#, cover_ext, mime));
+    fn sample_chapters() -> Vec<ChapterInfo> {
+        vec![
+            chapter("序言", 1, 1, true),
+            chapter("第一卷 风起", 3, 1, false),
+            chapter("第一章 甲", 4, 3, false),
+            chapter("第二章 乙", 6, 3, false),
+            chapter("后记", 8, 1, true),
+        ]
     }
 
-    // 写入资产文件
-    let mut image_slot_hrefs: HashMap<String, String> = HashMap::new();
-    let font_subset_text = if metadata.subset_fonts {
-        Some(build_font_subset_text(&content, &metadata))
-    } else {
-        None
-    };
-    for (i, asset) in metadata.assets.iter().enumerate() {
-        let normalized_asset_path = normalize_local_file_path(&asset.path);
-        if let Ok(mut asset_bytes) = fs::read(&normalized_asset_path) {
-            let sub_dir = match asset.category.as_str() {
-                "fonts" => "Fonts",
-                "images" => "Images",
-                _ => "Other",
-            };
-            let ext = Path::new(&asset.name)
-                .extension()
-                .and_then(|s| s.to_str())
-                .unwrap_or("")
-                .to_lowercase();
-            let safe_name = if !asset.role.trim().is_empty() && asset.category == "images" {
-                let stem = asset_slot_file_stem(&asset.role);
-                if ext.is_empty() {
-                    format!("{}.jpg", stem)
-                } else {
-                    format!("{}.{}", stem, ext)
-                }
-            } else {
-                let sanitized = sanitize_filename_part(&asset.name);
-                if sanitized.is_empty() {
-                    format!("asset_{}", i)
-                } else {
-                    sanitized
-                }
-            };
+    fn export(version: &str) -> Result<BTreeMap<String, String>, String> {
+        export_with(version, serde_json::json!({}))
+    }
 
-            if asset.category == "fonts" {
-                if let Some(subset_text) = font_subset_text.as_deref() {
-                    match try_subset_font_bytes(&asset_bytes, &ext, subset_text) {
-                        Ok(subsetted) => asset_bytes = subsetted,
-                        Err(err) => eprintln!("字体子集化已回退原文件: {} ({})", asset.name, err),
-                    }
-                }
-            }
+    fn export_with(
+        version: &str,
+        fields: serde_json::Value,
+    ) -> Result<BTreeMap<String, String>, String> {
+        export_book(
+            version,
+            fields,
+            "序言\n前言正文\n第一卷 风起\n第一章 甲\n正文甲\n第二章 乙\n正文乙\n后记\n完",
+            sample_chapters(),
+        )
+    }
 
-            let asset_filename = format!("OEBPS/{}/{}", sub_dir, safe_name);
-            zip.start_file(&asset_filename, options)
-                .map_err(|e| e.to_string())?;
-            zip.write_all(&asset_bytes).map_err(|e| e.to_string())?;
+    fn export_book(
+        version: &str,
+        fields: serde_json::Value,
+        content: &str,
+        chapters: Vec<ChapterInfo>,
+    ) -> Result<BTreeMap<String, String>, String> {
+        Ok(export_book_with_warnings(version, fields, content, chapters)?.0)
+    }
+
+    fn export_book_with_warnings(
+        version: &str,
+        fields: serde_json::Value,
+        content: &str,
+        chapters: Vec<ChapterInfo>,
+    ) -> Result<(BTreeMap<String, String>, Vec<String>), String> {
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let save_path = dir.path().join("书.epub");
+        let mut value = serde_json::json!({
+            "title": "测试书",
+            "creator": "作者",
+            "publisher": "",
+            "cover_path": "",
+            "uuid": "1234",
+            "md5": "abcd",
+            "epub_version": version,
+        });
+        if let (Some(target), Some(fields)) = (value.as_object_mut(), fields.as_object()) {
+            target.extend(fields.clone());
+        }
+        let metadata: EpubMetadata = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        let warnings = tauri::async_runtime::block_on(export_epub(
+            save_path.to_string_lossy().to_string(),
+            content.to_string(),
+            chapters,
+            metadata,
+        ))?;
+        let bytes = fs::read(&save_path).map_err(|e| e.to_string())?;
+        let files = read_zip_entries(&bytes)?
+            .into_iter()
+            .map(|(name, data)| (name, String::from_utf8_lossy(&data).to_string()))
+            .collect();
+        Ok((files, warnings))
+    }
 
-            let href = format!("{}/{}", sub_dir, safe_name);
-            let mime = asset_manifest_mime(&safe_name);
-            if !asset.role.trim().is_empty() && asset.category == "images" {
-                image_slot_hrefs.insert(asset.role.clone(), href.clone());
-            }
-            manifest_items.push_str(&format!(
-                r#"<item id="asset_{}" href="{}" media-type="{}"/>"#,
-                i, href, mime
-            ));
-        }
+    #[test]
+    fn toc_nests_chapters_under_volumes_and_keeps_meta_on_top() {
+        let depths = export_toc_depths(&sample_chapters());
+        assert_eq!(depths, vec![0, 0, 1, 1, 0]);
+        let entries: Vec<(usize, String, String)> = depths
+            .into_iter()
+            .zip(["a", "b", "c", "d", "e"])
+            .map(|(depth, label)| (depth, label.to_string(), format!("{}.xhtml", label)))
+            .collect();
+        let ol = build_nav_ol(&entries, "");
+        let compact: String = ol.lines().map(str::trim).collect();
+        assert_eq!(
+            compact,
+            concat!(
+                r#"<ol><li><a href="a.xhtml">a</a></li><li><a href="b.xhtml">b</a><ol>"#,
+                r#"<li><a href="c.xhtml">c</a></li><li><a href="d.xhtml">d</a></li></ol></li>"#,
+                r#"<li><a href="e.xhtml">e</a></li></ol>"#
+            )
+        );
     }
-    manifest_items
-        .push_str(r#"<item id="font.css" href="Styles/font.css" media-type="text/css"/>"#);
-    manifest_items
-        .push_str(r#"<item id="main.css" href="Styles/main.css" media-type="text/css"/>"#);
 
-    for (i, chapter) in chapters.iter().enumerate() {
-        let file_name_in_zip = format!("OEBPS/Text/chapter{}.xhtml", i);
-        let href_in_opf = format!("Text/chapter{}.xhtml", i);
-        let id = format!("chapter{}", i);
+    #[test]
+    fn epub3_export_adds_nav_landmarks_and_modified_date() -> Result<(), String> {
+        let files = export("3.0")?;
+        let opf = &files["OEBPS/content.opf"];
+        assert!(opf.contains(r#"version="3.0""#));
+        assert!(opf.contains("<dc:language>zh-CN</dc:language>"));
+        assert!(opf.contains(r#"<meta property="dcterms:modified">"#));
+        assert!(opf.contains(r#"properties="nav""#));
+        assert!(opf.contains(r#"<itemref idref="nav" linear="no"/>"#));
+        assert!(!opf.contains("<dc:publisher>"));
+        assert!(!opf.contains("opf:scheme"));
+        let nav = &files["OEBPS/nav.xhtml"];
+        assert!(nav.contains(r#"<a epub:type="bodymatter" href="Text/chapter1.xhtml">"#));
+        assert!(!nav.contains(r#"epub:type="cover""#));
+        assert!(files.contains_key("OEBPS/toc.ncx"));
+        assert!(files["OEBPS/Text/chapter2.xhtml"].contains("<!DOCTYPE html>\n"));
+        Ok(())
+    }
 
-        let start_line = chapter.line_number;
-        let end_line = if i + 1 < chapters.len() {
-            chapters[i + 1].line_number - 2 // line_number 指向标题的下一行，所以要减2
-        } else {
-            lines.len() - 1
-        };
-        let safe_end = end_line.min(lines.len() - 1);
-        let safe_start = start_line.min(safe_end);
-        let body_lines = if safe_start <= safe_end {
-            // `line_number` already points to the first body line (next line after title),
-            // so do not skip one more line here.
-            &lines[safe_start..=safe_end] // 使用 ..= 包含 safe_end
-        } else {
-            &[]
-        };
+    #[test]
+    fn epub2_export_keeps_opf2_layout() -> Result<(), String> {
+        let files = export("")?;
+        let opf = &files["OEBPS/content.opf"];
+        assert!(opf.contains(r#"version="2.0""#));
+        assert!(opf.contains("<dc:language>zh-CN</dc:language>"));
+        assert!(!files.contains_key("OEBPS/nav.xhtml"));
+        assert!(files["OEBPS/Text/chapter2.xhtml"].contains("XHTML 1.1"));
+        let ncx = &files["OEBPS/toc.ncx"];
+        let nesting: String = ncx
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim();
+                if line.starts_with("<navPoint") {
+                    Some('(')
+                } else if line == "</navPoint>" {
+                    Some(')')
+                } else {
+                    None
+                }
+            })
+            .collect();
+        assert_eq!(nesting, "()(()())()");
+        Ok(())
+    }
 
-        let mut html_body = String::new();
-        let mut class_attr = "te-book-body te-chapter-page";
+    #[test]
+    fn chapter_body_splits_only_at_paragraph_boundaries() {
+        let body = "  <h3 class=\"te-chapter-title\">标题</h3>\n  <p class=\"te-paragraph\">一</p>\n  <p class=\"te-paragraph\">二</p>\n  <p class=\"te-divider-line\">※※※</p>\n  <p class=\"te-paragraph\">三</p>\n";
+        assert_eq!(split_chapter_body(body, 0, 0), vec![body.to_string()]);
 
-        let (chap_num_raw, chap_name_raw) = split_title(&chapter.title);
-        let safe_display_title = if !chap_num_raw.is_empty() && !chap_name_raw.is_empty() {
-            format!(
-                "{} {}",
-                escape_xml(&chap_num_raw),
-                escape_xml(&chap_name_raw)
-            )
-        } else {
-            escape_xml(&chapter.title)
-        };
+        let parts = split_chapter_body(body, 0, 2);
+        assert_eq!(parts.len(), 2);
+        assert!(parts[0].starts_with("  <h3"));
+        assert!(parts[0].contains(">二</p>"));
+        assert!(parts[1].starts_with("  <p class=\"te-divider-line\">"));
+        assert_eq!(parts.concat(), body);
 
-        if chapter.is_meta {
-            class_attr = "te-book-body te-intro-page";
-            if i == 0 && has_cover {
-                html_body.push_str(&format!(
-                    "  <div class=\"te-cover-wrap\"><img class=\"te-cover-image\" src=\"../Images/cover.{}\" alt=\"封面\" /><br/>\n    <span class=\"book-name\">{}</span>\n  </div>\n\n",
-                    cover_ext,
-                    escape_xml(&metadata.title)
-                ));
+        // 阈值小于单段时每段单独成页，标题不会独占一页
+        let parts = split_chapter_body(body, 1, 0);
+        assert_eq!(parts.len(), 4);
+        assert!(parts[0].contains(">一</p>"));
+        assert_eq!(parts.concat(), body);
+    }
+
+    #[test]
+    fn oversized_chapters_get_continuation_files_outside_toc() -> Result<(), String> {
+        let content = "第一章 长\n第一段\n第二段\n第三段\n第二章 短\n只有一段";
+        for version in ["2.0", "3.0"] {
+            let files = export_book(
+                version,
+                serde_json::json!({ "split_max_paragraphs": 1 }),
+                content,
+                vec![
+                    chapter("第一章 长", 1, 3, false),
+                    chapter("第二章 短", 5, 3, false),
+                ],
+            )?;
+            assert!(files["OEBPS/Text/chapter0.xhtml"].contains(">第一段</p>"));
+            let continuation = &files["OEBPS/Text/chapter0_1.xhtml"];
+            assert!(continuation.contains(r#"<body class="te-book-body te-chapter-page "#));
+            assert!(continuation.contains(r#" te-continuation-page">"#));
+            assert!(continuation.contains(">第二段</p>"));
+            assert!(!continuation.contains("te-chapter-title"));
+            assert!(files.contains_key("OEBPS/Text/chapter0_2.xhtml"));
+            assert!(!files.contains_key("OEBPS/Text/chapter1_1.xhtml"));
+
+            let opf = &files["OEBPS/content.opf"];
+            let spine_positions: Vec<Option<usize>> = [
+                r#"<itemref idref="chapter0"/>"#,
+                r#"<itemref idref="chapter0_1"/>"#,
+                r#"<itemref idref="chapter0_2"/>"#,
+                r#"<itemref idref="chapter1"/>"#,
+            ]
+            .iter()
+            .map(|needle| opf.find(needle))
+            .collect();
+            assert!(spine_positions.iter().all(Option::is_some), "{}", opf);
+            assert!(spine_positions.windows(2).all(|w| w[0] < w[1]), "{}", opf);
+            assert!(opf.contains(r#"href="Text/chapter0_2.xhtml""#));
+
+            assert!(!files["OEBPS/toc.ncx"].contains("chapter0_"));
+            if version == "3.0" {
+                assert!(!files["OEBPS/nav.xhtml"].contains("chapter0_"));
             }
-            html_body.push_str(&format!(
-                "  <h1 class=\"te-intro-title\" title=\"{}\"><span><b>{}</b></span></h1>\n",
-                safe_display_title, safe_display_title
-            ));
-            append_text_body_lines(
-                &mut html_body,
-                body_lines,
-                true,
-                first_image_slot_for_placement(
-                    &image_slot_hrefs,
-                    &asset_slot_placements,
-                    "replace-ellipsis",
-                    "dividerImage",
-                ),
-            );
-        } else {
-            match chapter.level {
-                1 => {
-                    class_attr = "te-book-body te-volume-page";
-                    let safe_vol_num = escape_xml(&chap_num_raw);
-                    let safe_vol_name = escape_xml(&chap_name_raw);
+        }
+        Ok(())
+    }
 
-                    // We only use the vertical number styling if there's actually a volume number parsed
-                    let vertical_num = if !safe_vol_num.is_empty() {
-                        format_vertical_volume(&safe_vol_num)
-                    } else {
-                        String::new()
-                    };
+    #[test]
+    fn illustration_markers_pull_images_from_assets_and_source_folder() -> Result<(), String> {
+        let source = tempfile::tempdir().map_err(|e| e.to_string())?;
+        fs::write(source.path().join("插图01.jpg"), b"jpg").map_err(|e| e.to_string())?;
+        let asset_path = source.path().join("素材").join("彩页.png");
+        fs::create_dir_all(asset_path.parent().unwrap_or(source.path()))
+            .map_err(|e| e.to_string())?;
+        fs::write(&asset_path, b"png").map_err(|e| e.to_string())?;
+
+        let files = export_book(
+            "3.0",
+            serde_json::json!({
+                "source_path": source.path().join("书.txt").to_string_lossy(),
+                "assets": [{
+                    "name": "彩页.png",
+                    "path": asset_path.to_string_lossy(),
+                    "category": "images",
+                }],
+            }),
+            "第一章 图\n[img:插图01.jpg|初见]\n正文[img:彩页.png]\n[img:不存在.jpg]",
+            vec![chapter("第一章 图", 1, 3, false)],
+        )?;
+        assert_eq!(files["OEBPS/Images/插图01.jpg"], "jpg");
+        let opf = &files["OEBPS/content.opf"];
+        assert!(opf.contains(
+            r#"<item id="illustration_0" href="Images/插图01.jpg" media-type="image/jpeg"/>"#
+        ));
+        assert_eq!(opf.matches("彩页.png").count(), 1);
+        assert!(!opf.contains("不存在"));
+        let chapter_html = &files["OEBPS/Text/chapter0.xhtml"];
+        assert!(chapter_html.contains(r#"src="../Images/插图01.jpg" alt="初见""#));
+        assert!(chapter_html.contains(r#"src="../Images/彩页.png""#));
+        assert!(chapter_html.contains(r#"<p class="te-paragraph">[img:不存在.jpg]</p>"#));
+        Ok(())
+    }
 
-                    let formatted_name = if !safe_vol_name.is_empty() {
-                        safe_vol_name
-                    } else {
-                        safe_display_title.clone()
-                    };
+    #[test]
+    fn illustration_markers_reject_unsafe_paths_and_keep_cover_name() -> Result<(), String> {
+        let root = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let source = root.path().join("书");
+        fs::create_dir_all(&source).map_err(|e| e.to_string())?;
+        fs::write(source.join("cover.jpg"), b"illustration").map_err(|e| e.to_string())?;
+        fs::write(source.join("笔记.txt"), b"txt").map_err(|e| e.to_string())?;
+        fs::write(root.path().join("外部.jpg"), b"outside").map_err(|e| e.to_string())?;
+        let cover_path = root.path().join("封面.jpg");
+        fs::write(&cover_path, b"cover").map_err(|e| e.to_string())?;
+
+        let (files, warnings) = export_book_with_warnings(
+            "3.0",
+            serde_json::json!({
+                "source_path": source.join("书.txt").to_string_lossy(),
+                "cover_path": cover_path.to_string_lossy(),
+            }),
+            "第一章 图\n[img:cover.jpg]\n[img:../外部.jpg]\n[img:笔记.txt]\n[img:缺失.png]",
+            vec![chapter("第一章 图", 1, 3, false)],
+        )?;
+        assert_eq!(files["OEBPS/Images/cover.jpg"], "cover");
+        assert_eq!(files["OEBPS/Images/cover_2.jpg"], "illustration");
+        assert!(!files
+            .values()
+            .any(|data| data == "outside" || data == "txt"));
+        assert_eq!(
+            warnings,
+            vec![
+                "插图路径不合法，保留原标记: ../外部.jpg".to_string(),
+                "插图路径不合法，保留原标记: 笔记.txt".to_string(),
+                "插图未找到，保留原标记: 缺失.png".to_string(),
+            ]
+        );
+        Ok(())
+    }
 
-                    let inserted_volume_head = if let Some((role, href)) =
-                        first_image_slot_for_placement(
-                            &image_slot_hrefs,
-                            &asset_slot_placements,
-                            "volume-before-title",
-                            "volumeHead",
-                        ) {
-                        html_body.push_str(&image_slot_html(role, href));
-                        true
-                    } else {
-                        false
-                    };
+    fn note_rules(style: &str) -> Result<NoteRules, String> {
+        NoteRules::from_options(&NoteExportOptions {
+            style: style.to_string(),
+            ..Default::default()
+        })?
+        .ok_or_else(|| "注释未启用".to_string())
+    }
 
-                    if !inserted_volume_head {
-                        class_attr = "te-book-body te-volume-page te-volume-page--no-image";
-                    }
+    #[test]
+    fn chapter_notes_become_noterefs_with_backlinks() -> Result<(), String> {
+        let body = concat!(
+            "  <h3 class=\"te-chapter-title\">第一章【注1】</h3>\n",
+            "  <p class=\"te-paragraph\">他说【注1】，又说〔注：行内 &amp; 注〕。再提【注1】</p>\n",
+            "  <p class=\"te-paragraph\">【注1】：引用式注释</p>\n",
+            "  <p class=\"te-paragraph\">【注9】没有被引用的段落</p>\n",
+        );
+        let html = apply_chapter_notes(body, 2, &note_rules("footnote")?, true);
+        assert!(html.starts_with("  <h3 class=\"te-chapter-title\">第一章【注1】</h3>\n"));
+        assert!(html.contains(concat!(
+            r##"他说<sup><a class="te-noteref" epub:type="noteref" href="#note-2-1" id="noteref-2-1">[1]</a></sup>，"##,
+            r##"又说<sup><a class="te-noteref" epub:type="noteref" href="#note-2-2" id="noteref-2-2">[2]</a></sup>。"##,
+            r##"再提<sup><a class="te-noteref" epub:type="noteref" href="#note-2-1">[1]</a></sup></p>"##
+        )));
+        assert!(!html.contains("【注1】："));
+        assert!(html.contains(r#"<p class="te-paragraph">【注9】没有被引用的段落</p>"#));
+        assert!(html.ends_with(concat!(
+            r##"  <aside class="te-footnote" epub:type="footnote" id="note-2-1"><p class="te-footnote-text"><a class="te-footnote-backlink" href="#noteref-2-1">[1]</a> 引用式注释</p></aside>"##,
+            "\n",
+            r##"  <aside class="te-footnote" epub:type="footnote" id="note-2-2"><p class="te-footnote-text"><a class="te-footnote-backlink" href="#noteref-2-2">[2]</a> 行内 &amp; 注</p></aside>"##,
+            "\n"
+        )));
+
+        let epub2 = apply_chapter_notes(body, 2, &note_rules("footnote")?, false);
+        assert!(!epub2.contains("epub:type"));
+        assert!(epub2.contains(r#"<div class="te-footnote" id="note-2-1">"#));
+
+        let duokan = apply_chapter_notes(body, 2, &note_rules("duokan")?, false);
+        assert!(duokan.contains(
+            r##"<a class="duokan-footnote te-noteref" href="#note-2-1" id="noteref-2-1">[1]</a>"##
+        ));
+        assert!(duokan.contains(concat!(
+            "  <ol class=\"duokan-footnote-content te-footnotes\">\n",
+            r##"    <li class="duokan-footnote-item" id="note-2-1"><a class="duokan-footnote-item-link te-footnote-backlink" href="#noteref-2-1">[1]</a> 引用式注释</li>"##
+        )));
+        Ok(())
+    }
 
-                    html_body.push_str(&format!(
-                        "  <h1 class=\"te-volume-title\" title=\"{}\"><br /><br />\n  {}</h1>\n  <p class=\"te-volume-subtitle\">{}</p>\n",
-                        safe_display_title, vertical_num, formatted_name.trim()
-                    ));
+    #[test]
+    fn note_options_validate_style_and_patterns() -> Result<(), String> {
+        assert!(NoteRules::from_options(&NoteExportOptions::default())?.is_none());
+        assert!(note_rules("sidebar").is_err());
+        assert!(NoteRules::from_options(&NoteExportOptions {
+            style: "footnote".to_string(),
+            reference_pattern: "(".to_string(),
+            ..Default::default()
+        })
+        .is_err());
 
-                    // Add body content for Volume if they exist, to prevent loss of potential inner-body text
-                    append_text_body_lines(
-                        &mut html_body,
-                        body_lines,
-                        true,
-                        first_image_slot_for_placement(
-                            &image_slot_hrefs,
-                            &asset_slot_placements,
-                            "replace-ellipsis",
-                            "dividerImage",
-                        ),
-                    );
-                }
-                3 => {
-                    let safe_chap_num = escape_xml(&chap_num_raw);
-                    let safe_chap_name = escape_xml(&chap_name_raw);
+        let rules = NoteRules::from_options(&NoteExportOptions {
+            style: "footnote".to_string(),
+            reference_pattern: r"\[(\d+)\]".to_string(),
+            inline_pattern: r"（译注：(.+?)）".to_string(),
+        })?
+        .ok_or_else(|| "注释未启用".to_string())?;
+        let body = "  <p class=\"te-paragraph\">甲[1]乙（译注：丙）</p>\n  <p class=\"te-paragraph\">[1] 丁</p>\n";
+        let html = apply_chapter_notes(body, 0, &rules, true);
+        assert!(
+            html.contains(r##"<a class="te-footnote-backlink" href="#noteref-0-1">[1]</a> 丁"##)
+        );
+        assert!(
+            html.contains(r##"<a class="te-footnote-backlink" href="#noteref-0-2">[2]</a> 丙"##)
+        );
 
-                    let inserted_chapter_head = if let Some((role, href)) =
-                        first_image_slot_for_placement(
-                            &image_slot_hrefs,
-                            &asset_slot_placements,
-                            "chapter-before-title",
-                            "chapterHead",
-                        ) {
-                        html_body.push_str(&image_slot_html(role, href));
-                        true
-                    } else {
-                        false
-                    };
+        // 正则按原文书写，不需要照顾 &amp; 等转义
+        let rules = NoteRules::from_options(&NoteExportOptions {
+            style: "footnote".to_string(),
+            reference_pattern: r"<(\d+)>".to_string(),
+            inline_pattern: r"\(注&([^)]+)\)".to_string(),
+        })?
+        .ok_or_else(|| "注释未启用".to_string())?;
+        let body = "  <p class=\"te-paragraph\">A &amp; B&lt;1&gt;(注&amp;&lt;丙&gt;)</p>\n  <p class=\"te-paragraph\">&lt;1&gt; 丁 &amp; 戊</p>\n";
+        let html = apply_chapter_notes(body, 0, &rules, true);
+        assert!(html.contains(r##"A &amp; B<sup><a class="te-noteref""##));
+        assert!(html.contains(r##"href="#noteref-0-1">[1]</a> 丁 &amp; 戊</p>"##));
+        assert!(html.contains(r##"href="#noteref-0-2">[2]</a> &lt;丙&gt;</p>"##));
+        Ok(())
+    }
 
-                    if !inserted_chapter_head {
-                        class_attr = "te-book-body te-chapter-page te-chapter-page--no-image";
-                    }
+    #[test]
+    fn notes_follow_split_chapters_across_files() -> Result<(), String> {
+        let files = export_book(
+            "3.0",
+            serde_json::json!({
+                "notes": { "style": "footnote" },
+                "split_max_paragraphs": 1,
+            }),
+            "第一章 注\n正文【注1】\n第二段\n【注1】注释正文",
+            vec![chapter("第一章 注", 1, 3, false)],
+        )?;
+        let first = &files["OEBPS/Text/chapter0.xhtml"];
+        assert!(first.contains(r##"href="chapter0_1.xhtml#note-0-1" id="noteref-0-1""##));
+        let last = &files["OEBPS/Text/chapter0_1.xhtml"];
+        assert!(last.contains(">第二段</p>"));
+        assert!(
+            last.contains(r##"<aside class="te-footnote" epub:type="footnote" id="note-0-1">"##)
+        );
+        assert!(last.contains(r##"href="chapter0.xhtml#noteref-0-1""##));
+        assert!(!files.contains_key("OEBPS/Text/chapter0_2.xhtml"));
+        Ok(())
+    }
 
-                    if !safe_chap_num.is_empty() {
-                        html_body.push_str(&format!(
-                            "  <h3 class=\"te-chapter-title\"><span class=\"te-chapter-number\">{}</span><br/><b class=\"te-chapter-name\">{}</b></h3>\n",
-                            safe_chap_num, safe_chap_name
-                        ));
-                    } else {
-                        html_body.push_str(&format!(
-                            "  <h3 class=\"te-chapter-title\">{}</h3>\n",
-                            safe_display_title
-                        ));
-                    }
+    #[test]
+    fn extended_metadata_round_trips_in_both_versions() -> Result<(), String> {
+        let fields = serde_json::json!({
+            "language": "ja",
+            "creators": [
+                { "name": "甲", "file_as": "Jia" },
+                { "name": "乙", "role": "ill" }
+            ],
+            "contributors": [{ "name": "丙", "role": "trl" }],
+            "series": "某系列",
+            "series_index": 2.5,
+            "identifiers": [{ "scheme": "ISBN", "value": "9787020002207" }],
+            "date": "2020-05-01",
+            "rights": "版权所有",
+            "original_title": "原题",
+            "译者备注": "自定义",
+        });
+        let expected: OpfMetadataExt =
+            serde_json::from_value(fields.clone()).map_err(|e| e.to_string())?;
+        for version in ["2.0", "3.0"] {
+            let files = export_with(version, fields.clone())?;
+            let opf = &files["OEBPS/content.opf"];
+            let mut parsed = parse_opf_metadata_ext(opf);
+            // 缺省角色导出为 aut
+            parsed.creators[0].role.clear();
+            assert_eq!(parsed, expected, "version {}", version);
+            assert_eq!(opf.matches("<dc:date>").count(), 1);
+        }
+        let epub3_opf = &export_with("3.0", fields.clone())?["OEBPS/content.opf"];
+        assert!(epub3_opf.contains("urn:isbn:9787020002207"));
+        assert!(
+            epub3_opf.contains(r##"<meta refines="#series" property="group-position">2.5</meta>"##)
+        );
+        assert!(epub3_opf.contains(r#"<meta name="译者备注" content="自定义" />"#));
+        let epub2_opf = &export_with("2.0", fields)?["OEBPS/content.opf"];
+        assert!(epub2_opf.contains(
+            r#"<dc:creator id="creator" opf:role="aut" opf:file-as="Jia">甲</dc:creator>"#
+        ));
+        assert!(!epub2_opf.contains("belongs-to-collection"));
+        Ok(())
+    }
+}
 
-                    append_text_body_lines(
-                        &mut html_body,
-                        body_lines,
-                        true,
-                        first_image_slot_for_placement(
-                            &image_slot_hrefs,
-                            &asset_slot_placements,
-                            "replace-ellipsis",
-                            "dividerImage",
-                        ),
-                    );
-                }
-                _ => {
-                    html_body.push_str(&format!(
-                        "  <h{} class=\"te-chapter-title\">{}</h{}>\n",
-                        chapter.level, safe_display_title, chapter.level
-                    ));
-                    append_text_body_lines(
-                        &mut html_body,
-                        body_lines,
-                        true,
-                        first_image_slot_for_placement(
-                            &image_slot_hrefs,
-                            &asset_slot_placements,
-                            "replace-ellipsis",
-                            "dividerImage",
-                        ),
-                    );
+// --- EPUB 编辑器相关命令 ---
+
+#[tauri::command]
+async fn extract_epub(
+    app: tauri::AppHandle,
+    epub_path: String,
+) -> Result<Vec<EpubFileNode>, String> {
+    tauri::async_runtime::spawn_blocking(move || extract_epub_impl(app, epub_path))
+        .await
+        .map_err(|e| format!("解压 EPUB 任务失败: {}", e))?
+}
+
+fn extract_epub_impl(
+    app: tauri::AppHandle,
+    epub_path: String,
+) -> Result<Vec<EpubFileNode>, String> {
+    // 1. 检查是否已经有缓存且临时内容未关闭
+    let existing_temp_path = {
+        let cache_guard = lock_epub_cache()?;
+        if let Some(ref cache) = *cache_guard {
+            if cache.epub_path == epub_path {
+                if let Some(ref temp) = cache.temp_dir {
+                    Some(temp.path().to_path_buf())
+                } else {
+                    None
                 }
+            } else {
+                None
             }
+        } else {
+            None
+        }
+    };
+
+    let temp_path_buf: PathBuf;
+    let mut _temp_dir_keep: Option<TempDir> = None;
+
+    if let Some(path) = existing_temp_path {
+        temp_path_buf = path;
+    } else {
+        // 2. 在书库 _data/extract/ 下创建临时子目录并解压
+        let extract_root = library_extract_dir(&app)
+            .unwrap_or_else(|_| std::env::temp_dir().join("tepub-extract"));
+        ensure_dir(&extract_root).ok();
+        let temp_dir = tempfile::Builder::new()
+            .prefix("epub_")
+            .tempdir_in(&extract_root)
+            .or_else(|_| TempDir::new())
+            .map_err(|e| format!("无法创建临时目录: {}", e))?;
+        temp_path_buf = temp_dir.path().to_path_buf();
+
+        {
+            let file = fs::File::open(&epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
+            let mut archive =
+                zip::ZipArchive::new(file).map_err(|e| format!("无效的 EPUB 文件: {}", e))?;
+            archive
+                .extract(&temp_path_buf)
+                .map_err(|e| format!("解压失败: {}", e))?;
+        }
+        _temp_dir_keep = Some(temp_dir);
+    }
+
+    // 3. 遍历目录构建文件列表
+    let mut all_files = Vec::new();
+
+    for entry in WalkDir::new(&temp_path_buf)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if !entry.file_type().is_file() {
+            continue;
         }
 
-        let full_html = format!(
-            r#"<?xml version="1.0" encoding="utf-8"?>
-<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
-<html xmlns="http://www.w3.org/1999/xhtml">
-<head>
-  <title>{}</title>
-  <link href="../Styles/font.css" type="text/css" rel="stylesheet"/>
-  <link href="../Styles/main.css" type="text/css" rel="stylesheet"/>
-</head>
-<body{}>
-{}
-</body>
-</html>"#,
-            safe_display_title,
-            format!(" class=\"{}\"", class_attr),
-            html_body
-        );
+        let full_path = entry.path();
+        let relative_path = full_path.strip_prefix(&temp_path_buf).unwrap();
+        let path_str = relative_path.to_string_lossy().replace("\\", "/");
 
-        zip.start_file(&file_name_in_zip, options)
-            .map_err(|e| e.to_string())?;
-        zip.write_all(full_html.as_bytes())
-            .map_err(|e| e.to_string())?;
+        // Hiding system files/folders as requested
+        if path_str.starts_with("META-INF") || path_str == "mimetype" {
+            continue;
+        }
 
-        manifest_items.push_str(&format!(
-            r#"<item id="{}" href="{}" media-type="application/xhtml+xml"/>"#,
-            id, href_in_opf
-        ));
-        spine_refs.push_str(&format!(r#"<itemref idref="{}"/>"#, id));
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        let file_name = relative_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+
+        // 确定文件类型
+        let lower_file_name = file_name.to_ascii_lowercase();
+        let file_type = if lower_file_name.ends_with(".html")
+            || lower_file_name.ends_with(".htm")
+            || lower_file_name.ends_with(".xhtml")
+        {
+            "html"
+        } else if lower_file_name.ends_with(".css") {
+            "css"
+        } else if lower_file_name.ends_with(".xml")
+            || lower_file_name.ends_with(".opf")
+            || lower_file_name.ends_with(".ncx")
+        {
+            "xml"
+        } else if lower_file_name.ends_with(".jpg")
+            || lower_file_name.ends_with(".jpeg")
+            || lower_file_name.ends_with(".png")
+            || lower_file_name.ends_with(".gif")
+            || lower_file_name.ends_with(".webp")
+            || lower_file_name.ends_with(".bmp")
+            || lower_file_name.ends_with(".svg")
+        {
+            "image"
+        } else if lower_file_name.ends_with(".ttf")
+            || lower_file_name.ends_with(".otf")
+            || lower_file_name.ends_with(".woff")
+            || lower_file_name.ends_with(".woff2")
+        {
+            "font"
+        } else {
+            "other"
+        }
+        .to_string();
+
+        // 提取标题 (如果是 HTML) - 延迟到 load_epub_file_meta 按需补全
+        let title = None;
+        let mut resolution = None;
+
+        if file_type == "image" {
+            // 仅从文件大小预估，不读取图片头（image_dimensions 会全量解码图片头，大图很慢）
+            resolution = None;
+        }
+
+        all_files.push((path_str, file_name, file_type, size, title, resolution));
     }
 
-    let mut nav_stack_levels: Vec<u8> = Vec::new();
+    // 构建嵌套文件树
+    fn build_tree(
+        files: &[(String, String, String, u64, Option<String>, Option<String>)],
+    ) -> Vec<EpubFileNode> {
+        let mut root_map: HashMap<String, Vec<EpubFileNode>> = HashMap::new();
 
-    for (i, chapter) in chapters.iter().enumerate() {
-        let href_in_opf = format!("Text/chapter{}.xhtml", i);
-        let current_level = chapter.level;
-        let (chap_num_raw, chap_name_raw) = split_title(&chapter.title);
-        let safe_display_title = if !chap_num_raw.is_empty() && !chap_name_raw.is_empty() {
-            format!(
-                "{} {}",
-                escape_xml(&chap_num_raw),
-                escape_xml(&chap_name_raw)
-            )
-        } else {
-            escape_xml(&chapter.title)
-        };
+        for (full_path, file_name, file_type, size, title, res) in files {
+            let parts: Vec<&str> = full_path.split('/').collect();
 
-        while let Some(&top_level) = nav_stack_levels.last() {
-            if top_level >= current_level || chapter.is_meta {
-                ncx_navpoints.push_str("</navPoint>\n");
-                nav_stack_levels.pop();
+            if parts.len() == 1 {
+                // 根目录文件
+                root_map
+                    .entry("__root__".to_string())
+                    .or_insert_with(Vec::new)
+                    .push(EpubFileNode {
+                        name: file_name.clone(),
+                        path: full_path.clone(),
+                        file_type: file_type.clone(),
+                        size: Some(*size),
+                        title: title.clone(),
+                        resolution: res.clone(),
+                        children: None,
+                    });
             } else {
-                break;
+                // 有目录的文件
+                let dir_key = parts[0].to_string();
+                root_map
+                    .entry(dir_key)
+                    .or_insert_with(Vec::new)
+                    .push(EpubFileNode {
+                        name: file_name.clone(),
+                        path: full_path.clone(),
+                        file_type: file_type.clone(),
+                        size: Some(*size),
+                        title: title.clone(),
+                        resolution: res.clone(),
+                        children: None,
+                    });
             }
         }
 
-        ncx_navpoints.push_str(&format!(
-            r#"<navPoint id="navPoint-{}" playOrder="{}"><navLabel><text>{}</text></navLabel><content src="{}"/>"#,
-            play_order, play_order, safe_display_title, href_in_opf
-        ));
-        ncx_navpoints.push('\n');
+        let mut result = Vec::new();
 
-        if !chapter.is_meta {
-            nav_stack_levels.push(current_level);
-        } else {
-            ncx_navpoints.push_str("</navPoint>\n");
+        // 处理根目录文件
+        if let Some(root_files) = root_map.remove("__root__") {
+            result.extend(root_files);
         }
-        play_order += 1;
-    }
 
-    while nav_stack_levels.pop().is_some() {
-        ncx_navpoints.push_str("</navPoint>\n");
-    }
+        // 处理文件夹
+        let mut sorted_dirs: Vec<_> = root_map.into_iter().collect();
+        sorted_dirs.sort_by(|a, b| a.0.cmp(&b.0));
 
-    let date_str = chrono::Local::now().format("%Y-%m-%d").to_string();
-    let full_uuid = if metadata.uuid.starts_with("urn:uuid:") {
-        metadata.uuid.clone()
-    } else {
-        format!("urn:uuid:{}", metadata.uuid)
-    };
+        for (dir_name, files) in sorted_dirs {
+            // 按路径深度分组子文件夹
+            let mut subdir_map: HashMap<String, Vec<EpubFileNode>> = HashMap::new();
+            let mut dir_files = Vec::new();
 
-    let mut extra_metadata = String::new();
-    for tag in &metadata.tags {
-        let trimmed = tag.trim();
-        if trimmed.is_empty() {
-            continue;
-        }
-        extra_metadata.push_str(&format!(
-            "    <dc:subject>{}</dc:subject>\n",
-            escape_xml(trimmed)
-        ));
-    }
-    for (k, v) in &metadata.extra {
-        extra_metadata.push_str(&format!(
-            "    <dc:{} pub-type=\"zdy\">{}</dc:{}>\n",
-            escape_xml(k),
-            escape_xml(v),
-            escape_xml(k)
-        ));
-    }
+            for file in files {
+                let path_parts: Vec<&str> = file.path.split('/').collect();
+                if path_parts.len() == 2 {
+                    // 直接在当前目录下的文件
+                    dir_files.push(file);
+                } else if path_parts.len() > 2 {
+                    // 子目录中的文件
+                    let subdir = path_parts[1].to_string();
+                    subdir_map.entry(subdir).or_insert_with(Vec::new).push(file);
+                }
+            }
 
-    let opf_content = format!(
-        r#"<?xml version="1.0" encoding="utf-8"?>
-<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId" version="2.0">
-  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
-    <dc:title id="t1">{}</dc:title>
-    <dc:creator id="creator">{}</dc:creator>
-    <dc:date>{}</dc:date>
-    <dc:publisher>{}</dc:publisher>
-    <dc:identifier opf:scheme="UUID" id="BookId">{}</dc:identifier>
-    <dc:description>{}</dc:description>
-    <meta name="cover" content="cover-image" />
-    <meta property="reamicro:md5" content="{}" />
-{}  </metadata>
-  <manifest>
-    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
-    {}
-  </manifest>
-  <spine toc="ncx">
-    {}
-  </spine>
-</package>"#,
-        escape_xml(&metadata.title),
-        escape_xml(&metadata.creator),
-        date_str,
-        escape_xml(&metadata.publisher),
-        full_uuid,
-        escape_xml(&metadata.description),
-        metadata.md5,
-        extra_metadata,
-        manifest_items,
-        spine_refs
-    );
+            // Sort direct files
+            dir_files.sort_by(|a, b| a.name.cmp(&b.name));
 
-    zip.start_file("OEBPS/content.opf", options)
-        .map_err(|e| e.to_string())?;
-    zip.write_all(opf_content.as_bytes())
-        .map_err(|e| e.to_string())?;
+            // 创建子文件夹节点
+            let mut children = dir_files;
 
-    let ncx_content = format!(
-        r#"<?xml version="1.0" encoding="UTF-8"?>
-<!DOCTYPE ncx PUBLIC "-//NISO//DTD ncx 2005-1//EN" "http://www.daisy.org/z3986/2005/ncx-2005-1.dtd">
-<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
-  <head>
-    <meta name="dtb:uid" content="{}"/>
-    <meta name="dtb:depth" content="2"/>
-    <meta name="dtb:totalPageCount" content="0"/>
-    <meta name="dtb:maxPageNumber" content="0"/>
-  </head>
-  <docTitle><text>{}</text></docTitle>
-  <navMap>
-    {}
-  </navMap>
-</ncx>"#,
-        full_uuid,
-        escape_xml(&metadata.title),
-        ncx_navpoints
-    );
+            // Sort keys to ensure folders order
+            let mut subdir_names: Vec<_> = subdir_map.keys().cloned().collect();
+            subdir_names.sort();
+
+            for subdir_name in subdir_names {
+                if let Some(mut subdir_files) = subdir_map.remove(&subdir_name) {
+                    // Sort files inside subfolder
+                    subdir_files.sort_by(|a, b| a.name.cmp(&b.name));
+
+                    children.push(EpubFileNode {
+                        name: subdir_name.clone(),
+                        path: format!("{}/{}", dir_name, subdir_name),
+                        file_type: "folder".to_string(),
+                        size: None,
+                        title: None,
+                        resolution: None,
+                        children: Some(subdir_files),
+                    });
+                }
+            }
+
+            result.push(EpubFileNode {
+                name: dir_name.clone(),
+                path: dir_name,
+                file_type: "folder".to_string(),
+                size: None,
+                title: None,
+                resolution: None,
+                children: Some(children),
+            });
+        }
 
-    zip.start_file("OEBPS/toc.ncx", options)
-        .map_err(|e| e.to_string())?;
-    zip.write_all(ncx_content.as_bytes())
-        .map_err(|e| e.to_string())?;
+        result
+    }
 
-    zip.finish().map_err(|e| e.to_string())?;
-    Ok(())
-}
+    // 初始化全局缓存 (仅在新建时)
+    if _temp_dir_keep.is_some() {
+        let mut cache_guard = lock_epub_cache()?;
+        let mut cache = EpubCache::new(epub_path);
+        cache.temp_dir = _temp_dir_keep;
+        *cache_guard = Some(cache);
+    }
 
-// --- EPUB 编辑器相关命令 ---
+    Ok(build_tree(&all_files))
+}
 
-#[tauri::command]
-async fn extract_epub(
-    app: tauri::AppHandle,
-    epub_path: String,
-) -> Result<Vec<EpubFileNode>, String> {
-    tauri::async_runtime::spawn_blocking(move || extract_epub_impl(app, epub_path))
-        .await
-        .map_err(|e| format!("解压 EPUB 任务失败: {}", e))?
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EpubFileMeta {
+    path: String,
+    title: Option<String>,
+    resolution: Option<String>,
 }
 
-fn extract_epub_impl(
-    app: tauri::AppHandle,
+#[tauri::command]
+async fn load_epub_file_meta(
     epub_path: String,
-) -> Result<Vec<EpubFileNode>, String> {
-    // 1. 检查是否已经有缓存且临时内容未关闭
-    let existing_temp_path = {
-        let cache_guard = lock_epub_cache()?;
-        if let Some(ref cache) = *cache_guard {
-            if cache.epub_path == epub_path {
-                if let Some(ref temp) = cache.temp_dir {
-                    Some(temp.path().to_path_buf())
+    file_paths: Vec<String>,
+) -> Result<Vec<EpubFileMeta>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let temp_path: PathBuf = {
+            let cache_guard = lock_epub_cache()?;
+            if let Some(ref cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    if let Some(ref temp) = cache.temp_dir {
+                        Some(temp.path().to_path_buf())
+                    } else {
+                        None
+                    }
                 } else {
                     None
                 }
             } else {
                 None
             }
-        } else {
-            None
         }
-    };
+        .ok_or("EPUB 未加载或缓存失效".to_string())?;
 
-    let temp_path_buf: PathBuf;
-    let mut _temp_dir_keep: Option<TempDir> = None;
+        let mut results = Vec::with_capacity(file_paths.len());
+        for rel_path in file_paths {
+            let full_path = temp_path.join(&rel_path);
+            let lower = rel_path.to_ascii_lowercase();
+            let mut title = None;
+            let mut resolution = None;
 
-    if let Some(path) = existing_temp_path {
-        temp_path_buf = path;
-    } else {
-        // 2. 在书库 _data/extract/ 下创建临时子目录并解压
-        let extract_root = library_extract_dir(&app)
-            .unwrap_or_else(|_| std::env::temp_dir().join("tepub-extract"));
-        ensure_dir(&extract_root).ok();
-        let temp_dir = tempfile::Builder::new()
-            .prefix("epub_")
-            .tempdir_in(&extract_root)
-            .or_else(|_| TempDir::new())
-            .map_err(|e| format!("无法创建临时目录: {}", e))?;
-        temp_path_buf = temp_dir.path().to_path_buf();
+            if lower.ends_with(".html") || lower.ends_with(".htm") || lower.ends_with(".xhtml") {
+                if let Ok(content) = fs::read_to_string(&full_path) {
+                    title = extract_html_heading_title(&content);
+                }
+            } else if lower.ends_with(".jpg")
+                || lower.ends_with(".jpeg")
+                || lower.ends_with(".png")
+                || lower.ends_with(".gif")
+                || lower.ends_with(".webp")
+                || lower.ends_with(".bmp")
+                || lower.ends_with(".svg")
+            {
+                if let Ok((width, height)) = image::image_dimensions(&full_path) {
+                    resolution = Some(format!("{}x{}", width, height));
+                }
+            }
 
-        {
-            let file = fs::File::open(&epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
-            let mut archive =
-                zip::ZipArchive::new(file).map_err(|e| format!("无效的 EPUB 文件: {}", e))?;
-            archive
-                .extract(&temp_path_buf)
-                .map_err(|e| format!("解压失败: {}", e))?;
+            results.push(EpubFileMeta {
+                path: rel_path,
+                title,
+                resolution,
+            });
         }
-        _temp_dir_keep = Some(temp_dir);
-    }
+        Ok(results)
+    })
+    .await
+    .map_err(|e| format!("加载文件元信息失败: {}", e))?
+}
 
-    // 3. 遍历目录构建文件列表
-    let mut all_files = Vec::new();
+#[tauri::command]
+async fn get_epub_temp_dir_path(epub_path: String) -> Result<String, String> {
+    let cache_guard = lock_epub_cache()?;
+    if let Some(ref cache) = *cache_guard {
+        if cache.epub_path == epub_path {
+            if let Some(ref temp) = cache.temp_dir {
+                return Ok(temp.path().to_string_lossy().to_string());
+            }
+        }
+    }
+    Err("EPUB 未加载或缓存失效".to_string())
+}
 
-    for entry in WalkDir::new(&temp_path_buf)
-        .into_iter()
-        .filter_map(|e| e.ok())
-    {
-        if !entry.file_type().is_file() {
-            continue;
+#[tauri::command]
+async fn read_epub_file_content(epub_path: String, file_path: String) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        // 1. 获取临时目录路径
+        let temp_path: PathBuf = {
+            let cache_guard = lock_epub_cache()?;
+            if let Some(ref cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    if let Some(ref temp) = cache.temp_dir {
+                        Some(temp.path().to_path_buf())
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
         }
+        .ok_or("EPUB 未加载或缓存失效".to_string())?;
 
-        let full_path = entry.path();
-        let relative_path = full_path.strip_prefix(&temp_path_buf).unwrap();
-        let path_str = relative_path.to_string_lossy().replace("\\", "/");
+        // 2. 从临时文件读取
+        let target_path = temp_path.join(&file_path);
+        std::fs::read_to_string(target_path).map_err(|e| format!("读取文件失败: {}", e))
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-        // Hiding system files/folders as requested
-        if path_str.starts_with("META-INF") || path_str == "mimetype" {
-            continue;
+#[tauri::command]
+async fn read_epub_file_binary(epub_path: String, file_path: String) -> Result<Vec<u8>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        // 1. 获取临时目录路径
+        let temp_path: PathBuf = {
+            let cache_guard = lock_epub_cache()?;
+            if let Some(ref cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    if let Some(ref temp) = cache.temp_dir {
+                        Some(temp.path().to_path_buf())
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
         }
+        .ok_or("EPUB 未加载或缓存失效".to_string())?;
 
-        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
-        let file_name = relative_path
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
+        // 2. 从临时文件读取
+        let target_path = temp_path.join(&file_path);
+        std::fs::read(target_path).map_err(|e| format!("读取文件失败: {}", e))
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-        // 确定文件类型
-        let lower_file_name = file_name.to_ascii_lowercase();
-        let file_type = if lower_file_name.ends_with(".html")
-            || lower_file_name.ends_with(".htm")
-            || lower_file_name.ends_with(".xhtml")
-        {
-            "html"
-        } else if lower_file_name.ends_with(".css") {
-            "css"
-        } else if lower_file_name.ends_with(".xml")
-            || lower_file_name.ends_with(".opf")
-            || lower_file_name.ends_with(".ncx")
-        {
-            "xml"
-        } else if lower_file_name.ends_with(".jpg")
-            || lower_file_name.ends_with(".jpeg")
-            || lower_file_name.ends_with(".png")
-            || lower_file_name.ends_with(".gif")
-            || lower_file_name.ends_with(".webp")
-            || lower_file_name.ends_with(".bmp")
-            || lower_file_name.ends_with(".svg")
-        {
-            "image"
-        } else if lower_file_name.ends_with(".ttf")
-            || lower_file_name.ends_with(".otf")
-            || lower_file_name.ends_with(".woff")
-            || lower_file_name.ends_with(".woff2")
-        {
-            "font"
-        } else {
-            "other"
-        }
-        .to_string();
+// --- 批量读取 API ---
 
-        // 提取标题 (如果是 HTML) - 延迟到 load_epub_file_meta 按需补全
-        let title = None;
-        let mut resolution = None;
+#[tauri::command]
+async fn read_epub_files_batch(
+    epub_path: String,
+    file_paths: Vec<String>,
+) -> Result<HashMap<String, String>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        use std::io::Read;
+        use zip::ZipArchive;
 
-        if file_type == "image" {
-            // 仅从文件大小预估，不读取图片头（image_dimensions 会全量解码图片头，大图很慢）
-            resolution = None;
+        let mut results: HashMap<String, String> = HashMap::new();
+        let mut to_read: Vec<String> = Vec::new();
+
+        // 1. 检查缓存，收集需要读取的文件
+        {
+            let cache_guard = lock_epub_cache()?;
+            if let Some(ref cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    for path in &file_paths {
+                        if let Some(content) = cache.text_cache.get(path) {
+                            results.insert(path.clone(), content.clone());
+                        } else {
+                            to_read.push(path.clone());
+                        }
+                    }
+                } else {
+                    to_read = file_paths.clone();
+                }
+            } else {
+                to_read = file_paths.clone();
+            }
         }
 
-        all_files.push((path_str, file_name, file_type, size, title, resolution));
-    }
+        // 2. 批量读取未缓存的文件
+        if !to_read.is_empty() {
+            let file = fs::File::open(&epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
+            let mut archive =
+                ZipArchive::new(file).map_err(|e| format!("无效的 EPUB 文件: {}", e))?;
 
-    // 构建嵌套文件树
-    fn build_tree(
-        files: &[(String, String, String, u64, Option<String>, Option<String>)],
-    ) -> Vec<EpubFileNode> {
-        let mut root_map: HashMap<String, Vec<EpubFileNode>> = HashMap::new();
+            let mut new_contents: Vec<(String, String)> = Vec::new();
 
-        for (full_path, file_name, file_type, size, title, res) in files {
-            let parts: Vec<&str> = full_path.split('/').collect();
+            for path in to_read {
+                if let Ok(mut zip_file) = archive.by_name(&path) {
+                    let mut content = String::new();
+                    if zip_file.read_to_string(&mut content).is_ok() {
+                        results.insert(path.clone(), content.clone());
+                        new_contents.push((path, content));
+                    }
+                }
+            }
 
-            if parts.len() == 1 {
-                // 根目录文件
-                root_map
-                    .entry("__root__".to_string())
-                    .or_insert_with(Vec::new)
-                    .push(EpubFileNode {
-                        name: file_name.clone(),
-                        path: full_path.clone(),
-                        file_type: file_type.clone(),
-                        size: Some(*size),
-                        title: title.clone(),
-                        resolution: res.clone(),
-                        children: None,
-                    });
-            } else {
-                // 有目录的文件
-                let dir_key = parts[0].to_string();
-                root_map
-                    .entry(dir_key)
-                    .or_insert_with(Vec::new)
-                    .push(EpubFileNode {
-                        name: file_name.clone(),
-                        path: full_path.clone(),
-                        file_type: file_type.clone(),
-                        size: Some(*size),
-                        title: title.clone(),
-                        resolution: res.clone(),
-                        children: None,
-                    });
+            // 3. 存入缓存
+            {
+                let mut cache_guard = lock_epub_cache()?;
+                if let Some(ref mut cache) = *cache_guard {
+                    if cache.epub_path == epub_path {
+                        for (path, content) in new_contents {
+                            cache.text_cache.insert(path, content);
+                        }
+                    }
+                }
             }
         }
 
-        let mut result = Vec::new();
-
-        // 处理根目录文件
-        if let Some(root_files) = root_map.remove("__root__") {
-            result.extend(root_files);
-        }
+        Ok(results)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-        // 处理文件夹
-        let mut sorted_dirs: Vec<_> = root_map.into_iter().collect();
-        sorted_dirs.sort_by(|a, b| a.0.cmp(&b.0));
+#[tauri::command]
+async fn read_epub_binary_batch(
+    epub_path: String,
+    file_paths: Vec<String>,
+) -> Result<HashMap<String, Vec<u8>>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        use std::io::Read;
+        use zip::ZipArchive;
 
-        for (dir_name, files) in sorted_dirs {
-            // 按路径深度分组子文件夹
-            let mut subdir_map: HashMap<String, Vec<EpubFileNode>> = HashMap::new();
-            let mut dir_files = Vec::new();
+        let mut results: HashMap<String, Vec<u8>> = HashMap::new();
+        let mut to_read: Vec<String> = Vec::new();
 
-            for file in files {
-                let path_parts: Vec<&str> = file.path.split('/').collect();
-                if path_parts.len() == 2 {
-                    // 直接在当前目录下的文件
-                    dir_files.push(file);
-                } else if path_parts.len() > 2 {
-                    // 子目录中的文件
-                    let subdir = path_parts[1].to_string();
-                    subdir_map.entry(subdir).or_insert_with(Vec::new).push(file);
+        // 1. 检查缓存
+        {
+            let cache_guard = lock_epub_cache()?;
+            if let Some(ref cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    for path in &file_paths {
+                        if let Some(data) = cache.binary_cache.get(path) {
+                            results.insert(path.clone(), data.clone());
+                        } else {
+                            to_read.push(path.clone());
+                        }
+                    }
+                } else {
+                    to_read = file_paths.clone();
                 }
+            } else {
+                to_read = file_paths.clone();
             }
+        }
 
-            // Sort direct files
-            dir_files.sort_by(|a, b| a.name.cmp(&b.name));
-
-            // 创建子文件夹节点
-            let mut children = dir_files;
-
-            // Sort keys to ensure folders order
-            let mut subdir_names: Vec<_> = subdir_map.keys().cloned().collect();
-            subdir_names.sort();
+        // 2. 批量读取
+        if !to_read.is_empty() {
+            let file = fs::File::open(&epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
+            let mut archive =
+                ZipArchive::new(file).map_err(|e| format!("无效的 EPUB 文件: {}", e))?;
 
-            for subdir_name in subdir_names {
-                if let Some(mut subdir_files) = subdir_map.remove(&subdir_name) {
-                    // Sort files inside subfolder
-                    subdir_files.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut new_data: Vec<(String, Vec<u8>)> = Vec::new();
 
-                    children.push(EpubFileNode {
-                        name: subdir_name.clone(),
-                        path: format!("{}/{}", dir_name, subdir_name),
-                        file_type: "folder".to_string(),
-                        size: None,
-                        title: None,
-                        resolution: None,
-                        children: Some(subdir_files),
-                    });
+            for path in to_read {
+                if let Ok(mut zip_file) = archive.by_name(&path) {
+                    let mut buffer = Vec::new();
+                    if zip_file.read_to_end(&mut buffer).is_ok() {
+                        results.insert(path.clone(), buffer.clone());
+                        new_data.push((path, buffer));
+                    }
                 }
             }
 
-            result.push(EpubFileNode {
-                name: dir_name.clone(),
-                path: dir_name,
-                file_type: "folder".to_string(),
-                size: None,
-                title: None,
-                resolution: None,
-                children: Some(children),
-            });
+            // 3. 存入缓存
+            {
+                let mut cache_guard = lock_epub_cache()?;
+                if let Some(ref mut cache) = *cache_guard {
+                    if cache.epub_path == epub_path {
+                        for (path, data) in new_data {
+                            cache.binary_cache.insert(path, data);
+                        }
+                    }
+                }
+            }
         }
 
-        result
-    }
+        Ok(results)
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-    // 初始化全局缓存 (仅在新建时)
-    if _temp_dir_keep.is_some() {
-        let mut cache_guard = lock_epub_cache()?;
-        let mut cache = EpubCache::new(epub_path);
-        cache.temp_dir = _temp_dir_keep;
-        *cache_guard = Some(cache);
-    }
+// --- EPUB 文件保存命令 ---
 
-    Ok(build_tree(&all_files))
-}
+#[tauri::command]
+async fn save_epub_file_content(
+    epub_path: String,
+    file_path: String,
+    content: String,
+) -> Result<(), String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        // 1. 获取临时目录路径
+        let temp_path: PathBuf = {
+            let cache_guard = lock_epub_cache()?;
+            if let Some(ref cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    if let Some(ref temp) = cache.temp_dir {
+                        Some(temp.path().to_path_buf())
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        }
+        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())?;
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct EpubFileMeta {
-    path: String,
-    title: Option<String>,
-    resolution: Option<String>,
+        // 2. 写入临时文件
+        let target_path = temp_path.join(&file_path);
+        std::fs::write(target_path, &content).map_err(|e| format!("写入文件失败: {}", e))?;
+
+        // 3. 更新缓存 (Text Cache)
+        {
+            let mut cache_guard = lock_epub_cache()?;
+            if let Some(ref mut cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    cache.text_cache.insert(file_path, content);
+                }
+            }
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
 #[tauri::command]
-async fn load_epub_file_meta(
+async fn save_epub_file_binary(
     epub_path: String,
-    file_paths: Vec<String>,
-) -> Result<Vec<EpubFileMeta>, String> {
+    file_path: String,
+    content: Vec<u8>,
+) -> Result<(), String> {
     tauri::async_runtime::spawn_blocking(move || {
+        // 1. 获取临时目录路径
         let temp_path: PathBuf = {
             let cache_guard = lock_epub_cache()?;
             if let Some(ref cache) = *cache_guard {
@@ -8494,61 +16403,38 @@ async fn load_epub_file_meta(
                 None
             }
         }
-        .ok_or("EPUB 未加载或缓存失效".to_string())?;
+        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())?;
 
-        let mut results = Vec::with_capacity(file_paths.len());
-        for rel_path in file_paths {
-            let full_path = temp_path.join(&rel_path);
-            let lower = rel_path.to_ascii_lowercase();
-            let mut title = None;
-            let mut resolution = None;
+        // 2. 写入二进制文件
+        let target_path = temp_path.join(&file_path);
+        if let Some(parent) = target_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("无法创建目录: {}", e))?;
+        }
+        std::fs::write(target_path, content).map_err(|e| format!("写入文件失败: {}", e))?;
 
-            if lower.ends_with(".html") || lower.ends_with(".htm") || lower.ends_with(".xhtml") {
-                if let Ok(content) = fs::read_to_string(&full_path) {
-                    title = extract_html_heading_title(&content);
-                }
-            } else if lower.ends_with(".jpg")
-                || lower.ends_with(".jpeg")
-                || lower.ends_with(".png")
-                || lower.ends_with(".gif")
-                || lower.ends_with(".webp")
-                || lower.ends_with(".bmp")
-                || lower.ends_with(".svg")
-            {
-                if let Ok((width, height)) = image::image_dimensions(&full_path) {
-                    resolution = Some(format!("{}x{}", width, height));
+        // 3. 更新二进制缓存 (Binary Cache)
+        {
+            let mut cache_guard = lock_epub_cache()?;
+            if let Some(ref mut cache) = *cache_guard {
+                if cache.epub_path == epub_path {
+                    cache.binary_cache.insert(file_path, Default::default());
                 }
             }
-
-            results.push(EpubFileMeta {
-                path: rel_path,
-                title,
-                resolution,
-            });
         }
-        Ok(results)
+
+        Ok(())
     })
     .await
-    .map_err(|e| format!("加载文件元信息失败: {}", e))?
-}
-
-#[tauri::command]
-async fn get_epub_temp_dir_path(epub_path: String) -> Result<String, String> {
-    let cache_guard = lock_epub_cache()?;
-    if let Some(ref cache) = *cache_guard {
-        if cache.epub_path == epub_path {
-            if let Some(ref temp) = cache.temp_dir {
-                return Ok(temp.path().to_string_lossy().to_string());
-            }
-        }
-    }
-    Err("EPUB 未加载或缓存失效".to_string())
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
 #[tauri::command]
-async fn read_epub_file_content(epub_path: String, file_path: String) -> Result<String, String> {
+async fn save_epub_files_batch(
+    epub_path: String,
+    files: HashMap<String, Vec<u8>>,
+) -> Result<(), String> {
     tauri::async_runtime::spawn_blocking(move || {
-        // 1. 获取临时目录路径
+        // 1. Get Temp Path
         let temp_path: PathBuf = {
             let cache_guard = lock_epub_cache()?;
             if let Some(ref cache) = *cache_guard {
@@ -8565,20 +16451,50 @@ async fn read_epub_file_content(epub_path: String, file_path: String) -> Result<
                 None
             }
         }
-        .ok_or("EPUB 未加载或缓存失效".to_string())?;
+        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())?;
 
-        // 2. 从临时文件读取
-        let target_path = temp_path.join(&file_path);
-        std::fs::read_to_string(target_path).map_err(|e| format!("读取文件失败: {}", e))
+        // 2. Iterate and Write
+        for (file_path, content) in files {
+            let target_path = temp_path.join(&file_path);
+            if let Some(parent) = target_path.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| format!("无法创建目录: {}", e))?;
+            }
+            std::fs::write(target_path, &content)
+                .map_err(|e| format!("写入文件失败 {}: {}", file_path, e))?;
+
+            // Update Cache (Binary or Text doesn't matter for storage, but for cache structure)
+            // Since we don't know if it's text, we might clear both entries or just skip cache update for now?
+            // Actually, we should probably update cache if it exists.
+            // For simplicity and speed in batch mode, let's just invalidate the specific cache entries if they exist.
+            {
+                let mut cache_guard = lock_epub_cache()?;
+                if let Some(ref mut cache) = *cache_guard {
+                    if cache.epub_path == epub_path {
+                        // If it was text, update with valid utf8?
+                        // Risk of decoding binary as text.
+                        // Safer to remove from text_cache and let it re-read from disk on next access.
+                        cache.text_cache.remove(&file_path);
+                        cache.binary_cache.remove(&file_path);
+                    }
+                }
+            }
+        }
+
+        Ok(())
     })
     .await
     .map_err(|e| format!("任务失败: {}", e))?
 }
 
 #[tauri::command]
-async fn read_epub_file_binary(epub_path: String, file_path: String) -> Result<Vec<u8>, String> {
+async fn save_epub_to_disk(app: tauri::AppHandle, epub_path: String) -> Result<(), String> {
+    let checkpoint_dir = epub_checkpoint_dir(&get_history_base_dir(Some(&app)), &epub_path);
+    let retention = read_library_data(&app)
+        .map(|data| data.config.epub_history_retention)
+        .unwrap_or_default();
     tauri::async_runtime::spawn_blocking(move || {
-        // 1. 获取临时目录路径
+        use zip::write::FileOptions;
+
         let temp_path: PathBuf = {
             let cache_guard = lock_epub_cache()?;
             if let Some(ref cache) = *cache_guard {
@@ -8597,164 +16513,416 @@ async fn read_epub_file_binary(epub_path: String, file_path: String) -> Result<V
         }
         .ok_or("EPUB 未加载或缓存失效".to_string())?;
 
-        // 2. 从临时文件读取
-        let target_path = temp_path.join(&file_path);
-        std::fs::read(target_path).map_err(|e| format!("读取文件失败: {}", e))
-    })
-    .await
-    .map_err(|e| format!("任务失败: {}", e))?
-}
+        // 覆盖前先留检查点，失败则不保存，避免丢掉唯一副本
+        checkpoint_epub_file(&checkpoint_dir, Path::new(&epub_path), "save", &retention)
+            .map_err(|e| format!("创建 EPUB 检查点失败: {}", e))?;
 
-// --- 批量读取 API ---
+        let zip_file_path = format!("{}.zip.tmp", epub_path);
+        let backup_file_path = format!("{}.bak.tmp", epub_path);
 
-#[tauri::command]
-async fn read_epub_files_batch(
-    epub_path: String,
-    file_paths: Vec<String>,
-) -> Result<HashMap<String, String>, String> {
-    tauri::async_runtime::spawn_blocking(move || {
-        use std::io::Read;
-        use zip::ZipArchive;
+        if Path::new(&zip_file_path).exists() {
+            let _ = fs::remove_file(&zip_file_path);
+        }
+        if Path::new(&backup_file_path).exists() {
+            let _ = fs::remove_file(&backup_file_path);
+        }
 
-        let mut results: HashMap<String, String> = HashMap::new();
-        let mut to_read: Vec<String> = Vec::new();
+        let zip_file =
+            fs::File::create(&zip_file_path).map_err(|e| format!("创建 ZIP 失败: {}", e))?;
+        let mut zip_writer = zip::ZipWriter::new(zip_file);
 
-        // 1. 检查缓存，收集需要读取的文件
-        {
-            let cache_guard = lock_epub_cache()?;
-            if let Some(ref cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    for path in &file_paths {
-                        if let Some(content) = cache.text_cache.get(path) {
-                            results.insert(path.clone(), content.clone());
-                        } else {
-                            to_read.push(path.clone());
-                        }
-                    }
-                } else {
-                    to_read = file_paths.clone();
-                }
+        let options_deflated =
+            FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let options_stored =
+            FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+        for entry in WalkDir::new(&temp_path).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let full_path = entry.path();
+            let relative_path = full_path
+                .strip_prefix(&temp_path)
+                .map_err(|e| format!("计算相对路径失败: {}", e))?;
+            let path_str = relative_path.to_string_lossy().replace("\\", "/");
+
+            let options = if path_str == "mimetype" {
+                options_stored
             } else {
-                to_read = file_paths.clone();
+                options_deflated
+            };
+
+            zip_writer
+                .start_file(&path_str, options)
+                .map_err(|e| format!("写入文件失败: {}", e))?;
+
+            let content = fs::read(full_path).map_err(|e| format!("读取文件失败: {}", e))?;
+            zip_writer
+                .write_all(&content)
+                .map_err(|e| format!("写入内容失败: {}", e))?;
+        }
+
+        zip_writer
+            .finish()
+            .map_err(|e| format!("完成 ZIP 失败: {}", e))?;
+
+        if Path::new(&epub_path).exists() {
+            fs::rename(&epub_path, &backup_file_path)
+                .map_err(|e| format!("备份原 EPUB 失败: {}", e))?;
+        }
+
+        if let Err(e) = fs::rename(&zip_file_path, &epub_path) {
+            if Path::new(&backup_file_path).exists() {
+                let _ = fs::rename(&backup_file_path, &epub_path);
             }
+            return Err(format!("替换 EPUB 失败: {}", e));
         }
 
-        // 2. 批量读取未缓存的文件
-        if !to_read.is_empty() {
-            let file = fs::File::open(&epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
-            let mut archive =
-                ZipArchive::new(file).map_err(|e| format!("无效的 EPUB 文件: {}", e))?;
+        if Path::new(&backup_file_path).exists() {
+            let _ = fs::remove_file(&backup_file_path);
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|e| format!("保存 EPUB 任务失败: {}", e))?
+}
+
+// --- EPUB 版本检查点 ---
+// 每次 save_epub_to_disk 覆盖前把磁盘上的原文件整份复制到
+// history/epub/{文件名}-{路径哈希}/{毫秒}.{原因}.epub；与上一份相同则跳过。
+
+#[derive(Serialize)]
+struct EpubCheckpointFileDiff {
+    path: String,
+    // added / removed / modified
+    status: String,
+    // 非文本条目只报告状态，不给出行差异
+    binary: bool,
+    hunks: Vec<HistoryDiffHunk>,
+}
+
+#[derive(Serialize)]
+struct EpubCheckpointDiff {
+    unchanged: usize,
+    files: Vec<EpubCheckpointFileDiff>,
+}
+
+fn epub_checkpoint_dir(history_dir: &Path, epub_path: &str) -> PathBuf {
+    let (_, prefix) = history_file_prefix(epub_path);
+    history_dir.join("epub").join(prefix)
+}
+
+// 按时间从新到旧
+fn list_epub_checkpoints_in(dir: &Path) -> Vec<HistoryMeta> {
+    let mut list: Vec<HistoryMeta> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let filename = e.file_name().to_string_lossy().to_string();
+                    let (millis, reason) = filename.strip_suffix(".epub")?.split_once('.')?;
+                    let millis = millis.parse::<u64>().ok()?;
+                    let meta = e.metadata().ok()?;
+                    Some(HistoryMeta {
+                        path: e.path().to_string_lossy().to_string(),
+                        filename: filename.clone(),
+                        timestamp: millis / 1000,
+                        size: meta.len(),
+                        date_str: String::new(),
+                        reason: reason.to_string(),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    list.sort_by(|a, b| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| b.filename.cmp(&a.filename))
+    });
+    list
+}
+
+// 返回是否新建了检查点；原文件不存在（首次保存）时什么也不做。
+fn checkpoint_epub_file(
+    dir: &Path,
+    epub_path: &Path,
+    reason: &str,
+    retention: &HistoryRetentionConfig,
+) -> Result<bool, String> {
+    if !epub_path.is_file() {
+        return Ok(false);
+    }
+    let bytes = read_epub_bytes(epub_path)?;
+    let existing = list_epub_checkpoints_in(dir);
+    let unchanged = existing.first().is_some_and(|latest| {
+        fs::read(&latest.path).is_ok_and(|prev| md5::compute(&prev) == md5::compute(&bytes))
+    });
+    if !unchanged {
+        ensure_dir(dir)?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let target = dir.join(format!("{}.{}.epub", millis, history_reason_slug(reason)));
+        fs::write(&target, &bytes).map_err(|e| format!("写入检查点失败: {}", e))?;
+    }
+    let now_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for path in prune_history_snapshots(&list_epub_checkpoints_in(dir), retention, now_secs) {
+        let _ = fs::remove_file(path);
+    }
+    Ok(!unchanged)
+}
+
+fn resolve_epub_checkpoint(dir: &Path, checkpoint_path: &str) -> Result<PathBuf, String> {
+    let dir = fs::canonicalize(dir).map_err(|e| format!("检查点目录不可用: {}", e))?;
+    let path = fs::canonicalize(checkpoint_path).map_err(|e| format!("检查点不存在: {}", e))?;
+    if path.parent() != Some(dir.as_path())
+        || path.extension().and_then(|ext| ext.to_str()) != Some("epub")
+    {
+        return Err("不是当前 EPUB 的检查点".to_string());
+    }
+    Ok(path)
+}
+
+fn read_zip_entries(bytes: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, String> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+        .map_err(|e| format!("读取 EPUB 压缩包失败: {}", e))?;
+    let mut entries = BTreeMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| format!("读取 EPUB 条目失败: {}", e))?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().replace('\\', "/");
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| format!("读取 EPUB 条目失败: {}", e))?;
+        entries.insert(name, data);
+    }
+    Ok(entries)
+}
 
-            let mut new_contents: Vec<(String, String)> = Vec::new();
+fn entry_lines(data: Option<&Vec<u8>>) -> Option<Vec<String>> {
+    let Some(data) = data else {
+        return Some(Vec::new());
+    };
+    let text = String::from_utf8(data.clone()).ok()?;
+    Some(
+        normalize_line_endings(text)
+            .split('\n')
+            .map(str::to_string)
+            .collect(),
+    )
+}
 
-            for path in to_read {
-                if let Ok(mut zip_file) = archive.by_name(&path) {
-                    let mut content = String::new();
-                    if zip_file.read_to_string(&mut content).is_ok() {
-                        results.insert(path.clone(), content.clone());
-                        new_contents.push((path, content));
-                    }
-                }
+fn diff_epub_entries(
+    base: &BTreeMap<String, Vec<u8>>,
+    target: &BTreeMap<String, Vec<u8>>,
+) -> EpubCheckpointDiff {
+    let mut diff = EpubCheckpointDiff {
+        unchanged: 0,
+        files: Vec::new(),
+    };
+    let paths: BTreeSet<&String> = base.keys().chain(target.keys()).collect();
+    for path in paths {
+        let (old, new) = (base.get(path), target.get(path));
+        let status = match (old, new) {
+            (Some(a), Some(b)) if a == b => {
+                diff.unchanged += 1;
+                continue;
             }
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "modified",
+        };
+        let lines = if is_text_like_entry(path) {
+            entry_lines(old).zip(entry_lines(new))
+        } else {
+            None
+        };
+        let hunks = lines
+            .as_ref()
+            .map(|(a, b)| {
+                diff_line_ranges(a, b)
+                    .iter()
+                    .enumerate()
+                    .map(|(id, range)| history_diff_hunk(id, range, a, b))
+                    .collect()
+            })
+            .unwrap_or_default();
+        diff.files.push(EpubCheckpointFileDiff {
+            path: path.clone(),
+            status: status.to_string(),
+            binary: lines.is_none(),
+            hunks,
+        });
+    }
+    diff
+}
 
-            // 3. 存入缓存
-            {
-                let mut cache_guard = lock_epub_cache()?;
-                if let Some(ref mut cache) = *cache_guard {
-                    if cache.epub_path == epub_path {
-                        for (path, content) in new_contents {
-                            cache.text_cache.insert(path, content);
-                        }
-                    }
-                }
-            }
-        }
+#[tauri::command]
+fn list_epub_checkpoints(app: tauri::AppHandle, epub_path: String) -> Vec<HistoryMeta> {
+    list_epub_checkpoints_in(&epub_checkpoint_dir(
+        &get_history_base_dir(Some(&app)),
+        &epub_path,
+    ))
+}
 
-        Ok(results)
+// against 为空时与磁盘上当前的 EPUB 比较，否则与另一个检查点比较。
+#[tauri::command]
+async fn diff_epub_checkpoint(
+    app: tauri::AppHandle,
+    epub_path: String,
+    checkpoint: String,
+    against: Option<String>,
+) -> Result<EpubCheckpointDiff, String> {
+    let dir = epub_checkpoint_dir(&get_history_base_dir(Some(&app)), &epub_path);
+    tauri::async_runtime::spawn_blocking(move || {
+        let base = read_epub_bytes(&resolve_epub_checkpoint(&dir, &checkpoint)?)?;
+        let target = match against {
+            Some(other) => read_epub_bytes(&resolve_epub_checkpoint(&dir, &other)?)?,
+            None => read_epub_bytes(Path::new(&epub_path))?,
+        };
+        Ok(diff_epub_entries(
+            &read_zip_entries(&base)?,
+            &read_zip_entries(&target)?,
+        ))
     })
     .await
     .map_err(|e| format!("任务失败: {}", e))?
 }
 
+// 恢复前给当前文件再留一个检查点；若该 EPUB 正在编辑，丢弃解压缓存，前端需重新加载。
 #[tauri::command]
-async fn read_epub_binary_batch(
+async fn restore_epub_checkpoint(
+    app: tauri::AppHandle,
     epub_path: String,
-    file_paths: Vec<String>,
-) -> Result<HashMap<String, Vec<u8>>, String> {
+    checkpoint: String,
+) -> Result<(), String> {
+    let dir = epub_checkpoint_dir(&get_history_base_dir(Some(&app)), &epub_path);
+    let retention = read_library_data(&app)
+        .map(|data| data.config.epub_history_retention)
+        .unwrap_or_default();
     tauri::async_runtime::spawn_blocking(move || {
-        use std::io::Read;
-        use zip::ZipArchive;
-
-        let mut results: HashMap<String, Vec<u8>> = HashMap::new();
-        let mut to_read: Vec<String> = Vec::new();
-
-        // 1. 检查缓存
-        {
-            let cache_guard = lock_epub_cache()?;
-            if let Some(ref cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    for path in &file_paths {
-                        if let Some(data) = cache.binary_cache.get(path) {
-                            results.insert(path.clone(), data.clone());
-                        } else {
-                            to_read.push(path.clone());
-                        }
-                    }
-                } else {
-                    to_read = file_paths.clone();
-                }
-            } else {
-                to_read = file_paths.clone();
-            }
+        let source = resolve_epub_checkpoint(&dir, &checkpoint)?;
+        let bytes = read_epub_bytes(&source)?;
+        checkpoint_epub_file(&dir, Path::new(&epub_path), "before_restore", &retention)?;
+        let tmp_path = format!("{}.restore.tmp", epub_path);
+        fs::write(&tmp_path, &bytes).map_err(|e| format!("写入 EPUB 失败: {}", e))?;
+        if let Err(e) = fs::rename(&tmp_path, &epub_path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(format!("替换 EPUB 失败: {}", e));
         }
-
-        // 2. 批量读取
-        if !to_read.is_empty() {
-            let file = fs::File::open(&epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
-            let mut archive =
-                ZipArchive::new(file).map_err(|e| format!("无效的 EPUB 文件: {}", e))?;
-
-            let mut new_data: Vec<(String, Vec<u8>)> = Vec::new();
-
-            for path in to_read {
-                if let Ok(mut zip_file) = archive.by_name(&path) {
-                    let mut buffer = Vec::new();
-                    if zip_file.read_to_end(&mut buffer).is_ok() {
-                        results.insert(path.clone(), buffer.clone());
-                        new_data.push((path, buffer));
-                    }
-                }
-            }
-
-            // 3. 存入缓存
-            {
-                let mut cache_guard = lock_epub_cache()?;
-                if let Some(ref mut cache) = *cache_guard {
-                    if cache.epub_path == epub_path {
-                        for (path, data) in new_data {
-                            cache.binary_cache.insert(path, data);
-                        }
-                    }
-                }
-            }
+        let mut cache_guard = lock_epub_cache()?;
+        if cache_guard
+            .as_ref()
+            .is_some_and(|cache| cache.epub_path == epub_path)
+        {
+            *cache_guard = None;
         }
-
-        Ok(results)
+        Ok(())
     })
     .await
     .map_err(|e| format!("任务失败: {}", e))?
 }
 
-// --- EPUB 文件保存命令 ---
+#[cfg(test)]
+mod epub_checkpoint_tests {
+    use super::*;
+    use zip::write::FileOptions;
+
+    fn build_epub(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn checkpoints_skip_identical_files_and_respect_retention() -> Result<(), String> {
+        let root = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let epub = root.path().join("书.epub");
+        let dir = epub_checkpoint_dir(root.path(), &epub.to_string_lossy());
+        let retention = HistoryRetentionConfig {
+            max_count: 2,
+            ..Default::default()
+        };
+        // 首次保存时磁盘上还没有文件
+        assert!(!checkpoint_epub_file(&dir, &epub, "save", &retention)?);
+        for (round, text) in ["一", "一", "二", "三"].iter().enumerate() {
+            fs::write(&epub, build_epub(&[("OEBPS/a.xhtml", text.as_bytes())]))
+                .map_err(|e| e.to_string())?;
+            let created = checkpoint_epub_file(&dir, &epub, "save", &retention)?;
+            assert_eq!(created, round != 1);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let list = list_epub_checkpoints_in(&dir);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].reason, "save");
+        let latest = read_zip_entries(&read_epub_bytes(Path::new(&list[0].path))?)?;
+        assert_eq!(latest["OEBPS/a.xhtml"], "三".as_bytes());
+        assert!(resolve_epub_checkpoint(&dir, &list[1].path).is_ok());
+        assert!(resolve_epub_checkpoint(&dir, &epub.to_string_lossy()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn package_diff_reports_text_hunks_and_binary_changes() -> Result<(), String> {
+        let base = read_zip_entries(&build_epub(&[
+            ("mimetype", b"application/epub+zip"),
+            ("OEBPS/Text/c1.xhtml", "<p>甲</p>\n<p>乙</p>".as_bytes()),
+            ("OEBPS/Images/a.png", &[1, 2, 3]),
+            ("OEBPS/Text/old.xhtml", b"<p>x</p>"),
+        ]))?;
+        let target = read_zip_entries(&build_epub(&[
+            ("mimetype", b"application/epub+zip"),
+            ("OEBPS/Text/c1.xhtml", "<p>甲</p>\n<p>乙改</p>".as_bytes()),
+            ("OEBPS/Images/a.png", &[1, 2, 4]),
+            ("OEBPS/Text/new.xhtml", b"<p>y</p>"),
+        ]))?;
+        let diff = diff_epub_entries(&base, &target);
+        assert_eq!(diff.unchanged, 1);
+        let summary: Vec<(&str, &str, bool, usize)> = diff
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.status.as_str(), f.binary, f.hunks.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("OEBPS/Images/a.png", "modified", true, 0),
+                ("OEBPS/Text/c1.xhtml", "modified", false, 1),
+                ("OEBPS/Text/new.xhtml", "added", false, 1),
+                ("OEBPS/Text/old.xhtml", "removed", false, 1),
+            ]
+        );
+        let hunk = &diff.files[1].hunks[0];
+        assert_eq!(hunk.old_lines, vec!["<p>乙</p>"]);
+        assert_eq!(hunk.new_lines, vec!["<p>乙改</p>"]);
+        Ok(())
+    }
+}
 
 #[tauri::command]
-async fn save_epub_file_content(
+async fn search_in_files(
     epub_path: String,
-    file_path: String,
-    content: String,
-) -> Result<(), String> {
+    files: Vec<String>,
+    pattern: String,
+    is_regex: bool,
+    options: Option<FuzzySearchOptions>,
+) -> Result<usize, String> {
     tauri::async_runtime::spawn_blocking(move || {
-        // 1. 获取临时目录路径
+        // 1. 获取临时目录
         let temp_path: PathBuf = {
             let cache_guard = lock_epub_cache()?;
             if let Some(ref cache) = *cache_guard {
@@ -8771,285 +16939,749 @@ async fn save_epub_file_content(
                 None
             }
         }
-        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())?;
+        .ok_or("EPUB 未加载或缓存失效".to_string())?;
 
-        // 2. 写入临时文件
-        let target_path = temp_path.join(&file_path);
-        std::fs::write(target_path, &content).map_err(|e| format!("写入文件失败: {}", e))?;
+        if files.is_empty() {
+            return Ok(0);
+        }
 
-        // 3. 更新缓存 (Text Cache)
-        {
-            let mut cache_guard = lock_epub_cache()?;
-            if let Some(ref mut cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    cache.text_cache.insert(file_path, content);
+        let mut count = 0;
+        let options = options.unwrap_or_default();
+        let pattern = if options.variant_insensitive {
+            fold_variant_text(&pattern).into_owned()
+        } else {
+            pattern
+        };
+        let pinyin_query = if options.pinyin {
+            pinyin_query_chars(&pattern)
+        } else {
+            Vec::new()
+        };
+
+        // Pre-compile regex
+        let re = if is_regex && !options.pinyin {
+            Some(Regex::new(&pattern).map_err(|e| format!("正则表达式错误: {}", e))?)
+        } else {
+            None
+        };
+
+        for path in files {
+            let target_path = temp_path.join(path);
+            if let Ok(content) = fs::read_to_string(target_path) {
+                let content = if options.variant_insensitive {
+                    fold_variant_text(&content).into_owned()
+                } else {
+                    content
+                };
+                if options.pinyin {
+                    if !pinyin_query.is_empty() {
+                        count += content
+                            .lines()
+                            .map(|line| pinyin_match_ranges(line, &pinyin_query).len())
+                            .sum::<usize>();
+                    }
+                } else if let Some(ref regex) = re {
+                    count += regex.find_iter(&content).count();
+                } else {
+                    count += content.matches(&pattern).count();
                 }
             }
         }
 
-        Ok(())
+        Ok(count)
     })
     .await
     .map_err(|e| format!("任务失败: {}", e))?
 }
 
-#[tauri::command]
-async fn save_epub_file_binary(
-    epub_path: String,
-    file_path: String,
-    content: Vec<u8>,
-) -> Result<(), String> {
-    tauri::async_runtime::spawn_blocking(move || {
-        // 1. 获取临时目录路径
-        let temp_path: PathBuf = {
-            let cache_guard = lock_epub_cache()?;
-            if let Some(ref cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    if let Some(ref temp) = cache.temp_dir {
-                        Some(temp.path().to_path_buf())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }
-        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())?;
+#[derive(Serialize)]
+struct EpubFileSearchMatch {
+    #[serde(flatten)]
+    hit: MultilineMatch,
+    heading: Option<String>,
+}
 
-        // 2. 写入二进制文件
-        let target_path = temp_path.join(&file_path);
-        if let Some(parent) = target_path.parent() {
-            std::fs::create_dir_all(parent).map_err(|e| format!("无法创建目录: {}", e))?;
-        }
-        std::fs::write(target_path, content).map_err(|e| format!("写入文件失败: {}", e))?;
+#[derive(Serialize)]
+struct EpubFileSearchResult {
+    path: String,
+    matches: Vec<EpubFileSearchMatch>,
+}
 
-        // 3. 更新二进制缓存 (Binary Cache)
-        {
-            let mut cache_guard = lock_epub_cache()?;
-            if let Some(ref mut cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    cache.binary_cache.insert(file_path, Default::default());
-                }
-            }
+#[derive(Serialize)]
+struct EpubSearchReport {
+    total: usize,
+    truncated: bool,
+    // 与无法解码的实体（如 &copy;）交叠而跳过的匹配数
+    skipped: usize,
+    files: Vec<EpubFileSearchResult>,
+}
+
+#[derive(Serialize)]
+struct EpubReplaceReport {
+    replaced: usize,
+    // 仅文本节点替换有跳过的匹配；整文件替换不输出该字段
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<usize>,
+    changed_files: Vec<String>,
+}
+
+static HTML_HEADING_BLOCK_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?is)<h([1-6])\b[^>]*>(.*?)</h\1\s*>").expect("valid html heading block regex")
+});
+static HTML_TITLE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").expect("valid html title regex"));
+
+fn html_plain_text(fragment: &str) -> String {
+    decode_basic_html_entities(&strip_html_tags(fragment))
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// 文件内所有 h1-h6 的起始位置（行、列）与纯文本标题
+fn html_heading_positions(content: &str, index: &LineIndex) -> Vec<((usize, usize), String)> {
+    HTML_HEADING_BLOCK_RE
+        .captures_iter(content)
+        .filter_map(|caps| caps.ok())
+        .filter_map(|caps| {
+            let whole = caps.get(0)?;
+            let text = html_plain_text(caps.get(2)?.as_str());
+            (!text.is_empty()).then(|| (index.position(content, whole.start()), text))
+        })
+        .collect()
+}
+
+// 匹配归属到它之前最近的 h1-h6，标题之前的取 <title>。
+fn attach_epub_search_headings(
+    content: &str,
+    matches: Vec<MultilineMatch>,
+) -> Vec<EpubFileSearchMatch> {
+    let index = LineIndex::new(content);
+    let headings = html_heading_positions(content, &index);
+    let fallback = HTML_TITLE_RE
+        .captures(content)
+        .ok()
+        .flatten()
+        .and_then(|caps| caps.get(1).map(|m| html_plain_text(m.as_str())))
+        .filter(|text| !text.is_empty());
+    matches
+        .into_iter()
+        .map(|hit| {
+            let at = (hit.span.start_line, hit.span.start_column);
+            let heading = headings
+                .iter()
+                .rev()
+                .find(|(position, _)| *position <= at)
+                .map(|(_, text)| text.clone())
+                .or_else(|| fallback.clone());
+            EpubFileSearchMatch { hit, heading }
+        })
+        .collect()
+}
+
+// 按源码搜索，与 search_in_files 的计数口径一致。
+fn search_epub_files_in_dir(
+    temp_path: &Path,
+    files: &[String],
+    pattern: &str,
+    options: &MultilineSearchOptions,
+) -> Result<EpubSearchReport, String> {
+    let limit = options
+        .max_matches
+        .filter(|v| *v > 0)
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_LIMIT);
+    let mut report = EpubSearchReport {
+        total: 0,
+        truncated: false,
+        skipped: 0,
+        files: Vec::new(),
+    };
+    for path in files {
+        if report.total >= limit {
+            report.truncated = true;
+            break;
+        }
+        let Ok(content) = fs::read_to_string(temp_path.join(path)) else {
+            continue;
+        };
+        let file_options = MultilineSearchOptions {
+            max_matches: Some(limit - report.total),
+            ..options.clone()
+        };
+        let result = multiline_search_impl(&content, pattern, &file_options)?;
+        if result.matches.is_empty() {
+            continue;
         }
+        report.truncated |= result.truncated;
+        let matches = attach_epub_search_headings(&content, result.matches);
+        report.total += matches.len();
+        report.files.push(EpubFileSearchResult {
+            path: path.clone(),
+            matches,
+        });
+    }
+    Ok(report)
+}
 
-        Ok(())
+#[tauri::command]
+async fn search_in_files_detailed(
+    epub_path: String,
+    files: Vec<String>,
+    pattern: String,
+    options: Option<MultilineSearchOptions>,
+) -> Result<EpubSearchReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let temp_path = cached_epub_temp_path(&epub_path)?;
+        search_epub_files_in_dir(&temp_path, &files, &pattern, &options.unwrap_or_default())
     })
     .await
     .map_err(|e| format!("任务失败: {}", e))?
 }
 
+// 在解压目录中直接替换并同步 text_cache；只处理 files 中列出的文件。
 #[tauri::command]
-async fn save_epub_files_batch(
+async fn replace_in_files(
     epub_path: String,
-    files: HashMap<String, Vec<u8>>,
-) -> Result<(), String> {
+    files: Vec<String>,
+    pattern: String,
+    replacement: String,
+    options: Option<MultilineSearchOptions>,
+) -> Result<EpubReplaceReport, String> {
     tauri::async_runtime::spawn_blocking(move || {
-        // 1. Get Temp Path
-        let temp_path: PathBuf = {
-            let cache_guard = lock_epub_cache()?;
-            if let Some(ref cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    if let Some(ref temp) = cache.temp_dir {
-                        Some(temp.path().to_path_buf())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+        let options = options.unwrap_or_default();
+        if pattern.is_empty() || files.is_empty() {
+            return Ok(EpubReplaceReport {
+                replaced: 0,
+                skipped: None,
+                changed_files: Vec::new(),
+            });
         }
-        .ok_or_else(|| "EPUB 未加载或缓存失效".to_string())?;
-
-        // 2. Iterate and Write
-        for (file_path, content) in files {
-            let target_path = temp_path.join(&file_path);
-            if let Some(parent) = target_path.parent() {
-                std::fs::create_dir_all(parent).map_err(|e| format!("无法创建目录: {}", e))?;
+        let re = build_multiline_regex(&pattern, &options)?;
+        let targets: HashSet<String> = files.into_iter().collect();
+        let mut replaced = 0usize;
+        let mut error: Option<String> = None;
+        // 先全部算完，任一文件出错则不写入，避免只替换了一半
+        let updated = collect_cached_epub_text_rewrites(&epub_path, |path, text| {
+            if error.is_some() || !targets.contains(path) {
+                return None;
             }
-            std::fs::write(target_path, &content)
-                .map_err(|e| format!("写入文件失败 {}: {}", file_path, e))?;
-
-            // Update Cache (Binary or Text doesn't matter for storage, but for cache structure)
-            // Since we don't know if it's text, we might clear both entries or just skip cache update for now?
-            // Actually, we should probably update cache if it exists.
-            // For simplicity and speed in batch mode, let's just invalidate the specific cache entries if they exist.
-            {
-                let mut cache_guard = lock_epub_cache()?;
-                if let Some(ref mut cache) = *cache_guard {
-                    if cache.epub_path == epub_path {
-                        // If it was text, update with valid utf8?
-                        // Risk of decoding binary as text.
-                        // Safer to remove from text_cache and let it re-read from disk on next access.
-                        cache.text_cache.remove(&file_path);
-                        cache.binary_cache.remove(&file_path);
-                    }
+            match replace_all_counted(&re, text, &replacement, options.is_regex) {
+                Ok((next, count)) => {
+                    replaced += count;
+                    (next != text).then_some(next)
+                }
+                Err(e) => {
+                    error = Some(e);
+                    None
                 }
             }
-        }
-
-        Ok(())
+        })?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let changed_files = commit_cached_epub_text_rewrites(&epub_path, updated)?;
+        Ok(EpubReplaceReport {
+            replaced,
+            skipped: None,
+            changed_files,
+        })
     })
     .await
     .map_err(|e| format!("任务失败: {}", e))?
 }
 
-#[tauri::command]
-async fn save_epub_to_disk(epub_path: String) -> Result<(), String> {
-    tauri::async_runtime::spawn_blocking(move || {
-        use zip::write::FileOptions;
-
-        let temp_path: PathBuf = {
-            let cache_guard = lock_epub_cache()?;
-            if let Some(ref cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    if let Some(ref temp) = cache.temp_dir {
-                        Some(temp.path().to_path_buf())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }
-        .ok_or("EPUB 未加载或缓存失效".to_string())?;
-
-        let zip_file_path = format!("{}.zip.tmp", epub_path);
-        let backup_file_path = format!("{}.bak.tmp", epub_path);
+#[cfg(test)]
+mod epub_search_tests {
+    use super::*;
 
-        if Path::new(&zip_file_path).exists() {
-            let _ = fs::remove_file(&zip_file_path);
-        }
-        if Path::new(&backup_file_path).exists() {
-            let _ = fs::remove_file(&backup_file_path);
-        }
+    #[test]
+    fn reports_locations_and_containing_heading() -> Result<(), String> {
+        let temp = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let text_dir = temp.path().join("OEBPS/Text");
+        fs::create_dir_all(&text_dir).map_err(|e| e.to_string())?;
+        fs::write(
+            text_dir.join("c1.xhtml"),
+            "<html><head><title>卷一</title></head><body>\n<p>错字在前</p>\n<h2>第一章 <span>起</span></h2>\n<p>又见错字，错字。</p>\n</body></html>",
+        )
+        .map_err(|e| e.to_string())?;
+        fs::write(text_dir.join("c2.xhtml"), "<p>没有</p>").map_err(|e| e.to_string())?;
+        let files = vec![
+            "OEBPS/Text/c1.xhtml".to_string(),
+            "OEBPS/Text/c2.xhtml".to_string(),
+            "OEBPS/Text/missing.xhtml".to_string(),
+        ];
+        let options = MultilineSearchOptions {
+            is_regex: false,
+            context_chars: Some(2),
+            ..Default::default()
+        };
+        let report = search_epub_files_in_dir(temp.path(), &files, "错字", &options)?;
+        assert_eq!(report.total, 3);
+        assert_eq!(report.files.len(), 1);
+        let matches = &report.files[0].matches;
+        assert_eq!(matches[0].heading.as_deref(), Some("卷一"));
+        assert_eq!(matches[0].hit.span.start_line, 2);
+        assert_eq!(matches[0].hit.span.start_column, 3);
+        assert_eq!(matches[1].heading.as_deref(), Some("第一章 起"));
+        assert_eq!(matches[1].hit.span.start_line, 4);
+        assert_eq!(matches[2].hit.context_before, "字，");
+
+        let limited = search_epub_files_in_dir(
+            temp.path(),
+            &files,
+            "错字",
+            &MultilineSearchOptions {
+                max_matches: Some(2),
+                ..options
+            },
+        )?;
+        assert_eq!(limited.total, 2);
+        assert!(limited.truncated);
+        Ok(())
+    }
+}
 
-        let zip_file =
-            fs::File::create(&zip_file_path).map_err(|e| format!("创建 ZIP 失败: {}", e))?;
-        let mut zip_writer = zip::ZipWriter::new(zip_file);
+// --- EPUB 文本节点搜索替换 ---
+// 只在标签之间的文本里匹配：标签、属性、href 与实体引用都不会被改动，替换内容按 XML 转义写回。
 
-        let options_deflated =
-            FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
-        let options_stored =
-            FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+#[derive(Deserialize, Clone, Default)]
+struct TextNodeSearchOptions {
+    #[serde(flatten)]
+    search: MultilineSearchOptions,
+    // 只在这些元素（任一祖先即可）内匹配，如 p、h2
+    #[serde(default)]
+    elements: Vec<String>,
+    // 只在带这些 class 的元素（任一祖先即可）内匹配
+    #[serde(default)]
+    classes: Vec<String>,
+}
 
-        for entry in WalkDir::new(&temp_path).into_iter().filter_map(|e| e.ok()) {
-            if !entry.file_type().is_file() {
-                continue;
-            }
+impl TextNodeSearchOptions {
+    fn accepts(&self, elements: &[MarkupElement]) -> bool {
+        let element_ok = self.elements.is_empty()
+            || elements.iter().any(|e| {
+                self.elements
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&e.name))
+            });
+        let class_ok = self.classes.is_empty()
+            || elements
+                .iter()
+                .any(|e| self.classes.iter().any(|class| e.has_class(class)));
+        element_ok && class_ok
+    }
+}
 
-            let full_path = entry.path();
-            let relative_path = full_path
-                .strip_prefix(&temp_path)
-                .map_err(|e| format!("计算相对路径失败: {}", e))?;
-            let path_str = relative_path.to_string_lossy().replace("\\", "/");
+static XML_ENTITY_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"&(?:#[0-9]+|#[xX][0-9a-fA-F]+|[A-Za-z][A-Za-z0-9]*);").expect("valid entity regex")
+});
 
-            let options = if path_str == "mimetype" {
-                options_stored
-            } else {
-                options_deflated
+fn decode_xml_entity(entity: &str) -> Option<char> {
+    let name = entity.strip_prefix('&')?.strip_suffix(';')?;
+    match name {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
             };
-
-            zip_writer
-                .start_file(&path_str, options)
-                .map_err(|e| format!("写入文件失败: {}", e))?;
-
-            let content = fs::read(full_path).map_err(|e| format!("读取文件失败: {}", e))?;
-            zip_writer
-                .write_all(&content)
-                .map_err(|e| format!("写入内容失败: {}", e))?;
+            char::from_u32(code)
         }
+    }
+}
 
-        zip_writer
-            .finish()
-            .map_err(|e| format!("完成 ZIP 失败: {}", e))?;
-
-        if Path::new(&epub_path).exists() {
-            fs::rename(&epub_path, &backup_file_path)
-                .map_err(|e| format!("备份原 EPUB 失败: {}", e))?;
+/// 解码文本节点中的实体引用。offsets[i] 为解码结果第 i 个字节在原文中的位置（末尾多一项），
+/// 无法识别的实体原样保留，并返回它们在解码结果中的区间。
+fn decode_text_node_entities(text: &str) -> (String, Vec<usize>, Vec<(usize, usize)>) {
+    let mut decoded = String::with_capacity(text.len());
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    let mut opaque = Vec::new();
+    let mut copied = 0usize;
+    let push_source = |decoded: &mut String, offsets: &mut Vec<usize>, from: usize, to: usize| {
+        decoded.push_str(&text[from..to]);
+        offsets.extend(from..to);
+    };
+    for m in XML_ENTITY_RE.find_iter(text).flatten() {
+        push_source(&mut decoded, &mut offsets, copied, m.start());
+        match decode_xml_entity(m.as_str()) {
+            Some(c) => {
+                decoded.push(c);
+                offsets.extend(std::iter::repeat_n(m.start(), c.len_utf8()));
+            }
+            None => {
+                let start = decoded.len();
+                push_source(&mut decoded, &mut offsets, m.start(), m.end());
+                opaque.push((start, decoded.len()));
+            }
         }
+        copied = m.end();
+    }
+    push_source(&mut decoded, &mut offsets, copied, text.len());
+    offsets.push(text.len());
+    (decoded, offsets, opaque)
+}
 
-        if let Err(e) = fs::rename(&zip_file_path, &epub_path) {
-            if Path::new(&backup_file_path).exists() {
-                let _ = fs::rename(&backup_file_path, &epub_path);
+// 文件内的一个文本节点匹配：start/end 为整份 markup 中的字节区间
+struct TextNodeHit {
+    start: usize,
+    end: usize,
+    text: String,
+    captures: Vec<(usize, usize, usize)>,
+    context_before: String,
+    context_after: String,
+}
+
+// 在解码实体后的文字上匹配，区间再映射回原文；替换内容转义后写回，未命中部分保持原有写法。
+// 与无法解码的实体交叠的匹配计入 skipped。replacement 为 None 时只收集匹配，markup 原样返回。
+fn rewrite_text_node_matches(
+    markup: &str,
+    re: &Regex,
+    options: &TextNodeSearchOptions,
+    replacement: Option<&str>,
+    hits: &mut Vec<TextNodeHit>,
+    skipped: &mut usize,
+) -> Result<String, String> {
+    let context = options
+        .search
+        .context_chars
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_CONTEXT);
+    let mut error: Option<String> = None;
+    let output = map_markup_text_nodes_in_context(markup, |text, offset, elements| {
+        if error.is_some() || !options.accepts(elements) {
+            return text.to_string();
+        }
+        let (decoded, offsets, opaque) = decode_text_node_entities(text);
+        let mut out = String::new();
+        let mut copied = 0usize;
+        let mut rewritten = false;
+        for caps in re.captures_iter(&decoded) {
+            let caps = match caps {
+                Ok(caps) => caps,
+                Err(e) => {
+                    error = Some(format!("Regex Error: {}", e));
+                    break;
+                }
+            };
+            let Some(whole) = caps.get(0) else {
+                continue;
+            };
+            if opaque
+                .iter()
+                .any(|(start, end)| whole.start() < *end && *start < whole.end())
+            {
+                *skipped += 1;
+                continue;
+            }
+            let (start, end) = (offsets[whole.start()], offsets[whole.end()]);
+            let (context_before, context_after) =
+                match_context(&decoded, whole.start(), whole.end(), context);
+            hits.push(TextNodeHit {
+                start: offset + start,
+                end: offset + end,
+                text: whole.as_str().to_string(),
+                captures: (1..caps.len())
+                    .filter_map(|group| {
+                        caps.get(group).map(|m| {
+                            (
+                                group,
+                                offset + offsets[m.start()],
+                                offset + offsets[m.end()],
+                            )
+                        })
+                    })
+                    .collect(),
+                context_before,
+                context_after,
+            });
+            if let Some(replacement) = replacement {
+                let mut expanded = String::new();
+                if options.search.is_regex {
+                    caps.expand(replacement, &mut expanded);
+                } else {
+                    expanded.push_str(replacement);
+                }
+                out.push_str(&text[copied..start]);
+                out.push_str(&escape_xml(&expanded));
+                copied = end;
+                rewritten = true;
             }
-            return Err(format!("替换 EPUB 失败: {}", e));
         }
-
-        if Path::new(&backup_file_path).exists() {
-            let _ = fs::remove_file(&backup_file_path);
+        if !rewritten {
+            return text.to_string();
+        }
+        out.push_str(&text[copied..]);
+        out
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(output),
+    }
+}
+
+fn search_epub_text_nodes_in_dir(
+    temp_path: &Path,
+    files: &[String],
+    pattern: &str,
+    options: &TextNodeSearchOptions,
+) -> Result<EpubSearchReport, String> {
+    let mut report = EpubSearchReport {
+        total: 0,
+        truncated: false,
+        skipped: 0,
+        files: Vec::new(),
+    };
+    if pattern.is_empty() {
+        return Ok(report);
+    }
+    let re = build_multiline_regex(pattern, &options.search)?;
+    let names: Vec<Option<String>> = re
+        .capture_names()
+        .map(|name| name.map(str::to_string))
+        .collect();
+    let limit = options
+        .search
+        .max_matches
+        .filter(|v| *v > 0)
+        .unwrap_or(MULTILINE_SEARCH_DEFAULT_LIMIT);
+    for path in files {
+        let Ok(content) = fs::read_to_string(temp_path.join(path)) else {
+            continue;
+        };
+        let mut hits = Vec::new();
+        rewrite_text_node_matches(&content, &re, options, None, &mut hits, &mut report.skipped)?;
+        if hits.is_empty() {
+            continue;
+        }
+        if report.total + hits.len() > limit {
+            hits.truncate(limit - report.total);
+            report.truncated = true;
+        }
+        let index = LineIndex::new(&content);
+        let matches: Vec<MultilineMatch> = hits
+            .into_iter()
+            .map(|hit| MultilineMatch {
+                span: index.span(&content, hit.start, hit.end),
+                text: hit.text,
+                captures: hit
+                    .captures
+                    .into_iter()
+                    .map(|(group, start, end)| MultilineCapture {
+                        index: group,
+                        name: names.get(group).cloned().flatten(),
+                        text: content[start..end].to_string(),
+                        span: index.span(&content, start, end),
+                    })
+                    .collect(),
+                context_before: hit.context_before,
+                context_after: hit.context_after,
+            })
+            .collect();
+        let matches = attach_epub_search_headings(&content, matches);
+        report.total += matches.len();
+        if !matches.is_empty() {
+            report.files.push(EpubFileSearchResult {
+                path: path.clone(),
+                matches,
+            });
         }
+        if report.truncated {
+            break;
+        }
+    }
+    Ok(report)
+}
 
-        Ok(())
+#[tauri::command]
+async fn search_epub_text_nodes(
+    epub_path: String,
+    files: Vec<String>,
+    pattern: String,
+    options: Option<TextNodeSearchOptions>,
+) -> Result<EpubSearchReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let temp_path = cached_epub_temp_path(&epub_path)?;
+        search_epub_text_nodes_in_dir(&temp_path, &files, &pattern, &options.unwrap_or_default())
     })
     .await
-    .map_err(|e| format!("保存 EPUB 任务失败: {}", e))?
+    .map_err(|e| format!("任务失败: {}", e))?
 }
 
+// 与 save_epub_file_content 相同：改写解压目录中的文件并同步 text_cache。
 #[tauri::command]
-async fn search_in_files(
+async fn replace_epub_text_nodes(
     epub_path: String,
     files: Vec<String>,
     pattern: String,
-    is_regex: bool,
-) -> Result<usize, String> {
+    replacement: String,
+    options: Option<TextNodeSearchOptions>,
+) -> Result<EpubReplaceReport, String> {
     tauri::async_runtime::spawn_blocking(move || {
-        // 1. 获取临时目录
-        let temp_path: PathBuf = {
-            let cache_guard = lock_epub_cache()?;
-            if let Some(ref cache) = *cache_guard {
-                if cache.epub_path == epub_path {
-                    if let Some(ref temp) = cache.temp_dir {
-                        Some(temp.path().to_path_buf())
-                    } else {
-                        None
-                    }
-                } else {
+        let options = options.unwrap_or_default();
+        if pattern.is_empty() || files.is_empty() {
+            return Ok(EpubReplaceReport {
+                replaced: 0,
+                skipped: Some(0),
+                changed_files: Vec::new(),
+            });
+        }
+        let re = build_multiline_regex(&pattern, &options.search)?;
+        let targets: HashSet<String> = files.into_iter().collect();
+        let mut replaced = 0usize;
+        let mut skipped = 0usize;
+        let mut error: Option<String> = None;
+        let updated = collect_cached_epub_text_rewrites(&epub_path, |path, text| {
+            if error.is_some() || !targets.contains(path) {
+                return None;
+            }
+            let mut hits = Vec::new();
+            match rewrite_text_node_matches(
+                text,
+                &re,
+                &options,
+                Some(&replacement),
+                &mut hits,
+                &mut skipped,
+            ) {
+                Ok(next) => {
+                    replaced += hits.len();
+                    (next != text).then_some(next)
+                }
+                Err(e) => {
+                    error = Some(e);
                     None
                 }
-            } else {
-                None
             }
-        }
-        .ok_or("EPUB 未加载或缓存失效".to_string())?;
+        })?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let changed_files = commit_cached_epub_text_rewrites(&epub_path, updated)?;
+        Ok(EpubReplaceReport {
+            replaced,
+            skipped: Some(skipped),
+            changed_files,
+        })
+    })
+    .await
+    .map_err(|e| format!("任务失败: {}", e))?
+}
 
-        if files.is_empty() {
-            return Ok(0);
-        }
+#[cfg(test)]
+mod epub_text_node_tests {
+    use super::*;
+
+    const CHAPTER: &str = r#"<html><body>
+<h2 class="title">第一章 a</h2>
+<p class="note" title="a">a &amp; b <a href="a.xhtml">a</a></p>
+<p>x<span class="note">a</span></p>
+<script>var a = 1;</script>
+</body></html>"#;
+
+    fn replace(
+        pattern: &str,
+        replacement: &str,
+        options: &TextNodeSearchOptions,
+    ) -> Result<(String, usize), String> {
+        let re = build_multiline_regex(pattern, &options.search)?;
+        let mut hits = Vec::new();
+        let mut skipped = 0;
+        let out = rewrite_text_node_matches(
+            CHAPTER,
+            &re,
+            options,
+            Some(replacement),
+            &mut hits,
+            &mut skipped,
+        )?;
+        Ok((out, hits.len()))
+    }
+
+    #[test]
+    fn replaces_only_text_nodes_and_escapes_replacement() -> Result<(), String> {
+        let (out, count) = replace("a", "<A&B>", &TextNodeSearchOptions::default())?;
+        assert_eq!(count, 4);
+        assert!(
+            out.contains(r#"<h2 class="title">第一章 &lt;A&amp;B&gt;</h2>"#),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains(r#"<p class="note" title="a">&lt;A&amp;B&gt; &amp; b <a href="a.xhtml">"#),
+            "{}",
+            out
+        );
+        assert!(out.contains("<script>var a = 1;</script>"), "{}", out);
 
-        let mut count = 0;
+        let (out, count) = replace("amp", "X", &TextNodeSearchOptions::default())?;
+        assert_eq!(count, 0);
+        assert_eq!(out, CHAPTER);
+        Ok(())
+    }
 
-        // Pre-compile regex
-        let re = if is_regex {
-            Some(Regex::new(&pattern).map_err(|e| format!("正则表达式错误: {}", e))?)
-        } else {
-            None
+    #[test]
+    fn restricts_by_element_and_class() -> Result<(), String> {
+        let by_class = TextNodeSearchOptions {
+            classes: vec!["note".to_string()],
+            ..Default::default()
         };
+        let (out, count) = replace("a", "Z", &by_class)?;
+        assert_eq!(count, 3);
+        assert!(out.contains("第一章 a"));
+        assert!(out.contains(r#"<span class="note">Z</span>"#));
+
+        let by_element = TextNodeSearchOptions {
+            elements: vec!["H2".to_string()],
+            ..Default::default()
+        };
+        let (out, count) = replace("(第.章) a", "${1} 风起", &by_element)?;
+        assert_eq!(count, 1);
+        assert!(out.contains("第一章 风起"));
 
-        for path in files {
-            let target_path = temp_path.join(path);
-            if let Ok(content) = fs::read_to_string(target_path) {
-                if let Some(ref regex) = re {
-                    count += regex.find_iter(&content).count();
-                } else {
-                    count += content.matches(&pattern).count();
-                }
-            }
-        }
+        let temp = tempfile::tempdir().map_err(|e| e.to_string())?;
+        fs::write(temp.path().join("c1.xhtml"), CHAPTER).map_err(|e| e.to_string())?;
+        let report =
+            search_epub_text_nodes_in_dir(temp.path(), &["c1.xhtml".to_string()], "a", &by_class)?;
+        assert_eq!(report.total, 3);
+        let first = &report.files[0].matches[0];
+        assert_eq!(first.hit.span.start_line, 3);
+        assert_eq!(first.heading.as_deref(), Some("第一章 a"));
+        Ok(())
+    }
 
-        Ok(count)
-    })
-    .await
-    .map_err(|e| format!("任务失败: {}", e))?
+    #[test]
+    fn matches_decoded_text_and_maps_back_to_source() -> Result<(), String> {
+        let markup = "<p>Tom &amp; Jerry &quot;&#x4E2D;&quot; &copy;2020</p>";
+        let options = TextNodeSearchOptions::default();
+        let re = build_multiline_regex("Tom & Jerry", &options.search)?;
+        let mut hits = Vec::new();
+        let mut skipped = 0;
+        let out =
+            rewrite_text_node_matches(markup, &re, &options, Some("T<J"), &mut hits, &mut skipped)?;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].text, "Tom & Jerry");
+        assert_eq!(&markup[hits[0].start..hits[0].end], "Tom &amp; Jerry");
+        assert_eq!(out, "<p>T&lt;J &quot;&#x4E2D;&quot; &copy;2020</p>");
+
+        let re = build_multiline_regex("\"中\"", &options.search)?;
+        let mut hits = Vec::new();
+        rewrite_text_node_matches(markup, &re, &options, None, &mut hits, &mut skipped)?;
+        assert_eq!(&markup[hits[0].start..hits[0].end], "&quot;&#x4E2D;&quot;");
+
+        // &copy; 无法解码，与它交叠的匹配跳过并计数
+        let re = build_multiline_regex("copy", &options.search)?;
+        let mut hits = Vec::new();
+        let out =
+            rewrite_text_node_matches(markup, &re, &options, Some("x"), &mut hits, &mut skipped)?;
+        assert!(hits.is_empty());
+        assert_eq!(skipped, 1);
+        assert_eq!(out, markup);
+        Ok(())
+    }
 }
 
 #[tauri::command]
@@ -9995,6 +18627,37 @@ struct LibraryConfig {
     txt_ai_proofing: TxtAiProofingConfig,
     #[serde(default)]
     library_ai_match: LibraryAiMatchConfig,
+    #[serde(default)]
+    history_retention: HistoryRetentionConfig,
+    #[serde(default)]
+    epub_history_retention: HistoryRetentionConfig,
+}
+
+/// 历史快照（TXT）与检查点（EPUB）的保留策略，按单个文件计算；各项为 0 表示不限。
+/// 最新一份无论如何都保留。
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct HistoryRetentionConfig {
+    #[serde(default = "default_history_max_count")]
+    max_count: usize,
+    #[serde(default)]
+    max_age_days: u64,
+    #[serde(default)]
+    max_total_mb: u64,
+}
+
+impl Default for HistoryRetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_count: default_history_max_count(),
+            max_age_days: 0,
+            max_total_mb: 0,
+        }
+    }
+}
+
+fn default_history_max_count() -> usize {
+    10
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -10895,6 +19558,8 @@ struct MobileMakeEpubResult {
     title: String,
     chapter_count: usize,
     word_count: usize,
+    // 制作时的非致命问题，如未找到的插图
+    warnings: Vec<String>,
 }
 
 impl From<EpubParsedMeta> for MobileEpubMetadata {
@@ -11879,6 +20544,7 @@ fn write_opf_metadata(
     maker: &str,
     series: &str,
     tags: &[String],
+    ext: Option<&OpfMetadataExt>,
 ) -> String {
     let mut s = opf.to_string();
 
@@ -11919,9 +20585,494 @@ fn write_opf_metadata(
     s = replace_meta_by_name(&s, "calibre:series", series);
     s = replace_meta_by_name(&s, "maker", maker);
 
+    // 扩展字段整体覆盖；系列名以 series 参数为准
+    if let Some(ext) = ext {
+        s = apply_opf_metadata_ext(
+            &s,
+            &OpfMetadataExt {
+                series: series.to_string(),
+                ..ext.clone()
+            },
+        );
+    }
+
     s
 }
 
+fn opf_is_epub3(opf: &str) -> bool {
+    Regex::new(r#"<package\b[^>]*\sversion="3"#)
+        .ok()
+        .and_then(|re| re.is_match(opf).ok())
+        .unwrap_or(false)
+}
+
+fn opf_attr(attrs: &str, name: &str) -> Option<String> {
+    let re = Regex::new(&format!(r#"(?:^|\s){}="([^"]*)""#, re_escape(name))).ok()?;
+    match re.captures(attrs) {
+        Ok(Some(c)) => c.get(1).map(|m| xml_unescape(m.as_str().trim())),
+        _ => None,
+    }
+}
+
+// EPUB 3 <meta refines="#id" property="...">值</meta>
+fn opf_refine(opf: &str, id: &str, property: &str) -> Option<String> {
+    let pat = format!(
+        r##"<meta\s+(?=[^>]*refines="#{}")(?=[^>]*property="{}")[^>]*>([\s\S]*?)</meta>"##,
+        re_escape(id),
+        re_escape(property)
+    );
+    let re = Regex::new(&pat).ok()?;
+    match re.captures(opf) {
+        Ok(Some(c)) => c
+            .get(1)
+            .map(|m| xml_unescape(m.as_str().trim()))
+            .filter(|v| !v.is_empty()),
+        _ => None,
+    }
+}
+
+fn format_series_index(index: f64) -> String {
+    // f64 的 Display 不带多余的 .0：1.0 → "1"，1.5 → "1.5"
+    format!("{}", index)
+}
+
+// 每个元素一行、四空格缩进；第一个人用 id_prefix 本身作 id，与旧版 id="creator" 兼容
+fn render_opf_people(
+    tag: &str,
+    id_prefix: &str,
+    people: &[OpfContributor],
+    default_role: &str,
+    epub3: bool,
+) -> String {
+    let mut out = String::new();
+    for (i, person) in people
+        .iter()
+        .filter(|p| !p.name.trim().is_empty())
+        .enumerate()
+    {
+        let id = if i == 0 {
+            id_prefix.to_string()
+        } else {
+            format!("{}{}", id_prefix, i + 1)
+        };
+        let name = xml_escape(person.name.trim());
+        let role = match person.role.trim() {
+            "" => default_role,
+            role => role,
+        };
+        let file_as = person.file_as.trim();
+        if epub3 {
+            out.push_str(&format!("    <{0} id=\"{1}\">{2}</{0}>\n", tag, id, name));
+            if !role.is_empty() {
+                out.push_str(&format!(
+                    "    <meta refines=\"#{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>\n",
+                    id,
+                    xml_escape(role)
+                ));
+            }
+            if !file_as.is_empty() {
+                out.push_str(&format!(
+                    "    <meta refines=\"#{}\" property=\"file-as\">{}</meta>\n",
+                    id,
+                    xml_escape(file_as)
+                ));
+            }
+        } else {
+            let mut attrs = format!(" id=\"{}\"", id);
+            if !role.is_empty() {
+                attrs.push_str(&format!(" opf:role=\"{}\"", xml_escape(role)));
+            }
+            if !file_as.is_empty() {
+                attrs.push_str(&format!(" opf:file-as=\"{}\"", xml_escape(file_as)));
+            }
+            out.push_str(&format!("    <{0}{1}>{2}</{0}>\n", tag, attrs, name));
+        }
+    }
+    out
+}
+
+fn render_opf_identifiers(identifiers: &[OpfIdentifier], epub3: bool) -> String {
+    let mut out = String::new();
+    for (i, identifier) in identifiers
+        .iter()
+        .filter(|ident| !ident.value.trim().is_empty())
+        .enumerate()
+    {
+        let scheme = identifier.scheme.trim();
+        let value = identifier.value.trim();
+        if epub3 {
+            // EPUB 3 没有 opf:scheme：ISBN 用 urn:isbn: 形式，其他类型写 identifier-type
+            let is_isbn = scheme.eq_ignore_ascii_case("isbn");
+            let value = if is_isbn && !value.starts_with("urn:") {
+                format!("urn:isbn:{}", value.replace(['-', ' '], ""))
+            } else {
+                value.to_string()
+            };
+            out.push_str(&format!(
+                "    <dc:identifier id=\"ident{}\">{}</dc:identifier>\n",
+                i + 1,
+                xml_escape(&value)
+            ));
+            if !is_isbn && !scheme.is_empty() {
+                out.push_str(&format!(
+                    "    <meta refines=\"#ident{}\" property=\"identifier-type\">{}</meta>\n",
+                    i + 1,
+                    xml_escape(scheme)
+                ));
+            }
+        } else if scheme.is_empty() {
+            out.push_str(&format!(
+                "    <dc:identifier id=\"ident{}\">{}</dc:identifier>\n",
+                i + 1,
+                xml_escape(value)
+            ));
+        } else {
+            out.push_str(&format!(
+                "    <dc:identifier id=\"ident{}\" opf:scheme=\"{}\">{}</dc:identifier>\n",
+                i + 1,
+                xml_escape(scheme),
+                xml_escape(value)
+            ));
+        }
+    }
+    out
+}
+
+fn render_opf_series(series: &str, index: Option<f64>, epub3: bool) -> String {
+    let series = series.trim();
+    if series.is_empty() {
+        return String::new();
+    }
+    let mut out = format!(
+        "    <meta name=\"calibre:series\" content=\"{}\"/>\n",
+        xml_escape(series)
+    );
+    if let Some(index) = index {
+        out.push_str(&format!(
+            "    <meta name=\"calibre:series_index\" content=\"{}\"/>\n",
+            format_series_index(index)
+        ));
+    }
+    if epub3 {
+        out.push_str(&format!(
+            "    <meta property=\"belongs-to-collection\" id=\"series\">{}</meta>\n    <meta refines=\"#series\" property=\"collection-type\">series</meta>\n",
+            xml_escape(series)
+        ));
+        if let Some(index) = index {
+            out.push_str(&format!(
+                "    <meta refines=\"#series\" property=\"group-position\">{}</meta>\n",
+                format_series_index(index)
+            ));
+        }
+    }
+    out
+}
+
+// 导出时写在 metadata 末尾的部分：语言、作者、日期由模板自己处理
+fn render_opf_metadata_ext(ext: &OpfMetadataExt, epub3: bool) -> String {
+    let mut out = render_opf_people(
+        "dc:contributor",
+        "contributor",
+        &ext.contributors,
+        "",
+        epub3,
+    );
+    out.push_str(&render_opf_identifiers(&ext.identifiers, epub3));
+    if !ext.rights.trim().is_empty() {
+        out.push_str(&format!(
+            "    <dc:rights>{}</dc:rights>\n",
+            xml_escape(ext.rights.trim())
+        ));
+    }
+    out.push_str(&render_opf_series(&ext.series, ext.series_index, epub3));
+    if !ext.original_title.trim().is_empty() {
+        out.push_str(&format!(
+            "    <meta name=\"original-title\" content=\"{}\"/>\n",
+            xml_escape(ext.original_title.trim())
+        ));
+    }
+    out
+}
+
+// 删除 element_pattern 匹配的元素（第 1 组为属性串）以及 refines 指向它们的 meta；id 为 keep_id 的保留
+fn remove_opf_refined_elements(opf: &str, element_pattern: &str, keep_id: Option<&str>) -> String {
+    let Ok(re) = Regex::new(element_pattern) else {
+        return opf.to_string();
+    };
+    let mut out = String::with_capacity(opf.len());
+    let mut cursor = 0;
+    let mut removed_ids = Vec::new();
+    for caps in re.captures_iter(opf).flatten() {
+        let Some(whole) = caps.get(0) else {
+            continue;
+        };
+        let id = opf_attr(caps.get(1).map_or("", |m| m.as_str()), "id");
+        if id.is_some() && id.as_deref() == keep_id {
+            continue;
+        }
+        out.push_str(&opf[cursor..whole.start()]);
+        cursor = whole.end();
+        removed_ids.extend(id);
+    }
+    out.push_str(&opf[cursor..]);
+    for id in removed_ids {
+        out = remove_all_matches(
+            &out,
+            &format!(
+                r##"\s*<meta\s+(?:[^>]*\s)?refines="#{}"[^>]*>[\s\S]*?</meta>"##,
+                re_escape(&id)
+            ),
+        );
+    }
+    out
+}
+
+fn dc_element_pattern(tag: &str) -> String {
+    format!(r#"\s*<{0}((?:\s[^>]*)?)>[\s\S]*?</{0}>"#, re_escape(tag))
+}
+
+const OPF_COLLECTION_PATTERN: &str =
+    r#"\s*<meta((?:\s[^>]*)?\sproperty="belongs-to-collection"[^>]*)>[\s\S]*?</meta>"#;
+
+// 写回已有 OPF：按包版本选择 EPUB 2 / 3 写法。空字段删除对应元素；
+// 作者列表为空时保留现有 dc:creator，语言、日期为空时不动。
+fn apply_opf_metadata_ext(opf: &str, ext: &OpfMetadataExt) -> String {
+    let epub3 = opf_is_epub3(opf);
+    let mut s = opf.to_string();
+    let insert = |s: String, block: String| {
+        if block.is_empty() {
+            s
+        } else {
+            insert_before_metadata_close(&s, &format!("{}  ", block))
+        }
+    };
+
+    if !ext.language.trim().is_empty() {
+        s = replace_or_insert_dc(&s, "dc:language", ext.language.trim());
+    }
+    if !ext.date.trim().is_empty() {
+        s = replace_or_insert_dc(&s, "dc:date", ext.date.trim());
+    }
+    if ext.creators.iter().any(|p| !p.name.trim().is_empty()) {
+        s = remove_opf_refined_elements(&s, &dc_element_pattern("dc:creator"), None);
+        let block = render_opf_people("dc:creator", "creator", &ext.creators, "aut", epub3);
+        s = insert(s, block);
+    }
+    s = remove_opf_refined_elements(&s, &dc_element_pattern("dc:contributor"), None);
+    let block = render_opf_people(
+        "dc:contributor",
+        "contributor",
+        &ext.contributors,
+        "",
+        epub3,
+    );
+    s = insert(s, block);
+
+    // 只动书籍 UUID 以外的标识符；找不到 unique-identifier 时不碰，免得删掉主标识
+    let unique_id = Regex::new(r#"<package\b[^>]*\sunique-identifier="([^"]+)""#)
+        .ok()
+        .and_then(|re| re.captures(&s).ok().flatten())
+        .and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
+    if let Some(unique_id) = unique_id {
+        s = remove_opf_refined_elements(&s, &dc_element_pattern("dc:identifier"), Some(&unique_id));
+        let block = render_opf_identifiers(&ext.identifiers, epub3);
+        s = insert(s, block);
+    }
+
+    s = remove_all_matches(&s, &dc_element_pattern("dc:rights"));
+    if !ext.rights.trim().is_empty() {
+        s = insert(
+            s,
+            format!(
+                "    <dc:rights>{}</dc:rights>\n",
+                xml_escape(ext.rights.trim())
+            ),
+        );
+    }
+
+    s = replace_meta_by_name(&s, "calibre:series", "");
+    s = replace_meta_by_name(&s, "calibre:series_index", "");
+    s = remove_opf_refined_elements(&s, OPF_COLLECTION_PATTERN, None);
+    let block = render_opf_series(&ext.series, ext.series_index, epub3);
+    s = insert(s, block);
+
+    replace_meta_by_name(&s, "original-title", &ext.original_title)
+}
+
+fn parse_opf_people(opf: &str, tag: &str) -> Vec<OpfContributor> {
+    let Ok(re) = Regex::new(&dc_element_pattern(tag)) else {
+        return Vec::new();
+    };
+    let mut people = Vec::new();
+    for caps in re.captures_iter(opf).flatten() {
+        let attrs = caps.get(1).map_or("", |m| m.as_str());
+        let Some(name) = caps
+            .get(0)
+            .and_then(|m| extract_first_tag(m.as_str(), tag))
+            .filter(|name| !name.is_empty())
+        else {
+            continue;
+        };
+        let id = opf_attr(attrs, "id");
+        let refine = |property: &str| id.as_deref().and_then(|id| opf_refine(opf, id, property));
+        people.push(OpfContributor {
+            name,
+            role: opf_attr(attrs, "opf:role")
+                .or_else(|| refine("role"))
+                .unwrap_or_default(),
+            file_as: opf_attr(attrs, "opf:file-as")
+                .or_else(|| refine("file-as"))
+                .unwrap_or_default(),
+        });
+    }
+    people
+}
+
+// 读回 apply_opf_metadata_ext / export_epub 写入的字段，两种版本的写法都认
+fn parse_opf_metadata_ext(opf: &str) -> OpfMetadataExt {
+    let unique_id = Regex::new(r#"<package\b[^>]*\sunique-identifier="([^"]+)""#)
+        .ok()
+        .and_then(|re| re.captures(opf).ok().flatten())
+        .and_then(|c| c.get(1).map(|m| m.as_str().to_string()));
+    let mut identifiers = Vec::new();
+    if let Ok(re) = Regex::new(&dc_element_pattern("dc:identifier")) {
+        for caps in re.captures_iter(opf).flatten() {
+            let attrs = caps.get(1).map_or("", |m| m.as_str());
+            if unique_id.is_some() && opf_attr(attrs, "id") == unique_id {
+                continue;
+            }
+            let Some(value) = caps
+                .get(0)
+                .and_then(|m| extract_first_tag(m.as_str(), "dc:identifier"))
+            else {
+                continue;
+            };
+            let id = opf_attr(attrs, "id");
+            let (scheme, value) = match value.strip_prefix("urn:isbn:") {
+                Some(isbn) => ("ISBN".to_string(), isbn.to_string()),
+                None => (
+                    opf_attr(attrs, "opf:scheme")
+                        .or_else(|| {
+                            id.as_deref()
+                                .and_then(|id| opf_refine(opf, id, "identifier-type"))
+                        })
+                        .unwrap_or_default(),
+                    value,
+                ),
+            };
+            identifiers.push(OpfIdentifier { scheme, value });
+        }
+    }
+
+    let collection = Regex::new(OPF_COLLECTION_PATTERN)
+        .ok()
+        .and_then(|re| re.captures(opf).ok().flatten());
+    let collection_id = collection
+        .as_ref()
+        .and_then(|c| opf_attr(c.get(1).map_or("", |m| m.as_str()), "id"));
+    let series = extract_meta_by_name(opf, "calibre:series")
+        .or_else(|| {
+            collection
+                .as_ref()
+                .and_then(|c| c.get(0))
+                .and_then(|m| extract_first_tag(m.as_str(), "meta"))
+        })
+        .unwrap_or_default();
+    let series_index = extract_meta_by_name(opf, "calibre:series_index")
+        .or_else(|| {
+            collection_id
+                .as_deref()
+                .and_then(|id| opf_refine(opf, id, "group-position"))
+        })
+        .and_then(|index| index.parse::<f64>().ok());
+
+    OpfMetadataExt {
+        language: extract_first_tag(opf, "dc:language").unwrap_or_default(),
+        creators: parse_opf_people(opf, "dc:creator"),
+        contributors: parse_opf_people(opf, "dc:contributor"),
+        series,
+        series_index,
+        identifiers,
+        date: extract_first_tag(opf, "dc:date").unwrap_or_default(),
+        rights: extract_first_tag(opf, "dc:rights").unwrap_or_default(),
+        original_title: extract_meta_by_name(opf, "original-title").unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod opf_metadata_tests {
+    use super::*;
+
+    const EPUB3_OPF: &str = r##"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="BookId" version="3.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="BookId">urn:uuid:1</dc:identifier>
+    <dc:identifier id="old">urn:isbn:111</dc:identifier>
+    <dc:title>书</dc:title>
+    <dc:creator id="c1">旧作者</dc:creator>
+    <meta refines="#c1" property="role" scheme="marc:relators">aut</meta>
+    <meta property="belongs-to-collection" id="coll">旧系列</meta>
+    <meta refines="#coll" property="group-position">1</meta>
+    <dc:rights>旧</dc:rights>
+  </metadata>
+</package>"##;
+
+    fn write(opf: &str, series: &str, ext: Option<&OpfMetadataExt>) -> String {
+        write_opf_metadata(opf, "书", "", "", "", None, "", "", series, &[], ext)
+    }
+
+    #[test]
+    fn write_back_replaces_epub3_refines_and_keeps_book_id() {
+        let ext = OpfMetadataExt {
+            creators: vec![OpfContributor {
+                name: "新作者".to_string(),
+                file_as: "Xin".to_string(),
+                ..Default::default()
+            }],
+            identifiers: vec![OpfIdentifier {
+                scheme: "DOI".to_string(),
+                value: "10.1000/1".to_string(),
+            }],
+            series_index: Some(3.0),
+            ..Default::default()
+        };
+        let opf = write(EPUB3_OPF, "新系列", Some(&ext));
+        assert!(opf.contains(r#"<dc:identifier id="BookId">urn:uuid:1</dc:identifier>"#));
+        assert!(!opf.contains("旧作者") && !opf.contains("#c1"));
+        assert!(!opf.contains("旧系列") && !opf.contains("#coll"));
+        assert!(!opf.contains("<dc:rights>"));
+        let parsed = parse_opf_metadata_ext(&opf);
+        assert_eq!(parsed.creators[0].name, "新作者");
+        assert_eq!(parsed.creators[0].role, "aut");
+        assert_eq!(parsed.creators[0].file_as, "Xin");
+        assert_eq!(parsed.series, "新系列");
+        assert_eq!(parsed.series_index, Some(3.0));
+        assert_eq!(parsed.identifiers, ext.identifiers);
+        // 再写一次不应重复
+        let again = write(&opf, "新系列", Some(&ext));
+        assert_eq!(again.matches("belongs-to-collection").count(), 1);
+        assert_eq!(again.matches("<dc:identifier").count(), 2);
+    }
+
+    #[test]
+    fn write_back_without_ext_leaves_extended_fields_alone() {
+        let opf = write(EPUB3_OPF, "旧系列", None);
+        assert!(opf.contains("<dc:rights>旧</dc:rights>"));
+        assert!(opf.contains("#c1"));
+        assert_eq!(parse_opf_metadata_ext(&opf).series_index, Some(1.0));
+    }
+}
+
+#[tauri::command]
+async fn read_epub_opf_metadata(epub_path: String) -> Result<OpfMetadataExt, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (_opf_path, opf_xml) = read_opf_from_epub(Path::new(&epub_path))?;
+        Ok(parse_opf_metadata_ext(&opf_xml))
+    })
+    .await
+    .map_err(|e| format!("读取 EPUB 元数据任务失败: {}", e))?
+}
+
 // 读 epub 内 OPF 文本及其在 zip 内的路径（解析 container.xml）
 fn read_opf_from_epub(epub_path: &Path) -> Result<(String, String), String> {
     let file = fs::File::open(epub_path).map_err(|e| format!("无法打开 EPUB: {}", e))?;
@@ -12108,18 +21259,22 @@ fn mobile_default_chapter_rules() -> Vec<RegexRule> {
         RegexRule {
             level: 1,
             pattern: "^\\s*(?:内容简介|本书相关|完本感言)\\s*(?:[:：].*)?$".to_string(),
+            ..Default::default()
         },
         RegexRule {
             level: 1,
             pattern: "^\\s*(?:第\\s*[零〇一二两三四五六七八九十百千万0-9]+\\s*卷|卷\\s*[零〇一二两三四五六七八九十百千万0-9]+)(?:\\s+|[:：、.．\\-—]+)\\S+.*".to_string(),
+            ..Default::default()
         },
         RegexRule {
             level: 3,
             pattern: "^\\s*(?:简介|序(?:章|言)?|前言|楔子|后记|尾声)\\s*(?:[:：].*)?$".to_string(),
+            ..Default::default()
         },
         RegexRule {
             level: 3,
             pattern: "^\\s*(?:第\\s*[一二两三四五六七八九十零〇百千万0-9]+\\s*(?:[章节]|回(?:[^合]|$))|Chapter\\s*\\d+|终章(?:\\s+|[:：、.．\\-—])\\S+|(?:新增\\s*)?(?:番外|后日谈)(?:\\s+|[:：、.．\\-—])\\S+|【\\s*(?:番外|后日谈)\\s*】\\s*\\S+).*".to_string(),
+            ..Default::default()
         },
     ]
 }
@@ -12237,6 +21392,7 @@ async fn mobile_make_epub(
             level: 3,
             is_meta: false,
             word_count: content.chars().filter(|c| !c.is_whitespace()).count(),
+            rule_index: None,
         });
     }
 
@@ -12254,7 +21410,7 @@ async fn mobile_make_epub(
         uuid.trim().trim_start_matches("urn:uuid:").to_string()
     };
 
-    export_epub(
+    let warnings = export_epub(
         out_path.to_string_lossy().to_string(),
         content.clone(),
         chapters,
@@ -12271,6 +21427,12 @@ async fn mobile_make_epub(
             font_css: String::new(),
             subset_fonts: false,
             assets: Vec::new(),
+            source_path: source_path.clone(),
+            notes: NoteExportOptions::default(),
+            epub_version: String::new(),
+            split_max_kb: 0,
+            split_max_paragraphs: 0,
+            opf: OpfMetadataExt::default(),
             extra: HashMap::new(),
         },
     )
@@ -12281,6 +21443,7 @@ async fn mobile_make_epub(
         title: book_title,
         chapter_count,
         word_count,
+        warnings,
     })
 }
 
@@ -12349,6 +21512,7 @@ async fn mobile_update_epub_metadata(
             metadata.maker.trim(),
             metadata.series.trim(),
             &tags,
+            None,
         );
 
         let temp_root = {
@@ -12579,6 +21743,8 @@ async fn update_book_metadata(
     series: String,
     tags: Vec<String>,
     epub_uuid: String,
+    // 扩展 OPF 字段；不传则不改动这些元素
+    opf: Option<OpfMetadataExt>,
 ) -> Result<BookEntry, String> {
     let mut data = read_library_data(&app)?;
     let idx = data
@@ -12617,6 +21783,7 @@ async fn update_book_metadata(
                 &entry.maker,
                 &entry.series,
                 entry.tags.as_deref().unwrap_or(&[]),
+                opf.as_ref(),
             );
 
             // 默认不动 dcterms:modified；仅当 config.updateModifiedOnEdit=true 才覆盖为现在
@@ -13314,6 +22481,61 @@ mod toolbox_tests {
         assert!(css.contains("url('../Images/cover.png')"), "{}", css);
         Ok(())
     }
+
+    #[test]
+    fn toolbox_chinese_convert_rewrites_text_nodes_toc_and_metadata() -> Result<(), String> {
+        let temp = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let source = temp.path().join("book.epub");
+        let file = fs::File::create(&source).map_err(|e| e.to_string())?;
+        let mut writer = zip::ZipWriter::new(file);
+        write_zip_entry(&mut writer, "mimetype", b"application/epub+zip")?;
+        write_zip_entry(
+            &mut writer,
+            "META-INF/container.xml",
+            br#"<container><rootfiles><rootfile full-path="OPS/content.opf"/></rootfiles></container>"#,
+        )?;
+        write_zip_entry(
+            &mut writer,
+            "OPS/content.opf",
+            "<package><metadata><dc:title>头发</dc:title></metadata><manifest/></package>"
+                .as_bytes(),
+        )?;
+        write_zip_entry(
+            &mut writer,
+            "OPS/toc.ncx",
+            "<ncx><navLabel><text>第一章 开始</text></navLabel></ncx>".as_bytes(),
+        )?;
+        write_zip_entry(
+            &mut writer,
+            "OPS/Text/c1.xhtml",
+            r#"<html><body><p class="说">后来他说</p></body></html>"#.as_bytes(),
+        )?;
+        write_zip_entry(&mut writer, "OPS/Styles/main.css", "p { }".as_bytes())?;
+        writer.finish().map_err(|e| e.to_string())?;
+
+        let converted = toolbox_chinese_convert_impl(&source, "s2t")?;
+        assert!(converted.changed);
+        assert_eq!(converted.action, "chinese_s2t");
+        let converted_path = PathBuf::from(&converted.output_path);
+        let opf = read_epub_entry(&converted_path, "OPS/content.opf")?;
+        assert!(opf.contains("<dc:title>頭髮</dc:title>"), "{}", opf);
+        let ncx = read_epub_entry(&converted_path, "OPS/toc.ncx")?;
+        assert!(ncx.contains("第一章 開始"), "{}", ncx);
+        let chapter = read_epub_entry(&converted_path, "OPS/Text/c1.xhtml")?;
+        assert!(
+            chapter.contains(r#"<p class="说">後來他說</p>"#),
+            "{}",
+            chapter
+        );
+        assert_eq!(
+            read_epub_entry(&converted_path, "mimetype")?,
+            "application/epub+zip"
+        );
+
+        let again = toolbox_chinese_convert_impl(&converted_path, "s2t")?;
+        assert!(!again.changed);
+        Ok(())
+    }
 }
 
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -13408,6 +22630,7 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             read_text_file,
+            read_text_file_with_report,
             save_text_file,
             read_binary_file,
             search_book_covers,
@@ -13432,6 +22655,55 @@ pub fn run() {
             scan_chapters,
             advanced_search,
             advanced_replace,
+            open_text_buffer,
+            get_text_buffer_info,
+            read_text_buffer_lines,
+            scan_text_buffer_chapters,
+            search_text_buffer,
+            apply_text_buffer_patches,
+            save_text_buffer,
+            close_text_buffer,
+            infer_chapter_rules,
+            infer_text_buffer_chapter_rules,
+            check_chapter_numbering,
+            normalize_chapter_titles,
+            normalize_text_buffer_chapter_titles,
+            reflow_text,
+            reflow_text_buffer,
+            convert_chinese_text,
+            convert_text_buffer_chinese,
+            convert_epub_chinese,
+            normalize_punctuation,
+            normalize_text_buffer_punctuation,
+            normalize_epub_punctuation,
+            advanced_search_multiline,
+            search_text_buffer_multiline,
+            preview_replace,
+            apply_replace,
+            preview_text_buffer_replace,
+            apply_text_buffer_replace,
+            list_replace_rule_sets,
+            save_replace_rule_sets,
+            delete_replace_rule_set,
+            import_replace_rule_sets,
+            export_replace_rule_sets,
+            apply_replace_rule_set_to_text,
+            apply_replace_rule_set_to_text_buffer,
+            apply_replace_rule_set_to_epub,
+            search_in_files_detailed,
+            replace_in_files,
+            search_epub_text_nodes,
+            replace_epub_text_nodes,
+            diff_history,
+            restore_history_hunks,
+            read_history_snapshot,
+            list_epub_checkpoints,
+            diff_epub_checkpoint,
+            restore_epub_checkpoint,
+            text_statistics,
+            text_buffer_statistics,
+            epub_statistics,
+            read_epub_opf_metadata,
             export_epub,
             extract_epub,
             load_epub_file_meta,
@@ -13463,6 +22735,7 @@ pub fn run() {
             toolbox_font_decrypt,
             toolbox_epub_reformat,
             toolbox_image_convert,
+            toolbox_chinese_convert,
             toolbox_generate_ai_image,
             toolbox_epub_diagnose,
             toolbox_scan_batch_inputs,